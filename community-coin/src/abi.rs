@@ -44,4 +44,31 @@ pub enum Abi {
     /// * `value_ptr` - A pointer to the value in the contract's memory.
     /// * `value_len` - The length of the value.
     SetStorage,
+    /// Delete the contract once the current call returns, moving its
+    /// remaining balance to a beneficiary. Its code and storage are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `beneficiary_ptr` - A pointer to the beneficiary's address in the contract's memory.
+    /// * `beneficiary_len` - The length of the beneficiary's address.
+    SelfDestruct,
+}
+
+impl Abi {
+    /// The name contracts import this function under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Abi::GetBalance => "get_balance",
+            Abi::Transfer => "transfer",
+            Abi::GetStorage => "get_storage",
+            Abi::SetStorage => "set_storage",
+            Abi::SelfDestruct => "destroy",
+        }
+    }
+
+    /// Whether the call changes storage or balances, and so traps in a
+    /// view call.
+    pub fn mutates_state(&self) -> bool {
+        matches!(self, Abi::Transfer | Abi::SetStorage | Abi::SelfDestruct)
+    }
 }
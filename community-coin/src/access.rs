@@ -0,0 +1,74 @@
+//! Optional allowlist/denylist restricting which accounts may transact.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::RESERVED_ADDRESSES;
+
+/// Which accounts may send or receive transactions. Reserved system
+/// accounts are always permitted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessList {
+    /// When set, only these accounts may transact
+    pub allowlist: Option<BTreeSet<String>>,
+    /// Accounts that may never transact, even if allowlisted
+    pub denylist: BTreeSet<String>,
+}
+
+impl AccessList {
+    /// Build from comma-separated address lists, e.g. the `ALLOWLIST` and
+    /// `DENYLIST` environment variables. A missing allowlist allows everyone.
+    pub fn parse(allowlist: Option<&str>, denylist: Option<&str>) -> Self {
+        let split = |list: &str| -> BTreeSet<String> {
+            list.split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        AccessList {
+            allowlist: allowlist.map(split),
+            denylist: denylist.map(split).unwrap_or_default(),
+        }
+    }
+
+    /// Reject `address` if it is denied or missing from an active allowlist
+    pub fn check(&self, address: &str) -> Result<(), String> {
+        if RESERVED_ADDRESSES.contains(&address) {
+            return Ok(());
+        }
+        if self.denylist.contains(address) {
+            return Err(format!("Address {} is denylisted", address));
+        }
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.contains(address) {
+                return Err(format!("Address {} is not on the allowlist", address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `address` to the allowlist, switching to allowlist-only mode if
+    /// no allowlist was configured
+    pub fn allow(&mut self, address: String) {
+        self.allowlist.get_or_insert_with(BTreeSet::new).insert(address);
+    }
+
+    /// Remove `address` from the allowlist. The allowlist stays active even
+    /// when emptied.
+    pub fn disallow(&mut self, address: &str) {
+        if let Some(allowlist) = &mut self.allowlist {
+            allowlist.remove(address);
+        }
+    }
+
+    pub fn deny(&mut self, address: String) {
+        self.denylist.insert(address);
+    }
+
+    pub fn undeny(&mut self, address: &str) {
+        self.denylist.remove(address);
+    }
+}
@@ -0,0 +1,113 @@
+//! Conversion between base-unit amounts and human-readable decimal strings.
+
+/// Number of decimals used when a chain does not specify one.
+pub const DEFAULT_DECIMALS: u8 = 0;
+
+/// Largest supported number of decimals (`10^19` is the largest power of ten in a `u64`).
+pub const MAX_DECIMALS: u8 = 19;
+
+/// Format a base-unit amount as a decimal string, e.g. `1000000` with 6
+/// decimals becomes `"1.000000"`.
+pub fn to_display(amount: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+
+    // Past 38 decimals the scale overflows even a u128, and every amount
+    // is all fraction
+    let (whole, fraction) = match 10u128.checked_pow(decimals as u32) {
+        Some(scale) => (amount as u128 / scale, amount as u128 % scale),
+        None => (0, amount as u128),
+    };
+    format!(
+        "{}.{:0width$}",
+        whole,
+        fraction,
+        width = decimals as usize
+    )
+}
+
+/// Parse a decimal string into base units, rejecting input with more
+/// fractional digits than `decimals` allows.
+pub fn from_display(value: &str, decimals: u8) -> Result<u64, String> {
+    if decimals > MAX_DECIMALS {
+        return Err(format!("Unsupported decimals: {}", decimals));
+    }
+
+    let value = value.trim();
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (value, ""),
+    };
+
+    if whole.is_empty() && fraction.is_empty() {
+        return Err("Amount is empty".to_string());
+    }
+    if !whole.chars().all(|c| c.is_ascii_digit()) || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid amount: {}", value));
+    }
+    if fraction.len() > decimals as usize {
+        return Err(format!(
+            "Amount {} has more than {} decimal places",
+            value, decimals
+        ));
+    }
+
+    let scale = 10u64.pow(decimals as u32);
+    let whole_units = if whole.is_empty() {
+        0
+    } else {
+        whole
+            .parse::<u64>()
+            .map_err(|_| format!("Amount too large: {}", value))?
+    };
+    let fraction_units = if fraction.is_empty() {
+        0
+    } else {
+        let padded = format!("{:0<width$}", fraction, width = decimals as usize);
+        padded
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid amount: {}", value))?
+    };
+
+    whole_units
+        .checked_mul(scale)
+        .and_then(|units| units.checked_add(fraction_units))
+        .ok_or_else(|| format!("Amount too large: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_six_decimals() {
+        let units = from_display("1.000000", 6).unwrap();
+        assert_eq!(units, 1_000_000);
+        assert_eq!(to_display(units, 6), "1.000000");
+
+        assert_eq!(from_display("0.5", 6).unwrap(), 500_000);
+        assert_eq!(to_display(1_234_567, 6), "1.234567");
+    }
+
+    #[test]
+    fn test_display_with_more_decimals_than_a_u64_holds() {
+        assert_eq!(to_display(u64::MAX, 19), "1.8446744073709551615");
+        assert_eq!(to_display(5, 20), "0.00000000000000000005");
+        assert_eq!(to_display(5, 40), format!("0.{}5", "0".repeat(39)));
+    }
+
+    #[test]
+    fn test_rejects_over_precise_input() {
+        assert!(from_display("1.0000001", 6).is_err());
+        assert!(from_display("0.1", 0).is_err());
+        assert!(from_display("1.2.3", 6).is_err());
+        assert!(from_display("-1", 6).is_err());
+    }
+
+    #[test]
+    fn test_zero_decimals() {
+        assert_eq!(to_display(1000, 0), "1000");
+        assert_eq!(from_display("1000", 0).unwrap(), 1000);
+    }
+}
@@ -1,10 +1,25 @@
 //! A command-line interface for the Community Coin blockchain.
 
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use clap::Parser;
+use community_coin::amount::{from_display, to_display, DEFAULT_DECIMALS};
+use community_coin::blockchain::{SigScheme, Transaction, MIN_FEE};
+use community_coin::snapshot::parse_signing_key;
+use ed25519_dalek::{Signer, SigningKey};
+use reqwest::StatusCode;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Number of decimals the chain uses for display amounts
+    #[arg(long, global = true, default_value_t = DEFAULT_DECIMALS)]
+    decimals: u8,
+
+    /// Base URL of the node to talk to
+    #[arg(long, global = true, default_value = "http://localhost:8000")]
+    node: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -16,26 +31,284 @@ enum Commands {
         #[arg(short, long)]
         address: String,
     },
-    /// Send coins to another address
+    /// Send coins to another address, signed with an ed25519 key whose
+    /// public key is the sending address
+    #[command(alias = "send")]
     Transfer {
-        #[arg(short, long)]
-        from: String,
+        /// Hex-encoded 32-byte key seed
+        #[arg(long)]
+        private_key: String,
         #[arg(short, long)]
         to: String,
+        /// Amount in display units, e.g. `1.5`
         #[arg(short, long)]
-        amount: u64,
+        amount: String,
+        /// Fee in base units
+        #[arg(long, default_value_t = MIN_FEE)]
+        fee: u64,
+        /// Seconds to wait for the node before checking whether the
+        /// transfer landed and resending it
+        #[arg(long, default_value_t = 10)]
+        timeout_secs: u64,
+        /// Resends after a timeout before giving up
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
     },
 }
 
-fn main() {
+/// Why a submission didn't go through
+#[derive(Debug)]
+enum SubmitError {
+    /// No answer in time. The node may still have queued the transaction.
+    Timeout,
+    /// The node refused the transaction, or couldn't be reached
+    Failed(String),
+}
+
+/// Nonce the node expects on `address`'s next transaction
+async fn fetch_nonce(client: &reqwest::Client, node: &str, address: &str) -> Result<u64, String> {
+    let response: serde_json::Value = client
+        .get(format!("{}/nonce/{}", node, address))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch nonce: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("Failed to fetch nonce: {}", e))?;
+    response["nonce"]
+        .as_u64()
+        .ok_or_else(|| format!("Failed to fetch nonce: {}", response))
+}
+
+/// Whether the node has `tx_id`, pending or confirmed
+async fn transaction_known(client: &reqwest::Client, node: &str, tx_id: &str) -> Result<bool, String> {
+    let response = client
+        .get(format!("{}/transaction/{}", node, tx_id))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to look up {}: {}", tx_id, e))?;
+    match response.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        status => Err(format!("Failed to look up {}: HTTP {}", tx_id, status)),
+    }
+}
+
+async fn submit(client: &reqwest::Client, node: &str, tx: &Transaction) -> Result<(), SubmitError> {
+    let response = client
+        .post(format!("{}/submit-tx", node))
+        .json(tx)
+        .send()
+        .await
+        .map_err(|e| if e.is_timeout() { SubmitError::Timeout } else { SubmitError::Failed(e.to_string()) })?;
+    let status = response.status();
+    let body: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| if e.is_timeout() { SubmitError::Timeout } else { SubmitError::Failed(e.to_string()) })?;
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(SubmitError::Failed(body["error"].as_str().unwrap_or("rejected").to_string()))
+    }
+}
+
+/// Submit `tx`, resending it after a timeout up to `retries` times. Before
+/// each resend the node is asked whether an earlier attempt landed, and a
+/// resend carries the same id and nonce, so the transfer is queued at most
+/// once.
+async fn send_transaction(
+    client: &reqwest::Client,
+    node: &str,
+    tx: &Transaction,
+    retries: u32,
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        match submit(client, node, tx).await {
+            Ok(()) => return Ok(()),
+            Err(SubmitError::Failed(e)) if attempt == 0 => return Err(e),
+            // A resend is refused once an earlier attempt got through
+            Err(SubmitError::Failed(e)) => {
+                return match transaction_known(client, node, &tx.tx_id).await? {
+                    true => Ok(()),
+                    false => Err(e),
+                }
+            }
+            Err(SubmitError::Timeout) => {
+                if transaction_known(client, node, &tx.tx_id).await? {
+                    return Ok(());
+                }
+                if attempt == retries {
+                    return Err(format!("No answer from {} after {} attempts", node, attempt + 1));
+                }
+            }
+        }
+        attempt += 1;
+        println!("⚠ Submission timed out, resending {} (attempt {})", tx.tx_id, attempt + 1);
+    }
+}
+
+/// A transfer from `key`'s address, signed and identified by its content
+fn build_transfer(key: &SigningKey, to: String, amount: u64, fee: u64, nonce: u64) -> Transaction {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut tx = Transaction {
+        from: hex::encode(key.verifying_key().as_bytes()),
+        to,
+        amount,
+        fee,
+        timestamp,
+        tx_id: String::new(),
+        signature: String::new(),
+        nonce,
+        memo: None,
+        execute_at_height: None,
+        recent_block_hash: None,
+        sig_scheme: SigScheme::Ed25519,
+        contract: None,
+    };
+    tx.tx_id = tx.content_id();
+    tx.signature = hex::encode(key.sign(&tx.signing_payload()).to_bytes());
+    tx
+}
+
+#[tokio::main]
+async fn main() {
     let args = Args::parse();
 
     match &args.command {
         Commands::Balance { address } => {
             println!("Getting balance for address: {}", address);
         }
-        Commands::Transfer { from, to, amount } => {
-            println!("Transferring {} from {} to {}", amount, from, to);
+        Commands::Transfer {
+            private_key,
+            to,
+            amount,
+            fee,
+            timeout_secs,
+            retries,
+        } => {
+            let units = match from_display(amount, args.decimals) {
+                Ok(units) => units,
+                Err(e) => {
+                    eprintln!("Invalid amount: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let key = match parse_signing_key(private_key) {
+                Ok(key) => key,
+                Err(e) => {
+                    eprintln!("Invalid private key: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let from = hex::encode(key.verifying_key().as_bytes());
+            println!(
+                "Transferring {} ({} base units) from {} to {}",
+                to_display(units, args.decimals),
+                units,
+                from,
+                to
+            );
+
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(*timeout_secs))
+                .build()
+                .unwrap();
+            let result = match fetch_nonce(&client, &args.node, &from).await {
+                Ok(nonce) => {
+                    let tx = build_transfer(&key, to.clone(), units, *fee, nonce);
+                    send_transaction(&client, &args.node, &tx, *retries).await.map(|_| tx.tx_id)
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(tx_id) => println!("✓ Submitted {}", tx_id),
+                Err(e) => {
+                    eprintln!("Transfer failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::extract::{Path, State};
+    use axum::http::StatusCode;
+    use axum::routing::{get, post};
+    use axum::Json;
+    use community_coin::blockchain::CommunityBlockchain;
+
+    type Node = (Arc<CommunityBlockchain>, Arc<AtomicUsize>);
+
+    #[tokio::test]
+    async fn test_send_retries_timeout_without_duplicating() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sender = hex::encode(key.verifying_key().as_bytes());
+        let mut initial = HashMap::new();
+        initial.insert(sender.clone(), 1000);
+        let blockchain = Arc::new(CommunityBlockchain::new_in_memory(initial).unwrap());
+        let submissions = Arc::new(AtomicUsize::new(0));
+
+        // The first submission is lost, the second is queued but answered
+        // too late
+        let app = axum::Router::new()
+            .route(
+                "/nonce/:address",
+                get(|State((node, _)): State<Node>, Path(address): Path<String>| async move {
+                    Json(serde_json::json!({"nonce": node.next_nonce(&address)}))
+                }),
+            )
+            .route(
+                "/transaction/:tx_id",
+                get(|State((node, _)): State<Node>, Path(tx_id): Path<String>| async move {
+                    match node.find_transaction(&tx_id) {
+                        Some(_) => StatusCode::OK,
+                        None => StatusCode::NOT_FOUND,
+                    }
+                }),
+            )
+            .route(
+                "/submit-tx",
+                post(|State((node, submissions)): State<Node>, Json(tx): Json<Transaction>| async move {
+                    let attempt = submissions.fetch_add(1, Ordering::SeqCst);
+                    let result = match attempt {
+                        0 => Ok(()),
+                        _ => node.accept_transaction(tx),
+                    };
+                    if attempt < 2 {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                    }
+                    match result {
+                        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"success": true}))),
+                        Err(e) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": e}))),
+                    }
+                }),
+            )
+            .with_state((blockchain.clone(), submissions.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let nonce = fetch_nonce(&client, &node, &sender).await.unwrap();
+        assert_eq!(nonce, 1);
+        let tx = build_transfer(&key, "bob".to_string(), 100, MIN_FEE, nonce);
+        send_transaction(&client, &node, &tx, 3).await.unwrap();
+
+        // The second attempt was found on lookup, so no third was sent
+        assert_eq!(submissions.load(Ordering::SeqCst), 2);
+        assert_eq!(blockchain.get_pending(), vec![tx]);
+        assert_eq!(fetch_nonce(&client, &node, &sender).await.unwrap(), 2);
+    }
+}
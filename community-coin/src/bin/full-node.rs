@@ -1,44 +1,93 @@
 //! A full node for the Community Coin sovereign rollup.
 
-use community_coin::blockchain::CommunityBlockchain;
-use std::collections::HashMap;
+use clap::Parser;
+use community_coin::blockchain::{CommunityBlockchain, DEFAULT_PRUNE_DEPTH};
+use community_coin::gossip::{self, RebroadcastConfig};
+use community_coin::p2p::{self, NetworkService, P2pArgs};
+use community_coin::peers::PeerManager;
+use community_coin::startup::NodeArgs;
 use std::error::Error;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+#[derive(Parser, Debug)]
+struct FullNodeArgs {
+    #[command(flatten)]
+    node: NodeArgs,
+
+    #[command(flatten)]
+    p2p: P2pArgs,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let FullNodeArgs { node: args, p2p } = FullNodeArgs::parse();
     println!("Initializing Community Coin Full Node...\n");
 
+    let config = args.node_config()?;
+    let store = config.storage.backend.open(&config.storage.db_path)?;
+    let keypair = p2p::node_keypair(store.as_ref())?;
+
     // Load or create blockchain
-    let blockchain = match CommunityBlockchain::load("blockchain_state") {
+    let blockchain = match CommunityBlockchain::load_from_store(store.clone()) {
         Ok(bc) => {
+            args.check_loaded(&bc)?;
             println!("✓ Loaded existing blockchain from disk");
             bc
         }
         Err(_) => {
             println!("✓ Creating new blockchain");
-            let mut initial = HashMap::new();
+            CommunityBlockchain::from_genesis(&args.genesis.genesis()?, store)?
+        }
+    };
+
+    let blockchain = config.apply(blockchain);
 
-            // Initialize 50 demo users
-            for i in 1..=50 {
-                initial.insert(format!("user_{}", i), 1000u64);
-            }
+    // Full nodes prune old block bodies unless started with --archive
+    let blockchain = match args.pruning(Some(DEFAULT_PRUNE_DEPTH)) {
+        Some(depth) => blockchain.with_block_pruning(depth),
+        None => blockchain,
+    };
 
-            // Add named accounts
-            initial.insert("alice".to_string(), 10000);
-            initial.insert("bob".to_string(), 5000);
-            initial.insert("charlie".to_string(), 3000);
+    let genesis = blockchain
+        .genesis_hash()
+        .ok_or("Genesis hash unknown, start from a snapshot that carries it")?;
+    let blocks = blockchain.subscribe_blocks();
+    let blockchain = Arc::new(RwLock::new(blockchain));
 
-            CommunityBlockchain::new(initial, "blockchain_state")?
+    let peers = Arc::new(PeerManager::new(config.network.peer_limits()));
+    let mut network = NetworkService::new(
+        keypair,
+        genesis,
+        config.network.gossip_limits(),
+        peers,
+        !p2p.no_mdns,
+    )?;
+    let address = network
+        .listen(format!("/ip4/0.0.0.0/tcp/{}", p2p.p2p_port).parse()?)
+        .await?;
+    println!("✓ Listening for peers on {}/p2p/{}", address, network.peer_id());
+    for peer in p2p.bootstrap {
+        if let Err(e) = network.dial(peer.clone()) {
+            println!("⚠ Failed to dial {}: {}", peer, e);
         }
-    };
+    }
 
-    let _blockchain = Arc::new(RwLock::new(blockchain));
+    // Announce every block the chain accepts, and keep re-announcing
+    // transactions that sit in the mempool
+    let sink = network.sink();
+    tokio::spawn(gossip::run_rebroadcast(
+        blockchain.clone(),
+        sink.clone(),
+        RebroadcastConfig::default(),
+    ));
+    tokio::spawn(gossip::announce_blocks(blocks, sink));
 
-    // The API server is defined in `main.rs`.
-    // We need to figure out how to share the server logic.
-    // For now, we'll just print a message.
-    println!("Full node running. API server not yet implemented in this binary.");
+    println!("Full node running. Press Ctrl+C to stop.");
+    tokio::select! {
+        _ = network.run(blockchain) => {}
+        _ = tokio::signal::ctrl_c() => println!("Shutting down"),
+    }
 
     Ok(())
 }
@@ -1,38 +1,34 @@
 //! A sequencer for the Community Coin sovereign rollup.
 
+use clap::Parser;
 use community_coin::blockchain::CommunityBlockchain;
-use std::collections::HashMap;
+use community_coin::startup::NodeArgs;
 use std::error::Error;
 use std::sync::{Arc, RwLock};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args = NodeArgs::parse();
     println!("Initializing Community Coin Sequencer...\n");
 
+    let config = args.node_config()?;
+    let store = config.storage.backend.open(&config.storage.db_path)?;
+
     // Load or create blockchain
-    let blockchain = match CommunityBlockchain::load("blockchain_state") {
+    let blockchain = match CommunityBlockchain::load_from_store(store.clone()) {
         Ok(bc) => {
+            args.check_loaded(&bc)?;
             println!("✓ Loaded existing blockchain from disk");
             bc
         }
         Err(_) => {
             println!("✓ Creating new blockchain");
-            let mut initial = HashMap::new();
-
-            // Initialize 50 demo users
-            for i in 1..=50 {
-                initial.insert(format!("user_{}", i), 1000u64);
-            }
-
-            // Add named accounts
-            initial.insert("alice".to_string(), 10000);
-            initial.insert("bob".to_string(), 5000);
-            initial.insert("charlie".to_string(), 3000);
-
-            CommunityBlockchain::new(initial, "blockchain_state")?
+            CommunityBlockchain::from_genesis(&args.genesis.genesis()?, store)?
         }
     };
 
+    let blockchain = config.apply(blockchain);
+
     let _blockchain = Arc::new(RwLock::new(blockchain));
 
     // The API server is defined in `main.rs`.
@@ -1,12 +1,146 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use dashmap::DashMap;
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
 use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::{broadcast, Semaphore};
+
+use crate::access::AccessList;
+use crate::amount::{DEFAULT_DECIMALS, MAX_DECIMALS};
+use crate::genesis::Genesis;
+use crate::contract::{Contract, ContractAbi, ContractInfo, ContractReceipt, ContractTransaction};
+use crate::merkle::{self, MerkleProof};
+use crate::pow;
+use crate::settlement_layer::{BlockVote, Validator, ValidatorRegistry};
+use crate::snapshot::{Snapshot, SnapshotTrust};
+use crate::mempool::{Admission, Mempool, MempoolError, MempoolLimits, MempoolStats};
+use crate::state_tree::{StateProof, StateTree};
+use crate::storage::{Codec, KvStore, MemoryStore, StorageError, WriteOp};
+use crate::vm::{ContractContext, GasSchedule, Vm};
+
+/// Reserved account that issues newly minted coins
+pub const COINBASE_ADDRESS: &str = "coinbase";
+/// Reserved account that destroys any coins sent to it
+pub const BURN_ADDRESS: &str = "burn";
+/// Reserved account that proposes the genesis block
+pub const SYSTEM_ADDRESS: &str = "system";
+/// Reserved account that funds faucet payouts
+pub const FAUCET_ADDRESS: &str = "faucet";
+/// Reserved account that holds community treasury funds
+pub const TREASURY_ADDRESS: &str = "treasury";
+/// Reserved account holding staked coins. Sending coins to it stakes them,
+/// and only unstaking moves them out.
+pub const STAKE_ADDRESS: &str = "staking";
+/// Reserved account contract transactions are sent to. They carry a
+/// contract operation and its fee, never an amount.
+pub const CONTRACT_ADDRESS: &str = "contracts";
+
+/// Longest memo a transaction may carry, in bytes
+pub const MAX_MEMO_LEN: usize = 256;
+
+/// Default cap on the memo bytes of all transactions in one block
+pub const DEFAULT_MAX_BLOCK_MEMO_BYTES: u64 = 64 * 1024;
+
+/// Start of a transaction's canonical encoding, naming its layout
+pub const CANONICAL_TX_TAG: &[u8] = b"community-coin/tx/v1\n";
+
+/// Domain tag opening the bytes a proposer signs for a block
+pub const BLOCK_SIGNING_TAG: &[u8] = b"community-coin/block/v1\n";
+
+/// Start of the tx_id of a block's reward transaction, followed by the
+/// block's height
+pub const REWARD_TX_PREFIX: &str = "reward-";
+
+/// Smallest fee any transfer pays
+pub const MIN_FEE: u64 = 1;
+
+/// Memo bytes covered by each extra unit of fee
+pub const MEMO_BYTES_PER_FEE_UNIT: usize = 32;
+
+/// Gas of a contract call's limit covered by each extra unit of fee
+pub const GAS_PER_FEE_UNIT: u64 = 100_000;
+
+/// Every this many pending transactions adds another multiple of the base fee
+pub const CONGESTION_STEP: usize = 100;
+
+/// Default cap on the transactions in one block
+pub const DEFAULT_MAX_BLOCK_TXS: u64 = 1_000;
+
+/// Default cap on the encoded size of all transactions in one block
+pub const DEFAULT_MAX_BLOCK_BYTES: u64 = 1024 * 1024;
+
+/// Start of the error returned when a sender exceeds its transaction rate
+pub const SENDER_RATE_LIMITED: &str = "Sender rate limit exceeded";
+
+/// Start of the error returned when the node won't sign for a sender
+pub const CLIENT_SIGNING_REQUIRED: &str = "Transactions from this account must be signed by its key";
+
+/// Start of the error returned when every contract execution slot is taken
+pub const CONTRACT_EXECUTIONS_BUSY: &str = "Too many contract executions in progress";
+
+/// Default number of recent blocks `next_block_min_fee` looks at
+pub const DEFAULT_FEE_FLOOR_BLOCKS: usize = 10;
+
+/// Default longest a batched block waits before it is written to disk
+pub const DEFAULT_WRITE_BATCH_INTERVAL_SECS: u64 = 5;
+
+/// Default share of total validator stake whose votes finalize a block
+pub const DEFAULT_FINALITY_QUORUM: f64 = 2.0 / 3.0;
+
+/// New blocks buffered for each block subscriber
+pub const BLOCK_EVENT_BUFFER: usize = 64;
+
+/// Chain id of chains created without a genesis file
+pub const DEFAULT_CHAIN_ID: &str = "community-coin";
+
+/// Default number of latest blocks a transaction may reference
+pub const DEFAULT_RECENT_BLOCK_WINDOW: u64 = 150;
+
+/// Default seconds between blocks that block difficulty retargets toward
+pub const DEFAULT_TARGET_BLOCK_SECS: u64 = 10;
+
+/// Default number of blocks between block difficulty retargets
+pub const DEFAULT_RETARGET_INTERVAL: u64 = 10;
+
+/// Furthest a block's timestamp may run ahead of the local clock
+pub const MAX_BLOCK_FUTURE_SECS: u64 = 2 * 60 * 60;
+
+/// Most blocks of competing branches kept while they may still overtake
+/// the chain
+pub const MAX_SIDE_BLOCKS: usize = 256;
+
+/// Default most gas one contract call may be given
+pub const DEFAULT_MAX_GAS_LIMIT: u64 = 10_000_000;
+
+/// Default number of latest blocks whose transactions are remembered to
+/// turn away duplicates
+pub const DEFAULT_TX_DEDUP_BLOCKS: u64 = 150;
+
+/// Default depth below the tip past which a pruning node discards block
+/// bodies
+pub const DEFAULT_PRUNE_DEPTH: u64 = 10_000;
+
+/// Number of results per page of a transaction search
+pub const SEARCH_PAGE_SIZE: usize = 20;
+
+/// System-owned accounts that are not part of the community ranking
+pub const RESERVED_ADDRESSES: &[&str] = &[
+    SYSTEM_ADDRESS,
+    COINBASE_ADDRESS,
+    BURN_ADDRESS,
+    FAUCET_ADDRESS,
+    TREASURY_ADDRESS,
+    STAKE_ADDRESS,
+    CONTRACT_ADDRESS,
+];
 
 /// Transaction: User sends coins to another user with optional fee
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
 pub struct Transaction {
     pub from: String,
     pub to: String,
@@ -16,6 +150,277 @@ pub struct Transaction {
     pub tx_id: String,
     pub signature: String,
     pub nonce: u64,
+    /// Free-form note attached by the sender
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// Earliest block height this transaction may be mined at
+    #[serde(default)]
+    pub execute_at_height: Option<u64>,
+    /// Hash of a block from the last `recent_block_window` blocks, binding
+    /// the transaction to a recent view of the chain
+    #[serde(default)]
+    pub recent_block_hash: Option<String>,
+    /// Algorithm `signature` was made with
+    #[serde(default)]
+    pub sig_scheme: SigScheme,
+    /// Contract operation of a transaction to `CONTRACT_ADDRESS`
+    #[serde(default)]
+    pub contract: Option<ContractTransaction>,
+}
+
+/// JSON leaves out optional fields that are empty, as the API always has.
+/// Binary codecs can't tell a field is missing, so they get every field.
+impl Serialize for Transaction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        fn optional<S: SerializeStruct, T: Serialize>(
+            state: &mut S,
+            skip_empty: bool,
+            key: &'static str,
+            value: &Option<T>,
+        ) -> Result<(), S::Error> {
+            match value {
+                None if skip_empty => state.skip_field(key),
+                _ => state.serialize_field(key, value),
+            }
+        }
+
+        let skip_empty = serializer.is_human_readable();
+        let mut state = serializer.serialize_struct("Transaction", 13)?;
+        state.serialize_field("from", &self.from)?;
+        state.serialize_field("to", &self.to)?;
+        state.serialize_field("amount", &self.amount)?;
+        state.serialize_field("fee", &self.fee)?;
+        state.serialize_field("timestamp", &self.timestamp)?;
+        state.serialize_field("tx_id", &self.tx_id)?;
+        state.serialize_field("signature", &self.signature)?;
+        state.serialize_field("nonce", &self.nonce)?;
+        optional(&mut state, skip_empty, "memo", &self.memo)?;
+        optional(&mut state, skip_empty, "execute_at_height", &self.execute_at_height)?;
+        optional(&mut state, skip_empty, "recent_block_hash", &self.recent_block_hash)?;
+        state.serialize_field("sig_scheme", &self.sig_scheme)?;
+        optional(&mut state, skip_empty, "contract", &self.contract)?;
+        state.end()
+    }
+}
+
+/// Signature algorithm a transaction declares, so verifiers know how to
+/// check it
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SigScheme {
+    /// Hex SHA-256 digest of the signing payload, as the node issues for
+    /// the transactions it creates
+    #[default]
+    Sha256,
+    /// Hex ed25519 signature over the signing payload by the sender, whose
+    /// address is their hex-encoded public key
+    Ed25519,
+    /// Any scheme this node doesn't support. Never verifies.
+    #[serde(other)]
+    Unknown,
+}
+
+impl SigScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SigScheme::Sha256 => "sha256",
+            SigScheme::Ed25519 => "ed25519",
+            SigScheme::Unknown => "unknown",
+        }
+    }
+}
+
+impl Transaction {
+    /// The bytes a signature covers. Every scheme but the original
+    /// SHA-256 one signs the full [`Self::canonical_bytes`], so no field can
+    /// change once signed. The SHA-256 payload predates that and must stay
+    /// as it was for stored chains to verify; since anyone can recompute a
+    /// digest, it never protected the fields anyway. Either way a signature
+    /// made under one scheme can't pass under another.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        match self.sig_scheme {
+            SigScheme::Sha256 => legacy_signing_payload(
+                &self.tx_id,
+                &self.from,
+                self.memo.as_deref(),
+                self.execute_at_height,
+                self.recent_block_hash.as_deref(),
+                self.contract.as_ref(),
+            ),
+            _ => self.canonical_bytes(),
+        }
+    }
+
+    /// Account whose key signs the transaction: its sender, or for an
+    /// unstake the staker the coins go back to, as the staking account has
+    /// no key
+    pub fn signer(&self) -> &str {
+        if self.from == STAKE_ADDRESS {
+            &self.to
+        } else {
+            &self.from
+        }
+    }
+
+    /// Canonical encoding of every field but the signature: a version tag,
+    /// then the fields in declaration order. Strings are a little-endian
+    /// `u32` length and their bytes, numbers 8 little-endian bytes, and
+    /// options a `0` or a `1` followed by the value. A contract operation
+    /// comes last, as a string of its JSON.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        fn string(bytes: &mut Vec<u8>, value: &str) {
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+        }
+
+        let mut bytes = CANONICAL_TX_TAG.to_vec();
+        string(&mut bytes, &self.from);
+        string(&mut bytes, &self.to);
+        for number in [self.amount, self.fee, self.timestamp] {
+            bytes.extend_from_slice(&number.to_le_bytes());
+        }
+        string(&mut bytes, &self.tx_id);
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        match &self.memo {
+            Some(memo) => {
+                bytes.push(1);
+                string(&mut bytes, memo);
+            }
+            None => bytes.push(0),
+        }
+        match self.execute_at_height {
+            Some(height) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&height.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        match &self.recent_block_hash {
+            Some(hash) => {
+                bytes.push(1);
+                string(&mut bytes, hash);
+            }
+            None => bytes.push(0),
+        }
+        string(&mut bytes, self.sig_scheme.as_str());
+        // Appended only when present, so other transactions encode and
+        // sign as they always have
+        if let Some(op) = &self.contract {
+            string(&mut bytes, &contract_json(op));
+        }
+        bytes
+    }
+
+    /// Hex SHA-256 of the [`Self::canonical_bytes`] of everything but the
+    /// id and signature. Clients signing their own transactions use it as
+    /// the `tx_id`, so a resubmission of the same transfer carries the same
+    /// id and can be looked up first, while one differing in any field
+    /// doesn't.
+    pub fn content_id(&self) -> String {
+        let unsigned = Transaction {
+            tx_id: String::new(),
+            signature: String::new(),
+            ..self.clone()
+        };
+        format!("{:x}", Sha256::digest(unsigned.canonical_bytes()))
+    }
+
+    /// Contract a contract transaction deploys, calls or upgrades. A
+    /// deployment's address is derived from its sender, tx_id and code.
+    pub fn contract_address(&self) -> Option<String> {
+        match self.contract.as_ref()? {
+            ContractTransaction::Deploy { code, .. } => Some(deploy_address(&self.from, &self.tx_id, code)),
+            op => tx_target(op),
+        }
+    }
+
+    /// Encoded size in bytes: the canonical encoding and the signature
+    pub fn size(&self) -> u64 {
+        (self.canonical_bytes().len() + self.signature.len() / 2) as u64
+    }
+
+    /// Fee paid per 1000 bytes of [`Self::size`], which blocks are filled
+    /// and full mempools are trimmed by
+    pub fn fee_rate(&self) -> u64 {
+        self.fee.saturating_mul(1000) / self.size().max(1)
+    }
+}
+
+/// A contract operation as signatures and ids cover it
+fn contract_json(op: &ContractTransaction) -> String {
+    serde_json::to_string(op).unwrap_or_default()
+}
+
+/// Address of the contract `sender` deploys with the transaction `tx_id`
+fn deploy_address(sender: &str, tx_id: &str, code: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    for field in [sender, tx_id] {
+        hasher.update(field.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.update(code);
+    format!("contract_{}", &format!("{:x}", hasher.finalize())[..40])
+}
+
+/// Contract an operation calls or upgrades; a deployment's address comes
+/// from its transaction
+fn tx_target(op: &ContractTransaction) -> Option<String> {
+    match op {
+        ContractTransaction::Deploy { .. } => None,
+        ContractTransaction::Call { contract, .. } | ContractTransaction::UpgradeContract { contract, .. } => {
+            Some(contract.clone())
+        }
+    }
+}
+
+/// What a SHA-256 signature covers: the tx id, sender, memo, schedule
+/// height, recent block binding and any contract operation
+fn legacy_signing_payload(
+    tx_id: &str,
+    from: &str,
+    memo: Option<&str>,
+    execute_at_height: Option<u64>,
+    recent_block_hash: Option<&str>,
+    contract: Option<&ContractTransaction>,
+) -> Vec<u8> {
+    let mut bytes = tx_id.as_bytes().to_vec();
+    bytes.extend_from_slice(from.as_bytes());
+    if let Some(memo) = memo {
+        bytes.extend_from_slice(b"\nmemo:");
+        bytes.extend_from_slice(memo.as_bytes());
+    }
+    if let Some(height) = execute_at_height {
+        bytes.extend_from_slice(&height.to_le_bytes());
+    }
+    if let Some(hash) = recent_block_hash {
+        bytes.extend_from_slice(b"\nrecent:");
+        bytes.extend_from_slice(hash.as_bytes());
+    }
+    if let Some(op) = contract {
+        bytes.extend_from_slice(b"\ncontract:");
+        bytes.extend_from_slice(contract_json(op).as_bytes());
+    }
+    bytes
+}
+
+/// Optional parts of a transfer the node creates and signs
+#[derive(Debug, Clone, Default)]
+pub struct TransferOptions {
+    /// Free-form note attached by the sender
+    pub memo: Option<String>,
+    /// Earliest block height the transfer may be mined at
+    pub execute_at_height: Option<u64>,
+    /// Hash of a recent block to bind the transfer to
+    pub recent_block_hash: Option<String>,
+    /// Proof of work, required when the node sets a difficulty
+    pub pow_nonce: Option<u64>,
+    /// Fee to pay instead of [`CommunityBlockchain::estimate_fee`], at
+    /// least `MIN_FEE`
+    pub fee: Option<u64>,
+    /// Contract operation, for a transaction to `CONTRACT_ADDRESS`
+    pub contract: Option<ContractTransaction>,
 }
 
 /// Block: Contains multiple transactions with state root
@@ -28,6 +433,21 @@ pub struct Block {
     pub hash: String,
     pub proposer: String,
     pub state_root: String,
+    /// Merkle root of `transactions`, empty only on blocks stored before
+    /// blocks committed to it
+    #[serde(default)]
+    pub tx_root: String,
+    /// Leading zero bits the block's hash must have, 0 on chains without
+    /// proof of work
+    #[serde(default)]
+    pub difficulty: u32,
+    /// Nonce searched for until the hash meets `difficulty`
+    #[serde(default)]
+    pub pow_nonce: u64,
+    /// The proposer's ed25519 signature of `hash`, required once validators
+    /// have staked. Left out of the hash it signs.
+    #[serde(default)]
+    pub proposer_signature: Option<String>,
 }
 
 /// Wallet: Each user has a wallet with balance and history
@@ -48,26 +468,629 @@ pub struct TransactionIndex {
     pub tx_index_in_block: usize,
 }
 
+/// How a transaction would fare on submission, as reported by
+/// `check_transaction`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionCheck {
+    pub signature_valid: bool,
+    /// The nonce is above the sender's highest committed one
+    pub nonce_valid: bool,
+    pub committed_nonce: u64,
+}
+
+/// Balance of an account before and after a block
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub old_balance: u64,
+    pub new_balance: u64,
+}
+
+/// Every balance a block changed, keyed by address
+pub type StateDiff = BTreeMap<String, BalanceChange>;
+
+/// A validator's stake, proposal history and odds of proposing next
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ValidatorStats {
+    pub address: String,
+    pub stake: u64,
+    pub blocks_proposed: u64,
+    pub selection_probability: f64,
+}
+
+/// ChainParams: Chain-wide parameters fixed at genesis
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChainParams {
+    /// Name of the network, so chains started from otherwise identical
+    /// genesis files still have different genesis hashes
+    pub chain_id: String,
+    /// Number of decimal places between base units and display coins
+    pub decimals: u8,
+    /// Gas charged per class of contract operation
+    pub gas_schedule: GasSchedule,
+    /// Largest amount a single transaction may move
+    pub max_tx_amount: u64,
+    /// Largest total amount all transactions in one block may move
+    pub max_block_value: u64,
+    /// Most memo bytes all transactions in one block may carry together
+    pub max_block_memo_bytes: u64,
+    /// Most transactions one block may hold, besides its reward
+    pub max_block_txs: u64,
+    /// Largest encoded size of all transactions in one block together,
+    /// besides its reward
+    pub max_block_bytes: u64,
+    /// Order transactions from different senders go into blocks in
+    pub mempool_policy: MempoolPolicy,
+    /// How many of the latest blocks a transaction's `recent_block_hash`
+    /// may name
+    pub recent_block_window: u64,
+    /// Leading zero bits block hashes start out needing, 0 for no proof of
+    /// work
+    pub block_difficulty: u32,
+    /// Seconds between blocks that difficulty retargets toward
+    pub target_block_secs: u64,
+    /// Blocks between difficulty retargets
+    pub retarget_interval: u64,
+    /// New coins each block pays its proposer on top of its fees, 0 for no
+    /// block rewards
+    pub block_reward: u64,
+    /// Blocks between halvings of the block reward, 0 to keep it fixed
+    pub reward_halving_interval: u64,
+    /// Whether nodes may sign for named user accounts with the SHA-256
+    /// scheme, which anyone can recompute. Only for demo chains: otherwise
+    /// a user account's transactions must carry its owner's signature, and
+    /// blocks holding any other are refused.
+    pub node_signing: bool,
+    /// Most gas one contract call may be given. A call runs holding the
+    /// chain, so this bounds how long one can keep writers waiting.
+    pub max_gas_limit: u64,
+    /// How many of the latest blocks a confirmed transaction's tx_id may not
+    /// be included again for, 0 for no window
+    pub tx_dedup_blocks: u64,
+    /// Hex ed25519 public keys allowed to sign for the system accounts, as
+    /// the faucet and treasury have no key of their own
+    pub system_keys: Vec<String>,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            decimals: DEFAULT_DECIMALS,
+            gas_schedule: GasSchedule::default(),
+            max_tx_amount: 1_000_000_000_000,
+            max_block_value: 10_000_000_000_000,
+            max_block_memo_bytes: DEFAULT_MAX_BLOCK_MEMO_BYTES,
+            max_block_txs: DEFAULT_MAX_BLOCK_TXS,
+            max_block_bytes: DEFAULT_MAX_BLOCK_BYTES,
+            mempool_policy: MempoolPolicy::FeePriority,
+            recent_block_window: DEFAULT_RECENT_BLOCK_WINDOW,
+            block_difficulty: 0,
+            target_block_secs: DEFAULT_TARGET_BLOCK_SECS,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            block_reward: 0,
+            reward_halving_interval: 0,
+            node_signing: false,
+            max_gas_limit: DEFAULT_MAX_GAS_LIMIT,
+            tx_dedup_blocks: DEFAULT_TX_DEDUP_BLOCKS,
+            system_keys: Vec::new(),
+        }
+    }
+}
+
+impl ChainParams {
+    /// The defaults, with the node signing for named accounts as on a demo
+    /// chain
+    pub fn demo() -> Self {
+        ChainParams {
+            node_signing: true,
+            ..ChainParams::default()
+        }
+    }
+}
+
+/// Most transactions one sender may submit per window, refilled as a token
+/// bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SenderRateLimit {
+    pub limit: f64,
+    pub window_secs: u64,
+}
+
+/// Hold block writes in memory and commit them to storage together, once
+/// `max_blocks` blocks are waiting or the oldest has waited `interval_secs`.
+/// A crash loses at most that window of blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatching {
+    pub max_blocks: u64,
+    pub interval_secs: u64,
+}
+
+/// Blocks, diffs, stake changes and the wallets they touched, accepted but
+/// not yet written under [`WriteBatching`]. A wallet touched by several
+/// buffered blocks is written once per flush, as the last of them left it.
+#[derive(Debug, Default)]
+struct WriteBuffer {
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>, // None removes the key
+    blocks: u64,
+    oldest: Option<u64>, // when the first unflushed block was accepted
+}
+
+/// The mempool as written by [`CommunityBlockchain::persist_mempool`].
+/// Nonces are not stored: they are rebuilt from the chain and the restored
+/// transactions, so they can never run ahead of the mempool.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistedMempool {
+    transactions: Vec<Transaction>,
+    reservations: HashMap<String, (String, u64)>, // tx_id -> sender, amount reserved
+}
+
+/// What a crash right now would lose, as reported by `/health`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DurabilityWindow {
+    pub batching: Option<WriteBatching>,
+    pub unflushed_blocks: u64,
+    pub oldest_unflushed_secs: u64,
+}
+
+/// Outcome of [`CommunityBlockchain::load_transactions`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IngestReport {
+    pub accepted: usize,
+    /// Why each refused transaction was refused, by tx_id
+    pub rejected: BTreeMap<String, String>,
+}
+
+/// Which replay protections the node enforces, and how many transactions
+/// each has turned away since it started
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayProtectionStatus {
+    /// Relayed transactions must carry the sender's next unused nonce
+    pub nonce_enforcement: bool,
+    /// Transactions carry no chain id here; `recent_block_hash` ties them
+    /// to this chain instead
+    pub chain_id_binding: bool,
+    /// Transactions may name a recent block they are only valid after
+    pub recent_block_binding: bool,
+    pub recent_block_window: u64,
+    /// Pending and recently confirmed tx_ids are turned away
+    pub tx_dedup: bool,
+    pub tx_dedup_blocks: u64,
+    pub rejected: ReplayRejections,
+}
+
+/// Transactions rejected by each replay protection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ReplayRejections {
+    pub nonce: u64,
+    pub recent_block: u64,
+    pub duplicate: u64,
+}
+
+#[derive(Debug, Default)]
+struct ReplayCounters {
+    nonce: AtomicU64,
+    recent_block: AtomicU64,
+    duplicate: AtomicU64,
+}
+
+impl ReplayCounters {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ReplayRejections {
+        ReplayRejections {
+            nonce: self.nonce.load(Ordering::Relaxed),
+            recent_block: self.recent_block.load(Ordering::Relaxed),
+            duplicate: self.duplicate.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One page of an address's history, newest first
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage {
+    pub transactions: Vec<Transaction>,
+    /// Older transactions were left out
+    pub truncated: bool,
+    /// Pass as `before` to get the next, older page
+    pub next_cursor: Option<u64>,
+    /// Transactions in blocks whose bodies were pruned may be missing
+    pub pruned: bool,
+}
+
+/// The first block that failed verification, and why.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("block {index} is invalid: {reason}")]
+pub struct ChainVerificationError {
+    pub index: u64,
+    pub reason: ChainFault,
+}
+
+/// Ways a stored block can fail verification.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ChainFault {
+    #[error("index does not follow the previous block")]
+    IndexMismatch,
+    #[error("prev_hash does not match the previous block's hash")]
+    BrokenLink,
+    #[error("stored hash does not match the recomputed hash")]
+    HashMismatch,
+    #[error("transaction {0} has an invalid signature")]
+    InvalidSignature(String),
+    #[error("block is missing from storage")]
+    MissingBlock,
+    #[error("hash does not carry the required proof of work")]
+    InsufficientWork,
+    #[error("tx_root does not match the block's transactions")]
+    TxRootMismatch,
+}
+
+/// Errors from the chain's balance bookkeeping
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BlockchainError {
+    #[error("Arithmetic overflow in balance math")]
+    ArithmeticOverflow,
+    #[error("Supply does not reconcile: balances plus burned and fees come to {actual}, issuance to {expected}")]
+    SupplyMismatch { expected: u64, actual: u64 },
+}
+
+impl From<BlockchainError> for String {
+    fn from(e: BlockchainError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Ways a block offered to the chain can fail validation
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    #[error("Invalid block index: expected {expected}, got {actual}")]
+    InvalidIndex { expected: u64, actual: u64 },
+    #[error("Invalid previous hash in block {0}")]
+    InvalidPrevHash(u64),
+    #[error("Invalid block hash in block {0}")]
+    InvalidHash(u64),
+    #[error("Block {index} is timestamped {timestamp}, more than {MAX_BLOCK_FUTURE_SECS}s ahead of local time {now}")]
+    FutureTimestamp { index: u64, timestamp: u64, now: u64 },
+    #[error("Block {index} is timestamped {timestamp}, before its parent at {parent}")]
+    TimestampBeforeParent { index: u64, timestamp: u64, parent: u64 },
+    #[error("Transaction {tx_id} in block {index} has an invalid signature")]
+    InvalidSignature { index: u64, tx_id: String },
+    #[error("Transaction {tx_id} in block {index} has nonce {actual}, expected {expected}")]
+    InvalidNonce { index: u64, tx_id: String, expected: u64, actual: u64 },
+    #[error("Block {0} does not open with a reward transaction paying its proposer")]
+    MissingReward(u64),
+    #[error("Block {index} pays a reward of {actual}, expected {expected}")]
+    InvalidReward { index: u64, expected: u64, actual: u64 },
+    #[error("Block {0} carries a reward transaction it may not")]
+    UnexpectedReward(u64),
+    #[error("Transaction {tx_id} in block {index} issues coins outside the block reward")]
+    UnexpectedCoinbase { index: u64, tx_id: String },
+    #[error("Transaction {tx_id} appears twice in block {index}")]
+    DuplicateTransaction { index: u64, tx_id: String },
+    #[error("Transaction {tx_id} in block {index} spends more than {from} holds")]
+    InsufficientBalance { index: u64, tx_id: String, from: String },
+}
+
+impl From<BlockError> for String {
+    fn from(e: BlockError) -> Self {
+        e.to_string()
+    }
+}
+
+/// `a + b` for coin amounts, failing rather than wrapping
+pub fn add_amount(a: u64, b: u64) -> Result<u64, BlockchainError> {
+    a.checked_add(b).ok_or(BlockchainError::ArithmeticOverflow)
+}
+
+/// `a - b` for coin amounts, failing rather than wrapping
+pub fn sub_amount(a: u64, b: u64) -> Result<u64, BlockchainError> {
+    a.checked_sub(b).ok_or(BlockchainError::ArithmeticOverflow)
+}
+
+/// How block assembly prioritizes ready transactions across senders. Part
+/// of the chain params, since blocks must follow it to be valid, except
+/// that FIFO order is each node's own and only nonce order is checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MempoolPolicy {
+    /// Highest fee per byte first
+    #[default]
+    FeePriority,
+    /// Earliest to arrive in this node's mempool first. Senders choose
+    /// their timestamps, so those are never used to rank.
+    Fifo,
+    /// A shuffle keyed by `seed` and the parent block's hash, so the order
+    /// is fixed for each block but can't be bought or predicted before the
+    /// parent exists
+    Random { seed: u64 },
+}
+
+impl MempoolPolicy {
+    /// Parse `fee`, `fifo` or `random:<seed>`, as in `--mempool-policy`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "fee" | "fee-priority" => Ok(MempoolPolicy::FeePriority),
+            "fifo" => Ok(MempoolPolicy::Fifo),
+            other => other
+                .strip_prefix("random:")
+                .and_then(|seed| seed.parse().ok())
+                .map(|seed| MempoolPolicy::Random { seed })
+                .ok_or_else(|| format!("Unknown mempool policy {}, expected fee, fifo or random:<seed>", other)),
+        }
+    }
+
+    /// Rank of `tx` in a block on top of `prev_hash`, lowest first, given
+    /// its position in the mempool's arrival order
+    fn priority(&self, tx: &Transaction, prev_hash: &str, arrival: u64) -> u64 {
+        match self {
+            MempoolPolicy::FeePriority => u64::MAX - tx.fee_rate(),
+            MempoolPolicy::Fifo => arrival,
+            MempoolPolicy::Random { seed } => {
+                let mut hasher = Sha256::new();
+                hasher.update(seed.to_le_bytes());
+                hasher.update(prev_hash.as_bytes());
+                hasher.update(tx.tx_id.as_bytes());
+                u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+            }
+        }
+    }
+}
+
+/// Heap key of a ready transaction: policy rank, nonce, tx_id and sender,
+/// smallest first once wrapped in `Reverse`
+type ReadyKey = (u64, u64, String, String);
+
+/// Transactions to order into a block: each sender's nonce chain, plus a
+/// heap of the transactions that are ready, which is the head of every
+/// chain. Taking a transaction readies the next one of its sender, so
+/// senders are served by priority while each stays in nonce order.
+struct ReadyQueue {
+    chains: BTreeMap<String, VecDeque<Transaction>>,
+    ready: BinaryHeap<Reverse<ReadyKey>>,
+    policy: MempoolPolicy,
+    prev_hash: String,
+    /// Position of each tx_id in the mempool's arrival order
+    arrivals: HashMap<String, u64>,
+}
+
+impl ReadyQueue {
+    /// Order `chains` for a block on top of `prev_hash`, ranking by
+    /// `arrivals` under the FIFO policy
+    fn new(
+        chains: BTreeMap<String, VecDeque<Transaction>>,
+        policy: MempoolPolicy,
+        prev_hash: &str,
+        arrivals: HashMap<String, u64>,
+    ) -> Self {
+        let mut queue = ReadyQueue {
+            chains,
+            ready: BinaryHeap::new(),
+            policy,
+            prev_hash: prev_hash.to_string(),
+            arrivals,
+        };
+        let senders: Vec<String> = queue.chains.keys().cloned().collect();
+        for sender in senders {
+            queue.ready_next(&sender);
+        }
+        queue
+    }
+
+    /// Put `sender`'s next transaction, if any, on the ready heap
+    fn ready_next(&mut self, sender: &str) {
+        if let Some(tx) = self.chains.get(sender).and_then(VecDeque::front) {
+            let arrival = self.arrivals.get(&tx.tx_id).copied().unwrap_or(u64::MAX);
+            let priority = self.policy.priority(tx, &self.prev_hash, arrival);
+            self.ready
+                .push(Reverse((priority, tx.nonce, tx.tx_id.clone(), sender.to_string())));
+        }
+    }
+
+    /// Take the next transaction in canonical block order: the best ready
+    /// transaction by the mempool policy (by default highest fee per byte
+    /// first),
+    /// then nonce (lowest first), then tx_id. Except under the FIFO policy,
+    /// the order depends only on the set of transactions and the parent
+    /// block, never on the order they arrived in.
+    fn pop(&mut self) -> Option<Transaction> {
+        loop {
+            let Reverse((_, _, tx_id, sender)) = self.ready.pop()?;
+            // Held-back senders leave a stale entry behind
+            let Some(chain) = self.chains.get_mut(&sender) else {
+                continue;
+            };
+            if chain.front().is_none_or(|tx| tx.tx_id != tx_id) {
+                continue;
+            }
+            let tx = chain.pop_front();
+            self.ready_next(&sender);
+            return tx;
+        }
+    }
+
+    /// Drop the rest of `sender`'s chain, which cannot go in after a
+    /// transaction of theirs was left out
+    fn hold_back(&mut self, sender: &str) {
+        self.chains.remove(sender);
+    }
+}
+
+/// Contract state partway through a block's contract transactions, over
+/// the deployed contracts
+/// A contract together with the ABI it was deployed with
+type ContractState = (Contract, Option<ContractAbi>);
+
+#[derive(Default)]
+struct ContractBatch {
+    /// Contracts changed so far with their ABIs, `None` once removed
+    changed: BTreeMap<String, Option<ContractState>>,
+    receipts: Vec<(String, ContractReceipt)>,
+    /// Coins paid out by self-destructs, as `(contract, beneficiary, amount)`
+    payouts: Vec<(String, String, u64)>,
+}
+
+/// What a block's contract transactions replaced, stored under
+/// `contract_undo:<height>` for rolling the block back
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ContractUndo {
+    /// Each contract the block changed, with its ABI, as it was before.
+    /// `None` for one the block deployed.
+    contracts: Vec<(String, Option<ContractState>)>,
+    payouts: Vec<(String, String, u64)>,
+}
+
+/// The per-address transaction index. A rebuild fills a fresh one and
+/// swaps it in, so readers see either the old index or the new one.
+#[derive(Default)]
+struct TxIndex {
+    hot: DashMap<String, Vec<TransactionIndex>>,
+    archived: DashMap<String, u64>, // entries compacted out of `hot`, oldest first
+}
+
+impl TxIndex {
+    fn new(hot: DashMap<String, Vec<TransactionIndex>>) -> Arc<RwLock<Arc<Self>>> {
+        Arc::new(RwLock::new(Arc::new(TxIndex { hot, archived: DashMap::new() })))
+    }
+
+    /// Move an address's oldest entries past `max` out of memory
+    fn compact(&self, address: &str, max: Option<usize>) {
+        let Some(max) = max else {
+            return;
+        };
+        let excess = match self.hot.get_mut(address) {
+            Some(mut entries) if entries.len() > max => {
+                let excess = entries.len() - max;
+                entries.drain(..excess);
+                excess
+            }
+            _ => return,
+        };
+        *self.archived.entry(address.to_string()).or_insert(0) += excess as u64;
+    }
+}
+
 /// CommunityBlockchain: Production-ready blockchain with persistence
 pub struct CommunityBlockchain {
     chain: Arc<Mutex<Vec<Block>>>,
     wallets: Arc<DashMap<String, Wallet>>,
-    tx_index: Arc<DashMap<String, Vec<TransactionIndex>>>, // Per-user tx index
-    pending_txs: Arc<Mutex<Vec<Transaction>>>,
+    tx_index: Arc<RwLock<Arc<TxIndex>>>, // Per-user tx index, replaced whole on rebuild
+    tx_heights: Arc<DashMap<String, Vec<u64>>>, // tx_id -> heights of the blocks confirming it, lowest first
+    max_index_len: Option<usize>,
+    mempool: Arc<Mutex<Mempool>>,
     nonces: Arc<DashMap<String, u64>>, // Track nonce per user for ordering
-    state_db: sled::Db,
+    committed_nonces: Arc<DashMap<String, u64>>, // latest nonce each sender has on chain, for state roots
+    contracts: Arc<DashMap<String, Contract>>,
+    validators: Arc<Mutex<ValidatorRegistry>>,
+    votes: Arc<Mutex<BTreeMap<u64, BTreeSet<String>>>>, // height -> validators that voted
+    finalized_height: Arc<Mutex<u64>>,
+    side_blocks: Arc<Mutex<HashMap<String, Block>>>, // blocks of competing branches, by hash
+    finality_quorum: f64,
+    validator_key: Option<SigningKey>, // signs the blocks this node proposes as a validator
+    system_key: Option<SigningKey>, // signs for the system accounts, if listed in `params.system_keys`
+    access: Arc<Mutex<AccessList>>,
+    params: ChainParams,
+    state_db: Arc<dyn KvStore>,
+    codec: Codec,
+    sender_rate_limit: Option<SenderRateLimit>,
+    sender_buckets: Arc<DashMap<String, (f64, u64)>>, // tokens, last refill
+    max_chain_len: Option<usize>, // blocks kept in `chain`, older ones are read from storage
+    block_events: broadcast::Sender<Block>,
+    pow_difficulty: u32, // leading zero bits required of submissions, 0 = off
+    contract_slots: Option<Semaphore>, // caps simultaneous view calls
+    fee_floor_blocks: usize, // recent blocks the fee floor is taken over
+    recent_tx_ids: Arc<DashMap<String, u64>>, // tx_id -> height, for the blocks in the dedup window
+    replay_rejections: Arc<ReplayCounters>,
+    write_batching: Option<WriteBatching>,
+    write_buffer: Arc<Mutex<WriteBuffer>>,
+    reserve_pending: bool, // hold back pending outgoing amounts from the spendable balance
+    reserved: Arc<DashMap<String, u64>>, // per sender, amount plus fee of their pending transactions
+    reservations: Arc<DashMap<String, (String, u64)>>, // tx_id -> sender, amount reserved
+    base_height: u64, // first block held, above 0 for nodes started from a snapshot
+    base_nonces: HashMap<String, u64>, // committed nonces at `base_height`
+    base_supply: u64, // coins in existence at `base_height`
+    genesis_hash: Option<String>, // unknown for older databases and snapshots that didn't carry it
+    check_supply: bool, // reconcile the supply after every block in debug builds
+    snapshot_retention: Option<usize>, // stored snapshots kept by `save_snapshot`, all if unset
+    prune_depth: Option<u64>, // blocks below the tip kept whole, all if unset
+    pruned_height: Arc<Mutex<u64>>, // highest block whose body was discarded, 0 if none
+    pruned_flows: Arc<Mutex<(u64, u64)>>, // coins issued and destroyed by pruned blocks
+    pruned_nonces: Arc<DashMap<String, u64>>, // latest nonce each sender committed in a pruned block
+    clock: fn() -> u64,
 }
 
 impl CommunityBlockchain {
     /// Create new blockchain with sled persistence
     pub fn new(initial_wallets: HashMap<String, u64>, db_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let state_db = sled::open(db_path)?;
-        let now = current_timestamp();
+        Self::new_with_params(initial_wallets, ChainParams::default(), db_path)
+    }
+
+    /// Create new blockchain with explicit genesis parameters
+    pub fn new_with_params(
+        initial_wallets: HashMap<String, u64>,
+        params: ChainParams,
+        db_path: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_store(initial_wallets, params, Arc::new(sled::open(db_path)?))
+    }
+
+    /// Create new blockchain backed by a volatile in-memory store, so tests
+    /// never touch the filesystem. The node signs for named accounts, as on
+    /// a demo chain.
+    pub fn new_in_memory(initial_wallets: HashMap<String, u64>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_store(initial_wallets, ChainParams::demo(), Arc::new(MemoryStore::new()))
+    }
+
+    /// Create new blockchain on top of any storage backend
+    pub fn from_store(
+        initial_wallets: HashMap<String, u64>,
+        params: ChainParams,
+        state_db: Arc<dyn KvStore>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_store_with_clock(initial_wallets, params, state_db, current_timestamp)
+    }
+
+    /// Create new blockchain that reads the time from `clock` instead of the
+    /// system clock, so replays produce identical timestamps and hashes
+    pub(crate) fn from_store_with_clock(
+        initial_wallets: HashMap<String, u64>,
+        params: ChainParams,
+        state_db: Arc<dyn KvStore>,
+        clock: fn() -> u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::create(initial_wallets, &[], params, state_db, clock)
+    }
+
+    /// Create new blockchain from a genesis file's accounts, validators and
+    /// params. Validators' stakes are held by the staking account, as if
+    /// they had staked in block 0.
+    pub fn from_genesis(genesis: &Genesis, state_db: Arc<dyn KvStore>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::create(genesis.wallets()?, &genesis.validators, genesis.params(), state_db, current_timestamp)
+    }
+
+    fn create(
+        initial_wallets: HashMap<String, u64>,
+        genesis_validators: &[Validator],
+        params: ChainParams,
+        state_db: Arc<dyn KvStore>,
+        clock: fn() -> u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if params.decimals > MAX_DECIMALS {
+            return Err(format!("decimals must be at most {}", MAX_DECIMALS).into());
+        }
+
+        let now = clock();
 
         let wallets = Arc::new(DashMap::new());
-        let tx_index = Arc::new(DashMap::new());
+        let tx_index = DashMap::new();
         let nonces = Arc::new(DashMap::new());
+        let mut writes = Vec::new();
 
+        // Saturates rather than fails, and reconciliation then reports the
+        // overflow, for genesis files holding more than a u64 in total
+        let base_supply = initial_wallets.values().fold(0, |total: u64, balance| total.saturating_add(*balance));
+        let genesis_hash = Self::calculate_genesis_hash(&initial_wallets, genesis_validators, &params);
         for (address, balance) in initial_wallets {
             let wallet = Wallet {
                 address: address.clone(),
@@ -81,53 +1104,142 @@ impl CommunityBlockchain {
             tx_index.insert(address.clone(), Vec::new());
 
             // Persist wallet
-            let wallet_json = serde_json::to_string(&wallet)?;
-            state_db.insert(format!("wallet:{}", address).as_bytes(), wallet_json.as_bytes())?;
+            writes.push(WriteOp::Insert(
+                format!("wallet:{}", address).into_bytes(),
+                Codec::default().encode(&wallet)?,
+            ));
         }
 
-        // Genesis block
+        let mut validators = ValidatorRegistry::new();
+        for validator in genesis_validators {
+            validators.register(validator.address.clone(), validator.stake)?;
+            writes.push(WriteOp::Insert(
+                format!("validator:{}", validator.address).into_bytes(),
+                Codec::default().encode(validator)?,
+            ));
+        }
+
+        // Genesis block, whose hash is the genesis hash, so blocks built on
+        // another genesis never link to it
         let genesis = Block {
             index: 0,
             timestamp: now,
             transactions: vec![],
             prev_hash: "0".to_string(),
-            hash: "genesis".to_string(),
-            proposer: "system".to_string(),
+            hash: genesis_hash.clone(),
+            proposer: SYSTEM_ADDRESS.to_string(),
             state_root: "genesis_root".to_string(),
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
         };
 
         let chain = Arc::new(Mutex::new(vec![genesis.clone()]));
         
         // Persist genesis
-        let genesis_json = serde_json::to_string(&genesis)?;
-        state_db.insert(b"block:0", genesis_json.as_bytes())?;
+        writes.push(WriteOp::Insert(b"block:0".to_vec(), Codec::default().encode(&genesis)?));
+
+        // Persist chain params
+        writes.push(WriteOp::Insert(b"chain_params".to_vec(), Codec::default().encode(&params)?));
+        writes.push(WriteOp::Insert(b"base_supply".to_vec(), Codec::default().encode(&base_supply)?));
+        writes.push(WriteOp::Insert(b"genesis_hash".to_vec(), Codec::default().encode(&genesis_hash)?));
+
+        state_db.transaction(writes)?;
 
         Ok(CommunityBlockchain {
             chain,
             wallets,
-            tx_index,
-            pending_txs: Arc::new(Mutex::new(Vec::new())),
+            tx_index: TxIndex::new(tx_index),
+            tx_heights: Arc::new(DashMap::new()),
+            max_index_len: None,
+            mempool: Arc::new(Mutex::new(Mempool::new(MempoolLimits::default()))),
             nonces,
+            committed_nonces: Arc::new(DashMap::new()),
+            contracts: Arc::new(DashMap::new()),
+            validators: Arc::new(Mutex::new(validators)),
+            votes: Arc::new(Mutex::new(BTreeMap::new())),
+            finalized_height: Arc::new(Mutex::new(0)),
+            side_blocks: Arc::new(Mutex::new(HashMap::new())),
+            finality_quorum: DEFAULT_FINALITY_QUORUM,
+            validator_key: None,
+            system_key: None,
+            access: Arc::new(Mutex::new(AccessList::default())),
+            params,
             state_db,
+            codec: Codec::default(),
+            sender_rate_limit: None,
+            sender_buckets: Arc::new(DashMap::new()),
+            max_chain_len: None,
+            block_events: broadcast::channel(BLOCK_EVENT_BUFFER).0,
+            pow_difficulty: 0,
+            contract_slots: None,
+            fee_floor_blocks: DEFAULT_FEE_FLOOR_BLOCKS,
+            recent_tx_ids: Arc::new(DashMap::new()),
+            replay_rejections: Arc::new(ReplayCounters::default()),
+            write_batching: None,
+            write_buffer: Arc::new(Mutex::new(WriteBuffer::default())),
+            reserve_pending: true,
+            reserved: Arc::new(DashMap::new()),
+            reservations: Arc::new(DashMap::new()),
+            base_height: 0,
+            base_nonces: HashMap::new(),
+            base_supply,
+            genesis_hash: Some(genesis_hash),
+            check_supply: false,
+            snapshot_retention: None,
+            prune_depth: None,
+            pruned_height: Arc::new(Mutex::new(0)),
+            pruned_flows: Arc::new(Mutex::new((0, 0))),
+            pruned_nonces: Arc::new(DashMap::new()),
+            clock,
         })
     }
 
     /// Load blockchain from disk
     pub fn load(db_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let state_db = sled::open(db_path)?;
-        
+        Self::load_from_store(Arc::new(sled::open(db_path)?))
+    }
+
+    /// Load blockchain from any storage backend
+    pub fn load_from_store(state_db: Arc<dyn KvStore>) -> Result<Self, Box<dyn std::error::Error>> {
         let mut chain = Vec::new();
         let wallets = Arc::new(DashMap::new());
-        let tx_index = Arc::new(DashMap::new());
+        let tx_index = DashMap::new();
+        let tx_heights: Arc<DashMap<String, Vec<u64>>> = Arc::new(DashMap::new());
         let nonces = Arc::new(DashMap::new());
 
+        // Load chain params (older databases predate them)
+        let params = match state_db.get(b"chain_params")? {
+            Some(params_bytes) => Codec::decode(&params_bytes)?,
+            None => ChainParams::default(),
+        };
+
+        // Nodes started from a snapshot hold no blocks below its tip
+        let base_height: u64 = match state_db.get(b"snapshot_base")? {
+            Some(bytes) => Codec::decode(&bytes)?,
+            None => 0,
+        };
+        let base_nonces: HashMap<String, u64> = match state_db.get(b"snapshot_nonces")? {
+            Some(bytes) => Codec::decode(&bytes)?,
+            None => HashMap::new(),
+        };
+        let stored_base_supply: Option<u64> = match state_db.get(b"base_supply")? {
+            Some(bytes) => Some(Codec::decode(&bytes)?),
+            None => None,
+        };
+        let genesis_hash: Option<String> = match state_db.get(b"genesis_hash")? {
+            Some(bytes) => Some(Codec::decode(&bytes)?),
+            None => None,
+        };
+
         // Load all blocks
-        let mut block_idx = 0;
+        let mut block_idx = base_height;
         loop {
             let key = format!("block:{}", block_idx);
             match state_db.get(key.as_bytes())? {
                 Some(block_bytes) => {
-                    let block: Block = serde_json::from_slice(&block_bytes)?;
+                    let block: Block = Codec::decode(&block_bytes)?;
                     chain.push(block);
                     block_idx += 1;
                 }
@@ -135,44 +1247,498 @@ impl CommunityBlockchain {
             }
         }
 
+        if chain.is_empty() {
+            return Err("No blockchain found: the store has no genesis block".into());
+        }
+
         // Load all wallets and rebuild indices
-        for item in state_db.scan_prefix(b"wallet:") {
-            if let Ok((_key, value)) = item {
-                let wallet: Wallet = serde_json::from_slice(&value)?;
-                wallets.insert(wallet.address.clone(), wallet.clone());
-                nonces.insert(wallet.address.clone(), 0);
-                tx_index.insert(wallet.address.clone(), Vec::new());
+        for (_key, value) in state_db.scan_prefix(b"wallet:")? {
+            let wallet: Wallet = Codec::decode(&value)?;
+            wallets.insert(wallet.address.clone(), wallet.clone());
+            nonces.insert(wallet.address.clone(), 0);
+            tx_index.insert(wallet.address.clone(), Vec::new());
+        }
+        for block in &chain {
+            for (position, tx) in block.transactions.iter().enumerate() {
+                tx_heights.entry(tx.tx_id.clone()).or_default().push(block.index);
+                let mut parties = vec![&tx.from];
+                if tx.to != tx.from {
+                    parties.push(&tx.to);
+                }
+                for address in parties {
+                    tx_index
+                        .entry(address.clone())
+                        .or_insert_with(Vec::new)
+                        .push(TransactionIndex {
+                            tx_id: tx.tx_id.clone(),
+                            block_index: block.index,
+                            tx_index_in_block: position,
+                        });
+                }
             }
         }
 
-        Ok(CommunityBlockchain {
+        // Load deployed contracts
+        let contracts = Arc::new(DashMap::new());
+        for (_key, value) in state_db.scan_prefix(b"contract:")? {
+            let contract: Contract = Codec::decode(&value)?;
+            contracts.insert(contract.address.clone(), contract);
+        }
+
+        // Load registered validators
+        let mut validators = ValidatorRegistry::new();
+        for (_key, value) in state_db.scan_prefix(b"validator:")? {
+            let validator: Validator = Codec::decode(&value)?;
+            validators.register(validator.address, validator.stake)?;
+        }
+
+        // Blocks up to `pruned_height` are headers only
+        let pruned_height: u64 = match state_db.get(b"pruned_height")? {
+            Some(bytes) => Codec::decode(&bytes)?,
+            None => 0,
+        };
+        let pruned_flows: (u64, u64) = match state_db.get(b"pruned_flows")? {
+            Some(bytes) => Codec::decode(&bytes)?,
+            None => (0, 0),
+        };
+        let pruned_nonces = DashMap::new();
+        for (key, value) in state_db.scan_prefix(b"pruned_nonce:")? {
+            let sender = String::from_utf8_lossy(&key[b"pruned_nonce:".len()..]).into_owned();
+            pruned_nonces.insert(sender, Codec::decode::<u64>(&value)?);
+        }
+
+        let finalized_height: u64 = match state_db.get(b"finalized_height")? {
+            Some(bytes) => Codec::decode(&bytes)?,
+            None => 0,
+        };
+
+        let mut blockchain = CommunityBlockchain {
             chain: Arc::new(Mutex::new(chain)),
             wallets,
-            tx_index,
-            pending_txs: Arc::new(Mutex::new(Vec::new())),
+            tx_index: TxIndex::new(tx_index),
+            tx_heights,
+            max_index_len: None,
+            mempool: Arc::new(Mutex::new(Mempool::new(MempoolLimits::default()))),
             nonces,
+            committed_nonces: Arc::new(DashMap::new()),
+            contracts,
+            validators: Arc::new(Mutex::new(validators)),
+            votes: Arc::new(Mutex::new(BTreeMap::new())),
+            finalized_height: Arc::new(Mutex::new(finalized_height)),
+            side_blocks: Arc::new(Mutex::new(HashMap::new())),
+            finality_quorum: DEFAULT_FINALITY_QUORUM,
+            validator_key: None,
+            system_key: None,
+            access: Arc::new(Mutex::new(AccessList::default())),
+            params,
             state_db,
-        })
-    }
+            codec: Codec::default(),
+            sender_rate_limit: None,
+            sender_buckets: Arc::new(DashMap::new()),
+            max_chain_len: None,
+            block_events: broadcast::channel(BLOCK_EVENT_BUFFER).0,
+            pow_difficulty: 0,
+            contract_slots: None,
+            fee_floor_blocks: DEFAULT_FEE_FLOOR_BLOCKS,
+            recent_tx_ids: Arc::new(DashMap::new()),
+            replay_rejections: Arc::new(ReplayCounters::default()),
+            write_batching: None,
+            write_buffer: Arc::new(Mutex::new(WriteBuffer::default())),
+            reserve_pending: true,
+            reserved: Arc::new(DashMap::new()),
+            reservations: Arc::new(DashMap::new()),
+            base_height,
+            base_nonces,
+            base_supply: stored_base_supply.unwrap_or(0),
+            genesis_hash,
+            check_supply: false,
+            snapshot_retention: None,
+            prune_depth: None,
+            pruned_height: Arc::new(Mutex::new(pruned_height)),
+            pruned_flows: Arc::new(Mutex::new(pruned_flows)),
+            pruned_nonces: Arc::new(pruned_nonces),
+            clock: current_timestamp,
+        };
 
-    /// Create transaction with validation and nonce tracking
-    pub fn create_transaction(
-        &self,
-        from: String,
-        to: String,
-        amount: u64,
+        // Continue every sender's nonce sequence where the chain left it
+        for mut entry in blockchain.nonces.iter_mut() {
+            *entry.value_mut() = blockchain.committed_nonce(entry.key());
+            if *entry.value() > 0 {
+                blockchain.committed_nonces.insert(entry.key().clone(), *entry.value());
+            }
+        }
+
+        blockchain.rebuild_recent_tx_ids();
+
+        // Pick the mempool up from its last persisted copy
+        if let Some(bytes) = blockchain.state_db.get(b"mempool")? {
+            blockchain.restore_mempool(Codec::decode(&bytes)?);
+        }
+
+        // Databases from before supply reconciliation take the supply they
+        // hold now as the baseline
+        if stored_base_supply.is_none() {
+            let (issued, destroyed) = blockchain.supply_flows()?;
+            blockchain.base_supply = add_amount(blockchain.total_supply(), destroyed)?.saturating_sub(issued);
+            blockchain
+                .state_db
+                .insert(b"base_supply", &blockchain.codec.encode(&blockchain.base_supply)?)?;
+        }
+
+        Ok(blockchain)
+    }
+
+    /// Signed snapshot of the state at the current tip, for new nodes to
+    /// start from with [`Self::from_snapshot`]
+    pub fn export_snapshot(&self, key: &SigningKey) -> Snapshot {
+        let tip = self.chain.lock().unwrap().last().unwrap().clone();
+        let mut wallets: Vec<Wallet> = self.wallets.iter().map(|w| w.value().clone()).collect();
+        wallets.sort_by(|a, b| a.address.cmp(&b.address));
+        let nonces = wallets
+            .iter()
+            .map(|w| (w.address.clone(), self.committed_nonce(&w.address)))
+            .filter(|(_, nonce)| *nonce > 0)
+            .collect();
+
+        let mut contracts: Vec<Contract> = self.contracts.iter().map(|c| c.value().clone()).collect();
+        contracts.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let mut snapshot = Snapshot {
+            params: self.params.clone(),
+            genesis_hash: self.genesis_hash(),
+            tip,
+            wallets,
+            nonces,
+            state_root: self.state_root(),
+            contracts,
+            signature: String::new(),
+        };
+        snapshot.sign(key);
+        snapshot
+    }
+
+    /// Export a snapshot signed with `key` and keep it in storage under its
+    /// tip height, then prune stored snapshots down to the configured
+    /// retention. Returns the snapshot's height.
+    pub fn save_snapshot(&self, key: &SigningKey) -> Result<u64, StorageError> {
+        let snapshot = self.export_snapshot(key);
+        let height = snapshot.tip.index;
+        self.state_db
+            .insert(Self::snapshot_key(height).as_bytes(), &self.codec.encode(&snapshot)?)?;
+        if let Some(keep) = self.snapshot_retention {
+            self.prune_snapshots(keep)?;
+        }
+        Ok(height)
+    }
+
+    /// Heights of the stored snapshots, oldest first
+    pub fn stored_snapshots(&self) -> Result<Vec<u64>, StorageError> {
+        Ok(self
+            .state_db
+            .scan_prefix(b"snapshot:")?
+            .into_iter()
+            .filter_map(|(key, _)| std::str::from_utf8(&key[b"snapshot:".len()..]).ok()?.parse().ok())
+            .collect())
+    }
+
+    /// Delete all but the latest `keep` stored snapshots. The snapshot at
+    /// the checkpoint this node was started from is kept too. Returns how
+    /// many were deleted.
+    pub fn prune_snapshots(&self, keep: usize) -> Result<usize, StorageError> {
+        let heights = self.stored_snapshots()?;
+        let removals: Vec<WriteOp> = heights
+            .iter()
+            .rev()
+            .skip(keep)
+            .filter(|height| self.base_height == 0 || **height != self.base_height)
+            .map(|height| WriteOp::Remove(Self::snapshot_key(*height).into_bytes()))
+            .collect();
+        let removed = removals.len();
+        if removed > 0 {
+            self.state_db.transaction(removals)?;
+        }
+        Ok(removed)
+    }
+
+    /// The stored snapshot at `height`, if there is one
+    pub fn stored_snapshot(&self, height: u64) -> Result<Option<Snapshot>, StorageError> {
+        match self.state_db.get(Self::snapshot_key(height).as_bytes())? {
+            Some(bytes) => Ok(Some(Codec::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Zero-padded so keys sort by height
+    fn snapshot_key(height: u64) -> String {
+        format!("snapshot:{:020}", height)
+    }
+
+    /// Initialize an empty store from a snapshot whose checkpoint `trusted`
+    /// signed. The node holds no blocks below the snapshot's tip, which is
+    /// final.
+    pub fn from_snapshot(
+        snapshot: Snapshot,
+        trusted: &VerifyingKey,
+        state_db: Arc<dyn KvStore>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_trusted_snapshot(snapshot, &SnapshotTrust::Signer(*trusted), state_db)
+    }
+
+    /// Initialize an empty store from a snapshot that `trust` vouches for,
+    /// by signature or by its tip block's hash
+    pub fn from_trusted_snapshot(
+        snapshot: Snapshot,
+        trust: &SnapshotTrust,
+        state_db: Arc<dyn KvStore>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        trust.check(&snapshot)?;
+        let balances: HashMap<String, u64> = snapshot
+            .wallets
+            .iter()
+            .map(|w| (w.address.clone(), w.balance))
+            .collect();
+        let nonces: HashMap<String, u64> = snapshot.nonces.clone().into_iter().collect();
+        if Self::calculate_state_root(&balances, &nonces) != snapshot.state_root {
+            return Err("Snapshot balances do not match its state root".into());
+        }
+        // The genesis block's hash is a fixed label rather than a digest
+        let tip = &snapshot.tip;
+        if tip.index > 0 && Self::calculate_block_hash(tip) != tip.hash {
+            return Err("Snapshot tip block does not match its hash".into());
+        }
+        if state_db.get(b"chain_params")?.is_some() {
+            return Err("Cannot import a snapshot into a store that already holds a chain".into());
+        }
+
+        let codec = Codec::default();
+        let mut writes = vec![
+            WriteOp::Insert(b"chain_params".to_vec(), codec.encode(&snapshot.params)?),
+            WriteOp::Insert(format!("block:{}", tip.index).into_bytes(), codec.encode(tip)?),
+            WriteOp::Insert(b"snapshot_base".to_vec(), codec.encode(&tip.index)?),
+            WriteOp::Insert(b"snapshot_nonces".to_vec(), codec.encode(&snapshot.nonces)?),
+            WriteOp::Insert(b"base_supply".to_vec(), codec.encode(&Self::sum_balances(&balances)?)?),
+            WriteOp::Insert(b"finalized_height".to_vec(), codec.encode(&tip.index)?),
+        ];
+        if let Some(genesis_hash) = &snapshot.genesis_hash {
+            writes.push(WriteOp::Insert(b"genesis_hash".to_vec(), codec.encode(genesis_hash)?));
+        }
+        for wallet in &snapshot.wallets {
+            writes.push(WriteOp::Insert(
+                format!("wallet:{}", wallet.address).into_bytes(),
+                codec.encode(wallet)?,
+            ));
+        }
+        for contract in &snapshot.contracts {
+            writes.push(WriteOp::Insert(
+                format!("contract:{}", contract.address).into_bytes(),
+                codec.encode(contract)?,
+            ));
+        }
+        state_db.transaction(writes)?;
+
+        Self::load_from_store(state_db)
+    }
+
+    /// Download a snapshot over HTTP and initialize a fresh sled database at
+    /// `db_path` from it, see [`Self::from_snapshot`]
+    pub async fn from_snapshot_url(
+        url: &str,
+        db_path: &str,
+        trusted: &VerifyingKey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_snapshot_url_into(url, Arc::new(sled::open(db_path)?), &SnapshotTrust::Signer(*trusted)).await
+    }
+
+    /// Download a snapshot over HTTP and initialize any empty storage
+    /// backend from it, see [`Self::from_trusted_snapshot`]
+    pub async fn from_snapshot_url_into(
+        url: &str,
+        state_db: Arc<dyn KvStore>,
+        trust: &SnapshotTrust,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let snapshot: Snapshot = reqwest::get(url).await?.error_for_status()?.json().await?;
+        Self::from_trusted_snapshot(snapshot, trust, state_db)
+    }
+
+    /// Fetch the blocks above the tip from the node at `peer` (its base
+    /// URL) through `/block/:index` and add them one by one, validating each
+    /// as any other block, until the peer has no more. A peer whose genesis
+    /// block differs from ours, or either of which is unknown, is refused up
+    /// front. Returns how many blocks were added.
+    pub async fn sync_from_peer(&self, peer: &str) -> Result<u64, String> {
+        let client = reqwest::Client::new();
+        let ours = self
+            .genesis_hash()
+            .ok_or("Our genesis hash is unknown, so no peer can be told to be on our chain")?;
+        let theirs = Self::fetch_block(&client, peer, 0).await?;
+        if theirs.map(|block| block.hash) != Some(ours) {
+            return Err(format!("{} is on another chain: its genesis block differs from ours", peer));
+        }
+
+        let mut synced = 0;
+        loop {
+            let index = self.tip().index + 1;
+            let Some(block) = Self::fetch_block(&client, peer, index).await? else {
+                return Ok(synced);
+            };
+            if block.index != index {
+                return Err(format!("Asked for block {}, got block {}", index, block.index));
+            }
+            self.add_block(block)?;
+            synced += 1;
+        }
+    }
+
+    /// The block `peer` serves at `index`, `None` if it has none there
+    async fn fetch_block(client: &reqwest::Client, peer: &str, index: u64) -> Result<Option<Block>, String> {
+        let response = client
+            .get(format!("{}/block/{}", peer.trim_end_matches('/'), index))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to fetch block {}: {}", index, e))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        response
+            .error_for_status()
+            .map_err(|e| format!("Failed to fetch block {}: {}", index, e))?
+            .json()
+            .await
+            .map(Some)
+            .map_err(|e| format!("Block {} is malformed: {}", index, e))
+    }
+
+    /// Get chain params
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Digest of a genesis allocation, its validators and the params it
+    /// starts with. Nodes started from the same genesis config share it.
+    pub fn calculate_genesis_hash(
+        initial_wallets: &HashMap<String, u64>,
+        validators: &[Validator],
+        params: &ChainParams,
+    ) -> String {
+        let mut allocation: Vec<_> = initial_wallets.iter().collect();
+        allocation.sort();
+
+        let mut hasher = Sha256::new();
+        for (address, balance) in allocation {
+            hasher.update(format!("{}:{}\n", address, balance).as_bytes());
+        }
+        let mut stakes: Vec<_> = validators.iter().map(|v| (&v.address, v.stake)).collect();
+        stakes.sort();
+        for (address, stake) in stakes {
+            hasher.update(format!("validator {}:{}\n", address, stake).as_bytes());
+        }
+        hasher.update(serde_json::to_vec(params).unwrap_or_default());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Hash of the genesis this chain was created from, which is also block
+    /// 0's hash. `None` if neither the hash nor block 0 is held, as for a
+    /// node started from a snapshot that didn't carry the hash.
+    pub fn genesis_hash(&self) -> Option<String> {
+        self.genesis_hash
+            .clone()
+            .or_else(|| self.get_block_by_index(0).map(|block| block.hash))
+    }
+
+    /// Create transaction with validation and nonce tracking
+    pub fn create_transaction(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
     ) -> Result<String, String> {
-        if amount == 0 {
-            return Err("Amount must be greater than 0".to_string());
+        self.create_transaction_with_memo(from, to, amount, None)
+    }
+
+    /// Create transaction carrying an optional memo
+    pub fn create_transaction_with_memo(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        memo: Option<String>,
+    ) -> Result<String, String> {
+        self.create_scheduled_transaction(from, to, amount, memo, None)
+    }
+
+    /// Create transaction that is held in the mempool until the chain
+    /// reaches `execute_at_height`
+    pub fn create_scheduled_transaction(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        memo: Option<String>,
+        execute_at_height: Option<u64>,
+    ) -> Result<String, String> {
+        self.create_transaction_with_pow(from, to, amount, memo, execute_at_height, None)
+    }
+
+    /// Create transaction carrying the client's proof of work, which must
+    /// meet the node's difficulty when one is configured
+    pub fn create_transaction_with_pow(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        memo: Option<String>,
+        execute_at_height: Option<u64>,
+        pow_nonce: Option<u64>,
+    ) -> Result<String, String> {
+        self.create_transaction_with_options(
+            from,
+            to,
+            amount,
+            TransferOptions {
+                memo,
+                execute_at_height,
+                recent_block_hash: None,
+                pow_nonce,
+                fee: None,
+                contract: None,
+            },
+        )
+    }
+
+    /// Create transaction with any of the optional transfer fields
+    pub fn create_transaction_with_options(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        options: TransferOptions,
+    ) -> Result<String, String> {
+        self.check_node_signing(&from)?;
+        self.check_transfer(&from, &to, amount, options.memo.as_deref())?;
+        if let Some(hash) = &options.recent_block_hash {
+            self.check_recent_block(hash, self.next_height())?;
+        }
+        if self.pow_difficulty > 0 {
+            let payload = pow::payload(&from, &to, amount, self.next_nonce(&from));
+            if !options.pow_nonce.is_some_and(|pow_nonce| pow::verify(&payload, pow_nonce, self.pow_difficulty)) {
+                return Err(format!(
+                    "Missing or invalid proof of work (difficulty {})",
+                    self.pow_difficulty
+                ));
+            }
         }
 
         // Check sender exists
         let sender_wallet = self.wallets.get(&from)
             .ok_or("Sender wallet not found".to_string())?;
 
-        // Check balance (including fee: 1% of amount)
-        let fee = (amount as f64 * 0.01).ceil() as u64;
-        let total_cost = amount + fee;
+        // Check balance (including fee)
+        let fee = match options.fee {
+            Some(fee) if fee < MIN_FEE => return Err(format!("Fee must be at least {}", MIN_FEE)),
+            Some(fee) => fee,
+            None => self.estimate_fee(amount, options.memo.as_ref().map_or(0, String::len)),
+        };
+        let total_cost = add_amount(amount, fee)?;
 
         if sender_wallet.balance < total_cost {
             return Err(format!(
@@ -182,440 +1748,7329 @@ impl CommunityBlockchain {
         }
         drop(sender_wallet);
 
-        // Ensure recipient exists or will be created
-        if !self.wallets.contains_key(&to) {
-            let now = current_timestamp();
-            let new_wallet = Wallet {
-                address: to.clone(),
-                balance: 0,
-                tx_count: 0,
-                created_at: now,
-                last_updated: now,
-            };
-            self.wallets.insert(to.clone(), new_wallet);
-            self.tx_index.insert(to.clone(), Vec::new());
-            self.nonces.insert(to.clone(), 0);
+        self.check_sender_rate(&from, 1)?;
+        let mut tx_ids = self.enqueue_transactions(&from, vec![(to, amount, fee, options)])?;
+        Ok(tx_ids.remove(0))
+    }
+
+    /// Create one transaction from `from` per `(to, amount)` leg. Every leg
+    /// is checked, and the sender must afford them all, before any is
+    /// queued, so one bad leg fails the whole batch. Once queued the legs
+    /// are ordinary transactions: blocks may include them apart, and one
+    /// can still be dropped, e.g. by expiry, while the others are mined.
+    pub fn create_batch_transaction(
+        &self,
+        from: String,
+        legs: Vec<(String, u64)>,
+    ) -> Result<Vec<String>, String> {
+        if legs.is_empty() {
+            return Err("Batch has no transfers".to_string());
+        }
+        if self.pow_difficulty > 0 {
+            return Err("Batches are not accepted while proof of work is required".to_string());
         }
+        self.check_node_signing(&from)?;
 
-        // Get nonce
-        let mut nonce_entry = self.nonces.entry(from.clone()).or_insert(0);
-        *nonce_entry += 1;
-        let current_nonce = *nonce_entry;
-        drop(nonce_entry);
+        let mut total_cost: u64 = 0;
+        let mut fees = Vec::with_capacity(legs.len());
+        for (i, (to, amount)) in legs.iter().enumerate() {
+            self.check_transfer(&from, to, *amount, None)
+                .map_err(|e| format!("Transfer {}: {}", i, e))?;
+            let fee = self.estimate_fee(*amount, 0);
+            total_cost = add_amount(total_cost, add_amount(*amount, fee)?)?;
+            fees.push(fee);
+        }
 
-        let timestamp = current_timestamp();
-        let tx_id = format!("{}-{}-{}-{}", from, to, current_nonce, timestamp);
-        let signature = self.sign_transaction(&tx_id, &from);
+        let balance = self
+            .wallets
+            .get(&from)
+            .map(|wallet| wallet.balance)
+            .ok_or("Sender wallet not found".to_string())?;
+        if balance < total_cost {
+            return Err(format!(
+                "Insufficient balance: {} has {}, batch needs {}",
+                from, balance, total_cost
+            ));
+        }
 
-        let tx = Transaction {
-            from,
-            to,
-            amount,
-            fee,
-            timestamp,
-            tx_id: tx_id.clone(),
-            signature,
-            nonce: current_nonce,
-        };
+        self.check_sender_rate(&from, legs.len())?;
+        let legs = legs
+            .into_iter()
+            .zip(fees)
+            .map(|((to, amount), fee)| (to, amount, fee, TransferOptions::default()))
+            .collect();
+        self.enqueue_transactions(&from, legs)
+    }
+
+    /// Checks on a transfer that don't depend on the sender's balance
+    fn check_transfer(&self, from: &str, to: &str, amount: u64, memo: Option<&str>) -> Result<(), String> {
+        if memo.is_some_and(|memo| memo.len() > MAX_MEMO_LEN) {
+            return Err(format!("Memo exceeds {} bytes", MAX_MEMO_LEN));
+        }
+        // Such a transfer could never be mined
+        if memo.is_some_and(|memo| memo.len() as u64 > self.params.max_block_memo_bytes) {
+            return Err(format!(
+                "Memo exceeds the per-block limit of {} memo bytes",
+                self.params.max_block_memo_bytes
+            ));
+        }
+        if amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+        self.check_tx_amount(amount)?;
+        self.check_access(from, to)?;
+        if to == COINBASE_ADDRESS {
+            return Err("Cannot send to the coinbase account".to_string());
+        }
+        if from == STAKE_ADDRESS {
+            return Err("Staked coins can only leave by unstaking".to_string());
+        }
+        if to == STAKE_ADDRESS {
+            return Err("Coins can only be staked with a stake transaction signed by their owner".to_string());
+        }
+        if to == CONTRACT_ADDRESS {
+            return Err(format!("Only contract transactions may be sent to {}", CONTRACT_ADDRESS));
+        }
+        if from == to {
+            return Err("Cannot send to yourself".to_string());
+        }
+        Ok(())
+    }
+
+    /// Sign checked `(to, amount, fee, options)` transfers from `from` with
+    /// the sender's next nonces and add them to the mempool together,
+    /// reserving their cost and making room for them
+    fn enqueue_transactions(
+        &self,
+        from: &str,
+        legs: Vec<(String, u64, u64, TransferOptions)>,
+    ) -> Result<Vec<String>, String> {
+        let mut mempool = self.mempool.lock().unwrap();
+        self.expire_pending(&mut mempool);
+        let timestamp = (self.clock)();
+        let first_nonce = self.next_nonce(from);
+        let txs: Vec<Transaction> = legs
+            .into_iter()
+            .zip(first_nonce..)
+            .map(|((to, amount, fee, options), nonce)| {
+                let mut tx = Transaction {
+                    from: from.to_string(),
+                    tx_id: format!("{}-{}-{}-{}", from, to, nonce, timestamp),
+                    to,
+                    amount,
+                    fee,
+                    timestamp,
+                    signature: String::new(),
+                    nonce,
+                    memo: options.memo,
+                    execute_at_height: options.execute_at_height,
+                    recent_block_hash: options.recent_block_hash,
+                    sig_scheme: SigScheme::Sha256,
+                    contract: options.contract,
+                };
+                self.sign_as_node(&mut tx)?;
+                Ok(tx)
+            })
+            .collect::<Result<_, String>>()?;
 
-        let mut pending = self.pending_txs.lock().unwrap();
-        pending.push(tx);
+        let admission = mempool.admit(&txs)?;
+        let total_cost = txs
+            .iter()
+            .try_fold(0, |total, tx| add_amount(total, add_amount(tx.amount, tx.fee)?))?;
+        self.reserve(from, total_cost)?;
 
-        Ok(tx_id)
+        let mut tx_ids = Vec::with_capacity(txs.len());
+        for tx in &txs {
+            // Ensure recipient exists or will be created
+            if !self.wallets.contains_key(&tx.to) {
+                let now = (self.clock)();
+                let new_wallet = Wallet {
+                    address: tx.to.clone(),
+                    balance: 0,
+                    tx_count: 0,
+                    created_at: now,
+                    last_updated: now,
+                };
+                self.wallets.insert(tx.to.clone(), new_wallet);
+                self.tx_index().hot.insert(tx.to.clone(), Vec::new());
+                self.nonces.insert(tx.to.clone(), 0);
+            }
+            self.nonces.insert(from.to_string(), tx.nonce);
+            tx_ids.push(tx.tx_id.clone());
+        }
+        let costs: Vec<u64> = txs.iter().map(|tx| tx.amount.saturating_add(tx.fee)).collect();
+        self.admit_transactions(&mut mempool, &admission, txs);
+        for (tx_id, cost) in tx_ids.iter().zip(costs) {
+            self.track_reservation(tx_id, from, cost);
+        }
+        Ok(tx_ids)
     }
 
-    /// Sign transaction
-    fn sign_transaction(&self, tx_id: &str, sender: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(tx_id.as_bytes());
-        hasher.update(sender.as_bytes());
-        format!("{:x}", hasher.finalize())
+    /// Add `txs` to `mempool` as planned in `admission`: hand back the reservations of the transactions they
+    /// displace, and the nonces of those evicted
+    fn admit_transactions(&self, mempool: &mut Mempool, admission: &Admission, txs: Vec<Transaction>) {
+        for displaced in mempool.insert(admission, txs, (self.clock)()) {
+            self.release_reservation(&displaced.tx_id);
+            if admission.evicted.contains(&displaced.tx_id) {
+                if let Some(mut nonce) = self.nonces.get_mut(&displaced.from) {
+                    *nonce = (*nonce).min(displaced.nonce.saturating_sub(1));
+                }
+            }
+        }
     }
 
-    /// Verify transaction signature
-    fn verify_signature(tx: &Transaction) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(tx.tx_id.as_bytes());
-        hasher.update(tx.from.as_bytes());
-        format!("{:x}", hasher.finalize()) == tx.signature
+    /// Drop pending transactions that have outlived the mempool's time to
+    /// live, with their reservations, and roll their senders' nonces back
+    fn expire_pending(&self, mempool: &mut Mempool) {
+        let expired = mempool.expire((self.clock)());
+        let senders: BTreeSet<String> = expired.iter().map(|tx| tx.from.clone()).collect();
+        for tx in &expired {
+            self.release_reservation(&tx.tx_id);
+        }
+        for sender in senders {
+            self.reset_nonce(mempool, &sender);
+        }
     }
 
-    /// Calculate state root from wallet balances
-    fn calculate_state_root(&self, wallets: &HashMap<String, u64>) -> String {
-        let mut hasher = Sha256::new();
-        let mut sorted_wallets: Vec<_> = wallets.iter().collect();
-        sorted_wallets.sort_by_key(|(k, _)| *k);
+    /// Check `tx`'s signature and nonce without queuing it
+    pub fn check_transaction(&self, tx: &Transaction) -> TransactionCheck {
+        let committed_nonce = self.committed_nonce(&tx.from);
+        TransactionCheck {
+            signature_valid: self.signature_accepted(tx),
+            nonce_valid: tx.nonce > committed_nonce,
+            committed_nonce,
+        }
+    }
 
-        for (addr, balance) in sorted_wallets {
-            hasher.update(addr.as_bytes());
-            hasher.update(balance.to_le_bytes());
+    /// Queue a transaction relayed by a peer, checked like a local
+    /// submission but keeping the sender's signature, nonce and fee
+    pub fn accept_transaction(&self, tx: Transaction) -> Result<(), String> {
+        self.check_not_confirmed(&tx.tx_id, &|tx_id| self.recently_confirmed(tx_id))?;
+        let total_cost = self.check_relayed_transaction(&tx, self.committed_nonce(&tx.from))?;
+        if let Some(hash) = &tx.recent_block_hash {
+            self.check_recent_block(hash, self.next_height())?;
         }
 
-        format!("{:x}", hasher.finalize())
+        let mut mempool = self.mempool.lock().unwrap();
+        self.expire_pending(&mut mempool);
+        self.queue_relayed_transaction(&mut mempool, tx, total_cost)
     }
 
-    /// Mine a block (PoS-like with proposer)
-    pub fn mine_block(&self, proposer: String) -> Result<Block, String> {
-        let mut pending = self.pending_txs.lock().unwrap();
+    /// Queue a batch of signed transactions, e.g. to seed test data, with
+    /// the checks of [`Self::accept_transaction`] but taking the mempool
+    /// lock once for the whole batch. Each transaction is accepted or
+    /// rejected on its own.
+    pub fn load_transactions(&self, txs: Vec<Transaction>) -> IngestReport {
+        let next_height = self.next_height();
+        let mut committed_nonces: HashMap<String, u64> = HashMap::new();
+        let mut recent_blocks: Option<HashSet<String>> = None;
+        let mut report = IngestReport::default();
 
-        if pending.is_empty() {
-            return Err("No pending transactions to mine".to_string());
-        }
+        let mut mempool = self.mempool.lock().unwrap();
+        self.expire_pending(&mut mempool);
+        for tx in txs {
+            let committed_nonce = *committed_nonces
+                .entry(tx.from.clone())
+                .or_insert_with(|| self.committed_nonce(&tx.from));
+            let checked = self
+                .check_not_confirmed(&tx.tx_id, &|tx_id| self.recently_confirmed(tx_id))
+                .and_then(|_| self.check_relayed_transaction(&tx, committed_nonce))
+                .and_then(|total_cost| {
+                    if let Some(hash) = &tx.recent_block_hash {
+                        let recent = recent_blocks.get_or_insert_with(|| self.recent_block_hashes(next_height));
+                        self.check_recent_block_in(hash, recent)?;
+                    }
+                    Ok(total_cost)
+                });
 
-        // Validate transactions in order (nonce-based ordering)
-        let mut valid_txs = Vec::new();
-        let mut temp_balances: HashMap<String, u64> = HashMap::new();
-        let mut tx_nonces: HashMap<String, u64> = HashMap::new();
+            let tx_id = tx.tx_id.clone();
+            match checked.and_then(|total_cost| self.queue_relayed_transaction(&mut mempool, tx, total_cost)) {
+                Ok(()) => {
+                    report.accepted += 1;
+                }
+                Err(e) => {
+                    report.rejected.insert(tx_id, e);
+                }
+            }
+        }
+        report
+    }
 
-        // Initialize temp balances
-        for wallet_ref in self.wallets.iter() {
-            temp_balances.insert(wallet_ref.key().clone(), wallet_ref.value().balance);
+    /// Checks on a transaction from outside the node short of queuing it.
+    /// Returns what it costs the sender.
+    fn check_relayed_transaction(&self, tx: &Transaction, committed_nonce: u64) -> Result<u64, String> {
+        // Anyone can compute a SHA-256 digest, so off demo chains one from
+        // outside the node never vouches for a spend, not even from a
+        // system account
+        if !self.signature_verifies(tx, false) {
+            return Err("Invalid transaction signature".to_string());
+        }
+        if tx.fee < MIN_FEE {
+            return Err(format!("Fee must be at least {}", MIN_FEE));
+        }
+        Self::check_contract_shape(tx)?;
+        match &tx.contract {
+            Some(op) => self.check_contract_transaction(&tx.from, op)?,
+            None => self.check_transfer(&tx.from, &tx.to, tx.amount, tx.memo.as_deref())?,
         }
+        if tx.nonce <= committed_nonce {
+            ReplayCounters::count(&self.replay_rejections.nonce);
+            return Err(format!("Nonce {} of {} is already used", tx.nonce, tx.from));
+        }
+        let balance = self.get_balance(&tx.from)?;
+        let total_cost = add_amount(tx.amount, tx.fee)?;
+        if balance < total_cost {
+            return Err(format!(
+                "Insufficient balance: {} has {}, needs {}",
+                tx.from, balance, total_cost
+            ));
+        }
+        Ok(total_cost)
+    }
 
-        for tx in pending.iter() {
-            if !Self::verify_signature(tx) {
-                continue;
+    /// Add a checked transaction to the held mempool
+    fn queue_relayed_transaction(&self, mempool: &mut Mempool, tx: Transaction, total_cost: u64) -> Result<(), String> {
+        let admission = mempool.admit(std::slice::from_ref(&tx)).inspect_err(|e| {
+            if matches!(e, MempoolError::Duplicate(_)) {
+                ReplayCounters::count(&self.replay_rejections.duplicate);
             }
+        })?;
+        self.reserve(&tx.from, total_cost)?;
+        let (tx_id, from) = (tx.tx_id.clone(), tx.from.clone());
+        let mut nonce_entry = self.nonces.entry(from.clone()).or_insert(0);
+        *nonce_entry = (*nonce_entry).max(tx.nonce);
+        drop(nonce_entry);
+        self.admit_transactions(mempool, &admission, vec![tx]);
+        self.track_reservation(&tx_id, &from, total_cost);
+        Ok(())
+    }
 
-            // Check nonce ordering
-            let expected_nonce = tx_nonces.entry(tx.from.clone()).or_insert(0);
-            if tx.nonce != *expected_nonce + 1 {
-                continue;
-            }
-            *expected_nonce = tx.nonce;
+    /// Set `cost` of `sender`'s balance aside for a pending transaction, if
+    /// balance reservation is on. The check and the update happen under one
+    /// lock, so concurrent transfers cannot promise the same coins twice.
+    fn reserve(&self, sender: &str, cost: u64) -> Result<(), String> {
+        if !self.reserve_pending {
+            return Ok(());
+        }
+        let mut reserved = self.reserved.entry(sender.to_string()).or_insert(0);
+        let balance = self.wallets.get(sender).map_or(0, |wallet| wallet.balance);
+        let available = balance.saturating_sub(*reserved);
+        if available < cost {
+            return Err(format!(
+                "Insufficient balance: {} has {} not reserved by pending transactions, needs {}",
+                sender, available, cost
+            ));
+        }
+        *reserved = add_amount(*reserved, cost)?;
+        Ok(())
+    }
+
+    /// Remember what was reserved for `tx_id`, to hand back once it is
+    /// mined or dropped
+    fn track_reservation(&self, tx_id: &str, sender: &str, cost: u64) {
+        if self.reserve_pending {
+            self.reservations.insert(tx_id.to_string(), (sender.to_string(), cost));
+        }
+    }
 
-            let sender_balance = temp_balances.get(&tx.from).copied().unwrap_or(0);
-            if sender_balance >= tx.amount + tx.fee {
-                temp_balances.insert(tx.from.clone(), sender_balance - tx.amount - tx.fee);
-                let recipient_balance = temp_balances.get(&tx.to).copied().unwrap_or(0);
-                temp_balances.insert(tx.to.clone(), recipient_balance + tx.amount);
-                valid_txs.push(tx.clone());
+    /// Hand back what was reserved for `tx_id`, if anything
+    fn release_reservation(&self, tx_id: &str) {
+        if let Some((_, (sender, cost))) = self.reservations.remove(tx_id) {
+            if let Some(mut reserved) = self.reserved.get_mut(&sender) {
+                *reserved = reserved.saturating_sub(cost);
             }
         }
+    }
 
-        if valid_txs.is_empty() {
-            return Err("No valid transactions after validation".to_string());
+    /// Balance of `address` set aside for their pending transactions
+    pub fn reserved_balance(&self, address: &str) -> u64 {
+        self.reserved.get(address).map_or(0, |reserved| *reserved)
+    }
+
+    /// Nonce the next transaction from `address` will take
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).map_or(0, |nonce| *nonce) + 1
+    }
+
+    /// Fee `create_transaction` charges right now: 1% of the amount (at least
+    /// `MIN_FEE`) plus a unit per started `MEMO_BYTES_PER_FEE_UNIT` memo
+    /// bytes, multiplied by one more for every `CONGESTION_STEP` pending
+    /// transactions
+    pub fn estimate_fee(&self, amount: u64, memo_len: usize) -> u64 {
+        let base = ((amount as f64 * 0.01).ceil() as u64).max(MIN_FEE);
+        let memo_fee = memo_len.div_ceil(MEMO_BYTES_PER_FEE_UNIT) as u64;
+        let congestion = 1 + (self.mempool.lock().unwrap().len() / CONGESTION_STEP) as u64;
+        (base + memo_fee).saturating_mul(congestion)
+    }
+
+    /// Lowest fee paid by a transaction in the last `fee_floor_blocks`
+    /// blocks, a floor for getting into the next one. Fee-less supply
+    /// changes don't count, and `MIN_FEE` stands in when nothing was paid.
+    pub fn next_block_min_fee(&self) -> u64 {
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        let start = tip.saturating_sub(self.fee_floor_blocks as u64 - 1);
+        self.blocks_from(start)
+            .flat_map(|block| block.transactions)
+            .map(|tx| tx.fee)
+            .filter(|fee| *fee > 0)
+            .min()
+            .unwrap_or(MIN_FEE)
+    }
+
+    /// Take `count` tokens from `sender`'s bucket, or fail with
+    /// [`SENDER_RATE_LIMITED`] if it holds fewer
+    fn check_sender_rate(&self, sender: &str, count: usize) -> Result<(), String> {
+        let Some(rate) = self.sender_rate_limit else {
+            return Ok(());
+        };
+        let now = (self.clock)();
+        let mut entry = self
+            .sender_buckets
+            .entry(sender.to_string())
+            .or_insert_with(|| (rate.limit, now));
+
+        let time_passed = now.saturating_sub(entry.1);
+        entry.0 += (time_passed as f64 / rate.window_secs.max(1) as f64) * rate.limit;
+        entry.0 = entry.0.min(rate.limit);
+        entry.1 = now;
+
+        if entry.0 >= count as f64 {
+            entry.0 -= count as f64;
+            Ok(())
+        } else {
+            Err(format!(
+                "{}: {} allows {} transactions per {}s",
+                SENDER_RATE_LIMITED, sender, rate.limit, rate.window_secs
+            ))
+        }
+    }
+
+    /// Reject transfers where either side is denylisted or, in
+    /// allowlist-only mode, not allowlisted. Lists are node configuration,
+    /// so this only guards admission to the mempool: blocks are checked
+    /// alike on every node whatever its lists.
+    fn check_access(&self, from: &str, to: &str) -> Result<(), String> {
+        let access = self.access.lock().unwrap();
+        access.check(from)?;
+        access.check(to)
+    }
+
+    /// Current allowlist/denylist
+    pub fn access_list(&self) -> AccessList {
+        self.access.lock().unwrap().clone()
+    }
+
+    /// Replace the allowlist/denylist. Lists are node configuration and are
+    /// not persisted.
+    pub fn set_access_list(&self, access: AccessList) {
+        *self.access.lock().unwrap() = access;
+    }
+
+    /// Edit the allowlist/denylist in place
+    pub fn update_access_list(&self, update: impl FnOnce(&mut AccessList)) {
+        update(&mut self.access.lock().unwrap());
+    }
+
+    /// Reject amounts above the per-transaction cap
+    fn check_tx_amount(&self, amount: u64) -> Result<(), String> {
+        if amount > self.params.max_tx_amount {
+            return Err(format!(
+                "Amount {} exceeds the per-transaction limit of {}",
+                amount, self.params.max_tx_amount
+            ));
         }
+        Ok(())
+    }
+
+    /// Sign transaction
+    fn sign_transaction(
+        &self,
+        tx_id: &str,
+        sender: &str,
+        memo: Option<&str>,
+        execute_at_height: Option<u64>,
+        recent_block_hash: Option<&str>,
+        contract: Option<&ContractTransaction>,
+    ) -> String {
+        let payload = legacy_signing_payload(tx_id, sender, memo, execute_at_height, recent_block_hash, contract);
+        format!("{:x}", Sha256::digest(payload))
+    }
+
+    /// Whether `address` is an ed25519 public key, so its transactions are
+    /// signed outside the node by whoever holds the key
+    pub fn is_externally_owned(address: &str) -> bool {
+        address.len() == 64 && crate::snapshot::parse_verifying_key(address).is_ok()
+    }
+
+    /// Check the node may sign a transaction from `from`. It never can for
+    /// an externally-owned account, and unless the chain allows node
+    /// signing, only for system accounts, with its system key.
+    fn check_node_signing(&self, from: &str) -> Result<(), String> {
+        if Self::is_externally_owned(from) || (!self.params.node_signing && !Self::system_account(from)) {
+            return Err(format!(
+                "{}: {} must submit transactions signed with its own key",
+                CLIENT_SIGNING_REQUIRED, from
+            ));
+        }
+        if !self.params.node_signing && self.system_key().is_none() {
+            return Err(format!("This node holds no system key to sign for {}", from));
+        }
+        Ok(())
+    }
+
+    /// Whether `address` is a system account other than staking, whose
+    /// transactions are signed with one of the chain's system keys
+    fn system_account(address: &str) -> bool {
+        RESERVED_ADDRESSES.contains(&address) && address != STAKE_ADDRESS
+    }
+
+    /// The node's system key, if the chain lists it
+    fn system_key(&self) -> Option<&SigningKey> {
+        self.system_key.as_ref().filter(|key| {
+            let public = hex::encode(key.verifying_key().to_bytes());
+            self.params.system_keys.iter().any(|listed| listed.eq_ignore_ascii_case(&public))
+        })
+    }
+
+    /// Sign `tx` on its sender's behalf: with the SHA-256 scheme on chains
+    /// allowing node signing, otherwise with the node's system key
+    fn sign_as_node(&self, tx: &mut Transaction) -> Result<(), String> {
+        if self.params.node_signing {
+            tx.sig_scheme = SigScheme::Sha256;
+            tx.signature = format!("{:x}", Sha256::digest(tx.signing_payload()));
+            return Ok(());
+        }
+        let key = self
+            .system_key()
+            .ok_or_else(|| format!("This node holds no system key to sign for {}", tx.from))?;
+        tx.sig_scheme = SigScheme::Ed25519;
+        tx.signature = hex::encode(key.sign(&tx.signing_payload()).to_bytes());
+        Ok(())
+    }
+
+    /// Register an externally generated ed25519 public key as an empty
+    /// wallet. Only a transaction signed with the matching key can spend
+    /// from it.
+    pub fn register_wallet(&self, public_key: &str) -> Result<Wallet, String> {
+        if !Self::is_externally_owned(public_key) {
+            crate::snapshot::parse_verifying_key(public_key)?;
+            return Err("Public key must be 64 hex digits".to_string());
+        }
+        let address = public_key.to_lowercase();
+        if self.wallets.contains_key(&address) {
+            return Err(format!("Wallet {} already exists", address));
+        }
+
+        let now = (self.clock)();
+        let wallet = Wallet {
+            address: address.clone(),
+            balance: 0,
+            tx_count: 0,
+            created_at: now,
+            last_updated: now,
+        };
+        let wallet_bytes = self.codec.encode(&wallet).map_err(|e| e.to_string())?;
+        self.state_db
+            .insert(format!("wallet:{}", address).as_bytes(), &wallet_bytes)
+            .map_err(|e| format!("Failed to persist wallet: {}", e))?;
+        self.wallets.insert(address.clone(), wallet.clone());
+        self.tx_index().hot.entry(address).or_default();
+        Ok(wallet)
+    }
+
+    /// Whether `tx` may go into a new block: its signature verifies, a
+    /// SHA-256 one only on a chain allowing node signing, and a stake
+    /// change is signed with the staker's key. Blocks already stored were
+    /// accepted, so they are only held to [`Self::signature_verifies`].
+    fn signature_accepted(&self, tx: &Transaction) -> bool {
+        if Self::stakes_change(std::slice::from_ref(tx)) {
+            return tx.sig_scheme == SigScheme::Ed25519 && Self::verify_signature(tx, false);
+        }
+        self.signature_verifies(tx, false)
+    }
+
+    /// [`Self::verify_signature`], or for a system account's transaction or
+    /// a burn an ed25519 signature by one of the chain's system keys.
+    /// SHA-256 digests pass only in `stored` blocks or on chains allowing
+    /// node signing.
+    fn signature_verifies(&self, tx: &Transaction, stored: bool) -> bool {
+        Self::verify_signature(tx, stored || self.params.node_signing)
+            || (tx.sig_scheme == SigScheme::Ed25519
+                && (Self::system_account(&tx.from) || tx.to == BURN_ADDRESS)
+                && self.params.system_keys.iter().any(|key| Self::ed25519_signed(tx, key)))
+    }
+
+    /// Verify transaction signature under the scheme it declares. An
+    /// ed25519 one is checked against the key of [`Transaction::signer`].
+    /// A SHA-256 digest proves nothing, as anyone can compute it, so it is
+    /// legacy only: it passes just when `legacy` allows it, for blocks
+    /// already stored or on chains allowing node signing, and never for an
+    /// externally-owned sender. It must never authorise a relayed spend.
+    fn verify_signature(tx: &Transaction, legacy: bool) -> bool {
+        match tx.sig_scheme {
+            SigScheme::Sha256 => {
+                legacy
+                    && !Self::is_externally_owned(&tx.from)
+                    && format!("{:x}", Sha256::digest(tx.signing_payload())) == tx.signature
+            }
+            SigScheme::Ed25519 => Self::ed25519_signed(tx, tx.signer()),
+            SigScheme::Unknown => false,
+        }
+    }
+
+    /// Whether `tx` carries an ed25519 signature of its signing payload by
+    /// the hex public key `key`
+    fn ed25519_signed(tx: &Transaction, key: &str) -> bool {
+        let Ok(key) = crate::snapshot::parse_verifying_key(key) else {
+            return false;
+        };
+        let signature: Option<[u8; 64]> = hex::decode(&tx.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok());
+        signature.is_some_and(|signature| {
+            key.verify(&tx.signing_payload(), &ed25519_dalek::Signature::from_bytes(&signature)).is_ok()
+        })
+    }
+
+    /// Move `tx`'s amount and fee between `balances`, leaving them untouched
+    /// if any balance would overflow or go negative
+    fn apply_transfer(balances: &mut HashMap<String, u64>, tx: &Transaction) -> Result<(), BlockchainError> {
+        let mut updated = Vec::with_capacity(2);
+        // Coinbase issues new coins rather than spending a balance
+        if tx.from != COINBASE_ADDRESS {
+            let balance = balances.get(&tx.from).copied().unwrap_or(0);
+            updated.push((tx.from.clone(), sub_amount(balance, add_amount(tx.amount, tx.fee)?)?));
+        }
+        if tx.to != BURN_ADDRESS {
+            let balance = match updated.first() {
+                Some((address, balance)) if *address == tx.to => *balance,
+                _ => balances.get(&tx.to).copied().unwrap_or(0),
+            };
+            updated.push((tx.to.clone(), add_amount(balance, tx.amount)?));
+        }
+        balances.extend(updated);
+        Ok(())
+    }
+
+    /// Sparse Merkle tree over wallet balances and committed nonces.
+    /// Empty accounts, with neither a balance nor a nonce, are left out, so
+    /// creating one does not change the root.
+    fn state_tree(wallets: &HashMap<String, u64>, nonces: &HashMap<String, u64>) -> StateTree {
+        StateTree::new(
+            wallets
+                .iter()
+                .map(|(addr, balance)| (addr.as_str(), *balance, nonces.get(addr).copied().unwrap_or(0))),
+        )
+    }
+
+    /// Calculate state root from wallet balances and committed nonces
+    fn calculate_state_root(wallets: &HashMap<String, u64>, nonces: &HashMap<String, u64>) -> String {
+        Self::state_tree(wallets, nonces).root()
+    }
+
+    /// State root once `txs` are applied, given the balances they leave
+    /// behind for the addresses in `changed`
+    fn state_root_after(&self, changed: &HashMap<String, u64>, txs: &[Transaction]) -> String {
+        let (balances, nonces) = self.state_after(changed, txs);
+        Self::calculate_state_root(&balances, &nonces)
+    }
+
+    /// Balances and nonces once `txs` are applied, given the balances they
+    /// leave behind for the addresses in `changed`
+    fn state_after(
+        &self,
+        changed: &HashMap<String, u64>,
+        txs: &[Transaction],
+    ) -> (HashMap<String, u64>, HashMap<String, u64>) {
+        let mut balances: HashMap<String, u64> = self
+            .wallets
+            .iter()
+            .map(|w| (w.key().clone(), w.value().balance))
+            .collect();
+        balances.extend(changed.iter().map(|(address, balance)| (address.clone(), *balance)));
+        let mut nonces: HashMap<String, u64> = self
+            .committed_nonces
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        // Coinbase issues new coins rather than being an account
+        for tx in txs.iter().filter(|tx| tx.from != COINBASE_ADDRESS) {
+            nonces.insert(tx.from.clone(), tx.nonce);
+        }
+        (balances, nonces)
+    }
+
+    /// Hash of the current wallet balances and nonces, computed like a
+    /// block's state root
+    pub fn state_root(&self) -> String {
+        self.state_root_after(&HashMap::new(), &[])
+    }
+
+    /// Proof of `address`'s current balance and committed nonce, with the
+    /// state root it verifies against. Once any block past genesis is
+    /// added, that is the tip block's state root.
+    pub fn get_state_proof(&self, address: &str) -> (String, StateProof) {
+        let (balances, nonces) = self.state_after(&HashMap::new(), &[]);
+        let tree = Self::state_tree(&balances, &nonces);
+        (tree.root(), tree.prove(address))
+    }
+
+    /// Mine a block (PoS-like with proposer)
+    pub fn mine_block(&self, proposer: String) -> Result<Block, String> {
+        if let Some(expected) = self.next_proposer().filter(|expected| *expected != proposer) {
+            return Err(format!("{} is not the proposer for the next block, {} is", proposer, expected));
+        }
+        let key = self.proposer_key(&proposer)?;
+        let next_height = self.next_height();
+        let recent_blocks = self.recent_block_hashes(next_height);
+        let mut pending = self.mempool.lock().unwrap();
+        self.expire_pending(&mut pending);
+
+        if pending.is_empty() {
+            return Err("No pending transactions to mine".to_string());
+        }
+
+        // Each tx_id goes in at most once, and never again once confirmed
+        let mut seen = HashSet::new();
+        let signed: Vec<Transaction> = pending
+            .iter()
+            .filter(|tx| {
+                self.signature_accepted(tx) && !self.recently_confirmed(&tx.tx_id) && seen.insert(tx.tx_id.clone())
+            })
+            .cloned()
+            .collect();
+        let arrivals: HashMap<String, u64> = pending
+            .iter()
+            .zip(0..)
+            .map(|(tx, position)| (tx.tx_id.clone(), position))
+            .collect();
+        let mut queues = Self::sender_queues(signed);
+        let committed_nonces: HashMap<String, u64> = queues
+            .keys()
+            .map(|sender| (sender.clone(), self.committed_nonce(sender)))
+            .collect();
+
+        // Keep only each sender's contiguous run of nonces after the
+        // committed one, so a gap holds back everything after it
+        for (sender, queue) in queues.iter_mut() {
+            let mut expected_nonce = committed_nonces[sender] + 1;
+            let mut run = VecDeque::new();
+            for tx in queue.drain(..) {
+                if tx.nonce < expected_nonce {
+                    continue;
+                }
+                if tx.nonce != expected_nonce {
+                    break;
+                }
+                run.push_back(tx);
+                expected_nonce += 1;
+            }
+            *queue = run;
+        }
+
+        let mut valid_txs = Vec::new();
+        let mut block_value: u64 = 0;
+        let mut memo_bytes: u64 = 0;
+        let mut block_bytes: u64 = 0;
+        let mut temp_balances: HashMap<String, u64> = HashMap::new();
+        // Contract operations run as they will when the block is added, for
+        // the coins self-destructs pay out
+        let mut contracts = ContractBatch::default();
+
+        // Initialize temp balances
+        for wallet_ref in self.wallets.iter() {
+            temp_balances.insert(wallet_ref.key().clone(), wallet_ref.value().balance);
+        }
+
+        // Take transactions in the canonical order until the block is full.
+        // An unaffordable or not-yet-due transaction, or one that would
+        // overfill the block's value, memo or byte space, holds back the
+        // rest of its sender's run.
+        let prev_hash = self.chain.lock().unwrap().last().unwrap().hash.clone();
+        let mut ready = ReadyQueue::new(queues, self.params.mempool_policy, &prev_hash, arrivals);
+        while let Some(tx) = ready.pop() {
+            if valid_txs.len() as u64 >= self.params.max_block_txs {
+                break;
+            }
+            let new_block_value = block_value
+                .checked_add(tx.amount)
+                .filter(|value| *value <= self.params.max_block_value);
+            let new_memo_bytes = memo_bytes + Self::memo_bytes(&tx);
+            let new_block_bytes = block_bytes + tx.size();
+
+            let new_block_value = match new_block_value {
+                Some(value)
+                    if tx.amount <= self.params.max_tx_amount
+                        && new_memo_bytes <= self.params.max_block_memo_bytes
+                        && new_block_bytes <= self.params.max_block_bytes
+                        && tx.execute_at_height.is_none_or(|height| height <= next_height)
+                        && tx.recent_block_hash.as_ref().is_none_or(|hash| recent_blocks.contains(hash))
+                        && Self::apply_transfer(&mut temp_balances, &tx).is_ok() =>
+                {
+                    value
+                }
+                _ => {
+                    ready.hold_back(&tx.from);
+                    continue;
+                }
+            };
+
+            block_value = new_block_value;
+            memo_bytes = new_memo_bytes;
+            block_bytes = new_block_bytes;
+            self.run_contract_transaction(&mut contracts, &tx, next_height, &mut temp_balances);
+            valid_txs.push(tx);
+        }
+
+        if valid_txs.is_empty() {
+            return Err("No valid transactions after validation".to_string());
+        }
+
+        // Held-back transactions stay pending; stale and unsigned ones are dropped
+        let included: HashSet<&str> =
+            valid_txs.iter().map(|tx| tx.tx_id.as_str()).collect();
+        pending.retain(|tx| {
+            if included.contains(tx.tx_id.as_str()) {
+                return false;
+            }
+            let live = self.signature_accepted(tx)
+                && !self.recently_confirmed(&tx.tx_id)
+                && tx.nonce > committed_nonces.get(&tx.from).copied().unwrap_or(0)
+                && tx.recent_block_hash.as_ref().is_none_or(|hash| recent_blocks.contains(hash));
+            if !live {
+                self.release_reservation(&tx.tx_id);
+            }
+            live
+        });
+        drop(pending);
+
+        let chain = self.chain.lock().unwrap();
+        let last_block = chain.last().unwrap();
+        let prev_hash = last_block.hash.clone();
+        let new_index = last_block.index + 1;
+        // A clock behind the parent's still yields a valid block
+        let timestamp = (self.clock)().max(last_block.timestamp);
+        drop(chain);
+
+        let valid_txs = self.add_reward(new_index, &proposer, timestamp, valid_txs, &mut temp_balances)?;
+        let state_root = self.state_root_after(&temp_balances, &valid_txs);
+
+        let mut block = Block {
+            index: new_index,
+            timestamp,
+            tx_root: merkle::root(&valid_txs),
+            transactions: valid_txs,
+            prev_hash,
+            hash: String::new(),
+            proposer,
+            state_root,
+            difficulty: self.required_difficulty(new_index),
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+
+        Self::seal_block(&mut block);
+        if let Some(key) = key {
+            Self::sign_block(&mut block, key);
+        }
+
+        Ok(block)
+    }
+
+    /// Group transactions by sender, each group ordered by nonce and then
+    /// tx_id
+    fn sender_queues(txs: Vec<Transaction>) -> BTreeMap<String, VecDeque<Transaction>> {
+        let mut by_sender: BTreeMap<String, Vec<Transaction>> = BTreeMap::new();
+        for tx in txs {
+            by_sender.entry(tx.from.clone()).or_default().push(tx);
+        }
+        by_sender
+            .into_iter()
+            .map(|(sender, mut group)| {
+                group.sort_by(|a, b| (a.nonce, &a.tx_id).cmp(&(b.nonce, &b.tx_id)));
+                (sender, group.into())
+            })
+            .collect()
+    }
+
+    /// Calculate block hash. The proof of work fields are only hashed when
+    /// the block carries work, so chains without it keep their hashes, and
+    /// likewise an empty `tx_root` adds nothing.
+    fn calculate_block_hash(block: &Block) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(block.index.to_le_bytes());
+        hasher.update(block.timestamp.to_le_bytes());
+        hasher.update(block.prev_hash.as_bytes());
+        hasher.update(block.state_root.as_bytes());
+        hasher.update(block.tx_root.as_bytes());
+        if block.difficulty > 0 {
+            hasher.update(block.difficulty.to_le_bytes());
+            hasher.update(block.pow_nonce.to_le_bytes());
+        }
+
+        for tx in &block.transactions {
+            hasher.update(tx.tx_id.as_bytes());
+            if let Some(memo) = &tx.memo {
+                hasher.update(memo.as_bytes());
+            }
+        }
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Search for a `pow_nonce` that gives `block` a hash meeting its
+    /// difficulty, and set the hash
+    fn seal_block(block: &mut Block) {
+        loop {
+            block.hash = Self::calculate_block_hash(block);
+            if pow::hash_meets_difficulty(&block.hash, block.difficulty) {
+                return;
+            }
+            block.pow_nonce += 1;
+        }
+    }
+
+    /// Difficulty the block at `height` must be mined at
+    pub fn required_difficulty(&self, height: u64) -> u32 {
+        self.required_difficulty_with(height, &|index| self.get_block_by_index(index))
+    }
+
+    /// Difficulty at `height` with earlier blocks read through `lookup`. It
+    /// carries over from the block below, and every `retarget_interval`
+    /// blocks moves by a bit depending on how long the last interval took
+    /// against `target_block_secs` per block.
+    fn required_difficulty_with(&self, height: u64, lookup: &dyn Fn(u64) -> Option<Block>) -> u32 {
+        if self.params.block_difficulty == 0 {
+            return 0;
+        }
+        let Some(prev) = height.checked_sub(1).and_then(lookup) else {
+            return self.params.block_difficulty;
+        };
+        // Genesis carries no work
+        let current = if prev.index == 0 {
+            self.params.block_difficulty
+        } else {
+            prev.difficulty
+        };
+
+        let interval = self.params.retarget_interval.max(1);
+        if height <= interval || !height.is_multiple_of(interval) {
+            return current;
+        }
+        match lookup(height - 1 - interval) {
+            Some(first) => pow::retarget(
+                current,
+                prev.timestamp.saturating_sub(first.timestamp),
+                interval.saturating_mul(self.params.target_block_secs),
+            ),
+            None => current,
+        }
+    }
+
+    /// Check that `block` is mined at the difficulty its height requires
+    /// and its hash meets it
+    fn check_block_work(&self, block: &Block, lookup: &dyn Fn(u64) -> Option<Block>) -> Result<(), String> {
+        let required = self.required_difficulty_with(block.index, lookup);
+        if block.difficulty != required {
+            return Err(format!(
+                "Block {} has difficulty {}, expected {}",
+                block.index, block.difficulty, required
+            ));
+        }
+        if !pow::hash_meets_difficulty(&block.hash, block.difficulty) {
+            return Err(format!("Invalid proof of work in block {}", block.index));
+        }
+        Ok(())
+    }
+
+    /// Add block to chain and persist
+    pub fn add_block(&self, block: Block) -> Result<(), String> {
+        self.append_block(block, Vec::new())
+    }
+
+    /// [`Self::add_block`], storing `writes` in the same atomic write as the
+    /// block
+    fn append_block(&self, block: Block, mut writes: Vec<WriteOp>) -> Result<(), String> {
+        let chain = self.chain.lock().unwrap();
+        let last_block = chain.last().unwrap();
+
+        // A block that doesn't build on the tip is a copy or part of a
+        // competing branch
+        if block.index == 0 || block.prev_hash != last_block.hash {
+            drop(chain);
+            return self.add_side_block(block);
+        }
+
+        self.check_block_header(&block, last_block)?;
+        drop(chain);
+
+        self.check_block_work(&block, &|index| self.get_block_by_index(index))?;
+        self.check_proposer(&block)?;
+        self.check_coinbase(&block)?;
+        self.check_block_value(&block)?;
+        self.check_block_transactions(&block)?;
+        self.check_block_nonces(&block)?;
+        self.check_reward(&block)?;
+
+        // Snapshot balances the block is about to touch
+        let mut balances: HashMap<String, u64> = HashMap::new();
+        for tx in &block.transactions {
+            for address in [&tx.from, &tx.to] {
+                balances
+                    .entry(address.clone())
+                    .or_insert_with(|| self.wallets.get(address).map(|w| w.balance).unwrap_or(0));
+            }
+        }
+        let mut diff: StateDiff = balances
+            .iter()
+            .map(|(address, balance)| {
+                let change = BalanceChange {
+                    old_balance: *balance,
+                    new_balance: *balance,
+                };
+                (address.clone(), change)
+            })
+            .collect();
+
+        // Work out every new balance before touching a wallet, so a block
+        // that would overflow one is rejected instead of half applied
+        if merkle::root(&block.transactions) != block.tx_root {
+            return Err(format!("Invalid transaction root in block {}", block.index));
+        }
+        let mut contracts = ContractBatch::default();
+        for tx in &block.transactions {
+            let balance = balances.get(&tx.from).copied().unwrap_or(0);
+            let cost = tx.amount.checked_add(tx.fee);
+            if tx.from != COINBASE_ADDRESS && cost.is_some_and(|cost| cost > balance) {
+                return Err(BlockError::InsufficientBalance {
+                    index: block.index,
+                    tx_id: tx.tx_id.clone(),
+                    from: tx.from.clone(),
+                }
+                .into());
+            }
+            Self::apply_transfer(&mut balances, tx)?;
+            self.run_contract_transaction(&mut contracts, tx, block.index, &mut balances);
+        }
+        // Self-destructs may have paid out to accounts no transfer touched
+        for (contract, beneficiary, _) in &contracts.payouts {
+            for address in [contract, beneficiary] {
+                let balance = self.wallets.get(address).map_or(0, |wallet| wallet.balance);
+                diff.entry(address.clone()).or_insert(BalanceChange {
+                    old_balance: balance,
+                    new_balance: balance,
+                });
+            }
+        }
+        if self.state_root_after(&balances, &block.transactions) != block.state_root {
+            return Err(format!("Invalid state root in block {}", block.index));
+        }
+        let staked = Self::stakes_change(&block.transactions);
+        let validators = match staked {
+            true => Some(Self::validators_after(&self.validators.lock().unwrap(), &block.transactions, false)?),
+            false => None,
+        };
+
+        // Stage every wallet the block touches. Nothing in memory changes
+        // until the block, its diff, these wallets and any stake changes
+        // are stored in one atomic write, so a block that fails anywhere
+        // before then leaves no trace.
+        let now = (self.clock)();
+        let mut staged: HashMap<String, Wallet> = HashMap::new();
+        for tx in &block.transactions {
+            // Coinbase issues new coins rather than spending a balance
+            if tx.from != COINBASE_ADDRESS && !staged.contains_key(&tx.from) {
+                if let Some(sender) = self.wallets.get(&tx.from) {
+                    staged.insert(tx.from.clone(), sender.clone());
+                }
+            }
+            if let Some(sender) = staged.get_mut(&tx.from).filter(|_| tx.from != COINBASE_ADDRESS) {
+                sender.tx_count += 1;
+                sender.last_updated = now;
+            }
+
+            let recipient = staged.entry(tx.to.clone()).or_insert_with(|| match self.wallets.get(&tx.to) {
+                Some(wallet) => wallet.clone(),
+                None => Wallet {
+                    address: tx.to.clone(),
+                    balance: 0,
+                    tx_count: 0,
+                    created_at: now,
+                    last_updated: now,
+                },
+            });
+            recipient.last_updated = now;
+        }
+        for (contract, beneficiary, _) in &contracts.payouts {
+            for address in [contract, beneficiary] {
+                let wallet = staged.entry(address.clone()).or_insert_with(|| match self.wallets.get(address) {
+                    Some(wallet) => wallet.clone(),
+                    None => Wallet {
+                        address: address.clone(),
+                        balance: 0,
+                        tx_count: 0,
+                        created_at: now,
+                        last_updated: now,
+                    },
+                });
+                wallet.last_updated = now;
+            }
+        }
+        for (address, wallet) in staged.iter_mut() {
+            wallet.balance = balances[address];
+        }
+        for (address, change) in diff.iter_mut() {
+            change.new_balance = balances[address];
+        }
+        diff.retain(|_, change| change.old_balance != change.new_balance);
+
+        if let Some(validators) = &validators {
+            writes.extend(self.validator_writes(validators, &block.transactions)?);
+        }
+        if !contracts.receipts.is_empty() {
+            writes.extend(self.contract_writes(&contracts, block.index)?);
+        }
+        if let Err(e) = self.persist_block(&block, &diff, &staged, writes) {
+            return Err(format!("Failed to persist block: {}", e));
+        }
+
+        // Stored: apply the staged state in memory, which can't fail
+        for (address, wallet) in staged {
+            self.wallets.insert(address, wallet);
+        }
+        for (address, state) in contracts.changed {
+            match state {
+                Some((contract, _)) => {
+                    self.contracts.insert(address, contract);
+                }
+                None => {
+                    self.contracts.remove(&address);
+                }
+            }
+        }
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let entry = TransactionIndex {
+                tx_id: tx.tx_id.clone(),
+                block_index: block.index,
+                tx_index_in_block: position,
+            };
+            self.tx_index().hot.entry(tx.from.clone()).or_default().push(entry.clone());
+            // A transfer to oneself is listed once
+            if tx.to != tx.from {
+                self.tx_index().hot.entry(tx.to.clone()).or_default().push(entry);
+                self.compact_index(&tx.to);
+            }
+            self.compact_index(&tx.from);
+        }
+
+        for tx in block.transactions.iter().filter(|tx| tx.from != COINBASE_ADDRESS) {
+            self.committed_nonces.insert(tx.from.clone(), tx.nonce);
+        }
+
+        // Add to chain
+        let mut chain = self.chain.lock().unwrap();
+        chain.push(block.clone());
+        self.trim_window(&mut chain);
+        drop(chain);
+        self.index_recent_txs(&block);
+        for tx in &block.transactions {
+            self.tx_heights.entry(tx.tx_id.clone()).or_default().push(block.index);
+        }
+
+        if let Some(validators) = validators {
+            *self.validators.lock().unwrap() = validators;
+        }
+
+        for tx in &block.transactions {
+            self.release_reservation(&tx.tx_id);
+        }
+
+        // A batched block is durable once flushed; if that fails its writes
+        // stay buffered for the next flush
+        if let Err(e) = self.flush_writes_if_due() {
+            tracing::warn!(height = block.index, "Failed to flush buffered blocks: {}", e);
+        }
+        if let Err(e) = self.prune_blocks() {
+            tracing::warn!(height = block.index, "Failed to prune blocks: {}", e);
+        }
+
+        if cfg!(debug_assertions) && self.check_supply {
+            if let Err(e) = self.reconcile_supply() {
+                panic!("Block {} broke the supply invariant: {}", block.index, e);
+            }
+        }
+
+        // Nobody listening is fine
+        let _ = self.block_events.send(block);
+
+        Ok(())
+    }
+
+    /// Block reward at `height` under the emission schedule: `block_reward`,
+    /// halved every `reward_halving_interval` blocks when that is set
+    pub fn block_reward_at(&self, height: u64) -> u64 {
+        let halvings = match self.params.reward_halving_interval {
+            0 => 0,
+            interval => height / interval,
+        };
+        self.params.block_reward.checked_shr(halvings.min(64) as u32).unwrap_or(0)
+    }
+
+    /// Whether `tx` is a block's reward rather than a transfer or mint
+    fn is_reward(tx: &Transaction) -> bool {
+        tx.from == COINBASE_ADDRESS && tx.tx_id.starts_with(REWARD_TX_PREFIX)
+    }
+
+    /// Open `txs` with the reward for a block at `height`, paying `proposer`
+    /// the block reward plus every fee in `txs`, and credit it in
+    /// `balances`. On chains without block rewards `txs` come back as they
+    /// were.
+    fn add_reward(
+        &self,
+        height: u64,
+        proposer: &str,
+        timestamp: u64,
+        mut txs: Vec<Transaction>,
+        balances: &mut HashMap<String, u64>,
+    ) -> Result<Vec<Transaction>, String> {
+        if self.params.block_reward == 0 {
+            return Ok(txs);
+        }
+        let fees = txs.iter().try_fold(0, |total, tx| add_amount(total, tx.fee))?;
+        let tx_id = format!("{}{}", REWARD_TX_PREFIX, height);
+        let reward = Transaction {
+            from: COINBASE_ADDRESS.to_string(),
+            to: proposer.to_string(),
+            amount: add_amount(self.block_reward_at(height), fees)?,
+            fee: 0,
+            timestamp,
+            signature: self.sign_transaction(&tx_id, COINBASE_ADDRESS, None, None, None, None),
+            tx_id,
+            nonce: 0,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Sha256,
+            contract: None,
+        };
+        Self::apply_transfer(balances, &reward)?;
+        txs.insert(0, reward);
+        Ok(txs)
+    }
+
+    /// Check the only coins `block` issues are its reward, the transaction
+    /// opening it, and mints [`Self::mint_accepted`] takes. Any other
+    /// transfer from coinbase would skip the balance and nonce checks and
+    /// mint whatever it claims.
+    fn check_coinbase(&self, block: &Block) -> Result<(), BlockError> {
+        let issued = block.transactions.iter().enumerate().find(|(position, tx)| {
+            tx.from == COINBASE_ADDRESS
+                && match Self::is_reward(tx) {
+                    true => *position > 0,
+                    false => !self.mint_accepted(tx),
+                }
+        });
+        match issued {
+            Some((_, tx)) => Err(BlockError::UnexpectedCoinbase {
+                index: block.index,
+                tx_id: tx.tx_id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `tx`, a transfer from coinbase other than a reward, is a mint
+    /// signed by one of the chain's system keys. Coinbase has no nonces, so
+    /// a mint is bound to a recent block instead, and the tx_id dedup window
+    /// must outlast that binding for it not to be replayed.
+    fn mint_accepted(&self, tx: &Transaction) -> bool {
+        tx.recent_block_hash.is_some()
+            && self.params.tx_dedup_blocks >= self.params.recent_block_window
+            && self.signature_accepted(tx)
+    }
+
+    /// Check `block` opens with exactly one reward, paying its proposer the
+    /// block reward for its height plus its fees, or carries none on a
+    /// chain without block rewards
+    fn check_reward(&self, block: &Block) -> Result<(), BlockError> {
+        let rewards = block.transactions.iter().filter(|tx| Self::is_reward(tx)).count();
+        if self.params.block_reward == 0 {
+            return match rewards {
+                0 => Ok(()),
+                _ => Err(BlockError::UnexpectedReward(block.index)),
+            };
+        }
+
+        let reward = block
+            .transactions
+            .first()
+            .filter(|tx| {
+                Self::is_reward(tx)
+                    && tx.tx_id == format!("{}{}", REWARD_TX_PREFIX, block.index)
+                    && tx.to == block.proposer
+                    && tx.fee == 0
+            })
+            .ok_or(BlockError::MissingReward(block.index))?;
+        if rewards > 1 {
+            return Err(BlockError::UnexpectedReward(block.index));
+        }
+        let fees = block.transactions[1..].iter().fold(0u64, |total, tx| total.saturating_add(tx.fee));
+        let expected = self.block_reward_at(block.index).saturating_add(fees);
+        if reward.amount != expected {
+            return Err(BlockError::InvalidReward {
+                index: block.index,
+                expected,
+                actual: reward.amount,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check each sender's transactions in `block` carry the nonces right
+    /// after its committed one, with no gap or repeat, so a signed
+    /// transaction can't be replayed once its nonce is used. Coinbase
+    /// issues coins rather than being an account and has no nonces.
+    fn check_block_nonces(&self, block: &Block) -> Result<(), BlockError> {
+        let mut expected: HashMap<&str, u64> = HashMap::new();
+        for tx in block.transactions.iter().filter(|tx| tx.from != COINBASE_ADDRESS) {
+            let nonce = expected
+                .entry(tx.from.as_str())
+                .or_insert_with(|| self.committed_nonce(&tx.from) + 1);
+            if tx.nonce != *nonce {
+                return Err(BlockError::InvalidNonce {
+                    index: block.index,
+                    tx_id: tx.tx_id.clone(),
+                    expected: *nonce,
+                    actual: tx.nonce,
+                });
+            }
+            *nonce += 1;
+        }
+        Ok(())
+    }
+
+    /// Check `block` follows `parent`: the next index, linked by hash, a
+    /// hash matching its contents, a timestamp no earlier than the parent's
+    /// and not too far ahead of the local clock, and every transaction
+    /// signed by its sender
+    fn check_block_header(&self, block: &Block, parent: &Block) -> Result<(), BlockError> {
+        if block.index != parent.index + 1 {
+            return Err(BlockError::InvalidIndex {
+                expected: parent.index + 1,
+                actual: block.index,
+            });
+        }
+        if block.prev_hash != parent.hash {
+            return Err(BlockError::InvalidPrevHash(block.index));
+        }
+        if Self::calculate_block_hash(block) != block.hash {
+            return Err(BlockError::InvalidHash(block.index));
+        }
+
+        if block.timestamp < parent.timestamp {
+            return Err(BlockError::TimestampBeforeParent {
+                index: block.index,
+                timestamp: block.timestamp,
+                parent: parent.timestamp,
+            });
+        }
+        let now = (self.clock)();
+        if block.timestamp > now.saturating_add(MAX_BLOCK_FUTURE_SECS) {
+            return Err(BlockError::FutureTimestamp {
+                index: block.index,
+                timestamp: block.timestamp,
+                now,
+            });
+        }
+
+        // The reward's signature proves nothing; check_reward vouches for it
+        let unsigned = block
+            .transactions
+            .iter()
+            .find(|tx| !Self::is_reward(tx) && !self.signature_accepted(tx));
+        match unsigned {
+            Some(tx) => Err(BlockError::InvalidSignature {
+                index: block.index,
+                tx_id: tx.tx_id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Handle a block that doesn't build on the tip. A copy of a block
+    /// already held is ignored. Any other block must link onto the chain or
+    /// a stored side branch, and pass the header and work checks against
+    /// the branch below it; it is stored with the side blocks, and once its
+    /// branch outweighs the chain above their common ancestor, the chain
+    /// reorgs onto the branch and keeps the blocks it leaves as a side
+    /// branch in turn.
+    fn add_side_block(&self, block: Block) -> Result<(), String> {
+        let held = self.get_block_by_index(block.index).is_some_and(|held| held.hash == block.hash);
+        if held || self.side_blocks.lock().unwrap().contains_key(&block.hash) {
+            return Ok(());
+        }
+        if block.index == 0 {
+            return Err("Block conflicts with the chain at height 0".to_string());
+        }
+
+        // Walk back through side blocks to where the branch leaves the chain
+        let mut branch = vec![block];
+        let fork_height = loop {
+            let first = branch.last().unwrap();
+            let parent = self
+                .find_parent(first)
+                .ok_or(BlockError::InvalidPrevHash(first.index))?;
+            if parent.index + 1 != first.index {
+                return Err(BlockError::InvalidIndex {
+                    expected: parent.index + 1,
+                    actual: first.index,
+                }
+                .into());
+            }
+            if self.get_block_by_index(parent.index).is_some_and(|held| held.hash == parent.hash) {
+                break parent.index;
+            }
+            branch.push(parent);
+        };
+        branch.reverse();
+
+        let finalized_height = *self.finalized_height.lock().unwrap();
+        if fork_height < finalized_height {
+            return Err(format!("Cannot reorg below finalized height {}", finalized_height));
+        }
+
+        // Only the new block is unchecked, its ancestors on the branch were
+        // checked when they were stored. Its weight counts toward the fork
+        // choice, so its difficulty must be the one its branch requires.
+        let branch_lookup = |index: u64| match index.checked_sub(fork_height + 1) {
+            Some(offset) => branch.get(offset as usize).cloned(),
+            None => self.get_block_by_index(index),
+        };
+        let block = branch.last().unwrap();
+        let parent = branch_lookup(block.index - 1).ok_or(BlockError::InvalidPrevHash(block.index))?;
+        self.check_block_header(block, &parent)?;
+        self.check_block_work(block, &branch_lookup)?;
+        self.store_side_blocks(vec![block.clone()]);
+
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        let main: Vec<Block> = (fork_height + 1..=tip).filter_map(|index| self.get_block_by_index(index)).collect();
+        if !Self::outweighs(&branch, &main) {
+            return Ok(());
+        }
+
+        let mut side_blocks = self.side_blocks.lock().unwrap();
+        for block in &branch {
+            side_blocks.remove(&block.hash);
+        }
+        drop(side_blocks);
+        self.reorg(fork_height, branch)?;
+        self.store_side_blocks(main);
+        Ok(())
+    }
+
+    /// The block `block` builds on, from the chain or the side blocks
+    fn find_parent(&self, block: &Block) -> Option<Block> {
+        if let Some(parent) = self.side_blocks.lock().unwrap().get(&block.prev_hash) {
+            return Some(parent.clone());
+        }
+        let in_memory = self.chain.lock().unwrap().iter().find(|held| held.hash == block.prev_hash).cloned();
+        in_memory.or_else(|| {
+            self.get_block_by_index(block.index.checked_sub(1)?)
+                .filter(|held| held.hash == block.prev_hash)
+        })
+    }
+
+    /// Keep `blocks` as side blocks, dropping the lowest ones beyond
+    /// `MAX_SIDE_BLOCKS`
+    fn store_side_blocks(&self, blocks: Vec<Block>) {
+        let mut side_blocks = self.side_blocks.lock().unwrap();
+        for block in blocks {
+            side_blocks.insert(block.hash.clone(), block);
+        }
+        while side_blocks.len() > MAX_SIDE_BLOCKS {
+            let lowest = side_blocks
+                .values()
+                .min_by_key(|block| block.index)
+                .map(|block| block.hash.clone())
+                .unwrap();
+            side_blocks.remove(&lowest);
+        }
+    }
+
+    /// Work a block stands for: each bit of difficulty doubles it
+    fn block_work(block: &Block) -> u128 {
+        1 << block.difficulty.min(127)
+    }
+
+    /// Fork choice between a branch and the chain blocks it would replace.
+    /// The branch with more cumulative work wins, so without proof of work
+    /// the longer one does. On a tie, as with proof of work, the lower tip
+    /// hash counts as more work and wins.
+    fn outweighs(branch: &[Block], main: &[Block]) -> bool {
+        let work = |blocks: &[Block]| blocks.iter().map(Self::block_work).fold(0u128, u128::saturating_add);
+        match work(branch).cmp(&work(main)) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => match (branch.last(), main.last()) {
+                (Some(candidate), Some(existing)) => candidate.hash < existing.hash,
+                _ => false,
+            },
+        }
+    }
+
+    /// Number of blocks held from competing branches
+    pub fn side_block_count(&self) -> usize {
+        self.side_blocks.lock().unwrap().len()
+    }
+
+    /// Reject blocks with an oversized transaction or whose transactions
+    /// move more than the per-block cap in total, carry more memo bytes, or
+    /// are too many or too large together
+    fn check_block_value(&self, block: &Block) -> Result<(), String> {
+        // A reward moves no one's coins, so it counts toward none of the caps
+        let transfers = || block.transactions.iter().filter(|tx| !Self::is_reward(tx));
+        if transfers().count() as u64 > self.params.max_block_txs {
+            return Err(format!(
+                "Block holds more than the per-block limit of {} transactions",
+                self.params.max_block_txs
+            ));
+        }
+        if transfers().map(Transaction::size).sum::<u64>() > self.params.max_block_bytes {
+            return Err(format!(
+                "Block is larger than the per-block limit of {} bytes",
+                self.params.max_block_bytes
+            ));
+        }
+        let memo_bytes: u64 = block.transactions.iter().map(Self::memo_bytes).sum();
+        if memo_bytes > self.params.max_block_memo_bytes {
+            return Err(format!(
+                "Block carries more than the per-block limit of {} memo bytes",
+                self.params.max_block_memo_bytes
+            ));
+        }
+        let mut total: u64 = 0;
+        for tx in transfers() {
+            self.check_tx_amount(tx.amount)?;
+            total = total
+                .checked_add(tx.amount)
+                .filter(|total| *total <= self.params.max_block_value)
+                .ok_or_else(|| {
+                    format!(
+                        "Block moves more than the per-block limit of {}",
+                        self.params.max_block_value
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    fn memo_bytes(tx: &Transaction) -> u64 {
+        tx.memo.as_ref().map_or(0, |memo| memo.len() as u64)
+    }
+
+    /// Reject blocks with a transaction involving a blocked account, one
+    /// scheduled for a later height or one bound to a block outside the
+    /// recent window, or whose transactions are out of canonical order
+    fn check_block_transactions(&self, block: &Block) -> Result<(), String> {
+        self.check_block_transactions_with(block, &|index| self.get_block_by_index(index), &|tx_id| {
+            self.recently_confirmed(tx_id)
+        })
+    }
+
+    /// [`Self::check_block_transactions`] against the blocks `lookup` finds
+    /// below it, e.g. a branch not yet applied, and the tx_ids `confirmed`
+    /// says they hold
+    fn check_block_transactions_with(
+        &self,
+        block: &Block,
+        lookup: &dyn Fn(u64) -> Option<Block>,
+        confirmed: &dyn Fn(&str) -> bool,
+    ) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for tx in &block.transactions {
+            if !seen.insert(tx.tx_id.as_str()) {
+                ReplayCounters::count(&self.replay_rejections.duplicate);
+                return Err(BlockError::DuplicateTransaction {
+                    index: block.index,
+                    tx_id: tx.tx_id.clone(),
+                }
+                .into());
+            }
+        }
+        for tx in &block.transactions {
+            self.check_not_confirmed(&tx.tx_id, confirmed)?;
+        }
+        // The reward opens the block ahead of the canonical order
+        let transfers: Vec<&Transaction> = block.transactions.iter().filter(|tx| !Self::is_reward(tx)).collect();
+        if self.params.mempool_policy == MempoolPolicy::Fifo {
+            // Arrival order is the proposer's own, so only each sender's
+            // transactions can be held to an order
+            let mut last: HashMap<&str, (u64, &str)> = HashMap::new();
+            for tx in transfers {
+                let key = (tx.nonce, tx.tx_id.as_str());
+                if last.insert(tx.from.as_str(), key).is_some_and(|previous| previous > key) {
+                    return Err(format!(
+                        "Transaction {} is out of canonical order in block {}",
+                        tx.tx_id, block.index
+                    ));
+                }
+            }
+        } else {
+            let mut ready = ReadyQueue::new(
+                Self::sender_queues(transfers.iter().map(|tx| (*tx).clone()).collect()),
+                self.params.mempool_policy,
+                &block.prev_hash,
+                HashMap::new(),
+            );
+            for tx in transfers {
+                let expected = ready.pop();
+                if expected.is_none_or(|expected| expected.tx_id != tx.tx_id) {
+                    return Err(format!(
+                        "Transaction {} is out of canonical order in block {}",
+                        tx.tx_id, block.index
+                    ));
+                }
+            }
+        }
+        for tx in &block.transactions {
+            Self::check_contract_shape(tx)?;
+            if let Some(height) = tx.execute_at_height.filter(|height| *height > block.index) {
+                return Err(format!(
+                    "Transaction {} is scheduled for height {} but included at {}",
+                    tx.tx_id, height, block.index
+                ));
+            }
+        }
+        if block.transactions.iter().any(|tx| tx.recent_block_hash.is_some()) {
+            let recent_blocks = self.recent_block_hashes_with(block.index, lookup);
+            for tx in &block.transactions {
+                if let Some(hash) = tx.recent_block_hash.as_ref().filter(|hash| !recent_blocks.contains(*hash)) {
+                    ReplayCounters::count(&self.replay_rejections.recent_block);
+                    return Err(format!(
+                        "Transaction {} references block {}, which is not among the {} blocks below height {}",
+                        tx.tx_id, hash, self.params.recent_block_window, block.index
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Height the next block will have
+    fn next_height(&self) -> u64 {
+        self.chain.lock().unwrap().last().unwrap().index + 1
+    }
+
+    /// Hashes of the `recent_block_window` blocks below `height`, which a
+    /// transaction included at `height` may reference
+    fn recent_block_hashes(&self, height: u64) -> HashSet<String> {
+        self.recent_block_hashes_with(height, &|index| self.get_block_by_index(index))
+    }
+
+    fn recent_block_hashes_with(&self, height: u64, lookup: &dyn Fn(u64) -> Option<Block>) -> HashSet<String> {
+        (height.saturating_sub(self.params.recent_block_window)..height)
+            .filter_map(lookup)
+            .map(|block| block.hash)
+            .collect()
+    }
+
+    /// Whether `tx_id` is in one of the `tx_dedup_blocks` blocks below the
+    /// next height
+    fn recently_confirmed(&self, tx_id: &str) -> bool {
+        self.recent_tx_ids.contains_key(tx_id)
+    }
+
+    /// Transaction ids in the `tx_dedup_blocks` blocks `lookup` finds below
+    /// `height`, for a branch the index doesn't cover
+    fn confirmed_tx_ids_with(&self, height: u64, lookup: &dyn Fn(u64) -> Option<Block>) -> HashSet<String> {
+        (height.saturating_sub(self.params.tx_dedup_blocks)..height)
+            .filter_map(lookup)
+            .flat_map(|block| block.transactions)
+            .map(|tx| tx.tx_id)
+            .collect()
+    }
+
+    /// Index the tx_ids of `block`, just appended, and drop those of the
+    /// block that left the dedup window with it
+    fn index_recent_txs(&self, block: &Block) {
+        let window = self.params.tx_dedup_blocks;
+        if window == 0 {
+            return;
+        }
+        for tx in &block.transactions {
+            self.recent_tx_ids.insert(tx.tx_id.clone(), block.index);
+        }
+        if let Some(expired) = block.index.checked_sub(window).and_then(|index| self.get_block_by_index(index)) {
+            for tx in &expired.transactions {
+                self.recent_tx_ids.remove_if(&tx.tx_id, |_, height| *height == expired.index);
+            }
+        }
+    }
+
+    /// Undo [`Self::index_recent_txs`] for `block`, just rolled back
+    fn unindex_recent_txs(&self, block: &Block) {
+        let window = self.params.tx_dedup_blocks;
+        if window == 0 {
+            return;
+        }
+        for tx in &block.transactions {
+            self.recent_tx_ids.remove_if(&tx.tx_id, |_, height| *height == block.index);
+        }
+        if let Some(returned) = block.index.checked_sub(window).and_then(|index| self.get_block_by_index(index)) {
+            for tx in &returned.transactions {
+                self.recent_tx_ids.insert(tx.tx_id.clone(), returned.index);
+            }
+        }
+    }
+
+    /// Index the tx_ids of the `tx_dedup_blocks` blocks below the next
+    /// height from scratch
+    fn rebuild_recent_tx_ids(&self) {
+        self.recent_tx_ids.clear();
+        let next_height = self.next_height();
+        for block in (next_height.saturating_sub(self.params.tx_dedup_blocks)..next_height)
+            .filter_map(|index| self.get_block_by_index(index))
+        {
+            for tx in block.transactions {
+                self.recent_tx_ids.insert(tx.tx_id, block.index);
+            }
+        }
+    }
+
+    fn check_not_confirmed(&self, tx_id: &str, confirmed: &dyn Fn(&str) -> bool) -> Result<(), String> {
+        if confirmed(tx_id) {
+            ReplayCounters::count(&self.replay_rejections.duplicate);
+            Err(format!("Transaction {} is already confirmed", tx_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that a transaction referencing `hash` could be included at
+    /// `height`
+    fn check_recent_block(&self, hash: &str, height: u64) -> Result<(), String> {
+        self.check_recent_block_in(hash, &self.recent_block_hashes(height))
+    }
+
+    fn check_recent_block_in(&self, hash: &str, recent_blocks: &HashSet<String>) -> Result<(), String> {
+        if recent_blocks.contains(hash) {
+            Ok(())
+        } else {
+            ReplayCounters::count(&self.replay_rejections.recent_block);
+            Err(format!(
+                "Recent block hash {} is unknown or older than the last {} blocks",
+                hash, self.params.recent_block_window
+            ))
+        }
+    }
+
+    /// Reorganize the chain: roll back every block above `fork_height` and
+    /// apply `new_blocks` in their place. Fork choice is up to the caller.
+    /// Orphaned transactions that the new branch does not include are
+    /// returned to the mempool.
+    pub fn reorg(&self, fork_height: u64, new_blocks: Vec<Block>) -> Result<(), String> {
+        if new_blocks.is_empty() {
+            return Err("New branch is empty".to_string());
+        }
+
+        let orphaned = {
+            let mut chain = self.chain.lock().unwrap();
+            let tip = chain.last().unwrap().index;
+            if fork_height >= tip {
+                return Err("Fork point must be below the chain tip".to_string());
+            }
+            let finalized_height = *self.finalized_height.lock().unwrap();
+            if fork_height < finalized_height {
+                return Err(format!(
+                    "Cannot reorg below finalized height {}",
+                    finalized_height
+                ));
+            }
+            // Rolling back needs the bodies and diffs pruning discards
+            let pruned_height = *self.pruned_height.lock().unwrap();
+            if fork_height < pruned_height {
+                return Err(format!("Cannot reorg below pruned height {}", pruned_height));
+            }
+
+            let first = chain[0].index;
+            if fork_height < first {
+                return Err(format!(
+                    "Cannot reorg below height {}, the oldest block held in memory",
+                    first
+                ));
+            }
+            let fork_position = (fork_height - first) as usize;
+
+            // Validate the new branch links onto the fork point before touching state.
+            // Its blocks are checked against the branch below them, not the
+            // blocks it replaces.
+            let branch_lookup = |index: u64| {
+                if index > fork_height {
+                    new_blocks.get((index - fork_height - 1) as usize).cloned()
+                } else if index >= first {
+                    chain.get((index - first) as usize).cloned()
+                } else {
+                    self.stored_block(index)
+                }
+            };
+            let mut prev = &chain[fork_position];
+            for block in &new_blocks {
+                self.check_block_header(block, prev)?;
+                self.check_block_work(block, &branch_lookup)?;
+                self.check_proposer(block)?;
+                self.check_block_value(block)?;
+                let confirmed = self.confirmed_tx_ids_with(block.index, &branch_lookup);
+                self.check_block_transactions_with(block, &branch_lookup, &|tx_id| confirmed.contains(tx_id))?;
+                prev = block;
+            }
+
+            chain.split_off(fork_position + 1)
+        };
+
+        // Blocks come off one at a time, each leaving those below it whole.
+        // A block that can't be rolled back is left applied, so put it and
+        // those below it back on the chain and re-apply the ones above.
+        for (position, block) in orphaned.iter().enumerate().rev() {
+            if let Err(e) = self.rollback_block(block, block.index - 1) {
+                self.chain.lock().unwrap().extend(orphaned[..=position].iter().cloned());
+                let aborted = format!("Reorg aborted rolling back block {}: {}", block.index, e);
+                return Err(match self.restore_branch(block.index, &orphaned[position + 1..]) {
+                    Ok(()) => aborted,
+                    Err(restore) => {
+                        tracing::error!(fork_height, "Cannot restore the chain after a failed reorg: {}", restore);
+                        format!("{}, and restoring the old chain failed: {}", aborted, restore)
+                    }
+                });
+            }
+        }
+        // Votes were for the orphaned blocks
+        let orphaned_votes = self.votes.lock().unwrap().split_off(&(fork_height + 1));
+
+        // A branch block can still fail on state, e.g. a balance it spends
+        // that the old branch paid in. Switch back rather than stop halfway.
+        for block in &new_blocks {
+            if let Err(e) = self.add_block(block.clone()) {
+                let restored = self.restore_branch(fork_height, &orphaned);
+                self.votes.lock().unwrap().extend(orphaned_votes);
+                let aborted = format!("Reorg aborted at block {}: {}", block.index, e);
+                return Err(match restored {
+                    Ok(()) => aborted,
+                    Err(restore) => {
+                        tracing::error!(fork_height, "Cannot restore the chain after a failed reorg: {}", restore);
+                        format!("{}, and restoring the old chain failed: {}", aborted, restore)
+                    }
+                });
+            }
+        }
+
+        // Return orphaned transactions to the mempool ahead of newer ones,
+        // since they carry the sender's earlier nonces
+        let included: HashSet<&str> = new_blocks
+            .iter()
+            .flat_map(|b| b.transactions.iter().map(|tx| tx.tx_id.as_str()))
+            .collect();
+        let (requeued, dropped): (Vec<Transaction>, Vec<Transaction>) = orphaned
+            .iter()
+            .flat_map(|b| b.transactions.iter().cloned())
+            .filter(|tx| !included.contains(tx.tx_id.as_str()) && !Self::is_reward(tx))
+            .partition(|tx| self.signature_accepted(tx));
+
+        // Requeued transactions were paid for by the rolled back blocks, so
+        // their reservations come back without a balance check
+        if self.reserve_pending {
+            for tx in &requeued {
+                let cost = tx.amount.saturating_add(tx.fee);
+                let mut reserved = self.reserved.entry(tx.from.clone()).or_insert(0);
+                *reserved = reserved.saturating_add(cost);
+                self.track_reservation(&tx.tx_id, &tx.from, cost);
+            }
+        }
+        self.mempool.lock().unwrap().requeue(requeued, (self.clock)());
+
+        // Senders of dropped transactions get their nonce rolled back
+        for tx in &dropped {
+            self.rollback_nonce(&tx.from);
+        }
+
+        Ok(())
+    }
+
+    /// Roll back every block above `fork_height` and re-apply `blocks`, the
+    /// branch a failed reorg replaced
+    fn restore_branch(&self, fork_height: u64, blocks: &[Block]) -> Result<(), String> {
+        let applied = {
+            let mut chain = self.chain.lock().unwrap();
+            let keep = chain.iter().take_while(|block| block.index <= fork_height).count();
+            chain.split_off(keep)
+        };
+        for block in applied.iter().rev() {
+            self.rollback_block(block, fork_height)?;
+        }
+        for block in blocks {
+            self.append_block(block.clone(), Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Undo a block's effect on wallets, contracts, the tx index and storage
+    fn rollback_block(&self, block: &Block, fork_height: u64) -> Result<(), String> {
+        // Buffered blocks go to disk first so the writes below stick. If
+        // they can't, nothing has changed yet.
+        self.flush_writes()
+            .map_err(|e| format!("Failed to flush buffered blocks: {}", e))?;
+
+        // Work out every balance before changing any, so a block that can't
+        // be undone leaves the wallets as they were. Payouts came after the
+        // transfers that funded them.
+        let undo = self.contract_undo(block)?;
+        let balance_of = |balances: &HashMap<String, u64>, address: &str| {
+            balances
+                .get(address)
+                .copied()
+                .or_else(|| self.wallets.get(address).map(|wallet| wallet.balance))
+        };
+        let mut balances = HashMap::new();
+        for (contract, beneficiary, amount) in undo.iter().flat_map(|undo| undo.payouts.iter().rev()) {
+            if let Some(balance) = balance_of(&balances, beneficiary) {
+                balances.insert(beneficiary.clone(), sub_amount(balance, *amount)?);
+            }
+            if let Some(balance) = balance_of(&balances, contract) {
+                balances.insert(contract.clone(), add_amount(balance, *amount)?);
+            }
+        }
+        for tx in block.transactions.iter().rev() {
+            if tx.to != BURN_ADDRESS {
+                if let Some(balance) = balance_of(&balances, &tx.to) {
+                    balances.insert(tx.to.clone(), sub_amount(balance, tx.amount)?);
+                }
+            }
+            if tx.from != COINBASE_ADDRESS {
+                if let Some(balance) = balance_of(&balances, &tx.from) {
+                    balances.insert(tx.from.clone(), add_amount(balance, add_amount(tx.amount, tx.fee)?)?);
+                }
+            }
+        }
+
+        let contract_writes = match undo {
+            Some(undo) => self.undo_contracts(block, undo)?,
+            None => Vec::new(),
+        };
+        let now = (self.clock)();
+        for (address, balance) in &balances {
+            if let Some(mut wallet) = self.wallets.get_mut(address) {
+                wallet.balance = *balance;
+                wallet.last_updated = now;
+            }
+        }
+
+        for tx in block.transactions.iter().rev() {
+            if tx.from != COINBASE_ADDRESS {
+                if let Some(mut sender) = self.wallets.get_mut(&tx.from) {
+                    sender.tx_count = sender.tx_count.saturating_sub(1);
+                }
+            }
+
+            for address in [&tx.from, &tx.to] {
+                let emptied = match self.tx_index().hot.get_mut(address) {
+                    Some(mut user_txs) => {
+                        user_txs.retain(|entry| entry.block_index <= fork_height);
+                        user_txs.is_empty()
+                    }
+                    None => false,
+                };
+                // Compacted entries may have been orphaned too, so rebuild
+                // the address's index from the blocks that remain
+                if emptied && self.tx_index().archived.remove(address).is_some() {
+                    self.tx_index().hot.insert(address.clone(), self.scan_index(address, fork_height));
+                    self.compact_index(address);
+                }
+            }
+        }
+
+        for tx in block.transactions.iter().filter(|tx| tx.from != COINBASE_ADDRESS) {
+            match self.committed_nonce(&tx.from) {
+                0 => {
+                    self.committed_nonces.remove(&tx.from);
+                }
+                nonce => {
+                    self.committed_nonces.insert(tx.from.clone(), nonce);
+                }
+            }
+        }
+
+        if Self::stakes_change(&block.transactions) {
+            let mut validators = self.validators.lock().unwrap();
+            match Self::validators_after(&validators, &block.transactions, true) {
+                Ok(restored) => {
+                    if let Err(e) = self.persist_validators(&restored, &block.transactions) {
+                        tracing::error!(height = block.index, "Cannot persist restored stakes: {}", e);
+                    }
+                    *validators = restored;
+                }
+                Err(e) => tracing::error!(height = block.index, "Cannot restore stakes from block: {}", e),
+            }
+        }
+
+        self.unindex_recent_txs(block);
+        for tx in &block.transactions {
+            self.tx_heights.remove_if_mut(&tx.tx_id, |_, heights| {
+                heights.retain(|height| *height != block.index);
+                heights.is_empty()
+            });
+        }
+
+        let mut removal = vec![
+            WriteOp::Remove(format!("block:{}", block.index).into_bytes()),
+            WriteOp::Remove(format!("diff:{}", block.index).into_bytes()),
+        ];
+        removal.extend(contract_writes);
+        for address in balances.keys() {
+            if let Some(wallet) = self.wallets.get(address) {
+                let encoded = self.codec.encode(wallet.value()).map_err(|e| e.to_string())?;
+                removal.push(WriteOp::Insert(format!("wallet:{}", address).into_bytes(), encoded));
+            }
+        }
+        self.state_db
+            .transaction(removal)
+            .map_err(|e| format!("Failed to remove block {}: {}", block.index, e))
+    }
+
+    /// Highest nonce `sender` has committed on the canonical chain. Nonces
+    /// only grow along the chain, so this is the nonce of their latest sent
+    /// transaction.
+    fn committed_nonce(&self, sender: &str) -> u64 {
+        let latest_sent = |indices: Vec<TransactionIndex>| {
+            indices.iter().rev().find_map(|index| {
+                self.get_block_by_index(index.block_index)?
+                    .transactions
+                    .get(index.tx_index_in_block)
+                    .filter(|tx| tx.from == sender)
+                    .map(|tx| tx.nonce)
+            })
+        };
+        let hot = self.tx_index().hot.get(sender).map(|indices| indices.clone()).unwrap_or_default();
+        latest_sent(hot)
+            .or_else(|| latest_sent(self.archived_entries(sender)))
+            .or_else(|| self.pruned_nonces.get(sender).map(|nonce| *nonce))
+            .or_else(|| self.base_nonces.get(sender).copied())
+            .unwrap_or(0)
+    }
+
+    /// Reset a sender's nonce to the highest nonce still live on the
+    /// canonical chain or in the mempool
+    fn rollback_nonce(&self, sender: &str) {
+        self.reset_nonce(&self.mempool.lock().unwrap(), sender);
+    }
+
+    /// [`Self::rollback_nonce`] with the mempool already held
+    fn reset_nonce(&self, mempool: &Mempool, sender: &str) {
+        let chain_nonce = self.committed_nonce(sender);
+        let pending_nonce = mempool
+            .iter()
+            .filter(|tx| tx.from == sender)
+            .map(|tx| tx.nonce)
+            .max()
+            .unwrap_or(0);
+
+        self.nonces.insert(sender.to_string(), chain_nonce.max(pending_nonce));
+    }
+
+    /// Mint new coins to an account (testnet supply control). The mint is
+    /// committed immediately in its own block so it shows up in history.
+    /// Off demo chains it is signed with the node's system key, so peers
+    /// accept the block like any other.
+    pub fn mint(&self, to: String, amount: u64) -> Result<Block, String> {
+        if amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+        if self.params.tx_dedup_blocks < self.params.recent_block_window {
+            return Err("Mints need tx_dedup_blocks to cover recent_block_window, or they could be replayed".to_string());
+        }
+        if to == COINBASE_ADDRESS || to == BURN_ADDRESS || to == STAKE_ADDRESS {
+            return Err("Cannot mint to a reserved account".to_string());
+        }
+
+        self.commit_supply_transaction(COINBASE_ADDRESS.to_string(), to, amount, Vec::new())
+    }
+
+    /// Burn coins from an account (testnet supply control). The burn is
+    /// committed immediately in its own block so it shows up in history,
+    /// signed like a mint.
+    pub fn burn(&self, from: String, amount: u64) -> Result<Block, String> {
+        if amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+
+        let balance = self.get_balance(&from)?;
+        if balance < amount {
+            return Err(format!(
+                "Insufficient balance: {} has {}, cannot burn {}",
+                from, balance, amount
+            ));
+        }
+
+        self.commit_supply_transaction(from, BURN_ADDRESS.to_string(), amount, Vec::new())
+    }
+
+    /// Stake coins with `tx`, a transfer into `STAKE_ADDRESS` its sender
+    /// signed with their ed25519 key, making the sender a validator or
+    /// adding to its stake. Committed immediately in its own block.
+    pub fn stake(&self, tx: Transaction) -> Result<Block, String> {
+        if tx.to != STAKE_ADDRESS {
+            return Err(format!("A stake is a transfer to {}", STAKE_ADDRESS));
+        }
+        if tx.amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+        self.check_tx_amount(tx.amount)?;
+        let balance = self.get_balance(&tx.from)?;
+        let cost = add_amount(tx.amount, tx.fee)?;
+        if balance < cost {
+            return Err(format!(
+                "Insufficient balance: {} has {}, cannot stake {}",
+                tx.from, balance, tx.amount
+            ));
+        }
+
+        self.commit_stake_transaction(tx)
+    }
+
+    /// Return stake with `tx`, a fee-less transfer out of `STAKE_ADDRESS`
+    /// signed with the key of the validator it goes back to. A validator
+    /// left with no stake is no longer eligible to propose.
+    pub fn unstake(&self, tx: Transaction) -> Result<Block, String> {
+        if tx.from != STAKE_ADDRESS {
+            return Err(format!("An unstake is a transfer from {}", STAKE_ADDRESS));
+        }
+        if tx.amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+        if tx.fee != 0 {
+            return Err("Unstaking pays no fee".to_string());
+        }
+        let staked = self.validators.lock().unwrap().stake_of(&tx.to).unwrap_or(0);
+        if staked < tx.amount {
+            return Err(format!("{} has only {} staked", tx.to, staked));
+        }
+
+        self.commit_stake_transaction(tx)
+    }
+
+    /// Commit a checked stake change in a block of its own, once its
+    /// signature shows the staker made it
+    fn commit_stake_transaction(&self, tx: Transaction) -> Result<Block, String> {
+        if !self.signature_accepted(&tx) {
+            return Err(format!("A stake change must be signed with {}'s ed25519 key", tx.signer()));
+        }
+        let mut nonce_entry = self.nonces.entry(tx.from.clone()).or_insert(0);
+        *nonce_entry = (*nonce_entry).max(tx.nonce);
+        drop(nonce_entry);
+
+        self.commit_transaction(tx, Vec::new())
+    }
+
+    /// Whether any of `txs` stakes or unstakes
+    fn stakes_change(txs: &[Transaction]) -> bool {
+        txs.iter().any(|tx| tx.to == STAKE_ADDRESS || tx.from == STAKE_ADDRESS)
+    }
+
+    /// Validators once `txs` are applied, or undone: a transfer into
+    /// `STAKE_ADDRESS` stakes for its sender, one out of it unstakes for
+    /// its recipient
+    fn validators_after(
+        validators: &ValidatorRegistry,
+        txs: &[Transaction],
+        undo: bool,
+    ) -> Result<ValidatorRegistry, String> {
+        let mut updated = validators.clone();
+        for tx in txs {
+            let (staker, staking) = if tx.to == STAKE_ADDRESS {
+                (&tx.from, true)
+            } else if tx.from == STAKE_ADDRESS {
+                (&tx.to, false)
+            } else {
+                continue;
+            };
+            // The fee would come out of the staked coins
+            if !staking && !undo && tx.fee != 0 {
+                return Err(format!("Unstake {} pays a fee", tx.tx_id));
+            }
+            if staking != undo {
+                updated.register(staker.clone(), tx.amount)?;
+            } else {
+                updated.withdraw(staker, tx.amount)?;
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Store the stakes `txs` changed, removing validators left with none
+    fn persist_validators(&self, validators: &ValidatorRegistry, txs: &[Transaction]) -> Result<(), String> {
+        let writes = self.validator_writes(validators, txs)?;
+        self.state_db
+            .transaction(writes)
+            .map_err(|e| format!("Failed to persist validators: {}", e))
+    }
+
+    /// Writes storing the stakes `txs` changed, as `validators` now has them
+    fn validator_writes(&self, validators: &ValidatorRegistry, txs: &[Transaction]) -> Result<Vec<WriteOp>, String> {
+        let mut writes = Vec::new();
+        for tx in txs.iter().filter(|tx| tx.to == STAKE_ADDRESS || tx.from == STAKE_ADDRESS) {
+            let staker = if tx.to == STAKE_ADDRESS { &tx.from } else { &tx.to };
+            let key = format!("validator:{}", staker).into_bytes();
+            match validators.stake_of(staker) {
+                Some(stake) => {
+                    let validator = Validator {
+                        address: staker.clone(),
+                        stake,
+                    };
+                    writes.push(WriteOp::Insert(key, self.codec.encode(&validator).map_err(|e| e.to_string())?));
+                }
+                None => writes.push(WriteOp::Remove(key)),
+            }
+        }
+        Ok(writes)
+    }
+
+    /// Reject a block whose proposer isn't the validator selected for its
+    /// height, or that its proposer didn't sign. Until someone stakes,
+    /// anyone may propose.
+    fn check_proposer(&self, block: &Block) -> Result<(), String> {
+        let validators = self.validators.lock().unwrap();
+        match validators.select_proposer(block.index, &block.prev_hash) {
+            Some(expected) if expected != block.proposer => Err(format!(
+                "{} is not the proposer for block {}, {} is",
+                block.proposer, block.index, expected
+            )),
+            Some(_) if !Self::proposer_signed(block) => Err(format!(
+                "Block {} is not signed by its proposer {}",
+                block.index, block.proposer
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes a proposer signs for a block with `hash`
+    fn block_signing_bytes(hash: &str) -> Vec<u8> {
+        [BLOCK_SIGNING_TAG, hash.as_bytes()].concat()
+    }
+
+    /// Whether `block` carries its proposer's signature of its hash, the
+    /// proposer's address being its ed25519 public key
+    fn proposer_signed(block: &Block) -> bool {
+        let Ok(proposer) = crate::snapshot::parse_verifying_key(&block.proposer) else {
+            return false;
+        };
+        let signature: Option<[u8; 64]> = block
+            .proposer_signature
+            .as_deref()
+            .and_then(|signature| hex::decode(signature).ok())
+            .and_then(|bytes| bytes.try_into().ok());
+        signature.is_some_and(|signature| {
+            let message = Self::block_signing_bytes(&block.hash);
+            proposer.verify(&message, &ed25519_dalek::Signature::from_bytes(&signature)).is_ok()
+        })
+    }
+
+    /// Key to sign a block by `proposer` with: the node's validator key when
+    /// it is theirs, `None` while nobody has staked and blocks go unsigned.
+    /// Otherwise the block would be refused, so building it is an error.
+    fn proposer_key(&self, proposer: &str) -> Result<Option<&SigningKey>, String> {
+        match &self.validator_key {
+            Some(key) if hex::encode(key.verifying_key().to_bytes()) == proposer => Ok(Some(key)),
+            _ if self.validators.lock().unwrap().total_stake() > 0 => Err(format!(
+                "This node holds no validator key for {}, so it can't sign its blocks",
+                proposer
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sign sealed `block` as its proposer
+    fn sign_block(block: &mut Block, key: &SigningKey) {
+        let signature = key.sign(&Self::block_signing_bytes(&block.hash));
+        block.proposer_signature = Some(hex::encode(signature.to_bytes()));
+    }
+
+    /// Build, sign and append a fee-less block holding a single supply change
+    /// or other system transfer, bound to the tip, storing `writes` along
+    /// with it. The sender's nonce is given back if the block fails.
+    fn commit_supply_transaction(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        writes: Vec<WriteOp>,
+    ) -> Result<Block, String> {
+        self.check_tx_amount(amount)?;
+
+        let mut nonce_entry = self.nonces.entry(from.clone()).or_insert(0);
+        *nonce_entry += 1;
+        let nonce = *nonce_entry;
+        drop(nonce_entry);
+
+        let timestamp = (self.clock)();
+        let mut tx = Transaction {
+            tx_id: format!("{}-{}-{}-{}", from, to, nonce, timestamp),
+            from: from.clone(),
+            to,
+            amount,
+            fee: 0,
+            timestamp,
+            signature: String::new(),
+            nonce,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: Some(self.tip().hash),
+            sig_scheme: SigScheme::Sha256,
+            contract: None,
+        };
+        let committed = self
+            .sign_as_node(&mut tx)
+            .and_then(|()| self.commit_transaction(tx, writes));
+        if committed.is_err() {
+            self.rollback_nonce(&from);
+        }
+        committed
+    }
+
+    /// Append a block of its own holding `tx`, behind the proposer's reward
+    /// on chains that pay one, storing `writes` along with it
+    fn commit_transaction(&self, tx: Transaction, writes: Vec<WriteOp>) -> Result<Block, String> {
+        let mut balances: HashMap<String, u64> = self
+            .wallets
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().balance))
+            .collect();
+        Self::apply_transfer(&mut balances, &tx)?;
+
+        let (index, prev_hash, parent_timestamp) = {
+            let chain = self.chain.lock().unwrap();
+            let last_block = chain.last().unwrap();
+            (last_block.index + 1, last_block.hash.clone(), last_block.timestamp)
+        };
+
+        let proposer = self.next_proposer().unwrap_or_else(|| "admin".to_string());
+        let key = self.proposer_key(&proposer)?;
+        let timestamp = (self.clock)().max(parent_timestamp);
+        let txs = self.add_reward(index, &proposer, timestamp, vec![tx], &mut balances)?;
+        let state_root = self.state_root_after(&balances, &txs);
+        let mut block = Block {
+            index,
+            timestamp,
+            tx_root: merkle::root(&txs),
+            transactions: txs,
+            prev_hash,
+            hash: String::new(),
+            proposer,
+            state_root,
+            difficulty: self.required_difficulty(index),
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        Self::seal_block(&mut block);
+        if let Some(key) = key {
+            Self::sign_block(&mut block, key);
+        }
+
+        self.append_block(block.clone(), writes)?;
+        Ok(block)
+    }
+
+    /// Least fee a contract transaction carrying `op` pays: `MIN_FEE` plus
+    /// a unit per started `GAS_PER_FEE_UNIT` of a call's gas limit, or per
+    /// started `MEMO_BYTES_PER_FEE_UNIT` bytes of code deployed
+    pub fn contract_fee(op: &ContractTransaction) -> u64 {
+        let units = match op {
+            ContractTransaction::Call { gas_limit, .. } => gas_limit.div_ceil(GAS_PER_FEE_UNIT),
+            ContractTransaction::Deploy { code, .. } | ContractTransaction::UpgradeContract { code, .. } => {
+                code.len().div_ceil(MEMO_BYTES_PER_FEE_UNIT) as u64
+            }
+        };
+        MIN_FEE.saturating_add(units)
+    }
+
+    /// Queue a contract transaction from `sender`, signed by the node and
+    /// paying [`Self::contract_fee`]. Returns its tx_id and the contract it
+    /// deploys, calls or upgrades. The operation runs once a block includes
+    /// it, and [`Self::get_contract_receipt`] then has the outcome.
+    pub fn create_contract_transaction(
+        &self,
+        sender: String,
+        op: ContractTransaction,
+    ) -> Result<(String, String), String> {
+        self.check_node_signing(&sender)?;
+        self.check_contract_transaction(&sender, &op)?;
+        let fee = Self::contract_fee(&op);
+        let balance = self
+            .wallets
+            .get(&sender)
+            .map(|wallet| wallet.balance)
+            .ok_or("Sender wallet not found".to_string())?;
+        if balance < fee {
+            return Err(format!(
+                "Insufficient balance: {} has {}, needs a fee of {}",
+                sender, balance, fee
+            ));
+        }
+
+        self.check_sender_rate(&sender, 1)?;
+        let code = match &op {
+            ContractTransaction::Deploy { code, .. } => Some(code.clone()),
+            _ => None,
+        };
+        let target = tx_target(&op);
+        let options = TransferOptions {
+            fee: Some(fee),
+            contract: Some(op),
+            ..Default::default()
+        };
+        let tx_id = self
+            .enqueue_transactions(&sender, vec![(CONTRACT_ADDRESS.to_string(), 0, fee, options)])?
+            .remove(0);
+        let contract = match code {
+            Some(code) => deploy_address(&sender, &tx_id, &code),
+            None => target.unwrap_or_default(),
+        };
+        Ok((tx_id, contract))
+    }
+
+    /// Checks on a contract operation from `from` that don't depend on
+    /// balances or the state of contracts
+    fn check_contract_transaction(&self, from: &str, op: &ContractTransaction) -> Result<(), String> {
+        self.check_access(from, CONTRACT_ADDRESS)?;
+        let vm = Vm::new(self.params.gas_schedule);
+        match op {
+            ContractTransaction::Call { gas_limit, .. } => self.check_gas_limit(*gas_limit),
+            ContractTransaction::Deploy { code, abi, .. } => {
+                vm.validate(code).map_err(|e| e.to_string())?;
+                match abi {
+                    Some(abi) => vm.check_abi(code, abi).map_err(|e| e.to_string()),
+                    None => Ok(()),
+                }
+            }
+            ContractTransaction::UpgradeContract { code, .. } => vm.validate(code).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Check a contract transaction is shaped like one: sent to
+    /// `CONTRACT_ADDRESS` with no amount and a fee covering its operation.
+    /// Nothing else may be sent there.
+    fn check_contract_shape(tx: &Transaction) -> Result<(), String> {
+        match &tx.contract {
+            None if tx.to == CONTRACT_ADDRESS => Err(format!(
+                "Transaction {} to {} carries no contract operation",
+                tx.tx_id, CONTRACT_ADDRESS
+            )),
+            None => Ok(()),
+            Some(_) if tx.to != CONTRACT_ADDRESS => Err(format!(
+                "Contract transaction {} must be sent to {}",
+                tx.tx_id, CONTRACT_ADDRESS
+            )),
+            Some(_) if tx.amount != 0 => Err(format!("Contract transaction {} carries an amount", tx.tx_id)),
+            Some(op) if tx.fee < Self::contract_fee(op) => Err(format!(
+                "Contract transaction {} pays a fee of {}, below the {} its operation costs",
+                tx.tx_id,
+                tx.fee,
+                Self::contract_fee(op)
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Outcome of the contract transaction `tx_id`, once a block on the
+    /// chain has applied it
+    pub fn get_contract_receipt(&self, tx_id: &str) -> Option<ContractReceipt> {
+        let bytes = self.stored_value(&format!("receipt:{}", tx_id))?;
+        Codec::decode(&bytes).ok()
+    }
+
+    /// A contract and its ABI as `batch` leaves them
+    fn batched_contract(&self, batch: &ContractBatch, address: &str) -> Option<ContractState> {
+        match batch.changed.get(address) {
+            Some(changed) => changed.clone(),
+            None => self
+                .get_contract(address)
+                .map(|contract| (contract, self.get_contract_abi(address))),
+        }
+    }
+
+    /// Apply the contract operation `tx` carries, if any, into `batch` as
+    /// part of the block at `height`, paying out a self-destructed
+    /// contract's coins in `balances`. An operation that fails gets a
+    /// receipt with the error and changes nothing, but its transaction
+    /// still pays the fee, so every node applies the block alike.
+    fn run_contract_transaction(
+        &self,
+        batch: &mut ContractBatch,
+        tx: &Transaction,
+        height: u64,
+        balances: &mut HashMap<String, u64>,
+    ) {
+        let Some(op) = &tx.contract else {
+            return;
+        };
+        let contract = tx.contract_address().unwrap_or_default();
+        let receipt = self
+            .execute_contract(batch, tx, op, &contract, height, balances)
+            .unwrap_or_else(|error| ContractReceipt {
+                contract,
+                return_value: None,
+                gas_used: 0,
+                error: Some(error),
+            });
+        batch.receipts.push((tx.tx_id.clone(), receipt));
+    }
+
+    /// [`Self::run_contract_transaction`] on `address`, failing instead of
+    /// writing a failed receipt
+    fn execute_contract(
+        &self,
+        batch: &mut ContractBatch,
+        tx: &Transaction,
+        op: &ContractTransaction,
+        address: &str,
+        height: u64,
+        balances: &mut HashMap<String, u64>,
+    ) -> Result<ContractReceipt, String> {
+        let vm = Vm::new(self.params.gas_schedule);
+        let current = self.batched_contract(batch, address);
+        let receipt = |return_value, gas_used| ContractReceipt {
+            contract: address.to_string(),
+            return_value,
+            gas_used,
+            error: None,
+        };
+
+        match op {
+            ContractTransaction::Deploy { code, admin, abi } => {
+                if current.is_some() {
+                    return Err(format!("Contract {} already exists", address));
+                }
+                vm.validate(code).map_err(|e| e.to_string())?;
+                if let Some(abi) = abi {
+                    vm.check_abi(code, abi).map_err(|e| e.to_string())?;
+                }
+                let contract = Contract {
+                    address: address.to_string(),
+                    deployer: tx.from.clone(),
+                    admin: admin.clone(),
+                    code: code.clone(),
+                    version: 1,
+                    storage: HashMap::new(),
+                    deployed_at: height,
+                };
+                batch.changed.insert(address.to_string(), Some((contract, abi.clone())));
+                Ok(receipt(None, 0))
+            }
+            ContractTransaction::Call {
+                function,
+                gas_limit,
+                ..
+            } => {
+                self.check_gas_limit(*gas_limit)?;
+                let (mut contract, abi) = current.ok_or("Contract not found".to_string())?;
+                if let Some(abi) = &abi {
+                    abi.check_call(function)?;
+                }
+
+                let balance_of = |balances: &HashMap<String, u64>, address: &str| {
+                    balances
+                        .get(address)
+                        .copied()
+                        .unwrap_or_else(|| self.wallets.get(address).map_or(0, |wallet| wallet.balance))
+                };
+                let mut visible: HashMap<String, u64> = self
+                    .wallets
+                    .iter()
+                    .map(|w| (w.key().clone(), w.value().balance))
+                    .collect();
+                visible.extend(balances.iter().map(|(address, balance)| (address.clone(), *balance)));
+                let mut context = ContractContext {
+                    contract: address.to_string(),
+                    storage: contract.storage.clone(),
+                    balances: visible,
+                    transfers: Vec::new(),
+                    destroyed: None,
+                };
+                let result = vm
+                    .execute(&contract.code, function, &mut context, *gas_limit)
+                    .map_err(|e| e.to_string())?;
+                if !context.transfers.is_empty() {
+                    return Err("Contract transfers are not supported yet".to_string());
+                }
+
+                match context.destroyed {
+                    // Its coins go to the beneficiary along with the removal
+                    Some(beneficiary) => {
+                        let balance = balance_of(balances, address);
+                        if balance > 0 {
+                            if beneficiary == address
+                                || [COINBASE_ADDRESS, STAKE_ADDRESS, CONTRACT_ADDRESS].contains(&beneficiary.as_str())
+                            {
+                                return Err(format!("Cannot pay out {} to {}", address, beneficiary));
+                            }
+                            let paid = add_amount(balance_of(balances, &beneficiary), balance)?;
+                            balances.insert(address.to_string(), 0);
+                            balances.insert(beneficiary.clone(), paid);
+                            batch.payouts.push((address.to_string(), beneficiary, balance));
+                        }
+                        batch.changed.insert(address.to_string(), None);
+                    }
+                    None => {
+                        contract.storage = context.storage;
+                        batch.changed.insert(address.to_string(), Some((contract, abi)));
+                    }
+                }
+                Ok(receipt(result.return_value, result.gas_used))
+            }
+            ContractTransaction::UpgradeContract { code, .. } => {
+                let (mut contract, abi) = current.ok_or("Contract not found".to_string())?;
+                if contract.admin.as_deref() != Some(tx.from.as_str()) {
+                    return Err(format!("{} is not the admin of {}", tx.from, address));
+                }
+                vm.validate(code).map_err(|e| e.to_string())?;
+                contract.code = code.clone();
+                contract.version += 1;
+                batch.changed.insert(address.to_string(), Some((contract, abi)));
+                Ok(receipt(None, 0))
+            }
+        }
+    }
+
+    /// Writes storing what `batch` did in the block at `height`: the
+    /// contracts it changed, a receipt per transaction, and what it
+    /// replaced for a rollback to restore
+    fn contract_writes(&self, batch: &ContractBatch, height: u64) -> Result<Vec<WriteOp>, String> {
+        let mut undo = ContractUndo {
+            contracts: Vec::new(),
+            payouts: batch.payouts.clone(),
+        };
+        let mut writes = Vec::new();
+        for (address, state) in &batch.changed {
+            let before = self
+                .get_contract(address)
+                .map(|contract| (contract, self.get_contract_abi(address)));
+            undo.contracts.push((address.clone(), before));
+            writes.extend(self.contract_state_writes(address, state.as_ref())?);
+        }
+        for (tx_id, receipt) in &batch.receipts {
+            let encoded = self.codec.encode(receipt).map_err(|e| e.to_string())?;
+            writes.push(WriteOp::Insert(format!("receipt:{}", tx_id).into_bytes(), encoded));
+        }
+        let encoded = self.codec.encode(&undo).map_err(|e| e.to_string())?;
+        writes.push(WriteOp::Insert(format!("contract_undo:{}", height).into_bytes(), encoded));
+        Ok(writes)
+    }
+
+    /// Writes storing the contract at `address` and its ABI as `state` has
+    /// them, or removing both for `None`
+    fn contract_state_writes(
+        &self,
+        address: &str,
+        state: Option<&ContractState>,
+    ) -> Result<Vec<WriteOp>, String> {
+        let contract_key = format!("contract:{}", address).into_bytes();
+        let abi_key = format!("abi:{}", address).into_bytes();
+        let Some((contract, abi)) = state else {
+            return Ok(vec![WriteOp::Remove(contract_key), WriteOp::Remove(abi_key)]);
+        };
+        let mut writes = vec![WriteOp::Insert(
+            contract_key,
+            self.codec.encode(contract).map_err(|e| e.to_string())?,
+        )];
+        writes.push(match abi {
+            Some(abi) => WriteOp::Insert(abi_key, self.codec.encode(abi).map_err(|e| e.to_string())?),
+            None => WriteOp::Remove(abi_key),
+        });
+        Ok(writes)
+    }
+
+    /// The undo record of `block`'s contract transactions, if it has any
+    fn contract_undo(&self, block: &Block) -> Result<Option<ContractUndo>, String> {
+        if !block.transactions.iter().any(|tx| tx.contract.is_some()) {
+            return Ok(None);
+        }
+        let bytes = self
+            .stored_value(&format!("contract_undo:{}", block.index))
+            .ok_or_else(|| format!("Block {} has no contract undo record", block.index))?;
+        Codec::decode(&bytes).map(Some).map_err(|e| e.to_string())
+    }
+
+    /// Put back the contracts `block`'s contract transactions changed, as
+    /// its `undo` record has them. Returns the writes storing that and
+    /// dropping its receipts and undo record. Paid out coins are left to
+    /// the caller.
+    fn undo_contracts(&self, block: &Block, undo: ContractUndo) -> Result<Vec<WriteOp>, String> {
+        let mut writes = vec![WriteOp::Remove(format!("contract_undo:{}", block.index).into_bytes())];
+        for (address, state) in undo.contracts {
+            writes.extend(self.contract_state_writes(&address, state.as_ref())?);
+            match state {
+                Some((contract, _)) => {
+                    self.contracts.insert(address, contract);
+                }
+                None => {
+                    self.contracts.remove(&address);
+                }
+            }
+        }
+        for tx in block.transactions.iter().filter(|tx| tx.contract.is_some()) {
+            writes.push(WriteOp::Remove(format!("receipt:{}", tx.tx_id).into_bytes()));
+        }
+        Ok(writes)
+    }
+
+    /// Reject a contract call given more gas than the chain allows
+    fn check_gas_limit(&self, gas_limit: u64) -> Result<(), String> {
+        if gas_limit > self.params.max_gas_limit {
+            return Err(format!(
+                "Gas limit {} exceeds the per-call limit of {}",
+                gas_limit, self.params.max_gas_limit
+            ));
+        }
+        Ok(())
+    }
+
+    /// Run a contract function as a view call: it may read storage and
+    /// balances, but traps on anything that would change them. Nothing is
+    /// committed and no one is charged.
+    pub fn call_contract_view(
+        &self,
+        contract: &str,
+        function: &str,
+        gas_limit: u64,
+    ) -> Result<ContractReceipt, String> {
+        self.check_gas_limit(gas_limit)?;
+        // Held until the call returns
+        let _slot = match &self.contract_slots {
+            Some(slots) => Some(
+                slots
+                    .try_acquire()
+                    .map_err(|_| CONTRACT_EXECUTIONS_BUSY.to_string())?,
+            ),
+            None => None,
+        };
+
+        if let Some(abi) = self.get_contract_abi(contract) {
+            abi.check_call(function)?;
+        }
+        let code = self.get_contract(contract).ok_or("Contract not found".to_string())?;
+        let context = ContractContext {
+            contract: contract.to_string(),
+            storage: code.storage,
+            balances: self
+                .wallets
+                .iter()
+                .map(|w| (w.key().clone(), w.value().balance))
+                .collect(),
+            transfers: Vec::new(),
+            destroyed: None,
+        };
+        let result = Vm::new(self.params.gas_schedule)
+            .call_view(&code.code, function, &context, gas_limit)
+            .map_err(|e| e.to_string())?;
+
+        Ok(ContractReceipt {
+            contract: contract.to_string(),
+            return_value: result.return_value,
+            gas_used: result.gas_used,
+            error: None,
+        })
+    }
+
+    /// Register a validator with `stake`, or add to its existing stake.
+    /// Returns the validator's total stake. The address must be the ed25519
+    /// public key the validator signs its blocks with.
+    pub fn register_validator(&self, address: String, stake: u64) -> Result<u64, String> {
+        if !Self::is_externally_owned(&address) {
+            return Err(format!("Validator {} must be an ed25519 public key", address));
+        }
+        let mut validators = self.validators.lock().unwrap();
+        let mut updated = validators.clone();
+        let total = updated.register(address.clone(), stake)?;
+
+        let validator_bytes = self
+            .codec
+            .encode(&Validator {
+                address: address.clone(),
+                stake: total,
+            })
+            .map_err(|e| e.to_string())?;
+        self.state_db
+            .insert(format!("validator:{}", address).as_bytes(), &validator_bytes)
+            .map_err(|e| format!("Failed to persist validator: {}", e))?;
+
+        *validators = updated;
+        Ok(total)
+    }
+
+    /// Record a validator's vote for a block on this chain. Once validators
+    /// holding the finality quorum of stake have voted for a block, it and
+    /// everything below it is final and can no longer be reorged. Returns the
+    /// finalized height.
+    pub fn submit_block_vote(&self, vote: BlockVote) -> Result<u64, String> {
+        if !vote.verify() {
+            return Err("Invalid vote signature".to_string());
+        }
+        let validators = self.validators.lock().unwrap();
+        if validators.stake_of(&vote.validator).is_none() {
+            return Err(format!("{} is not a registered validator", vote.validator));
+        }
+        let on_chain = self
+            .get_block_by_index(vote.index)
+            .is_some_and(|block| block.hash == vote.hash);
+        if !on_chain {
+            return Err(format!("Vote for block {} is not on this chain", vote.index));
+        }
+
+        let mut finalized_height = self.finalized_height.lock().unwrap();
+        if vote.index <= *finalized_height {
+            return Ok(*finalized_height);
+        }
+
+        let mut votes = self.votes.lock().unwrap();
+        let voters = votes.entry(vote.index).or_default();
+        voters.insert(vote.validator);
+        let voted_stake = voters
+            .iter()
+            .filter_map(|validator| validators.stake_of(validator))
+            .fold(0u64, u64::saturating_add);
+
+        if voted_stake as f64 >= self.finality_quorum * validators.total_stake() as f64 {
+            let bytes = self.codec.encode(&vote.index).map_err(|e| e.to_string())?;
+            self.state_db
+                .insert(b"finalized_height", &bytes)
+                .map_err(|e| format!("Failed to persist finalized height: {}", e))?;
+            *finalized_height = vote.index;
+            *votes = votes.split_off(&(vote.index + 1));
+        }
+        Ok(*finalized_height)
+    }
+
+    /// Highest block that can no longer be reorged
+    pub fn finalized_height(&self) -> u64 {
+        *self.finalized_height.lock().unwrap()
+    }
+
+    /// Validator chosen to propose the block after the current tip
+    pub fn next_proposer(&self) -> Option<String> {
+        let (height, seed) = {
+            let chain = self.chain.lock().unwrap();
+            let tip = chain.last().unwrap();
+            (tip.index + 1, tip.hash.clone())
+        };
+        self.validators.lock().unwrap().select_proposer(height, &seed)
+    }
+
+    /// Stake, blocks proposed and next-height selection odds per validator
+    pub fn get_validator_stats(&self) -> Vec<ValidatorStats> {
+        let mut proposed: HashMap<String, u64> = HashMap::new();
+        for block in self.blocks_from(0) {
+            *proposed.entry(block.proposer.clone()).or_default() += 1;
+        }
+
+        let validators = self.validators.lock().unwrap();
+        validators
+            .validators()
+            .into_iter()
+            .map(|validator| ValidatorStats {
+                blocks_proposed: proposed.get(&validator.address).copied().unwrap_or(0),
+                selection_probability: validators.selection_probability(&validator.address),
+                address: validator.address,
+                stake: validator.stake,
+            })
+            .collect()
+    }
+
+    /// Get a deployed contract
+    pub fn get_contract(&self, address: &str) -> Option<Contract> {
+        self.contracts.get(address).map(|c| c.value().clone())
+    }
+
+    /// The ABI a contract was deployed with, if it declared one
+    pub fn get_contract_abi(&self, address: &str) -> Option<ContractAbi> {
+        let bytes = self.stored_value(&format!("abi:{}", address))?;
+        Codec::decode(&bytes).ok()
+    }
+
+    /// Every deployed contract, oldest deployment first
+    pub fn list_contracts(&self) -> Vec<ContractInfo> {
+        let mut contracts: Vec<ContractInfo> = self
+            .contracts
+            .iter()
+            .map(|contract| ContractInfo::from(contract.value()))
+            .collect();
+        contracts.sort_by(|a, b| (a.deployed_at, &a.address).cmp(&(b.deployed_at, &b.address)));
+        contracts
+    }
+
+    /// Flush all pending writes to disk, including any held back by write
+    /// batching
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        self.flush_writes()?;
+        let state_db = self.state_db.clone();
+        tokio::task::spawn_blocking(move || state_db.flush())
+            .await
+            .map_err(|e| StorageError(e.to_string()))?
+    }
+
+    /// Persist a block, its state diff and other writes such as stake
+    /// changes together with the wallets it touches as it leaves them, in
+    /// one atomic write, or buffer them all when write batching is on.
+    /// Other wallets are not rewritten.
+    fn persist_block(
+        &self,
+        block: &Block,
+        diff: &StateDiff,
+        staged: &HashMap<String, Wallet>,
+        extra_writes: Vec<WriteOp>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writes = vec![
+            WriteOp::Insert(format!("block:{}", block.index).into_bytes(), self.codec.encode(block)?),
+            WriteOp::Insert(format!("diff:{}", block.index).into_bytes(), self.codec.encode(diff)?),
+        ];
+        writes.extend(extra_writes);
+        for wallet in staged.values() {
+            writes.push(WriteOp::Insert(format!("wallet:{}", wallet.address).into_bytes(), self.codec.encode(wallet)?));
+        }
+
+        if self.write_batching.is_some() {
+            let now = (self.clock)();
+            let mut buffer = self.write_buffer.lock().unwrap();
+            for write in writes {
+                match write {
+                    WriteOp::Insert(key, value) => buffer.writes.insert(key, Some(value)),
+                    WriteOp::Remove(key) => buffer.writes.insert(key, None),
+                };
+            }
+            buffer.blocks += 1;
+            buffer.oldest.get_or_insert(now);
+            return Ok(());
+        }
+
+        self.state_db.transaction(writes)?;
+        Ok(())
+    }
+
+    /// Commit every buffered block, diff, stake change and wallet in one
+    /// atomic write. Does nothing when nothing is buffered.
+    pub fn flush_writes(&self) -> Result<(), StorageError> {
+        let mut buffer = self.write_buffer.lock().unwrap();
+        if buffer.blocks == 0 {
+            return Ok(());
+        }
+
+        let writes: Vec<WriteOp> = buffer
+            .writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => WriteOp::Insert(key.clone(), value.clone()),
+                None => WriteOp::Remove(key.clone()),
+            })
+            .collect();
+        self.state_db.transaction(writes)?;
+
+        *buffer = WriteBuffer::default();
+        Ok(())
+    }
+
+    /// Flush buffered writes once the batch is full or its interval has run
+    /// out. Called after each block, and by a timer while none arrive.
+    pub fn flush_writes_if_due(&self) -> Result<(), StorageError> {
+        let Some(batching) = self.write_batching else {
+            return Ok(());
+        };
+        let buffer = self.write_buffer.lock().unwrap();
+        let due = match buffer.oldest {
+            Some(oldest) => {
+                buffer.blocks >= batching.max_blocks || (self.clock)().saturating_sub(oldest) >= batching.interval_secs
+            }
+            None => false,
+        };
+        drop(buffer);
+        match due {
+            true => self.flush_writes(),
+            false => Ok(()),
+        }
+    }
+
+    /// Write the pending transactions and their balance reservations to
+    /// storage, replacing the previous copy, so a restart resumes with the
+    /// mempool as of this call. Returns how many transactions were written.
+    pub fn persist_mempool(&self) -> Result<usize, StorageError> {
+        let mut pending = self.mempool.lock().unwrap();
+        self.expire_pending(&mut pending);
+        let mempool = PersistedMempool {
+            transactions: pending.to_vec(),
+            reservations: self
+                .reservations
+                .iter()
+                .map(|r| (r.key().clone(), r.value().clone()))
+                .collect(),
+        };
+        drop(pending);
+
+        self.state_db.insert(b"mempool", &self.codec.encode(&mempool)?)?;
+        Ok(mempool.transactions.len())
+    }
+
+    /// Queue the transactions of a persisted mempool that no block has
+    /// included since, with their reservations
+    fn restore_mempool(&self, mempool: PersistedMempool) {
+        let restored: Vec<Transaction> = mempool
+            .transactions
+            .into_iter()
+            .filter(|tx| tx.nonce > self.committed_nonce(&tx.from))
+            .collect();
+        for tx in &restored {
+            if let Some((sender, cost)) = mempool.reservations.get(&tx.tx_id) {
+                let mut reserved = self.reserved.entry(sender.clone()).or_insert(0);
+                *reserved = reserved.saturating_add(*cost);
+                self.reservations.insert(tx.tx_id.clone(), (sender.clone(), *cost));
+            }
+        }
+        let senders: BTreeSet<String> = restored.iter().map(|tx| tx.from.clone()).collect();
+        let mut mempool = self.mempool.lock().unwrap();
+        let now = (self.clock)();
+        for tx in restored {
+            mempool.push(tx, now);
+        }
+        drop(mempool);
+        for sender in senders {
+            self.rollback_nonce(&sender);
+        }
+    }
+
+    /// Blocks accepted but not yet on disk, and for how long
+    pub fn durability_window(&self) -> DurabilityWindow {
+        let buffer = self.write_buffer.lock().unwrap();
+        DurabilityWindow {
+            batching: self.write_batching,
+            unflushed_blocks: buffer.blocks,
+            oldest_unflushed_secs: buffer.oldest.map_or(0, |oldest| (self.clock)().saturating_sub(oldest)),
+        }
+    }
+
+    /// A value written for `key` that is still waiting in the write buffer
+    fn buffered(&self, key: &str) -> Option<Vec<u8>> {
+        self.write_buffer.lock().unwrap().writes.get(key.as_bytes()).cloned().flatten()
+    }
+
+    /// The value stored for `key`, counting writes and removals still
+    /// waiting in the write buffer
+    fn stored_value(&self, key: &str) -> Option<Vec<u8>> {
+        if let Some(value) = self.write_buffer.lock().unwrap().writes.get(key.as_bytes()) {
+            return value.clone();
+        }
+        self.state_db.get(key.as_bytes()).ok()?
+    }
+
+    /// Get the balances changed by the block at `index`
+    pub fn get_diff(&self, index: u64) -> Result<StateDiff, String> {
+        let key = format!("diff:{}", index);
+        let diff_bytes = match self.buffered(&key) {
+            Some(bytes) => bytes,
+            None => self
+                .state_db
+                .get(key.as_bytes())
+                .map_err(|e| e.to_string())?
+                .ok_or("Diff not found".to_string())?,
+        };
+        Codec::decode(&diff_bytes).map_err(|e| e.to_string())
+    }
+
+    /// Stop reserving the amount and fee of pending outgoing transactions,
+    /// checking each new one against the sender's whole balance instead. A
+    /// sender can then queue more than they hold, and the extras are only
+    /// left out when blocks are mined.
+    pub fn without_balance_reservation(mut self) -> Self {
+        self.reserve_pending = false;
+        self
+    }
+
+    /// In debug builds, reconcile the supply after every block added and
+    /// panic if it no longer adds up. Each check walks the whole chain.
+    pub fn with_supply_checks(mut self) -> Self {
+        self.check_supply = true;
+        self
+    }
+
+    /// Batch block writes instead of committing each block on its own
+    pub fn with_write_batching(mut self, batching: WriteBatching) -> Self {
+        self.write_batching = Some(WriteBatching {
+            max_blocks: batching.max_blocks.max(1),
+            ..batching
+        });
+        self
+    }
+
+    /// Limit how fast each sender may create transactions, whatever IP they
+    /// come from
+    pub fn with_sender_rate_limit(mut self, rate: SenderRateLimit) -> Self {
+        self.sender_rate_limit = Some(rate);
+        self
+    }
+
+    /// Finalize blocks once validators holding this share of total stake
+    /// have voted for them
+    pub fn with_finality_quorum(mut self, quorum: f64) -> Self {
+        self.finality_quorum = quorum;
+        self
+    }
+
+    /// Sign the blocks this node proposes with `key`, whose public half is
+    /// the validator address it stakes under
+    pub fn with_validator_key(mut self, key: SigningKey) -> Self {
+        self.validator_key = Some(key);
+        self
+    }
+
+    /// Sign the system accounts' transactions this node creates with `key`,
+    /// which only counts if the chain lists it in `system_keys`
+    pub fn with_system_key(mut self, key: SigningKey) -> Self {
+        self.system_key = Some(key);
+        self
+    }
+
+    /// Receive every block added to the chain from now on. A subscriber
+    /// that falls more than `BLOCK_EVENT_BUFFER` blocks behind skips ahead.
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<Block> {
+        self.block_events.subscribe()
+    }
+
+    /// Require new transactions to carry a proof of work with this many
+    /// leading zero bits (0 turns the check off)
+    pub fn with_pow_difficulty(mut self, difficulty: u32) -> Self {
+        self.pow_difficulty = difficulty;
+        self
+    }
+
+    /// Leading zero bits required of a transaction's proof of work
+    pub fn pow_difficulty(&self) -> u32 {
+        self.pow_difficulty
+    }
+
+    /// Run at most `max` contract view calls at once. Calls beyond that
+    /// fail with [`CONTRACT_EXECUTIONS_BUSY`] instead of waiting.
+    pub fn with_max_contract_executions(mut self, max: usize) -> Self {
+        self.contract_slots = Some(Semaphore::new(max));
+        self
+    }
+
+    /// Take the fee floor reported by `next_block_min_fee` over the last
+    /// `blocks` blocks
+    pub fn with_fee_floor_blocks(mut self, blocks: usize) -> Self {
+        self.fee_floor_blocks = blocks.max(1);
+        self
+    }
+
+    /// Bound the mempool by `limits` instead of the defaults. Transactions
+    /// already pending stay.
+    pub fn with_mempool_limits(self, limits: MempoolLimits) -> Self {
+        {
+            let mut mempool = self.mempool.lock().unwrap();
+            let pending = std::mem::replace(&mut *mempool, Mempool::new(limits));
+            let now = (self.clock)();
+            for tx in pending.iter() {
+                mempool.push(tx.clone(), now);
+            }
+        }
+        self
+    }
+
+    /// Keep only the latest `keep` snapshots stored by [`Self::save_snapshot`]
+    pub fn with_snapshot_retention(mut self, keep: usize) -> Self {
+        self.snapshot_retention = Some(keep);
+        self
+    }
+
+    /// Discard the transactions of blocks more than `depth` below the tip,
+    /// keeping their headers, after each block. Blocks the recent-block,
+    /// dedup and fee floor windows still read are always kept whole.
+    pub fn with_block_pruning(mut self, depth: u64) -> Self {
+        self.prune_depth = Some(depth);
+        self
+    }
+
+    /// Highest block whose body was pruned, 0 if none was
+    pub fn pruned_height(&self) -> u64 {
+        *self.pruned_height.lock().unwrap()
+    }
+
+    /// Whether the block at `index` has been pruned to its header
+    pub fn is_pruned(&self, index: u64) -> bool {
+        index > self.base_height && index <= self.pruned_height()
+    }
+
+    /// Prune the bodies and state diffs of blocks past the pruning depth,
+    /// tallying what they issued and destroyed and the nonces their senders
+    /// committed, so supply checks and nonces don't need them. Returns how
+    /// many blocks were pruned.
+    pub fn prune_blocks(&self) -> Result<u64, String> {
+        let Some(depth) = self.prune_depth else {
+            return Ok(0);
+        };
+        let keep = depth
+            .max(1)
+            .max(self.params.recent_block_window)
+            .max(self.params.tx_dedup_blocks)
+            .max(self.fee_floor_blocks as u64);
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        let first = self.pruned_height().max(self.base_height) + 1;
+        let last = tip.saturating_sub(keep);
+        if last < first {
+            return Ok(0);
+        }
+
+        // Buffered copies would shadow the pruned ones
+        self.flush_writes().map_err(|e| e.to_string())?;
+        let (mut issued, mut destroyed) = *self.pruned_flows.lock().unwrap();
+        let mut nonces: HashMap<String, u64> = HashMap::new();
+        let mut writes = Vec::new();
+        for index in first..=last {
+            let mut block = self
+                .get_block_by_index(index)
+                .ok_or_else(|| format!("Block {} is missing", index))?;
+            for tx in &block.transactions {
+                if tx.from == COINBASE_ADDRESS {
+                    issued = add_amount(issued, tx.amount)?;
+                } else {
+                    nonces.insert(tx.from.clone(), tx.nonce);
+                }
+                if tx.to == BURN_ADDRESS {
+                    destroyed = add_amount(destroyed, tx.amount)?;
+                }
+                destroyed = add_amount(destroyed, tx.fee)?;
+            }
+            block.transactions.clear();
+            let encoded = self.codec.encode(&block).map_err(|e| e.to_string())?;
+            writes.push(WriteOp::Insert(format!("block:{}", index).into_bytes(), encoded));
+            writes.push(WriteOp::Remove(format!("diff:{}", index).into_bytes()));
+            writes.push(WriteOp::Remove(format!("contract_undo:{}", index).into_bytes()));
+        }
+        for (sender, nonce) in &nonces {
+            let encoded = self.codec.encode(nonce).map_err(|e| e.to_string())?;
+            writes.push(WriteOp::Insert(format!("pruned_nonce:{}", sender).into_bytes(), encoded));
+        }
+        let encoded = self.codec.encode(&last).map_err(|e| e.to_string())?;
+        writes.push(WriteOp::Insert(b"pruned_height".to_vec(), encoded));
+        let encoded = self.codec.encode(&(issued, destroyed)).map_err(|e| e.to_string())?;
+        writes.push(WriteOp::Insert(b"pruned_flows".to_vec(), encoded));
+        self.state_db
+            .transaction(writes)
+            .map_err(|e| format!("Failed to store pruned blocks: {}", e))?;
+
+        for block in self.chain.lock().unwrap().iter_mut() {
+            if (first..=last).contains(&block.index) {
+                block.transactions = Vec::new();
+            }
+        }
+        for (sender, nonce) in nonces {
+            self.pruned_nonces.insert(sender, nonce);
+        }
+        *self.pruned_flows.lock().unwrap() = (issued, destroyed);
+        *self.pruned_height.lock().unwrap() = last;
+        Ok(last - first + 1)
+    }
+
+    /// Which replay protections are on, and what each has rejected so far
+    pub fn replay_protection_status(&self) -> ReplayProtectionStatus {
+        ReplayProtectionStatus {
+            nonce_enforcement: true,
+            chain_id_binding: false,
+            recent_block_binding: self.params.recent_block_window > 0,
+            recent_block_window: self.params.recent_block_window,
+            tx_dedup: true,
+            tx_dedup_blocks: self.params.tx_dedup_blocks,
+            rejected: self.replay_rejections.snapshot(),
+        }
+    }
+
+    /// Keep at most `len` of each address's latest transactions in the
+    /// in-memory index. Older ones are found by scanning stored blocks.
+    pub fn with_max_index_len(mut self, len: usize) -> Self {
+        self.max_index_len = Some(len.max(1));
+        let addresses: Vec<String> = self.tx_index().hot.iter().map(|entry| entry.key().clone()).collect();
+        for address in addresses {
+            self.compact_index(&address);
+        }
+        self
+    }
+
+    /// Move an address's oldest index entries past the cap out of memory
+    fn compact_index(&self, address: &str) {
+        self.tx_index().compact(address, self.max_index_len);
+    }
+
+    /// The current transaction index
+    fn tx_index(&self) -> Arc<TxIndex> {
+        self.tx_index.read().unwrap().clone()
+    }
+
+    /// Rebuild the per-address transaction index from the stored blocks,
+    /// replacing what is in memory. Returns the number of blocks scanned.
+    pub fn rebuild_tx_index(&self) -> u64 {
+        self.rebuild_tx_index_with_progress(|_, _| {})
+    }
+
+    /// [`Self::rebuild_tx_index`], calling `progress(scanned, total)` after
+    /// each block
+    pub fn rebuild_tx_index_with_progress(&self, mut progress: impl FnMut(u64, u64)) -> u64 {
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        let total = tip - self.base_height + 1;
+
+        let mut rebuilt: HashMap<String, Vec<TransactionIndex>> = self
+            .wallets
+            .iter()
+            .map(|wallet| (wallet.key().clone(), Vec::new()))
+            .collect();
+        let mut scanned = 0;
+        for block in self.blocks_from(0) {
+            for (position, tx) in block.transactions.iter().enumerate() {
+                let mut parties = vec![&tx.from];
+                if tx.to != tx.from {
+                    parties.push(&tx.to);
+                }
+                for address in parties {
+                    rebuilt.entry(address.clone()).or_default().push(TransactionIndex {
+                        tx_id: tx.tx_id.clone(),
+                        block_index: block.index,
+                        tx_index_in_block: position,
+                    });
+                }
+            }
+            scanned += 1;
+            progress(scanned, total);
+        }
+
+        // Build the new index aside and swap it in whole, so readers never
+        // see it half built
+        let index = TxIndex::default();
+        for (address, entries) in rebuilt {
+            index.hot.insert(address.clone(), entries);
+            index.compact(&address, self.max_index_len);
+        }
+        *self.tx_index.write().unwrap() = Arc::new(index);
+        scanned
+    }
+
+    /// Index entries compacted out of memory for `address`: everything
+    /// before its oldest in-memory entry, found by scanning stored blocks
+    fn archived_entries(&self, address: &str) -> Vec<TransactionIndex> {
+        if self.tx_index().archived.get(address).is_none_or(|count| *count == 0) {
+            return Vec::new();
+        }
+        let first_hot = self
+            .tx_index()
+            .hot
+            .get(address)
+            .and_then(|entries| entries.first().map(|e| (e.block_index, e.tx_index_in_block)));
+        let last_block = match first_hot {
+            Some((block_index, _)) => block_index,
+            None => self.chain.lock().unwrap().last().unwrap().index,
+        };
+        self.scan_index(address, last_block)
+            .into_iter()
+            .filter(|e| first_hot.is_none_or(|first| (e.block_index, e.tx_index_in_block) < first))
+            .collect()
+    }
+
+    /// Index entries for `address` in blocks up to `last_block`, read block
+    /// by block
+    fn scan_index(&self, address: &str, last_block: u64) -> Vec<TransactionIndex> {
+        let mut entries = Vec::new();
+        for block in (self.base_height..=last_block).map_while(|index| self.get_block_by_index(index)) {
+            for (position, tx) in block.transactions.iter().enumerate() {
+                if tx.from == address || tx.to == address {
+                    entries.push(TransactionIndex {
+                        tx_id: tx.tx_id.clone(),
+                        block_index: block.index,
+                        tx_index_in_block: position,
+                    });
+                }
+            }
+        }
+        entries
+    }
+
+    /// Keep only the latest `len` blocks in memory. Older blocks stay in
+    /// storage and are read back on demand.
+    pub fn with_max_chain_len(mut self, len: usize) -> Self {
+        self.max_chain_len = Some(len.max(1));
+        self.trim_window(&mut self.chain.lock().unwrap());
+        self
+    }
+
+    fn trim_window(&self, chain: &mut Vec<Block>) {
+        if let Some(max) = self.max_chain_len {
+            if chain.len() > max {
+                chain.drain(..chain.len() - max);
+            }
+        }
+    }
+
+    /// Write future records with `codec`. Existing records stay readable;
+    /// call [`Self::migrate_storage`] to rewrite them.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Rewrite every stored record not already in this node's codec, in one
+    /// atomic write. Returns how many records were rewritten.
+    pub fn migrate_storage(&self) -> Result<usize, StorageError> {
+        let mut writes = Vec::new();
+        self.recode::<ChainParams>(b"chain_params", &mut writes)?;
+        self.recode::<Block>(b"block:", &mut writes)?;
+        self.recode::<StateDiff>(b"diff:", &mut writes)?;
+        self.recode::<Wallet>(b"wallet:", &mut writes)?;
+        self.recode::<Contract>(b"contract:", &mut writes)?;
+        self.recode::<ContractAbi>(b"abi:", &mut writes)?;
+        self.recode::<ContractReceipt>(b"receipt:", &mut writes)?;
+        self.recode::<ContractUndo>(b"contract_undo:", &mut writes)?;
+        self.recode::<Validator>(b"validator:", &mut writes)?;
+        self.recode::<Snapshot>(b"snapshot:", &mut writes)?;
+
+        let rewritten = writes.len();
+        if rewritten > 0 {
+            self.state_db.transaction(writes)?;
+        }
+        Ok(rewritten)
+    }
+
+    fn recode<T: Serialize + serde::de::DeserializeOwned>(
+        &self,
+        prefix: &[u8],
+        writes: &mut Vec<WriteOp>,
+    ) -> Result<(), StorageError> {
+        for (key, value) in self.state_db.scan_prefix(prefix)? {
+            if Codec::of(&value) != self.codec {
+                let record: T = Codec::decode(&value)?;
+                writes.push(WriteOp::Insert(key, self.codec.encode(&record)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get wallet
+    pub fn get_wallet(&self, address: &str) -> Result<Wallet, String> {
+        self.wallets
+            .get(address)
+            .map(|w| w.value().clone())
+            .ok_or("Wallet not found".to_string())
+    }
+
+    /// Get all wallets except `exclude`, richest first (for leaderboard).
+    /// Pass `RESERVED_ADDRESSES` for the community ranking.
+    pub fn get_leaderboard(&self, exclude: &[&str]) -> Vec<Wallet> {
+        let mut wallets: Vec<_> = self.wallets
+            .iter()
+            .filter(|entry| !exclude.contains(&entry.key().as_str()))
+            .map(|entry| entry.value().clone())
+            .collect();
+        wallets.sort_by_key(|w| std::cmp::Reverse(w.balance));
+        wallets
+    }
+
+    /// Get user transactions (fast due to indexing)
+    pub fn get_user_transactions(&self, address: &str) -> Vec<Transaction> {
+        self.get_user_transactions_page(address, 0, usize::MAX)
+    }
+
+    /// Number of committed transactions sending to or from `address`
+    pub fn get_user_transaction_count(&self, address: &str) -> u64 {
+        let archived = self.tx_index().archived.get(address).map_or(0, |count| *count);
+        archived + self.tx_index().hot.get(address).map_or(0, |indices| indices.len() as u64)
+    }
+
+    /// Get up to `limit` of an address's transactions, skipping the first
+    /// `offset`. Pages reaching into compacted history scan storage.
+    pub fn get_user_transactions_page(&self, address: &str, offset: usize, limit: usize) -> Vec<Transaction> {
+        let archived = self.tx_index().archived.get(address).map_or(0, |count| *count as usize);
+        let (mut indices, skip) = if offset < archived {
+            (self.archived_entries(address), offset)
+        } else {
+            (Vec::new(), offset - archived)
+        };
+        if let Some(hot) = self.tx_index().hot.get(address) {
+            indices.extend(hot.iter().cloned());
+        }
+        let indices: Vec<TransactionIndex> = indices.into_iter().skip(skip).take(limit).collect();
+
+        let mut txs = Vec::new();
+        let mut block: Option<Block> = None;
+        for index in indices {
+            if block.as_ref().map(|b| b.index) != Some(index.block_index) {
+                block = self.get_block_by_index(index.block_index);
+            }
+            if let Some(tx) = block.as_ref().and_then(|b| b.transactions.get(index.tx_index_in_block)) {
+                txs.push(tx.clone());
+            }
+        }
+
+        txs
+    }
+
+    /// Up to `limit` of an address's most recent transactions, newest first,
+    /// from those before position `before` in its history (all of them when
+    /// `None`). `next_cursor` continues with the older ones.
+    pub fn get_recent_user_transactions(&self, address: &str, before: Option<u64>, limit: usize) -> HistoryPage {
+        let total = self.get_user_transaction_count(address);
+        let end = before.map_or(total, |before| before.min(total));
+        let start = end.saturating_sub(limit as u64);
+        let mut transactions = self.get_user_transactions_page(address, start as usize, (end - start) as usize);
+        // Pruned blocks lose their transactions, and after a restart their
+        // index entries too, so a page reaching back to the start may miss some
+        let pruned = (transactions.len() as u64) < end - start || (start == 0 && self.pruned_height() > self.base_height);
+        transactions.reverse();
+        HistoryPage {
+            transactions,
+            truncated: start > 0,
+            next_cursor: (start > 0).then_some(start),
+            pruned,
+        }
+    }
+
+    /// Search an address's own transactions for a case-insensitive memo
+    /// substring. Only the address's indexed history is scanned; `page`
+    /// starts at 0 and holds up to `SEARCH_PAGE_SIZE` results.
+    pub fn search_transactions(&self, address: &str, query: &str, page: usize) -> Vec<Transaction> {
+        let query = query.to_lowercase();
+        self.get_user_transactions(address)
+            .into_iter()
+            .filter(|tx| {
+                tx.memo
+                    .as_ref()
+                    .is_some_and(|memo| memo.to_lowercase().contains(&query))
+            })
+            .skip(page.saturating_mul(SEARCH_PAGE_SIZE))
+            .take(SEARCH_PAGE_SIZE)
+            .collect()
+    }
+
+    /// Get pending transactions
+    pub fn get_pending(&self) -> Vec<Transaction> {
+        self.mempool.lock().unwrap().to_vec()
+    }
+
+    /// Mempool size, fees and limits, and what has expired, been evicted,
+    /// replaced or dropped from it
+    pub fn mempool_stats(&self) -> MempoolStats {
+        self.mempool.lock().unwrap().stats((self.clock)())
+    }
+
+    /// Take the pending transaction `tx_id` out of the mempool, along with
+    /// its sender's later pending transactions, which could no longer be
+    /// mined. Their reservations are handed back and the sender's nonce
+    /// rolls back. Returns the transactions dropped.
+    pub fn drop_transaction(&self, tx_id: &str) -> Result<Vec<Transaction>, String> {
+        let mut mempool = self.mempool.lock().unwrap();
+        let dropped = mempool.remove(tx_id);
+        let Some(first) = dropped.first() else {
+            return Err(format!("Transaction {} is not pending", tx_id));
+        };
+        for tx in &dropped {
+            self.release_reservation(&tx.tx_id);
+        }
+        self.reset_nonce(&mempool, &first.from);
+        Ok(dropped)
+    }
+
+    /// Find a transaction by id, with the height of the block confirming it
+    /// (`None` while it is pending). A tx_id confirmed more than once, far
+    /// enough apart to pass the dedup window, is found in the latest block.
+    pub fn find_transaction(&self, tx_id: &str) -> Option<(Transaction, Option<u64>)> {
+        if let Some(tx) = self.mempool.lock().unwrap().get(tx_id) {
+            return Some((tx.clone(), None));
+        }
+        let index = *self.tx_heights.get(tx_id)?.last()?;
+        let block = self.get_block_by_index(index)?;
+        let tx = block.transactions.into_iter().find(|tx| tx.tx_id == tx_id)?;
+        Some((tx, Some(index)))
+    }
+
+    /// Proof that the confirmed transaction `tx_id` is in its block, for
+    /// light clients holding only block headers
+    pub fn get_merkle_proof(&self, tx_id: &str) -> Option<MerkleProof> {
+        let block_index = self.find_transaction(tx_id)?.1?;
+        let block = self.get_block_by_index(block_index)?;
+        if block.tx_root.is_empty() {
+            return None;
+        }
+        let position = block.transactions.iter().position(|tx| tx.tx_id == tx_id)?;
+        Some(MerkleProof {
+            tx_id: tx_id.to_string(),
+            block_index,
+            position,
+            siblings: merkle::proof(&block.transactions, position)?,
+            root: block.tx_root,
+        })
+    }
+
+    /// Pending transactions sent or received by `address`
+    pub fn get_pending_for_address(&self, address: &str) -> Vec<Transaction> {
+        self.mempool
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|tx| tx.from == address || tx.to == address)
+            .cloned()
+            .collect()
+    }
+
+    /// Get blockchain
+    pub fn get_chain(&self) -> Vec<Block> {
+        self.blocks_from(0).collect()
+    }
+
+    /// The latest block, cloned without copying the rest of the chain
+    pub fn tip(&self) -> Block {
+        self.chain.lock().unwrap().last().unwrap().clone()
+    }
+
+    /// Get up to `limit` blocks starting at height `offset`
+    pub fn get_blocks(&self, offset: usize, limit: usize) -> Vec<Block> {
+        self.blocks_from(offset as u64).take(limit).collect()
+    }
+
+    /// Get the block at height `index`, from memory if it is recent enough
+    /// and from storage otherwise
+    pub fn get_block_by_index(&self, index: u64) -> Option<Block> {
+        {
+            let chain = self.chain.lock().unwrap();
+            if index >= chain[0].index {
+                return chain.get((index - chain[0].index) as usize).cloned();
+            }
+        }
+        self.stored_block(index)
+    }
+
+    /// Read the block at height `index` from storage, or the write buffer
+    fn stored_block(&self, index: u64) -> Option<Block> {
+        let key = format!("block:{}", index);
+        let bytes = match self.buffered(&key) {
+            Some(bytes) => bytes,
+            None => self.state_db.get(key.as_bytes()).ok()??,
+        };
+        Codec::decode(&bytes).ok()
+    }
+
+    /// Blocks from height `start` up to the current tip, skipping any below
+    /// the snapshot this node started from
+    fn blocks_from(&self, start: u64) -> impl Iterator<Item = Block> + '_ {
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        (start.max(self.base_height)..=tip).map_while(move |index| self.get_block_by_index(index))
+    }
+
+    pub fn get_balance(&self, address: &str) -> Result<u64, String> {
+        self.get_wallet(address).map(|w| w.balance)
+    }
+
+    /// Total coins currently held across all wallets, capped at `u64::MAX`
+    /// rather than wrapping; [`Self::reconcile_supply`] reports an overflow
+    pub fn total_supply(&self) -> u64 {
+        self.wallets.iter().fold(0, |total, entry| total.saturating_add(entry.value().balance))
+    }
+
+    fn sum_balances(balances: &HashMap<String, u64>) -> Result<u64, BlockchainError> {
+        balances.values().try_fold(0, |total, balance| add_amount(total, *balance))
+    }
+
+    /// Coins issued by coinbase transactions, and coins destroyed by burns
+    /// and fees, in the blocks above `base_height`
+    fn supply_flows(&self) -> Result<(u64, u64), BlockchainError> {
+        // Pruned blocks keep no transactions, their flows were tallied here
+        let (mut issued, mut destroyed) = *self.pruned_flows.lock().unwrap();
+        for block in self.blocks_from(self.base_height + 1) {
+            for tx in &block.transactions {
+                if tx.from == COINBASE_ADDRESS {
+                    issued = add_amount(issued, tx.amount)?;
+                }
+                if tx.to == BURN_ADDRESS {
+                    destroyed = add_amount(destroyed, tx.amount)?;
+                }
+                destroyed = add_amount(destroyed, tx.fee)?;
+            }
+        }
+        Ok((issued, destroyed))
+    }
+
+    /// Check that no coins appeared or vanished along the way: the wallet
+    /// balances plus everything burned or paid in fees must equal the
+    /// starting supply plus everything minted
+    pub fn reconcile_supply(&self) -> Result<(), BlockchainError> {
+        let (issued, destroyed) = self.supply_flows()?;
+        let held = self
+            .wallets
+            .iter()
+            .try_fold(0, |total, entry| add_amount(total, entry.value().balance))?;
+        let expected = add_amount(self.base_supply, issued)?;
+        let actual = add_amount(held, destroyed)?;
+        if actual != expected {
+            return Err(BlockchainError::SupplyMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Verify chain integrity
+    pub fn verify_chain(&self) -> bool {
+        self.verify_chain_detailed().is_ok()
+    }
+
+    /// Walk the chain from genesis, or from the snapshot the node started
+    /// from, recomputing hashes and checking linkage and transaction
+    /// signatures, and report the first invalid block
+    pub fn verify_chain_detailed(&self) -> Result<(), ChainVerificationError> {
+        let tip = self.chain.lock().unwrap().last().unwrap().index;
+        let missing = |index| ChainVerificationError {
+            index,
+            reason: ChainFault::MissingBlock,
+        };
+
+        let mut previous = self
+            .get_block_by_index(self.base_height)
+            .ok_or_else(|| missing(self.base_height))?;
+        for index in self.base_height + 1..=tip {
+            let current = self.get_block_by_index(index).ok_or_else(|| missing(index))?;
+            let fail = |reason| ChainVerificationError {
+                index: current.index,
+                reason,
+            };
+
+            if current.index != previous.index + 1 {
+                return Err(fail(ChainFault::IndexMismatch));
+            }
+            if current.prev_hash != previous.hash {
+                return Err(fail(ChainFault::BrokenLink));
+            }
+            // A pruned block's hash can't be recomputed without its
+            // transactions; it is held to its link with the next block
+            let pruned = self.is_pruned(current.index);
+            if !pruned && Self::calculate_block_hash(&current) != current.hash {
+                return Err(fail(ChainFault::HashMismatch));
+            }
+            if self.check_block_work(&current, &|index| self.get_block_by_index(index)).is_err() {
+                return Err(fail(ChainFault::InsufficientWork));
+            }
+            if let Some(tx) = current.transactions.iter().find(|tx| !self.signature_verifies(tx, true)) {
+                return Err(fail(ChainFault::InvalidSignature(tx.tx_id.clone())));
+            }
+            // Blocks stored before blocks had a root can't be checked
+            if !pruned && !current.tx_root.is_empty() && merkle::root(&current.transactions) != current.tx_root {
+                return Err(fail(ChainFault::TxRootMismatch));
+            }
+            previous = current;
+        }
+
+        Ok(())
+    }
+
+    /// Get stats
+    pub fn get_stats(&self) -> serde_json::Value {
+        // verify_chain takes the chain lock itself, so run it first
+        let is_valid = self.verify_chain();
+        let height = self.chain.lock().unwrap().last().unwrap().index;
+        let total_txs: u64 = self.blocks_from(0).map(|b| b.transactions.len() as u64).sum();
+        let pending = self.mempool.lock().unwrap().len();
+        let total_coins = self.total_supply();
+
+        serde_json::json!({
+            "chain_height": height,
+            "total_blocks": height + 1,
+            "total_wallets": self.wallets.len(),
+            "total_transactions": total_txs,
+            "pending_transactions": pending,
+            "side_blocks": self.side_block_count(),
+            "pruned_height": self.pruned_height(),
+            "total_coins": total_coins,
+            "total_supply": total_coins,
+            "decimals": self.params.decimals,
+            "is_valid": is_valid,
+        })
+    }
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_with_fees() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("bob".to_string(), 500);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        let tx_id = blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+
+        assert!(!tx_id.is_empty());
+        let pending = blockchain.get_pending();
+        assert_eq!(pending[0].fee, 1); // 1% of 100
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_block_persistence() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 899); // 1000 - 100 - 1 fee
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_leaderboard_ordering() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("bob".to_string(), 500);
+        initial.insert("charlie".to_string(), 750);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let leaderboard = blockchain.get_leaderboard(RESERVED_ADDRESSES);
+
+        assert_eq!(leaderboard[0].address, "alice");
+        assert_eq!(leaderboard[1].address, "charlie");
+        assert_eq!(leaderboard[2].address, "bob");
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_fast_transaction_lookup() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        for _ in 0..100 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 1)
+                .unwrap();
+        }
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        let history = blockchain.get_user_transactions("alice");
+        assert_eq!(history.len(), 100);
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_decimals_persist_across_load() {
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let params = ChainParams {
+            decimals: 6,
+            ..ChainParams::default()
+        };
+
+        let blockchain = CommunityBlockchain::from_store(HashMap::new(), params, store.clone()).unwrap();
+        assert_eq!(blockchain.get_stats()["decimals"], 6);
+        drop(blockchain);
+
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.params().decimals, 6);
+    }
+
+    #[test]
+    fn test_mine_block_holds_back_nonce_gap() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        // Commit nonces 1..=4 first
+        for _ in 0..4 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 100)
+                .unwrap();
+        }
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        // Submit 8 before 5 and 6 so arrival order differs from nonce order
+        for nonce in [8, 6, 5] {
+            let tx_id = format!("alice-bob-{}-0", nonce);
+            let tx = Transaction {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: 100,
+                fee: 1,
+                timestamp: 0,
+                signature: blockchain.sign_transaction(&tx_id, "alice", None, None, None, None),
+                tx_id,
+                nonce,
+                memo: None,
+                execute_at_height: None,
+                recent_block_hash: None,
+                sig_scheme: SigScheme::Sha256,
+                contract: None,
+            };
+            blockchain.mempool.lock().unwrap().push(tx, current_timestamp());
+        }
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        let nonces: Vec<u64> = block.transactions.iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![5, 6]);
+
+        let pending = blockchain.get_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].nonce, 8);
+    }
+
+    #[test]
+    fn test_block_order_ignores_mempool_arrival_order() {
+        let initial: HashMap<String, u64> = ["alice", "bob", "charlie"]
+            .iter()
+            .map(|name| (name.to_string(), 10_000))
+            .collect();
+        let node = || {
+            CommunityBlockchain::from_store_with_clock(
+                initial.clone(),
+                ChainParams::demo(),
+                Arc::new(MemoryStore::new()),
+                || 1_700_000_000,
+            )
+            .unwrap()
+        };
+
+        let source = node();
+        for (from, to, amount) in [
+            ("alice", "bob", 100),
+            ("bob", "charlie", 100),
+            ("charlie", "alice", 1_000),
+            ("alice", "charlie", 100),
+        ] {
+            source
+                .create_transaction(from.to_string(), to.to_string(), amount)
+                .unwrap();
+        }
+        let txs = source.get_pending();
+
+        let mut blocks = Vec::new();
+        for arrival in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            let node = node();
+            for i in arrival {
+                node.mempool.lock().unwrap().push(txs[i].clone(), current_timestamp());
+            }
+            blocks.push(node.mine_block("proposer".to_string()).unwrap());
+        }
+        assert!(blocks.iter().all(|block| block.hash == blocks[0].hash));
+
+        // Highest fee first, then nonce, then tx_id
+        let order: Vec<(&str, u64)> = blocks[0]
+            .transactions
+            .iter()
+            .map(|tx| (tx.from.as_str(), tx.nonce))
+            .collect();
+        assert_eq!(order, vec![("charlie", 1), ("alice", 1), ("bob", 1), ("alice", 2)]);
+
+        // A block listing the same transactions in another order is refused
+        let receiver = node();
+        let mut reordered = blocks[0].clone();
+        reordered.transactions.swap(0, 1);
+        reordered.hash = CommunityBlockchain::calculate_block_hash(&reordered);
+        assert!(receiver.add_block(reordered).unwrap_err().contains("canonical order"));
+        receiver.add_block(blocks[0].clone()).unwrap();
+    }
+
+    #[test]
+    fn test_block_assembly_keeps_every_sender_in_nonce_order() {
+        let initial: HashMap<String, u64> = ["alice", "bob", "carol"]
+            .iter()
+            .map(|name| (name.to_string(), 1_000_000))
+            .collect();
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        // Fees rise along alice's chain and fall along bob's, so the best
+        // fee is often a transaction whose predecessor is still waiting
+        for (from, amount) in [
+            ("alice", 100),
+            ("bob", 9_000),
+            ("carol", 500),
+            ("alice", 5_000),
+            ("bob", 200),
+            ("alice", 20_000),
+            ("carol", 500),
+            ("bob", 100),
+            ("carol", 7_000),
+            ("alice", 300),
+        ] {
+            blockchain.create_transaction(from.to_string(), "dave".to_string(), amount).unwrap();
+        }
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 10);
+
+        let mut next_nonce: HashMap<&str, u64> = HashMap::new();
+        for tx in &block.transactions {
+            let expected = next_nonce.entry(tx.from.as_str()).or_insert(1);
+            assert_eq!(tx.nonce, *expected, "{} is out of nonce order", tx.tx_id);
+            *expected += 1;
+        }
+
+        // The best ready transaction goes first, so alice's 200-fee
+        // transfer waits behind her 1-fee one
+        let order: Vec<(&str, u64)> = block.transactions.iter().map(|tx| (tx.from.as_str(), tx.nonce)).collect();
+        assert_eq!(
+            order,
+            vec![
+                ("bob", 1),
+                ("carol", 1),
+                ("carol", 2),
+                ("carol", 3),
+                ("bob", 2),
+                ("alice", 1),
+                ("alice", 2),
+                ("alice", 3),
+                ("alice", 4),
+                ("bob", 3),
+            ]
+        );
+
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.get_pending().is_empty());
+    }
+
+    #[test]
+    fn test_mempool_policy_sets_inclusion_order() {
+        // (sender, fee, timestamp)
+        let mempool = [("alice", 5, 300), ("bob", 1, 100), ("carol", 3, 200)];
+        let node = |policy: MempoolPolicy| {
+            let initial: HashMap<String, u64> = mempool.iter().map(|(from, ..)| (from.to_string(), 1000)).collect();
+            let params = ChainParams {
+                mempool_policy: policy,
+                ..ChainParams::demo()
+            };
+            let blockchain = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+            for (from, fee, timestamp) in mempool {
+                let tx_id = format!("{}-dave-1-{}", from, timestamp);
+                let tx = Transaction {
+                    from: from.to_string(),
+                    to: "dave".to_string(),
+                    amount: 10,
+                    fee,
+                    timestamp,
+                    signature: blockchain.sign_transaction(&tx_id, from, None, None, None, None),
+                    tx_id,
+                    nonce: 1,
+                    memo: None,
+                    execute_at_height: None,
+                    recent_block_hash: None,
+                    sig_scheme: SigScheme::Sha256,
+                    contract: None,
+                };
+                blockchain.accept_transaction(tx).unwrap();
+            }
+            blockchain
+        };
+        let order = |block: &Block| block.transactions.iter().map(|tx| tx.from.clone()).collect::<Vec<_>>();
+
+        let fee = node(MempoolPolicy::default());
+        assert_eq!(order(&fee.mine_block("proposer".to_string()).unwrap()), ["alice", "carol", "bob"]);
+
+        // FIFO goes by arrival, not the timestamps senders pick, and since
+        // other nodes saw other arrivals they check only nonce order
+        let fifo = node(MempoolPolicy::parse("fifo").unwrap());
+        assert_eq!(order(&fifo.mine_block("proposer".to_string()).unwrap()), ["alice", "bob", "carol"]);
+        let mut by_fee = node(MempoolPolicy::default()).mine_block("proposer".to_string()).unwrap();
+        by_fee.prev_hash = fifo.tip().hash;
+        CommunityBlockchain::seal_block(&mut by_fee);
+        fifo.add_block(by_fee).unwrap();
+
+        // A random order is keyed by the parent block, so it is the same on
+        // every node mining on top of it
+        let policy = MempoolPolicy::parse("random:7").unwrap();
+        let random = node(policy).mine_block("proposer".to_string()).unwrap();
+        assert_eq!(order(&random), ["carol", "alice", "bob"]);
+        assert_eq!(order(&node(policy).mine_block("proposer".to_string()).unwrap()), order(&random));
+        node(policy).add_block(random.clone()).unwrap();
+
+        // Nodes on a different policy reject the block as out of order. The
+        // policy is part of the genesis, so re-parent the block onto theirs.
+        let fee = node(MempoolPolicy::FeePriority);
+        let mut random = random;
+        random.prev_hash = fee.tip().hash;
+        CommunityBlockchain::seal_block(&mut random);
+        let err = fee.add_block(random).unwrap_err();
+        assert!(err.contains("canonical order"), "{}", err);
+        assert!(MempoolPolicy::parse("lottery").is_err());
+    }
+
+    #[test]
+    fn test_load_transactions_ingests_bulk_batch() {
+        let initial: HashMap<String, u64> = (0..10).map(|i| (format!("sender_{}", i), 100_000)).collect();
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let signed = |from: &str, nonce: u64| {
+            let tx_id = format!("{}-carol-{}-1700000000", from, nonce);
+            Transaction {
+                from: from.to_string(),
+                to: "carol".to_string(),
+                amount: 10,
+                fee: 1,
+                timestamp: 1_700_000_000,
+                signature: blockchain.sign_transaction(&tx_id, from, None, None, None, None),
+                tx_id,
+                nonce,
+                memo: None,
+                execute_at_height: None,
+                recent_block_hash: None,
+                sig_scheme: SigScheme::Sha256,
+                contract: None,
+            }
+        };
+
+        let mut txs: Vec<Transaction> = (0..10)
+            .flat_map(|i| (1..=100).map(move |nonce| (format!("sender_{}", i), nonce)))
+            .map(|(from, nonce)| signed(&from, nonce))
+            .collect();
+        let mut forged = signed("sender_0", 101);
+        forged.signature = "00".repeat(32);
+        txs.push(forged.clone());
+        txs.push(txs[0].clone());
+
+        let report = blockchain.load_transactions(txs.clone());
+        assert_eq!(report.accepted, 1000);
+        assert_eq!(report.rejected.len(), 2);
+        assert_eq!(report.rejected[&forged.tx_id], "Invalid transaction signature");
+        assert!(report.rejected[&txs[0].tx_id].contains("already pending"));
+        assert_eq!(blockchain.get_pending().len(), 1000);
+        assert_eq!(blockchain.next_nonce("sender_3"), 101);
+
+        for tx in txs.iter().step_by(97).take(10) {
+            let check = blockchain.check_transaction(tx);
+            assert!(check.signature_valid && check.nonce_valid, "{} failed its check", tx.tx_id);
+        }
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 1000);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 10_000);
+    }
+
+    #[test]
+    fn test_duplicate_transaction_is_rejected_without_double_credit() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("erin".to_string(), 1000);
+        let params = ChainParams {
+            tx_dedup_blocks: 2,
+            ..ChainParams::demo()
+        };
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, params, store.clone()).unwrap();
+        let tx_id = "alice-carol-1-1700000000".to_string();
+        let tx = Transaction {
+            from: "alice".to_string(),
+            to: "carol".to_string(),
+            amount: 10,
+            fee: 1,
+            timestamp: 1_700_000_000,
+            signature: blockchain.sign_transaction(&tx_id, "alice", None, None, None, None),
+            tx_id,
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Sha256,
+            contract: None,
+        };
+
+        blockchain.accept_transaction(tx.clone()).unwrap();
+        let err = blockchain.accept_transaction(tx.clone()).unwrap_err();
+        assert!(err.contains("already pending"), "{}", err);
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // A block carrying the transaction twice is refused
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        let mut doubled = block.clone();
+        doubled.transactions.push(tx.clone());
+        doubled.hash = CommunityBlockchain::calculate_block_hash(&doubled);
+        let err = blockchain.add_block(doubled).unwrap_err();
+        assert!(err.contains("appears twice"), "{}", err);
+
+        blockchain.add_block(block).unwrap();
+        let err = blockchain.accept_transaction(tx.clone()).unwrap_err();
+        assert!(err.contains("already confirmed"), "{}", err);
+        assert_eq!(blockchain.load_transactions(vec![tx.clone()]).accepted, 0);
+
+        // Nor may a later block include it again
+        blockchain.create_transaction("alice".to_string(), "dave".to_string(), 5).unwrap();
+        let mut replay = blockchain.mine_block("proposer".to_string()).unwrap();
+        replay.transactions.insert(0, tx.clone());
+        replay.hash = CommunityBlockchain::calculate_block_hash(&replay);
+        let err = blockchain.add_block(replay).unwrap_err();
+        assert!(err.contains("already confirmed"), "{}", err);
+
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 10);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 989);
+
+        // A node restarted from the store still remembers it
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        let err = reloaded.accept_transaction(tx.clone()).unwrap_err();
+        assert!(err.contains("already confirmed"), "{}", err);
+        assert_eq!(reloaded.find_transaction(&tx.tx_id).unwrap().1, Some(1));
+
+        // Once two blocks are above it, only its nonce turns it away
+        blockchain.create_transaction("erin".to_string(), "dave".to_string(), 5).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.accept_transaction(tx.clone()).unwrap_err().contains("already confirmed"));
+        blockchain.create_transaction("erin".to_string(), "dave".to_string(), 5).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        let err = blockchain.accept_transaction(tx).unwrap_err();
+        assert!(!err.contains("already confirmed"), "{}", err);
+    }
+
+    #[test]
+    fn test_reserved_balance_caps_concurrent_transfers() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+
+        // Without reservation every transfer is checked against the full balance
+        let unreserved = CommunityBlockchain::new_in_memory(initial.clone())
+            .unwrap()
+            .without_balance_reservation();
+        for _ in 0..20 {
+            unreserved.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        }
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let accepted: Vec<String> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..32)
+                .map(|_| scope.spawn(|| blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100)))
+                .collect();
+            handles.into_iter().filter_map(|handle| handle.join().unwrap().ok()).collect()
+        });
+
+        // Each transfer costs 100 plus a fee of 1
+        assert_eq!(accepted.len(), 9);
+        assert_eq!(blockchain.reserved_balance("alice"), 9 * 101);
+        let err = blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap_err();
+        assert!(err.contains("not reserved"));
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 9);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.reserved_balance("alice"), 0);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1000 - 9 * 101);
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 50).unwrap();
+    }
+
+    #[test]
+    fn test_block_diff_lists_affected_accounts() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("bob".to_string(), 500);
+        initial.insert("carol".to_string(), 300);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        blockchain
+            .create_transaction("alice".to_string(), "dave".to_string(), 50)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        let index = block.index;
+        blockchain.add_block(block).unwrap();
+
+        let diff = blockchain.get_diff(index).unwrap();
+        let expected: StateDiff = [
+            ("alice", 1000, 848), // 1000 - 100 - 1 fee - 50 - 1 fee
+            ("bob", 500, 600),
+            ("dave", 0, 50),
+        ]
+        .into_iter()
+        .map(|(address, old_balance, new_balance)| {
+            (address.to_string(), BalanceChange { old_balance, new_balance })
+        })
+        .collect();
+        assert_eq!(diff, expected);
+
+        assert!(blockchain.get_diff(0).is_err());
+    }
+
+    #[test]
+    fn test_block_memo_cap_splits_large_memo_transfers() {
+        let params = ChainParams {
+            max_block_memo_bytes: 500,
+            ..ChainParams::demo()
+        };
+        let initial: HashMap<String, u64> = ["alice", "bob", "carol"]
+            .iter()
+            .map(|address| (address.to_string(), 10_000))
+            .collect();
+        let blockchain =
+            CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+        let memo = "m".repeat(200);
+        for from in ["alice", "bob", "carol"] {
+            blockchain
+                .create_transaction_with_memo(from.to_string(), "dave".to_string(), 10, Some(memo.clone()))
+                .unwrap();
+        }
+        let pending = blockchain.get_pending();
+
+        // Two 200-byte memos fit under the cap, the third waits for the next block
+        let first = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(first.transactions.len(), 2);
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // A block carrying all three is rejected outright
+        let last = blockchain.get_chain().last().unwrap().clone();
+        let mut oversized = Block {
+            index: last.index + 1,
+            timestamp: current_timestamp(),
+            transactions: pending,
+            prev_hash: last.hash,
+            hash: String::new(),
+            proposer: "proposer".to_string(),
+            state_root: String::new(),
+            tx_root: String::new(),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        oversized.hash = CommunityBlockchain::calculate_block_hash(&oversized);
+        let err = blockchain.add_block(oversized).unwrap_err();
+        assert!(err.contains("memo bytes"), "{}", err);
+
+        blockchain.add_block(first).unwrap();
+        let second = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(second.transactions.len(), 1);
+        blockchain.add_block(second).unwrap();
+        assert_eq!(blockchain.get_balance("dave").unwrap(), 30);
+    }
+
+    #[test]
+    fn test_fee_market_fills_blocks_by_fee_rate_and_evicts_lowest() {
+        let params = ChainParams {
+            max_block_txs: 2,
+            ..ChainParams::demo()
+        };
+        let initial: HashMap<String, u64> = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|address| (address.to_string(), 10_000))
+            .collect();
+        let blockchain = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new()))
+            .unwrap()
+            .with_mempool_limits(MempoolLimits {
+                max_txs: 3,
+                ..MempoolLimits::default()
+            });
+        let transfer = |from: &str, fee: u64| {
+            blockchain.create_transaction_with_options(
+                from.to_string(),
+                "erin".to_string(),
+                10,
+                TransferOptions {
+                    fee: Some(fee),
+                    ..TransferOptions::default()
+                },
+            )
+        };
+        assert!(transfer("alice", 0).unwrap_err().contains("at least"));
+        for (from, fee) in [("alice", 50), ("bob", 10), ("carol", 30)] {
+            transfer(from, fee).unwrap();
+        }
+
+        // A full pool turns away a transaction that outbids no one, and
+        // otherwise drops the lowest fee rate, whose sender's nonce is free
+        // again
+        assert!(transfer("dave", 10).unwrap_err().starts_with(crate::mempool::MEMPOOL_FULL));
+        transfer("dave", 40).unwrap();
+        let pending = blockchain.get_pending();
+        assert!(pending.iter().all(|tx| tx.from != "bob"));
+        assert_eq!(blockchain.next_nonce("bob"), 1);
+
+        // Blocks take the highest fee rates up to the transaction cap
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        let senders: Vec<&str> = block.transactions.iter().map(|tx| tx.from.as_str()).collect();
+        assert_eq!(senders, ["alice", "dave"]);
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // A block over the cap is rejected outright
+        let last = blockchain.get_chain().last().unwrap().clone();
+        let mut crowded = Block {
+            index: last.index + 1,
+            timestamp: current_timestamp(),
+            transactions: pending,
+            prev_hash: last.hash,
+            hash: String::new(),
+            proposer: "proposer".to_string(),
+            state_root: String::new(),
+            tx_root: String::new(),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        crowded.hash = CommunityBlockchain::calculate_block_hash(&crowded);
+        let err = blockchain.add_block(crowded).unwrap_err();
+        assert!(err.contains("limit of 2 transactions"), "{}", err);
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_mempool_replaces_drops_and_expires_pending_transactions() {
+        static NOW: AtomicU64 = AtomicU64::new(1_700_000_000);
+        let key = SigningKey::from_bytes(&[4; 32]);
+        let sender = hex::encode(key.verifying_key().as_bytes());
+        let initial: HashMap<String, u64> =
+            [(sender.clone(), 1_000), ("bob".to_string(), 1_000)].into_iter().collect();
+        let blockchain = CommunityBlockchain::from_store_with_clock(
+            initial,
+            ChainParams::demo(),
+            Arc::new(MemoryStore::new()),
+            || NOW.load(Ordering::SeqCst),
+        )
+        .unwrap()
+        .with_mempool_limits(MempoolLimits {
+            max_per_sender: 2,
+            ttl_secs: 60,
+            ..MempoolLimits::default()
+        });
+        let signed = |nonce: u64, fee: u64| {
+            let mut tx = Transaction {
+                from: sender.clone(),
+                to: "carol".to_string(),
+                amount: 100,
+                fee,
+                timestamp: NOW.load(Ordering::SeqCst),
+                tx_id: String::new(),
+                signature: String::new(),
+                nonce,
+                memo: None,
+                execute_at_height: None,
+                recent_block_hash: None,
+                sig_scheme: SigScheme::Ed25519,
+                contract: None,
+            };
+            tx.tx_id = tx.content_id();
+            tx.signature = hex::encode(ed25519_dalek::Signer::sign(&key, &tx.signing_payload()).to_bytes());
+            tx
+        };
+
+        // A second transaction with a pending nonce must outbid it by 10%
+        let first = signed(1, 20);
+        blockchain.accept_transaction(first.clone()).unwrap();
+        assert!(blockchain.accept_transaction(first).unwrap_err().contains("already pending"));
+        assert!(blockchain.accept_transaction(signed(1, 21)).unwrap_err().contains("at least 22"));
+        let replacement = signed(1, 22);
+        blockchain.accept_transaction(replacement.clone()).unwrap();
+        assert_eq!(blockchain.get_pending(), vec![replacement.clone()]);
+        assert_eq!(blockchain.reserved_balance(&sender), 122);
+
+        // Senders are capped, and dropping a transaction takes its sender's
+        // later ones with it
+        blockchain.accept_transaction(signed(2, 10)).unwrap();
+        assert!(blockchain.accept_transaction(signed(3, 10)).unwrap_err().contains("(2)"));
+        assert_eq!(blockchain.drop_transaction(&replacement.tx_id).unwrap().len(), 2);
+        assert!(blockchain.drop_transaction(&replacement.tx_id).is_err());
+        assert_eq!(blockchain.reserved_balance(&sender), 0);
+        assert_eq!(blockchain.next_nonce(&sender), 1);
+
+        // Transactions expire once they have waited out their time to live
+        blockchain.create_transaction("bob".to_string(), "carol".to_string(), 10).unwrap();
+        NOW.fetch_add(60, Ordering::SeqCst);
+        blockchain.accept_transaction(signed(1, 10)).unwrap();
+        assert!(blockchain.get_pending().iter().all(|tx| tx.from != "bob"));
+        assert_eq!((blockchain.reserved_balance("bob"), blockchain.next_nonce("bob")), (0, 1));
+
+        let stats = blockchain.mempool_stats();
+        assert_eq!((stats.pending, stats.replaced, stats.dropped, stats.expired), (1, 1, 2, 1));
+    }
+
+    #[test]
+    fn test_block_value_cap_rejects_oversized_block() {
+        let params = ChainParams {
+            max_tx_amount: 1_000,
+            max_block_value: 1_500,
+            ..ChainParams::demo()
+        };
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("bob".to_string(), 10_000);
+        let blockchain =
+            CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+
+        assert!(blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 1_001)
+            .is_err());
+
+        // Each transfer is under the per-transaction cap, together they are not
+        blockchain
+            .create_transaction("alice".to_string(), "carol".to_string(), 1_000)
+            .unwrap();
+        blockchain
+            .create_transaction("bob".to_string(), "carol".to_string(), 1_000)
+            .unwrap();
+        let pending = blockchain.get_pending();
+
+        // Mining leaves the second transfer for a later block
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // A block carrying both is rejected outright
+        let last = blockchain.get_chain().last().unwrap().clone();
+        let mut oversized = Block {
+            index: last.index + 1,
+            timestamp: current_timestamp(),
+            transactions: pending,
+            prev_hash: last.hash,
+            hash: String::new(),
+            proposer: "proposer".to_string(),
+            state_root: String::new(),
+            tx_root: String::new(),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        oversized.hash = CommunityBlockchain::calculate_block_hash(&oversized);
+        let err = blockchain.add_block(oversized).unwrap_err();
+        assert!(err.contains("per-block limit"));
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 0);
+    }
+
+    /// Queue a contract transaction from `sender`, seal it into a block and
+    /// return its receipt, or the error that refused or failed it
+    fn apply_contract(
+        blockchain: &CommunityBlockchain,
+        sender: &str,
+        op: ContractTransaction,
+    ) -> Result<ContractReceipt, String> {
+        let (tx_id, _) = blockchain.create_contract_transaction(sender.to_string(), op)?;
+        let block = blockchain.mine_block("proposer".to_string())?;
+        blockchain.add_block(block)?;
+        let receipt = blockchain.get_contract_receipt(&tx_id).ok_or("No receipt")?;
+        match receipt.error {
+            Some(error) => Err(error),
+            None => Ok(receipt),
+        }
+    }
+
+    #[test]
+    fn test_contract_upgrade_keeps_storage_and_requires_admin() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("bob".to_string(), 10_000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+        let call = |contract: &str, function: &str| ContractTransaction::Call {
+            contract: contract.to_string(),
+            function: function.to_string(),
+            gas_limit: 1_000_000,
+        };
+
+        let deploy = ContractTransaction::Deploy {
+            code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+            admin: Some("alice".to_string()),
+            abi: None,
+        };
+        let fee = CommunityBlockchain::contract_fee(&deploy);
+        let contract = apply_contract(&blockchain, "alice", deploy).unwrap().contract;
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 10_000 - fee);
+        for _ in 0..2 {
+            apply_contract(&blockchain, "bob", call(&contract, "increment")).unwrap();
+        }
+        // Each call paid for its gas limit
+        let call_fee = CommunityBlockchain::contract_fee(&call(&contract, "increment"));
+        assert_eq!(call_fee, MIN_FEE + 10);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 10_000 - 2 * call_fee);
+
+        let greedy = ContractTransaction::Call {
+            contract: contract.clone(),
+            function: "increment".to_string(),
+            gas_limit: DEFAULT_MAX_GAS_LIMIT + 1,
+        };
+        let refused = blockchain.create_contract_transaction("bob".to_string(), greedy);
+        assert!(refused.unwrap_err().contains("per-call limit"));
+
+        // The new version counts in steps of ten. Bob's upgrade makes it
+        // into a block and pays its fee, but changes nothing.
+        let v2 = crate::vm::COUNTER_WAT.replace("(i64.const 1)", "(i64.const 10)");
+        let rejected = apply_contract(
+            &blockchain,
+            "bob",
+            ContractTransaction::UpgradeContract {
+                contract: contract.clone(),
+                code: v2.as_bytes().to_vec(),
+            },
+        );
+        assert!(rejected.unwrap_err().contains("not the admin"));
+        assert_eq!(blockchain.get_contract(&contract).unwrap().version, 1);
+
+        apply_contract(
+            &blockchain,
+            "alice",
+            ContractTransaction::UpgradeContract {
+                contract: contract.clone(),
+                code: v2.as_bytes().to_vec(),
+            },
+        )
+        .unwrap();
+
+        let count = blockchain.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+        assert_eq!(count.return_value, Some(2));
+
+        apply_contract(&blockchain, "bob", call(&contract, "increment")).unwrap();
+        drop(blockchain);
+
+        // Upgraded code and storage are persisted
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        let count = reloaded.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+        assert_eq!(count.return_value, Some(12));
+        assert_eq!(reloaded.get_contract(&contract).unwrap().version, 2);
+    }
+
+    #[test]
+    fn test_contract_transactions_apply_on_every_node() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let proposer = CommunityBlockchain::new_in_memory(initial.clone()).unwrap();
+        let peer = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        let (_, contract) = proposer
+            .create_contract_transaction(
+                "alice".to_string(),
+                ContractTransaction::Deploy {
+                    code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+                    admin: None,
+                    abi: None,
+                },
+            )
+            .unwrap();
+        let call = ContractTransaction::Call {
+            contract: contract.clone(),
+            function: "increment".to_string(),
+            gas_limit: 1_000_000,
+        };
+        let (call_id, _) = proposer.create_contract_transaction("alice".to_string(), call.clone()).unwrap();
+        // Nothing runs until a block includes it
+        assert!(proposer.get_contract(&contract).is_none());
+        assert!(proposer.get_contract_receipt(&call_id).is_none());
+
+        let block = proposer.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        proposer.add_block(block.clone()).unwrap();
+        peer.add_block(block.clone()).unwrap();
+        for node in [&proposer, &peer] {
+            assert_eq!(node.get_contract(&contract).unwrap().deployed_at, 1);
+            let count = node.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+            assert_eq!(count.return_value, Some(1));
+            assert!(node.get_contract_receipt(&call_id).unwrap().gas_used > 0);
+            assert_eq!(node.state_root(), block.state_root);
+        }
+
+        // An operation that claims to cost less than it does is refused
+        let mut tx = proposer.get_chain()[1].transactions[1].clone();
+        tx.fee = MIN_FEE;
+        let err = CommunityBlockchain::check_contract_shape(&tx).unwrap_err();
+        assert!(err.contains("below the"));
+        tx.contract = None;
+        let err = CommunityBlockchain::check_contract_shape(&tx).unwrap_err();
+        assert!(err.contains("carries no contract operation"));
+    }
+
+    #[test]
+    fn test_contract_view_call_leaves_state_alone() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let deploy = ContractTransaction::Deploy {
+            code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+            admin: None,
+            abi: None,
+        };
+        let increment = |contract: &str| ContractTransaction::Call {
+            contract: contract.to_string(),
+            function: "increment".to_string(),
+            gas_limit: 1_000_000,
+        };
+        let contract = apply_contract(&blockchain, "alice", deploy.clone()).unwrap().contract;
+        apply_contract(&blockchain, "alice", increment(&contract)).unwrap();
+        let storage = blockchain.get_contract(&contract).unwrap().storage;
+        let balance = blockchain.get_balance("alice").unwrap();
+        assert_eq!(
+            balance,
+            1000 - CommunityBlockchain::contract_fee(&deploy) - CommunityBlockchain::contract_fee(&increment(&contract))
+        );
+
+        let count = blockchain.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+        assert_eq!(count.return_value, Some(1));
+        assert!(count.gas_used > 0);
+
+        let err = blockchain.call_contract_view(&contract, "increment", 1_000_000).unwrap_err();
+        assert!(err.contains("not allowed in a view call"));
+        let err = blockchain.call_contract_view(&contract, "get_count", u64::MAX).unwrap_err();
+        assert!(err.contains("per-call limit"));
+        assert_eq!(blockchain.get_contract(&contract).unwrap().storage, storage);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), balance);
+        assert_eq!(blockchain.get_chain().len(), 3);
+    }
+
+    #[test]
+    fn test_contract_abi_is_stored_and_checked() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("bob".to_string(), 10_000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+        let abi: ContractAbi = serde_json::from_value(serde_json::json!({
+            "functions": [
+                {"name": "increment"},
+                {"name": "get_count", "returns": ["i64"]},
+            ]
+        }))
+        .unwrap();
+        let deploy = |abi: ContractAbi| {
+            apply_contract(
+                &blockchain,
+                "alice",
+                ContractTransaction::Deploy {
+                    code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+                    admin: None,
+                    abi: Some(abi),
+                },
+            )
+        };
+        let call = |contract: &str, function: &str| {
+            apply_contract(
+                &blockchain,
+                "bob",
+                ContractTransaction::Call {
+                    contract: contract.to_string(),
+                    function: function.to_string(),
+                    gas_limit: 1_000_000,
+                },
+            )
+        };
+
+        // An ABI that disagrees with the code is refused
+        let mut wrong = abi.clone();
+        wrong.functions[1].returns = vec![crate::contract::AbiType::I32];
+        assert!(deploy(wrong).unwrap_err().contains("does not match its ABI"));
+        let mut missing = abi.clone();
+        missing.functions.push(crate::contract::AbiFunction {
+            name: "reset".to_string(),
+            args: Vec::new(),
+            returns: Vec::new(),
+        });
+        assert!(deploy(missing).unwrap_err().contains("does not export `reset`"));
+
+        let contract = deploy(abi.clone()).unwrap().contract;
+        assert_eq!(blockchain.get_contract_abi(&contract), Some(abi.clone()));
+
+        call(&contract, "increment").unwrap();
+        let count = blockchain.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+        assert_eq!(count.return_value, Some(1));
+        // `load` is not exported, but the ABI rejects it before the VM runs
+        assert!(call(&contract, "load").unwrap_err().contains("not in the contract's ABI"));
+
+        drop(blockchain);
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.get_contract_abi(&contract), Some(abi));
+        assert_eq!(reloaded.get_contract_abi("contract_unknown"), None);
+    }
+
+    #[test]
+    fn test_self_destruct_pays_out_and_removes_contract() {
+        const VAULT_WAT: &str = r#"
+(module
+  (import "env" "destroy" (func $destroy (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "bob")
+  (func (export "ping") (result i64) (i64.const 1))
+  (func (export "close") (call $destroy (i32.const 0) (i32.const 3))))
+"#;
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+        let call = |contract: &str, function: &str| {
+            apply_contract(
+                &blockchain,
+                "alice",
+                ContractTransaction::Call {
+                    contract: contract.to_string(),
+                    function: function.to_string(),
+                    gas_limit: 1_000_000,
+                },
+            )
+        };
+
+        let contract = apply_contract(
+            &blockchain,
+            "alice",
+            ContractTransaction::Deploy {
+                code: VAULT_WAT.as_bytes().to_vec(),
+                admin: None,
+                abi: Some(ContractAbi {
+                    functions: vec![
+                        crate::contract::AbiFunction {
+                            name: "ping".to_string(),
+                            args: Vec::new(),
+                            returns: vec![crate::contract::AbiType::I64],
+                        },
+                        crate::contract::AbiFunction {
+                            name: "close".to_string(),
+                            args: Vec::new(),
+                            returns: Vec::new(),
+                        },
+                    ],
+                }),
+            },
+        )
+        .unwrap()
+        .contract;
+        blockchain.create_transaction("alice".to_string(), contract.clone(), 500).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(call(&contract, "ping").unwrap().return_value, Some(1));
+        let before_close = blockchain.get_chain().last().unwrap().clone();
+
+        call(&contract, "close").unwrap();
+        assert_eq!(blockchain.get_balance(&contract).unwrap(), 0);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 500);
+        assert!(blockchain.get_contract(&contract).is_none());
+        assert!(blockchain.get_contract_abi(&contract).is_none());
+        assert!(blockchain.list_contracts().is_empty());
+        assert_eq!(call(&contract, "ping").unwrap_err(), "Contract not found");
+        assert!(blockchain.reconcile_supply().is_ok());
+
+        // Orphaning the blocks from the close on brings the contract and
+        // its coins back
+        let mut competing = Block {
+            index: before_close.index + 1,
+            timestamp: current_timestamp(),
+            transactions: vec![],
+            prev_hash: before_close.hash.clone(),
+            hash: String::new(),
+            proposer: "other".to_string(),
+            state_root: before_close.state_root.clone(),
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
+        blockchain.reorg(before_close.index, vec![competing]).unwrap();
+        assert_eq!(blockchain.get_balance(&contract).unwrap(), 500);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 0);
+        assert!(blockchain.get_contract(&contract).is_some());
+        assert!(blockchain.get_contract_abi(&contract).is_some());
+        assert!(blockchain.reconcile_supply().is_ok());
+
+        // The orphaned close and ping went back to the mempool and run again
+        assert_eq!(blockchain.get_pending().len(), 2);
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        drop(blockchain);
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert!(reloaded.get_contract(&contract).is_none());
+        assert_eq!(reloaded.get_balance("bob").unwrap(), 500);
+    }
+
+    #[test]
+    fn test_list_contracts_reports_metadata() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let deploy = |sender: &str, code: &str| {
+            apply_contract(
+                &blockchain,
+                sender,
+                ContractTransaction::Deploy {
+                    code: code.as_bytes().to_vec(),
+                    admin: None,
+                    abi: None,
+                },
+            )
+            .unwrap()
+            .contract
+        };
+
+        let counter = deploy("alice", crate::vm::COUNTER_WAT);
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        let empty = deploy("bob", "(module)");
+
+        assert_eq!(
+            blockchain.list_contracts(),
+            vec![
+                ContractInfo {
+                    address: counter,
+                    deployer: "alice".to_string(),
+                    code_size: crate::vm::COUNTER_WAT.len(),
+                    deployed_at: 1,
+                },
+                ContractInfo {
+                    address: empty,
+                    deployer: "bob".to_string(),
+                    code_size: "(module)".len(),
+                    deployed_at: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_contract_view_calls_beyond_limit_are_rejected() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_max_contract_executions(1);
+        let contract = apply_contract(
+            &blockchain,
+            "alice",
+            ContractTransaction::Deploy {
+                code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+                admin: None,
+                abi: None,
+            },
+        )
+        .unwrap()
+        .contract;
+        let view = || blockchain.call_contract_view(&contract, "get_count", 1_000_000);
+
+        // With the only slot taken, a view call is turned away
+        let slot = blockchain.contract_slots.as_ref().unwrap().try_acquire().unwrap();
+        assert!(view().unwrap_err().starts_with(CONTRACT_EXECUTIONS_BUSY));
+        drop(slot);
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(view)).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(results.iter().any(|r| r.is_ok()));
+        assert!(results
+            .iter()
+            .filter_map(|r| r.as_ref().err())
+            .all(|e| e.starts_with(CONTRACT_EXECUTIONS_BUSY)));
+        assert_eq!(view().unwrap().return_value, Some(0));
+    }
+
+    #[test]
+    fn test_search_transactions_by_memo() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("carol".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        for memo in ["Rent for March", "coffee", "RENT for April"] {
+            blockchain
+                .create_transaction_with_memo(
+                    "alice".to_string(),
+                    "bob".to_string(),
+                    100,
+                    Some(memo.to_string()),
+                )
+                .unwrap();
+        }
+        // Someone else's rent payment must not show up in alice's search
+        blockchain
+            .create_transaction_with_memo(
+                "carol".to_string(),
+                "dave".to_string(),
+                100,
+                Some("rent".to_string()),
+            )
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        let found = blockchain.search_transactions("alice", "rent", 0);
+        let memos: Vec<_> = found.iter().map(|tx| tx.memo.clone().unwrap()).collect();
+        assert_eq!(memos, vec!["Rent for March", "RENT for April"]);
+
+        assert_eq!(blockchain.search_transactions("alice", "coffee", 0).len(), 1);
+        assert!(blockchain.search_transactions("alice", "rent", 1).is_empty());
+        assert!(blockchain
+            .create_transaction_with_memo(
+                "alice".to_string(),
+                "bob".to_string(),
+                1,
+                Some("x".repeat(MAX_MEMO_LEN + 1)),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_self_transfers_are_rejected() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        let rejected = blockchain.create_transaction("alice".to_string(), "alice".to_string(), 100);
+        assert_eq!(rejected.unwrap_err(), "Cannot send to yourself");
+
+        // One self-leg sinks the whole batch
+        let legs = vec![
+            ("bob".to_string(), 100),
+            ("alice".to_string(), 100),
+            ("carol".to_string(), 100),
+        ];
+        let rejected = blockchain.create_batch_transaction("alice".to_string(), legs);
+        assert_eq!(rejected.unwrap_err(), "Transfer 1: Cannot send to yourself");
+        assert!(blockchain.get_pending().is_empty());
+        assert_eq!(blockchain.next_nonce("alice"), 1);
+
+        let legs = vec![("bob".to_string(), 100), ("carol".to_string(), 100)];
+        let tx_ids = blockchain.create_batch_transaction("alice".to_string(), legs).unwrap();
+        assert_eq!(tx_ids.len(), 2);
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_denylisted_sender_is_rejected() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("mallory".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        // Queued before the denylist is in place
+        blockchain
+            .create_transaction("mallory".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+
+        blockchain.set_access_list(AccessList::parse(None, Some("mallory")));
+        let rejected = blockchain.create_transaction("mallory".to_string(), "bob".to_string(), 100);
+        assert!(rejected.unwrap_err().contains("denylisted"));
+        let rejected = blockchain.create_transaction("alice".to_string(), "mallory".to_string(), 100);
+        assert!(rejected.unwrap_err().contains("denylisted"));
+
+        // The list is this node's alone, so blocks are taken as every
+        // other node takes them
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("mallory").unwrap(), 899);
+
+        blockchain.update_access_list(|access| access.undeny("mallory"));
+        blockchain
+            .create_transaction("mallory".to_string(), "bob".to_string(), 100)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_allowlist_only_mode_rejects_unknown_addresses() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("stranger".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        blockchain.set_access_list(AccessList::parse(Some("alice, bob"), None));
+
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let rejected = blockchain.create_transaction("alice".to_string(), "carol".to_string(), 100);
+        assert!(rejected.unwrap_err().contains("not on the allowlist"));
+        let rejected = blockchain.create_transaction("stranger".to_string(), "bob".to_string(), 100);
+        assert!(rejected.unwrap_err().contains("not on the allowlist"));
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+
+        blockchain.update_access_list(|access| access.allow("carol".to_string()));
+        blockchain
+            .create_transaction("alice".to_string(), "carol".to_string(), 100)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_detailed_verification_reports_first_invalid_block() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        for _ in 0..5 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 100)
+                .unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(blockchain.verify_chain_detailed(), Ok(()));
+
+        let original = blockchain.chain.lock().unwrap()[3].clone();
+
+        // A forged signature doesn't change the block hash
+        blockchain.chain.lock().unwrap()[3].transactions[0].signature = "forged".to_string();
+        assert_eq!(
+            blockchain.verify_chain_detailed(),
+            Err(ChainVerificationError {
+                index: 3,
+                reason: ChainFault::InvalidSignature(original.transactions[0].tx_id.clone()),
+            })
+        );
+
+        // Later blocks are also broken by the rewrite, but block 3 is reported
+        let mut tampered = original.clone();
+        tampered.timestamp += 1;
+        blockchain.chain.lock().unwrap()[3] = tampered;
+        let err = blockchain.verify_chain_detailed().unwrap_err();
+        assert_eq!(err.index, 3);
+        assert_eq!(err.reason, ChainFault::HashMismatch);
+        assert!(!blockchain.verify_chain());
+
+        blockchain.chain.lock().unwrap()[3] = original;
+        assert!(blockchain.verify_chain());
+    }
+
+    #[test]
+    fn test_scheduled_transaction_waits_for_height() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("dave".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let height = blockchain.get_chain().last().unwrap().index;
+
+        let scheduled = blockchain
+            .create_scheduled_transaction(
+                "alice".to_string(),
+                "carol".to_string(),
+                500,
+                None,
+                Some(height + 2),
+            )
+            .unwrap();
+        blockchain
+            .create_transaction("dave".to_string(), "erin".to_string(), 100)
+            .unwrap();
+
+        // Skipped at N+1, while other transfers go through
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.index, height + 1);
+        assert!(block.transactions.iter().all(|tx| tx.tx_id != scheduled));
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // Moving the schedule earlier breaks the signature
+        let mut forged = blockchain.get_pending()[0].clone();
+        forged.execute_at_height = Some(height + 1);
+        assert!(!CommunityBlockchain::verify_signature(&forged, true));
+
+        // Included at N+2
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.index, height + 2);
+        assert_eq!(block.transactions[0].tx_id, scheduled);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 500);
+    }
+
+    #[test]
+    fn test_recent_block_hash_must_be_within_window() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("dave".to_string(), 10_000);
+        let params = ChainParams {
+            recent_block_window: 2,
+            ..ChainParams::demo()
+        };
+        let blockchain =
+            CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+        let transfer = |from: &str, recent_block_hash: Option<String>, execute_at_height: Option<u64>| {
+            blockchain.create_transaction_with_options(
+                from.to_string(),
+                "carol".to_string(),
+                100,
+                TransferOptions {
+                    recent_block_hash,
+                    execute_at_height,
+                    ..TransferOptions::default()
+                },
+            )
+        };
+        let advance = || {
+            transfer("dave", None, None).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            block
+        };
+
+        let genesis = blockchain.get_chain()[0].hash.clone();
+        advance();
+        advance();
+        let tip = advance();
+
+        // The tip is within the window, genesis three blocks back is not
+        let bound = transfer("alice", Some(tip.hash.clone()), None).unwrap();
+        let err = transfer("alice", Some(genesis.clone()), None).unwrap_err();
+        assert!(err.contains("older than the last 2 blocks"));
+        assert!(transfer("alice", Some("unknown".to_string()), None).is_err());
+
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        let bound_tx = block.transactions[0].clone();
+        assert_eq!(bound_tx.tx_id, bound);
+        assert_eq!(bound_tx.recent_block_hash, Some(tip.hash.clone()));
+        blockchain.add_block(block.clone()).unwrap();
+
+        // The reference is signed
+        let mut forged = bound_tx;
+        forged.recent_block_hash = Some(genesis);
+        assert!(!CommunityBlockchain::verify_signature(&forged, true));
+
+        // Held back until its reference has aged out, then dropped
+        let stale = transfer("alice", Some(block.hash), Some(block.index + 3)).unwrap();
+        advance();
+        advance();
+        let stale_tx = blockchain.get_pending().into_iter().find(|tx| tx.tx_id == stale).unwrap();
+        let block = advance();
+        assert!(block.transactions.iter().all(|tx| tx.tx_id != stale));
+        assert!(blockchain.get_pending().is_empty());
+
+        // and a block carrying it anyway is rejected
+        let last = blockchain.get_chain().last().unwrap().clone();
+        let mut late = Block {
+            index: last.index + 1,
+            timestamp: last.timestamp + 1,
+            transactions: vec![stale_tx],
+            prev_hash: last.hash,
+            hash: String::new(),
+            proposer: "proposer".to_string(),
+            state_root: String::new(),
+            tx_root: String::new(),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        late.hash = CommunityBlockchain::calculate_block_hash(&late);
+        let err = blockchain.add_block(late).unwrap_err();
+        assert!(err.contains("not among the 2 blocks below"));
+    }
+
+    #[test]
+    fn test_bincode_storage_round_trip_and_migration() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        // Genesis is written as JSON, everything after as bincode
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone())
+            .unwrap()
+            .with_codec(Codec::Bincode);
+
+        blockchain
+            .create_transaction_with_memo(
+                "alice".to_string(),
+                "bob".to_string(),
+                100,
+                Some("lunch".to_string()),
+            )
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        let validator = hex::encode(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes());
+        assert!(blockchain.register_validator("val".to_string(), 500).is_err());
+        blockchain.register_validator(validator, 500).unwrap();
+
+        assert_eq!(Codec::of(&store.get(b"block:1").unwrap().unwrap()), Codec::Bincode);
+        assert_eq!(Codec::of(&store.get(b"block:0").unwrap().unwrap()), Codec::Json);
+
+        let reloaded = CommunityBlockchain::load_from_store(store.clone())
+            .unwrap()
+            .with_codec(Codec::Bincode);
+        assert_eq!(
+            serde_json::to_value(reloaded.get_chain()).unwrap(),
+            serde_json::to_value(blockchain.get_chain()).unwrap()
+        );
+        assert_eq!(reloaded.get_balance("bob").unwrap(), 100);
+        assert_eq!(reloaded.get_diff(1).unwrap(), blockchain.get_diff(1).unwrap());
+        assert_eq!(reloaded.get_validator_stats()[0].stake, 500);
+
+        // Migration rewrites the JSON leftovers, and only those
+        assert_eq!(reloaded.migrate_storage().unwrap(), 2); // chain_params, block:0
+        assert_eq!(Codec::of(&store.get(b"block:0").unwrap().unwrap()), Codec::Bincode);
+        assert_eq!(reloaded.migrate_storage().unwrap(), 0);
+        let migrated = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(migrated.get_chain().len(), 2);
+    }
+
+    #[test]
+    fn test_user_transaction_count() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("bob".to_string(), 10_000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain =
+            CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+
+        for (from, to) in [("alice", "bob"), ("bob", "carol"), ("alice", "carol")] {
+            blockchain
+                .create_transaction(from.to_string(), to.to_string(), 100)
+                .unwrap();
+        }
+        // Still pending, so not counted yet
+        assert_eq!(blockchain.get_user_transaction_count("alice"), 0);
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        assert_eq!(blockchain.get_user_transaction_count("alice"), 2);
+        assert_eq!(blockchain.get_user_transaction_count("bob"), 2);
+        assert_eq!(blockchain.get_user_transaction_count("carol"), 2);
+        assert_eq!(blockchain.get_user_transaction_count("nobody"), 0);
+        assert_eq!(
+            blockchain.get_user_transaction_count("alice"),
+            blockchain.get_user_transactions("alice").len() as u64
+        );
+
+        // The index is rebuilt on load
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.get_user_transaction_count("alice"), 2);
+        assert_eq!(reloaded.get_user_transaction_count("carol"), 2);
+    }
+
+    #[test]
+    fn test_compacted_index_keeps_old_transactions_queryable() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_max_index_len(3);
+        for amount in 1..=5 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), amount * 100)
+                .unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+
+        // Only the three most recent stay in the hot index
+        let hot: Vec<u64> = blockchain.tx_index().hot.get("alice").unwrap().iter().map(|e| e.block_index).collect();
+        assert_eq!(hot, vec![3, 4, 5]);
+        assert_eq!(blockchain.get_user_transaction_count("alice"), 5);
+
+        // Older ones come back from storage, in order
+        let amounts = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.amount).collect::<Vec<_>>();
+        assert_eq!(amounts(blockchain.get_user_transactions("bob")), vec![100, 200, 300, 400, 500]);
+        assert_eq!(amounts(blockchain.get_user_transactions_page("alice", 1, 3)), vec![200, 300, 400]);
+        assert_eq!(amounts(blockchain.get_user_transactions_page("alice", 4, 3)), vec![500]);
+
+        // Bob's last send is compacted away behind three receipts, yet his
+        // next transfer still follows on from its nonce
+        blockchain
+            .create_transaction("bob".to_string(), "carol".to_string(), 10)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        for _ in 0..3 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 10)
+                .unwrap();
+        }
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.tx_index().hot.get("bob").unwrap().iter().all(|e| e.block_index == 7));
+
+        blockchain
+            .create_transaction("bob".to_string(), "carol".to_string(), 10)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions[0].nonce, 2);
+    }
+
+    #[test]
+    fn test_fee_estimate_matches_charged_fee() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1_000_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let memo = "x".repeat(40);
+
+        let estimate = blockchain.estimate_fee(1000, memo.len());
+        assert_eq!(estimate, 10 + 2); // 1% plus two memo units
+        blockchain
+            .create_transaction_with_memo(
+                "alice".to_string(),
+                "bob".to_string(),
+                1000,
+                Some(memo.clone()),
+            )
+            .unwrap();
+        assert_eq!(blockchain.get_pending()[0].fee, estimate);
+
+        // A congested mempool raises the fee
+        while blockchain.get_pending().len() < CONGESTION_STEP {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 1)
+                .unwrap();
+        }
+        let estimate = blockchain.estimate_fee(1000, memo.len());
+        assert_eq!(estimate, 2 * (10 + 2));
+        blockchain
+            .create_transaction_with_memo(
+                "alice".to_string(),
+                "bob".to_string(),
+                1000,
+                Some(memo),
+            )
+            .unwrap();
+        assert_eq!(blockchain.get_pending().last().unwrap().fee, estimate);
+    }
+
+    #[test]
+    fn test_sender_rate_limit_rejects_burst() {
+        let mut initial = HashMap::new();
+        initial.insert("spammer".to_string(), 100_000);
+        initial.insert("alice".to_string(), 100_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_sender_rate_limit(SenderRateLimit {
+                limit: 3.0,
+                window_secs: 60,
+            });
+
+        for _ in 0..3 {
+            blockchain
+                .create_transaction("spammer".to_string(), "bob".to_string(), 10)
+                .unwrap();
+        }
+        let rejected = blockchain.create_transaction("spammer".to_string(), "bob".to_string(), 10);
+        assert!(rejected.unwrap_err().starts_with(SENDER_RATE_LIMITED));
+        assert_eq!(blockchain.get_pending().len(), 3);
+
+        // Other senders have their own bucket
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 10)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_transfer_requires_proof_of_work_when_configured() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_pow_difficulty(8);
+        let transfer = |pow_nonce| {
+            blockchain.create_transaction_with_pow(
+                "alice".to_string(),
+                "bob".to_string(),
+                100,
+                None,
+                None,
+                pow_nonce,
+            )
+        };
+
+        let payload = pow::payload("alice", "bob", 100, blockchain.next_nonce("alice"));
+        let invalid = (0..).find(|n| !pow::verify(&payload, *n, 8)).unwrap();
+        assert!(transfer(None).unwrap_err().contains("proof of work"));
+        assert!(transfer(Some(invalid)).unwrap_err().contains("difficulty 8"));
+        assert!(blockchain.get_pending().is_empty());
+
+        transfer(Some(pow::solve(&payload, 8))).unwrap();
+        assert_eq!(blockchain.get_pending().len(), 1);
+        assert_eq!(blockchain.next_nonce("alice"), 2);
+    }
+
+    #[test]
+    fn test_validator_quorum_finalizes_block_and_blocks_reorg() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        for _ in 0..3 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 100)
+                .unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let keys: Vec<SigningKey> = (1..=3).map(|seed| SigningKey::from_bytes(&[seed; 32])).collect();
+        for key in &keys {
+            blockchain.register_validator(hex::encode(key.verifying_key().to_bytes()), 100).unwrap();
+        }
+        let block_2 = blockchain.get_chain()[2].clone();
+        let vote = |key: &SigningKey| BlockVote::new(2, block_2.hash.clone(), key);
+
+        // A vote signed by any key but the validator's doesn't count, nor
+        // does one from outside the validator set
+        let mut forged = vote(&SigningKey::from_bytes(&[66; 32]));
+        forged.validator = vote(&keys[1]).validator;
+        assert_eq!(blockchain.submit_block_vote(forged), Err("Invalid vote signature".to_string()));
+        let outsider = blockchain.submit_block_vote(vote(&SigningKey::from_bytes(&[66; 32])));
+        assert!(outsider.unwrap_err().contains("not a registered"));
+
+        // One third of the stake is not enough, two thirds is
+        assert_eq!(blockchain.submit_block_vote(vote(&keys[0])).unwrap(), 0);
+        assert_eq!(blockchain.submit_block_vote(vote(&keys[1])).unwrap(), 2);
+        assert_eq!(blockchain.finalized_height(), 2);
+
+        // A competing branch forking below the finalized block is refused
+        let block_1 = blockchain.get_chain()[1].clone();
+        let mut competing = Block {
+            index: 2,
+            timestamp: block_2.timestamp + 1,
+            transactions: vec![],
+            prev_hash: block_1.hash,
+            hash: String::new(),
+            proposer: "other".to_string(),
+            state_root: String::new(),
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
+        let err = blockchain.reorg(1, vec![competing]).unwrap_err();
+        assert!(err.contains("finalized height 2"));
+        assert_eq!(blockchain.get_chain()[2].hash, block_2.hash);
+
+        // Above the finalized block reorgs still work. Block 3 is rolled
+        // back, so the empty block leaves the state as block 2 did.
+        let proposer = blockchain.validators.lock().unwrap().select_proposer(3, &block_2.hash).unwrap();
+        let mut competing = Block {
+            index: 3,
+            timestamp: block_2.timestamp + 1,
+            transactions: vec![],
+            prev_hash: block_2.hash.clone(),
+            hash: String::new(),
+            proposer: proposer.clone(),
+            state_root: block_2.state_root.clone(),
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
+        let key = keys.iter().find(|key| hex::encode(key.verifying_key().to_bytes()) == proposer).unwrap();
+        CommunityBlockchain::sign_block(&mut competing, key);
+        blockchain.reorg(2, vec![competing]).unwrap();
+    }
+
+    #[test]
+    fn test_bounded_window_serves_old_blocks_from_storage() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_max_chain_len(3);
+        for _ in 0..6 {
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 100)
+                .unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+
+        // Only blocks 4..=6 stay in memory
+        let window: Vec<u64> = blockchain.chain.lock().unwrap().iter().map(|b| b.index).collect();
+        assert_eq!(window, vec![4, 5, 6]);
+
+        // Older blocks and history still come back, read from storage
+        let block_1 = blockchain.get_block_by_index(1).unwrap();
+        assert_eq!(block_1.transactions[0].nonce, 1);
+        assert!(blockchain.get_block_by_index(7).is_none());
+        assert_eq!(blockchain.get_chain().len(), 7);
+        assert_eq!(blockchain.get_blocks(1, 2)[1].index, 2);
+        let history = blockchain.get_user_transactions("alice");
+        let nonces: Vec<u64> = history.iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![1, 2, 3, 4, 5, 6]);
+        assert!(blockchain.verify_chain());
+        assert_eq!(blockchain.get_stats()["total_transactions"], 6);
+
+        // Nonces keep following on from the committed chain
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions[0].nonce, 7);
+    }
+
+    #[test]
+    fn test_reload_from_sled_and_memory_stores() {
+        let stores: Vec<Arc<dyn KvStore>> = vec![
+            Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            Arc::new(MemoryStore::new()),
+        ];
+
+        for store in stores {
+            let mut initial = HashMap::new();
+            initial.insert("alice".to_string(), 1000);
+
+            let blockchain =
+                CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+            blockchain
+                .create_transaction("alice".to_string(), "bob".to_string(), 100)
+                .unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+            drop(blockchain);
+
+            let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+            assert_eq!(reloaded.get_chain().len(), 2);
+            assert_eq!(reloaded.get_balance("alice").unwrap(), 899);
+            assert_eq!(reloaded.get_balance("bob").unwrap(), 100);
+        }
+    }
+
+    #[test]
+    fn test_blocks_carry_proof_of_work_and_retarget() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let params = ChainParams {
+            block_difficulty: 8,
+            target_block_secs: 600,
+            retarget_interval: 2,
+            ..ChainParams::demo()
+        };
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, params, store).unwrap();
+        let mine = || {
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            blockchain.mine_block("proposer".to_string()).unwrap()
+        };
+
+        // Blocks come far faster than one per 600s, so block 4 needs more work
+        for expected in [8, 8, 8, 9] {
+            let block = mine();
+            assert_eq!(block.difficulty, expected);
+            assert!(pow::hash_meets_difficulty(&block.hash, expected));
+            blockchain.add_block(block).unwrap();
+        }
+
+        let block = mine();
+        let mut easier = block.clone();
+        easier.difficulty = 8;
+        CommunityBlockchain::seal_block(&mut easier);
+        assert!(blockchain.add_block(easier).unwrap_err().contains("expected 9"));
+
+        let mut unworked = block.clone();
+        unworked.pow_nonce = (0..)
+            .find(|nonce| {
+                unworked.pow_nonce = *nonce;
+                !pow::hash_meets_difficulty(&CommunityBlockchain::calculate_block_hash(&unworked), 9)
+            })
+            .unwrap();
+        unworked.hash = CommunityBlockchain::calculate_block_hash(&unworked);
+        assert!(blockchain.add_block(unworked).unwrap_err().contains("Invalid proof of work"));
+
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.verify_chain());
+
+        // Slow blocks ease the work off, but never below a bit
+        assert_eq!(pow::retarget(9, 5_000, 1_200), 8);
+        assert_eq!(pow::retarget(9, 1_200, 1_200), 9);
+        assert_eq!(pow::retarget(1, 5_000, 1_200), 1);
+    }
+
+    #[test]
+    fn test_block_commits_to_transactions_by_merkle_root() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let tx_ids: Vec<String> = ["bob", "carol", "dave"]
+            .into_iter()
+            .map(|to| blockchain.create_transaction("alice".to_string(), to.to_string(), 10).unwrap())
+            .collect();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.tx_root, merkle::root(&block.transactions));
+
+        // Swapping a transaction for another breaks the root, even with the
+        // block hash recomputed
+        let mut forged = block.clone();
+        forged.transactions[1].amount = 20;
+        forged.hash = CommunityBlockchain::calculate_block_hash(&forged);
+        let err = blockchain.add_block(forged).unwrap_err();
+        assert!(err.contains("Invalid transaction root"));
+
+        blockchain.add_block(block.clone()).unwrap();
+        for tx_id in &tx_ids {
+            let tx = block.transactions.iter().find(|tx| tx.tx_id == *tx_id).unwrap();
+            let proof = blockchain.get_merkle_proof(tx_id).unwrap();
+            assert_eq!((proof.block_index, proof.root.as_str()), (1, block.tx_root.as_str()));
+            assert!(proof.verify(tx));
+            assert_eq!(blockchain.find_transaction(tx_id).unwrap().1, Some(1));
+        }
+        assert!(blockchain.get_merkle_proof("unknown").is_none());
+        assert!(blockchain.find_transaction("unknown").is_none());
+        assert!(blockchain.verify_chain());
+    }
+
+    #[test]
+    fn test_state_proofs_verify_against_block_state_roots() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let mine = |to: &str| {
+            blockchain.create_transaction("alice".to_string(), to.to_string(), 100).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            block
+        };
+
+        let block_1 = mine("bob");
+        let (root, proof) = blockchain.get_state_proof("alice");
+        assert_eq!(root, block_1.state_root);
+        assert_eq!((proof.balance, proof.nonce), (blockchain.get_balance("alice").unwrap(), 1));
+        assert!(proof.verify(&block_1.state_root));
+
+        let block_2 = mine("carol");
+        let (root, bob) = blockchain.get_state_proof("bob");
+        assert_eq!(root, block_2.state_root);
+        assert!(bob.verify(&block_2.state_root) && bob.balance == 100);
+        // The earlier proof still holds for the block it was taken at only
+        assert!(!proof.verify(&block_2.state_root));
+        assert!(proof.verify(&block_1.state_root));
+
+        let (_, nobody) = blockchain.get_state_proof("nobody");
+        assert_eq!((nobody.balance, nobody.nonce), (0, 0));
+        assert!(nobody.verify(&block_2.state_root));
+    }
+
+    /// A stake change signed with `key`: a stake into the staking account,
+    /// or with `unstake` a return out of it, at the sending account's `nonce`
+    fn stake_tx(key: &SigningKey, amount: u64, nonce: u64, unstake: bool) -> Transaction {
+        let staker = hex::encode(key.verifying_key().to_bytes());
+        let (from, to) = match unstake {
+            false => (staker, STAKE_ADDRESS.to_string()),
+            true => (STAKE_ADDRESS.to_string(), staker),
+        };
+        let mut tx = Transaction {
+            tx_id: format!("{}-{}-{}", from, to, nonce),
+            from,
+            to,
+            amount,
+            fee: 0,
+            timestamp: 1_700_000_000,
+            signature: String::new(),
+            nonce,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Ed25519,
+            contract: None,
+        };
+        tx.signature = hex::encode(key.sign(&tx.signing_payload()).to_bytes());
+        tx
+    }
+
+    #[test]
+    fn test_stake_selects_proposer_and_rejects_others() {
+        let (alice_key, bob_key) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+        let alice = hex::encode(alice_key.verifying_key().to_bytes());
+        let bob = hex::encode(bob_key.verifying_key().to_bytes());
+        let mut initial = HashMap::new();
+        initial.insert(alice.clone(), 1000);
+        initial.insert(bob.clone(), 1000);
+        initial.insert("carol".to_string(), 1000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone())
+            .unwrap()
+            .with_validator_key(alice_key.clone());
+        assert!(blockchain.next_proposer().is_none());
+        // The node restarted holding the key of whichever validator is due
+        let restart = |address: &str| {
+            let key = if address == alice { &alice_key } else { &bob_key };
+            CommunityBlockchain::load_from_store(store.clone()).unwrap().with_validator_key(key.clone())
+        };
+
+        // A stake is signed by its owner, not by anyone naming them
+        let mut forged = stake_tx(&alice_key, 300, 1, false);
+        forged.from = bob.clone();
+        assert!(blockchain.stake(forged).unwrap_err().contains("signed with"));
+        let mut node_signed = stake_tx(&alice_key, 300, 1, false);
+        node_signed.sig_scheme = SigScheme::Sha256;
+        node_signed.signature = format!("{:x}", Sha256::digest(node_signed.signing_payload()));
+        assert!(blockchain.stake(node_signed).is_err());
+        let transfer = blockchain.create_transaction("carol".to_string(), STAKE_ADDRESS.to_string(), 10);
+        assert!(transfer.unwrap_err().contains("stake transaction"));
+
+        blockchain.stake(stake_tx(&alice_key, 300, 1, false)).unwrap();
+        // Alice is the only validator, so her key signs bob's stake in
+        blockchain.stake(stake_tx(&bob_key, 100, 1, false)).unwrap();
+        assert!(blockchain.stake(stake_tx(&bob_key, 5_000, 2, false)).is_err());
+        assert_eq!(blockchain.get_balance(&alice).unwrap(), 700);
+        assert_eq!(blockchain.get_balance(STAKE_ADDRESS).unwrap(), 400);
+        let staked = blockchain.create_transaction(STAKE_ADDRESS.to_string(), "mallory".to_string(), 10);
+        assert!(staked.unwrap_err().contains("unstaking"));
+        drop(blockchain);
+
+        // Only the selected validator may mine, and only with its key. A
+        // block relabelled for another, or left unsigned, is refused.
+        let proposer = restart(&alice).next_proposer().unwrap();
+        let other = if proposer == alice { bob.clone() } else { alice.clone() };
+        assert!(restart(&other).mine_block(proposer.clone()).unwrap_err().contains("no validator key"));
+        let blockchain = restart(&proposer);
+        blockchain.create_transaction("carol".to_string(), "dave".to_string(), 10).unwrap();
+        let refused = blockchain.mine_block(other.clone()).unwrap_err();
+        assert!(refused.contains("not the proposer"));
+        let block = blockchain.mine_block(proposer.clone()).unwrap();
+        let mut relabelled = block.clone();
+        relabelled.proposer = other.clone();
+        relabelled.hash = CommunityBlockchain::calculate_block_hash(&relabelled);
+        assert!(blockchain.add_block(relabelled).unwrap_err().contains("not the proposer"));
+        let mut unsigned = block.clone();
+        unsigned.proposer_signature = None;
+        assert!(blockchain.add_block(unsigned).unwrap_err().contains("not signed"));
+        blockchain.add_block(block).unwrap();
+        let proposer = blockchain.next_proposer().unwrap();
+        drop(blockchain);
+
+        // Only the owner's key takes stake back, never more than staked,
+        // and unstaking everything leaves the validator set
+        let blockchain = restart(&proposer);
+        let mut stolen = stake_tx(&bob_key, 100, 1, true);
+        stolen.to = "mallory".to_string();
+        assert!(blockchain.unstake(stolen).is_err());
+        assert!(blockchain.unstake(stake_tx(&bob_key, 101, 1, true)).is_err());
+        blockchain.unstake(stake_tx(&bob_key, 100, 1, true)).unwrap();
+        assert_eq!(blockchain.get_balance(&bob).unwrap(), 1000);
+        assert_eq!(blockchain.next_proposer().unwrap(), alice);
+        drop(blockchain);
+
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.next_proposer().unwrap(), alice);
+        assert_eq!(reloaded.get_validator_stats().len(), 1);
+        assert!(reloaded.verify_chain());
+    }
+
+    #[test]
+    fn test_add_block_rejects_malformed_blocks_with_typed_errors() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial.clone()).unwrap();
+        source.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = source.mine_block("proposer".to_string()).unwrap();
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        let resealed = |edit: &dyn Fn(&mut Block)| {
+            let mut forged = block.clone();
+            edit(&mut forged);
+            forged.tx_root = merkle::root(&forged.transactions);
+            forged.hash = CommunityBlockchain::calculate_block_hash(&forged);
+            blockchain.add_block(forged)
+        };
+        let tx_id = block.transactions[0].tx_id.clone();
+
+        assert_eq!(
+            resealed(&|b| b.index = 5),
+            Err(BlockError::InvalidIndex { expected: 1, actual: 5 }.into())
+        );
+        assert_eq!(
+            resealed(&|b| b.prev_hash = "0".repeat(64)),
+            Err(BlockError::InvalidPrevHash(1).into())
+        );
+        let mut unsealed = block.clone();
+        unsealed.timestamp += 1;
+        assert_eq!(blockchain.add_block(unsealed), Err(BlockError::InvalidHash(1).into()));
+
+        let future = resealed(&|b| b.timestamp += MAX_BLOCK_FUTURE_SECS + 60).unwrap_err();
+        assert!(future.contains("ahead of local time"));
+        let past = resealed(&|b| b.timestamp = 0).unwrap_err();
+        assert!(past.contains("before its parent"));
+
+        assert_eq!(
+            resealed(&|b| b.transactions[0].signature = "forged".to_string()),
+            Err(BlockError::InvalidSignature { index: 1, tx_id: tx_id.clone() }.into())
+        );
+        assert_eq!(
+            resealed(&|b| b.transactions.push(b.transactions[0].clone())),
+            Err(BlockError::DuplicateTransaction { index: 1, tx_id: tx_id.clone() }.into())
+        );
+        // A correctly signed transfer of more than the sender holds
+        let overspent = resealed(&|b| {
+            let tx = &mut b.transactions[0];
+            tx.amount = 5_000;
+            tx.signature = format!("{:x}", Sha256::digest(tx.signing_payload()));
+        });
+        assert_eq!(
+            overspent,
+            Err(BlockError::InsufficientBalance {
+                index: 1,
+                tx_id,
+                from: "alice".to_string()
+            }
+            .into())
+        );
+
+        // None of it touched the chain, and the real block still applies
+        assert_eq!(blockchain.get_chain().len(), 1);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1000);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+    }
+
+    #[test]
+    fn test_fork_choice_follows_the_heavier_branch() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        let node = || {
+            CommunityBlockchain::from_store_with_clock(
+                initial.clone(),
+                ChainParams::demo(),
+                Arc::new(MemoryStore::new()),
+                || 1_700_000_000,
+            )
+            .unwrap()
+        };
+        let mine = |miner: &CommunityBlockchain, to: &str| {
+            miner.create_transaction("alice".to_string(), to.to_string(), 100).unwrap();
+            let block = miner.mine_block("proposer".to_string()).unwrap();
+            miner.add_block(block.clone()).unwrap();
+            block
+        };
+        let (a, b, blockchain) = (node(), node(), node());
+        let a_blocks: Vec<Block> = (0..2).map(|_| mine(&a, "bob")).collect();
+        let b_blocks: Vec<Block> = (0..3).map(|_| mine(&b, "carol")).collect();
+        let tip = |blockchain: &CommunityBlockchain| blockchain.get_chain().last().unwrap().hash.clone();
+
+        for block in &a_blocks {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        // A shorter branch is only stored, and a block whose parent is
+        // unknown is refused
+        blockchain.add_block(b_blocks[0].clone()).unwrap();
+        assert_eq!(tip(&blockchain), a_blocks[1].hash);
+        assert_eq!(blockchain.side_block_count(), 1);
+        assert!(blockchain.add_block(b_blocks[2].clone()).unwrap_err().contains("previous hash"));
+
+        // A side block claiming more work than its branch requires is
+        // neither stored nor weighed
+        let mut heavy = b_blocks[1].clone();
+        heavy.difficulty = 8;
+        CommunityBlockchain::seal_block(&mut heavy);
+        assert!(blockchain.add_block(heavy).unwrap_err().contains("difficulty 8, expected 0"));
+        assert_eq!(tip(&blockchain), a_blocks[1].hash);
+        assert_eq!(blockchain.side_block_count(), 1);
+
+        // Once it is longer the chain reorgs onto it, and keeps the blocks it
+        // left as a side branch
+        blockchain.add_block(b_blocks[1].clone()).unwrap();
+        blockchain.add_block(b_blocks[2].clone()).unwrap();
+        assert_eq!(tip(&blockchain), b_blocks[2].hash);
+        assert_eq!(blockchain.side_block_count(), 2);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 0);
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 300);
+        assert!(blockchain.get_user_transactions("bob").is_empty());
+        assert!(blockchain.verify_chain());
+
+        // The first branch overtakes it again
+        let more: Vec<Block> = (0..2).map(|_| mine(&a, "bob")).collect();
+        for block in &more {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(tip(&blockchain), more[1].hash);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 400);
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 0);
+        assert_eq!(blockchain.get_user_transactions("bob").len(), 4);
+        assert!(blockchain.get_user_transactions("carol").is_empty());
+        assert_eq!(blockchain.side_block_count(), 3);
+        assert!(blockchain.verify_chain());
+    }
+
+    #[test]
+    fn test_block_nonces_stop_replays() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let params = ChainParams {
+            tx_dedup_blocks: 0,
+            ..ChainParams::demo()
+        };
+        let blockchain = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let first = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(first.clone()).unwrap();
+
+        let next_block = |transactions: Vec<Transaction>| {
+            let mut block = first.clone();
+            block.index = 2;
+            block.prev_hash = first.hash.clone();
+            block.tx_root = merkle::root(&transactions);
+            block.transactions = transactions;
+            block.hash = CommunityBlockchain::calculate_block_hash(&block);
+            blockchain.add_block(block)
+        };
+        let tx = first.transactions[0].clone();
+
+        // With the tx_id window off, the used nonce still refuses a replay
+        assert_eq!(
+            next_block(vec![tx.clone()]),
+            Err(BlockError::InvalidNonce { index: 2, tx_id: tx.tx_id.clone(), expected: 2, actual: 1 }.into())
+        );
+        // and a gap is refused too
+        let mut skipping = tx.clone();
+        skipping.nonce = 3;
+        skipping.tx_id = "skipping".to_string();
+        skipping.signature = format!("{:x}", Sha256::digest(skipping.signing_payload()));
+        assert!(next_block(vec![skipping]).unwrap_err().contains("expected 2"));
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+
+        // An ed25519 signature covers the nonce
+        let key = SigningKey::from_bytes(&[9; 32]);
+        let mut signed = tx;
+        signed.from = hex::encode(key.verifying_key().as_bytes());
+        signed.sig_scheme = SigScheme::Ed25519;
+        signed.signature = hex::encode(ed25519_dalek::Signer::sign(&key, &signed.signing_payload()).to_bytes());
+        assert!(CommunityBlockchain::verify_signature(&signed, true));
+        signed.nonce += 1;
+        assert!(!CommunityBlockchain::verify_signature(&signed, true));
+    }
+
+    #[test]
+    fn test_ed25519_signature_covers_every_field() {
+        let key = SigningKey::from_bytes(&[6; 32]);
+        let mut tx = Transaction {
+            from: hex::encode(key.verifying_key().as_bytes()),
+            to: "bob".to_string(),
+            amount: 100,
+            fee: 1,
+            timestamp: 1_700_000_000,
+            tx_id: "signed".to_string(),
+            signature: String::new(),
+            nonce: 1,
+            memo: Some("rent".to_string()),
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Ed25519,
+            contract: None,
+        };
+        tx.signature = hex::encode(ed25519_dalek::Signer::sign(&key, &tx.signing_payload()).to_bytes());
+        assert!(CommunityBlockchain::verify_signature(&tx, true));
+        assert!(tx.signing_payload().starts_with(CANONICAL_TX_TAG));
+
+        let edits: Vec<fn(&mut Transaction)> = vec![
+            |tx| tx.to = "mallory".to_string(),
+            |tx| tx.amount += 1,
+            |tx| tx.fee = 0,
+            |tx| tx.timestamp += 1,
+            |tx| tx.memo = None,
+            |tx| tx.execute_at_height = Some(5),
+            |tx| tx.recent_block_hash = Some("0".repeat(64)),
+            // Moving bytes between fields changes the encoding too
+            |tx| {
+                tx.to = "bo".to_string();
+                tx.tx_id = "bsigned".to_string();
+            },
+        ];
+        for edit in edits {
+            let mut tampered = tx.clone();
+            edit(&mut tampered);
+            assert!(!CommunityBlockchain::verify_signature(&tampered, true));
+        }
+    }
+
+    #[test]
+    fn test_blocks_pay_proposer_reward_and_fees() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 100_000);
+        let params = ChainParams {
+            block_reward: 50,
+            reward_halving_interval: 2,
+            ..ChainParams::demo()
+        };
+        let blockchain = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+        let candidate = |amount: u64| {
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), amount).unwrap();
+            blockchain.mine_block("miner".to_string()).unwrap()
+        };
+
+        // 50 at height 1, halved to 25 from height 2, plus the 1% fee
+        let block = candidate(1000);
+        let reward = &block.transactions[0];
+        assert_eq!(reward.tx_id, "reward-1");
+        assert_eq!((reward.from.as_str(), reward.to.as_str()), (COINBASE_ADDRESS, "miner"));
+        assert_eq!(reward.amount, 60);
+        blockchain.add_block(block).unwrap();
+        let block = candidate(500);
+        assert_eq!(block.transactions[0].amount, 30);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("miner").unwrap(), 90);
+        blockchain.reconcile_supply().unwrap();
+        assert_eq!(blockchain.block_reward_at(4), 12);
+        assert_eq!(blockchain.block_reward_at(200), 0);
+
+        // The reward must come first, go to the proposer and add up
+        let block = candidate(100);
+        let resealed = |edit: &dyn Fn(&mut Block)| {
+            let mut forged = block.clone();
+            edit(&mut forged);
+            forged.tx_root = merkle::root(&forged.transactions);
+            forged.hash = CommunityBlockchain::calculate_block_hash(&forged);
+            blockchain.add_block(forged)
+        };
+        assert_eq!(
+            resealed(&|b| b.transactions[0].amount += 1),
+            Err(BlockError::InvalidReward { index: 3, expected: 26, actual: 27 }.into())
+        );
+        assert_eq!(resealed(&|b| { b.transactions.remove(0); }), Err(BlockError::MissingReward(3).into()));
+        assert_eq!(
+            resealed(&|b| b.transactions[0].to = "mallory".to_string()),
+            Err(BlockError::MissingReward(3).into())
+        );
+        // Coinbase pays nothing but the reward opening the block
+        let forged_mint = Transaction {
+            from: COINBASE_ADDRESS.to_string(),
+            to: "mallory".to_string(),
+            amount: 1_000_000,
+            fee: 0,
+            timestamp: block.timestamp,
+            tx_id: "free-money".to_string(),
+            signature: blockchain.sign_transaction("free-money", COINBASE_ADDRESS, None, None, None, None),
+            nonce: 0,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Sha256,
+            contract: None,
+        };
+        assert_eq!(
+            resealed(&|b| b.transactions.push(forged_mint.clone())),
+            Err(BlockError::UnexpectedCoinbase { index: 3, tx_id: "free-money".to_string() }.into())
+        );
+        assert_eq!(
+            resealed(&|b| b.transactions[0] = forged_mint.clone()),
+            Err(BlockError::UnexpectedCoinbase { index: 3, tx_id: "free-money".to_string() }.into())
+        );
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("miner").unwrap(), 116);
+        assert!(blockchain.verify_chain());
+    }
+
+    #[test]
+    fn test_peer_verifies_block_state_root() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let proposer = CommunityBlockchain::new_in_memory(initial.clone()).unwrap();
+        let peer = CommunityBlockchain::new_in_memory(initial).unwrap();
+        proposer.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = proposer.mine_block("proposer".to_string()).unwrap();
+
+        // Claiming a different post-block state is caught even when the
+        // block hash is recomputed to match
+        let mut tampered = block.clone();
+        tampered.state_root = CommunityBlockchain::calculate_state_root(
+            &[("alice".to_string(), 899), ("bob".to_string(), 100)].into(),
+            &HashMap::new(),
+        );
+        tampered.hash = CommunityBlockchain::calculate_block_hash(&tampered);
+        let err = peer.add_block(tampered).unwrap_err();
+        assert!(err.contains("Invalid state root"), "{}", err);
+        assert_eq!(peer.get_chain().len(), 1);
+
+        // The root commits to alice's nonce as well as the balances
+        peer.add_block(block.clone()).unwrap();
+        proposer.add_block(block.clone()).unwrap();
+        assert_eq!(peer.state_root(), block.state_root);
+        assert_eq!(proposer.state_root(), block.state_root);
+        assert_eq!(peer.get_balance("bob").unwrap(), 100);
+    }
+
+    #[test]
+    fn test_reorg_removes_orphaned_history() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let genesis_root = blockchain.state_root();
+        let tx_id = blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_user_transactions("alice").len(), 1);
+
+        // Competing empty branch from genesis
+        let genesis = blockchain.get_chain()[0].clone();
+        let mut competing = Block {
+            index: 1,
+            timestamp: current_timestamp(),
+            transactions: vec![],
+            prev_hash: genesis.hash,
+            hash: String::new(),
+            proposer: "other".to_string(),
+            state_root: genesis_root,
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
+
+        blockchain.reorg(0, vec![competing.clone()]).unwrap();
+
+        assert!(blockchain.get_user_transactions("alice").is_empty());
+        assert!(blockchain.get_user_transactions("bob").is_empty());
+        assert!(!blockchain.tx_heights.contains_key(&tx_id));
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1000);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 0);
+        assert_eq!(blockchain.get_chain()[1].hash, competing.hash);
+
+        let pending = blockchain.get_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].tx_id, tx_id);
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_failed_reorg_restores_the_old_branch() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let genesis_root = blockchain.state_root();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block.clone()).unwrap();
+        let root = blockchain.state_root();
+
+        // The branch's first block applies; its second claims a state it
+        // doesn't produce
+        let genesis = blockchain.get_chain()[0].clone();
+        let empty = |index: u64, prev_hash: String, state_root: String| {
+            let mut block = Block {
+                index,
+                timestamp: current_timestamp(),
+                transactions: vec![],
+                prev_hash,
+                hash: String::new(),
+                proposer: "other".to_string(),
+                state_root,
+                tx_root: merkle::root(&[]),
+                difficulty: 0,
+                pow_nonce: 0,
+                proposer_signature: None,
+            };
+            block.hash = CommunityBlockchain::calculate_block_hash(&block);
+            block
+        };
+        let first = empty(1, genesis.hash, genesis_root);
+        let second = empty(2, first.hash.clone(), "0".repeat(64));
+        let err = blockchain.reorg(0, vec![first, second]).unwrap_err();
+        assert!(err.contains("Invalid state root"), "{}", err);
+
+        // Everything is as it was before the reorg
+        let chain = blockchain.get_chain();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1].hash, block.hash);
+        assert_eq!(blockchain.state_root(), root);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 899);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+        assert_eq!(blockchain.get_user_transactions("bob").len(), 1);
+        assert!(blockchain.get_pending().is_empty());
+        assert!(blockchain.verify_chain());
+    }
+
+    #[test]
+    fn test_reorg_that_cannot_roll_back_restores_the_chain() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+        let genesis = blockchain.get_chain()[0].clone();
+        let genesis_root = blockchain.state_root();
+        let contract = apply_contract(
+            &blockchain,
+            "alice",
+            ContractTransaction::Deploy {
+                code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+                admin: None,
+                abi: None,
+            },
+        )
+        .unwrap()
+        .contract;
+        blockchain.create_transaction("alice".to_string(), "carol".to_string(), 50).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        let tip = blockchain.tip();
+        let root = blockchain.state_root();
+        let alice = blockchain.get_balance("alice").unwrap();
+
+        // Block 1 lost the record that undoes its deploy, so block 2 is
+        // rolled back and re-applied, and block 1 is never touched
+        store.remove(b"contract_undo:1").unwrap();
+        let mut competing = Block {
+            index: 1,
+            timestamp: current_timestamp(),
+            transactions: vec![],
+            prev_hash: genesis.hash,
+            hash: String::new(),
+            proposer: "other".to_string(),
+            state_root: genesis_root,
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
+        let err = blockchain.reorg(0, vec![competing]).unwrap_err();
+        assert!(err.contains("rolling back block 1"), "{}", err);
+
+        let chain = blockchain.get_chain();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(blockchain.tip().hash, tip.hash);
+        assert_eq!(blockchain.state_root(), root);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), alice);
+        assert_eq!(blockchain.get_balance("carol").unwrap(), 50);
+        assert_eq!(blockchain.get_user_transactions("carol").len(), 1);
+        assert!(blockchain.get_contract(&contract).is_some());
+        assert!(blockchain.get_pending().is_empty());
+    }
+
+    #[test]
+    fn test_competing_block_at_tip_height_keeps_chain_contiguous() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let genesis_root = blockchain.state_root();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let tip = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(tip.clone()).unwrap();
+
+        let genesis = blockchain.get_chain()[0].clone();
+        let competitor = |timestamp: u64| {
+            let mut block = Block {
+                index: 1,
+                timestamp,
+                transactions: vec![],
+                prev_hash: genesis.hash.clone(),
+                hash: String::new(),
+                proposer: "other".to_string(),
+                state_root: genesis_root.clone(),
+                tx_root: merkle::root(&[]),
+                difficulty: 0,
+                pow_nonce: 0,
+                proposer_signature: None,
+            };
+            block.hash = CommunityBlockchain::calculate_block_hash(&block);
+            block
+        };
+        // However low the tip's hash, keep trying until one of each turns up
+        let (mut stronger, mut weaker) = (Vec::new(), Vec::new());
+        for i in 0.. {
+            let block = competitor(tip.timestamp + 1 + i);
+            match block.hash < tip.hash {
+                true => stronger.push(block),
+                false => weaker.push(block),
+            }
+            if !stronger.is_empty() && !weaker.is_empty() {
+                break;
+            }
+        }
+        let contiguous = || {
+            let chain = blockchain.get_chain();
+            chain.iter().enumerate().all(|(i, block)| block.index == i as u64)
+        };
+
+        // The same block again, or a weaker competitor, changes nothing
+        blockchain.add_block(tip.clone()).unwrap();
+        blockchain.add_block(weaker[0].clone()).unwrap();
+        assert_eq!(blockchain.get_chain().len(), 2);
+        assert_eq!(blockchain.get_chain()[1].hash, tip.hash);
+        assert!(contiguous());
+
+        // A stronger competitor replaces the tip and its transfer goes back
+        // to the mempool
+        blockchain.add_block(stronger[0].clone()).unwrap();
+        assert_eq!(blockchain.get_chain().len(), 2);
+        assert_eq!(blockchain.get_chain()[1].hash, stronger[0].hash);
+        assert!(contiguous());
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1000);
+        assert_eq!(blockchain.get_pending().len(), 1);
+
+        // Below the tip only copies are accepted
+        blockchain.add_block(genesis.clone()).unwrap();
+        let mut fake_genesis = genesis.clone();
+        fake_genesis.timestamp += 1;
+        fake_genesis.hash = CommunityBlockchain::calculate_block_hash(&fake_genesis);
+        assert!(blockchain.add_block(fake_genesis).is_err());
+        assert!(contiguous());
+    }
+
+    #[test]
+    fn test_mint_increases_supply_and_records_history() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let supply_before = blockchain.total_supply();
+
+        blockchain.mint("alice".to_string(), 500).unwrap();
+
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1500);
+        assert_eq!(blockchain.total_supply(), supply_before + 500);
+        assert_eq!(blockchain.get_stats()["total_supply"], supply_before + 500);
+
+        let history = blockchain.get_user_transactions("alice");
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].from, COINBASE_ADDRESS);
+        assert_eq!(history[0].amount, 500);
+
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_supply_reconciles_across_fees_mints_and_burns() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert(TREASURY_ADDRESS.to_string(), 5_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap().with_supply_checks();
+
+        for (from, to, amount) in [("alice", "bob", 1_000), ("bob", "carol", 300), ("alice", "carol", 2_500)] {
+            blockchain.create_transaction(from.to_string(), to.to_string(), amount).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain.mint("carol".to_string(), 700).unwrap();
+        blockchain.burn("alice".to_string(), 400).unwrap();
+
+        // Fees of 10, 3 and 25 left circulation along with the burn
+        assert_eq!(blockchain.total_supply(), 15_000 + 700 - 400 - 38);
+        assert_eq!(blockchain.reconcile_supply(), Ok(()));
+
+        let reloaded = CommunityBlockchain::load_from_store(blockchain.state_db.clone()).unwrap();
+        assert_eq!(reloaded.reconcile_supply(), Ok(()));
+    }
+
+    #[test]
+    fn test_supply_reconciliation_catches_injected_coins() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.reconcile_supply(), Ok(()));
+
+        blockchain.wallets.get_mut("bob").unwrap().balance += 5;
+        assert_eq!(
+            blockchain.reconcile_supply(),
+            Err(BlockchainError::SupplyMismatch { expected: 1_000, actual: 1_005 })
+        );
+    }
+
+    #[test]
+    fn test_burn_decreases_supply() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
 
-        pending.clear();
-        drop(pending);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
 
-        let chain = self.chain.lock().unwrap();
-        let last_block = chain.last().unwrap();
-        let prev_hash = last_block.hash.clone();
-        let new_index = last_block.index + 1;
-        drop(chain);
+        assert!(blockchain.burn("alice".to_string(), 5000).is_err());
+        blockchain.burn("alice".to_string(), 300).unwrap();
 
-        let state_root = self.calculate_state_root(&temp_balances);
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 700);
+        assert_eq!(blockchain.total_supply(), 700);
+        assert_eq!(blockchain.get_user_transactions("alice")[0].to, BURN_ADDRESS);
+        assert!(blockchain.verify_chain());
 
-        let mut block = Block {
-            index: new_index,
-            timestamp: current_timestamp(),
-            transactions: valid_txs,
-            prev_hash,
-            hash: String::new(),
-            proposer,
-            state_root,
+        drop(blockchain);
+    }
+
+    #[test]
+    fn test_mints_and_burns_signed_with_a_system_key_are_accepted_by_peers() {
+        let system_key = SigningKey::from_bytes(&[31; 32]);
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let params = ChainParams {
+            system_keys: vec![hex::encode(system_key.verifying_key().to_bytes())],
+            ..ChainParams::default()
         };
+        let node = || CommunityBlockchain::from_store(initial.clone(), params.clone(), Arc::new(MemoryStore::new())).unwrap();
+        let (admin, peer) = (node().with_system_key(system_key), node());
 
-        block.hash = self.calculate_block_hash(&block);
+        let minted = admin.mint("alice".to_string(), 500).unwrap();
+        peer.add_block(minted).unwrap();
+        let burned = admin.burn("alice".to_string(), 200).unwrap();
+        peer.add_block(burned).unwrap();
+        assert_eq!(peer.get_balance("alice").unwrap(), 1300);
+        assert_eq!(peer.total_supply(), admin.total_supply());
+        assert!(peer.verify_chain());
 
-        Ok(block)
+        // Without a system key a burn fails and gives its nonce back, so
+        // alice's next transfer still follows the burn's nonce 1
+        assert!(peer.burn("alice".to_string(), 100).unwrap_err().contains("no system key"));
+        assert!(peer.mint("alice".to_string(), 100).is_err());
+        assert_eq!(peer.next_nonce("alice"), 2);
+        assert_eq!(peer.get_balance("alice").unwrap(), 1300);
     }
 
-    /// Calculate block hash
-    fn calculate_block_hash(&self, block: &Block) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(block.index.to_le_bytes());
-        hasher.update(block.timestamp.to_le_bytes());
-        hasher.update(block.prev_hash.as_bytes());
-        hasher.update(block.state_root.as_bytes());
+    #[test]
+    fn test_leaderboard_excludes_reserved_accounts() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert(TREASURY_ADDRESS.to_string(), 1_000_000);
+        initial.insert(FAUCET_ADDRESS.to_string(), 500_000);
 
-        for tx in &block.transactions {
-            hasher.update(tx.tx_id.as_bytes());
-        }
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
 
-        format!("{:x}", hasher.finalize())
+        let community = blockchain.get_leaderboard(RESERVED_ADDRESSES);
+        assert_eq!(community.len(), 1);
+        assert_eq!(community[0].address, "alice");
+
+        let everyone = blockchain.get_leaderboard(&[]);
+        assert_eq!(everyone.len(), 3);
+        assert_eq!(everyone[0].address, TREASURY_ADDRESS);
+
+        drop(blockchain);
     }
 
-    /// Add block to chain and persist
-    pub fn add_block(&self, block: Block) -> Result<(), String> {
-        let chain = self.chain.lock().unwrap();
-        let last_block = chain.last().unwrap();
+    #[test]
+    fn test_snapshot_retention_keeps_latest_and_checkpoint() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let advance = |blockchain: &CommunityBlockchain| {
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        };
+        advance(&source);
+        let signer = SigningKey::from_bytes(&[9; 32]);
 
-        // Validate block
-        if block.index != last_block.index + 1 {
-            return Err("Invalid block index".to_string());
+        // This node's checkpoint is the snapshot at height 1
+        let node = CommunityBlockchain::from_snapshot(
+            source.export_snapshot(&signer),
+            &signer.verifying_key(),
+            Arc::new(MemoryStore::new()),
+        )
+        .unwrap();
+        // It holds no block 0 but still knows which chain it is on
+        assert_eq!(node.genesis_hash(), source.genesis_hash());
+        assert_eq!(node.save_snapshot(&signer).unwrap(), 1);
+        for _ in 0..4 {
+            advance(&node);
+            node.save_snapshot(&signer).unwrap();
         }
+        assert_eq!(node.stored_snapshots().unwrap(), vec![1, 2, 3, 4, 5]);
 
-        if block.prev_hash != last_block.hash {
-            return Err("Invalid previous hash".to_string());
-        }
+        assert_eq!(node.prune_snapshots(2).unwrap(), 2);
+        assert_eq!(node.stored_snapshots().unwrap(), vec![1, 4, 5]);
 
-        let calc_hash = self.calculate_block_hash(&block);
-        if calc_hash != block.hash {
-            return Err("Invalid block hash".to_string());
-        }
+        // With a retention set, every new snapshot prunes the old ones
+        let node = node.with_snapshot_retention(2);
+        advance(&node);
+        node.save_snapshot(&signer).unwrap();
+        assert_eq!(node.stored_snapshots().unwrap(), vec![1, 5, 6]);
+    }
 
-        drop(chain);
+    #[tokio::test]
+    async fn test_node_starts_from_downloaded_snapshot() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial).unwrap();
+        source.create_transaction("alice".to_string(), "bob".to_string(), 300).unwrap();
+        let block = source.mine_block("proposer".to_string()).unwrap();
+        source.add_block(block).unwrap();
+        // Alice's only send is below the snapshot's tip block
+        source.create_transaction("bob".to_string(), "carol".to_string(), 50).unwrap();
+        let block = source.mine_block("proposer".to_string()).unwrap();
+        source.add_block(block).unwrap();
 
-        // Apply transactions to wallets
-        for tx in &block.transactions {
-            if let Some(mut sender) = self.wallets.get_mut(&tx.from) {
-                sender.balance -= tx.amount + tx.fee;
-                sender.tx_count += 1;
-                sender.last_updated = current_timestamp();
-            }
+        let signer = SigningKey::from_bytes(&[9; 32]);
+        let snapshot = source.export_snapshot(&signer);
+        let mut forged = snapshot.clone();
+        forged.wallets.iter_mut().find(|w| w.address == "bob").unwrap().balance = 1000;
+        forged.state_root = CommunityBlockchain::calculate_state_root(
+            &forged.wallets.iter().map(|w| (w.address.clone(), w.balance)).collect(),
+            &forged.nonces.clone().into_iter().collect(),
+        );
 
-            let mut recipient = self.wallets.entry(tx.to.clone())
-                .or_insert_with(|| Wallet {
-                    address: tx.to.clone(),
-                    balance: 0,
-                    tx_count: 0,
-                    created_at: current_timestamp(),
-                    last_updated: current_timestamp(),
-                });
-            recipient.balance += tx.amount;
-            recipient.last_updated = current_timestamp();
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(move || async move { axum::Json(snapshot) }))
+            .route("/forged", axum::routing::get(move || async move { axum::Json(forged) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
 
-            // Update per-user transaction index
-            let mut user_txs = self.tx_index.entry(tx.from.clone())
-                .or_insert_with(Vec::new);
-            user_txs.push(TransactionIndex {
-                tx_id: tx.tx_id.clone(),
-                block_index: block.index,
-                tx_index_in_block: block.transactions.iter().position(|t| t.tx_id == tx.tx_id).unwrap(),
-            });
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("node");
+        let node = CommunityBlockchain::from_snapshot_url(
+            &format!("http://{}/snapshot", addr),
+            db_path.to_str().unwrap(),
+            &signer.verifying_key(),
+        )
+        .await
+        .unwrap();
 
-            let mut recipient_txs = self.tx_index.entry(tx.to.clone())
-                .or_insert_with(Vec::new);
-            recipient_txs.push(TransactionIndex {
-                tx_id: tx.tx_id.clone(),
-                block_index: block.index,
-                tx_index_in_block: block.transactions.iter().position(|t| t.tx_id == tx.tx_id).unwrap(),
-            });
+        for address in ["alice", "bob", "carol"] {
+            assert_eq!(node.get_balance(address), source.get_balance(address));
         }
+        assert_eq!(node.state_root(), source.state_root());
+        assert_eq!(node.get_chain().last().unwrap().hash, source.get_chain().last().unwrap().hash);
+        assert!(node.verify_chain());
 
-        // Persist block and wallets to disk
-        if let Err(e) = self.persist_block(&block) {
-            return Err(format!("Failed to persist block: {}", e));
-        }
+        // The node carries on from the snapshot, nonces included
+        node.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = node.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions[0].nonce, 2);
+        node.add_block(block).unwrap();
+        assert!(node.verify_chain());
 
-        for wallet_ref in self.wallets.iter() {
-            let wallet_json = serde_json::to_string(&wallet_ref.value()).unwrap();
-            let _ = self.state_db.insert(
-                format!("wallet:{}", wallet_ref.key()).as_bytes(),
-                wallet_json.as_bytes(),
+        let forged_path = dir.path().join("forged");
+        let err = CommunityBlockchain::from_snapshot_url(
+            &format!("http://{}/forged", addr),
+            forged_path.to_str().unwrap(),
+            &signer.verifying_key(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Snapshot checkpoint signature is invalid");
+    }
+
+    #[tokio::test]
+    async fn test_fast_sync_from_trusted_header() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = Arc::new(CommunityBlockchain::new_in_memory(initial).unwrap());
+        let advance = |to: &str| {
+            source.create_transaction("alice".to_string(), to.to_string(), 100).unwrap();
+            let block = source.mine_block("proposer".to_string()).unwrap();
+            source.add_block(block).unwrap();
+        };
+        advance("bob");
+        advance("carol");
+        let mut snapshot = source.export_snapshot(&SigningKey::from_bytes(&[9; 32]));
+        snapshot.signature.clear();
+        let trusted = snapshot.tip.hash.clone();
+        advance("dave");
+        advance("erin");
+
+        let served = source.clone();
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(move || async move { axum::Json(snapshot) }))
+            .route(
+                "/block/:index",
+                axum::routing::get(move |axum::extract::Path(index): axum::extract::Path<u64>| async move {
+                    match served.get_block_by_index(index) {
+                        Some(block) => Ok(axum::Json(block)),
+                        None => Err(axum::http::StatusCode::NOT_FOUND),
+                    }
+                }),
             );
-        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
 
-        // Add to chain
-        let mut chain = self.chain.lock().unwrap();
-        chain.push(block);
+        // An unsigned snapshot is taken on the trusted header's word only
+        let url = format!("http://{}/snapshot", addr);
+        let err = CommunityBlockchain::from_snapshot_url_into(
+            &url,
+            Arc::new(MemoryStore::new()),
+            &SnapshotTrust::Header("ab".repeat(32)),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("is not the trusted block"));
 
-        Ok(())
+        let node = CommunityBlockchain::from_snapshot_url_into(
+            &url,
+            Arc::new(MemoryStore::new()),
+            &SnapshotTrust::Header(trusted),
+        )
+        .await
+        .unwrap();
+        assert_eq!(node.tip().index, 2);
+        assert_eq!(node.sync_from_peer(&format!("http://{}", addr)).await.unwrap(), 2);
+        assert_eq!(node.tip().hash, source.tip().hash);
+        assert_eq!(node.state_root(), source.state_root());
+        assert_eq!(node.get_balance("erin"), Ok(100));
+        assert!(node.verify_chain());
     }
 
-    /// Persist block to disk
-    fn persist_block(&self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
-        let block_json = serde_json::to_string(block)?;
-        self.state_db.insert(
-            format!("block:{}", block.index).as_bytes(),
-            block_json.as_bytes(),
-        )?;
-        Ok(())
-    }
+    #[test]
+    fn test_snapshot_carries_contract_storage() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let contract = apply_contract(
+            &source,
+            "alice",
+            ContractTransaction::Deploy {
+                code: crate::vm::COUNTER_WAT.as_bytes().to_vec(),
+                admin: None,
+                abi: None,
+            },
+        )
+        .unwrap()
+        .contract;
+        apply_contract(
+            &source,
+            "alice",
+            ContractTransaction::Call {
+                contract: contract.clone(),
+                function: "increment".to_string(),
+                gas_limit: 1_000_000,
+            },
+        )
+        .unwrap();
 
-    /// Get wallet
-    pub fn get_wallet(&self, address: &str) -> Result<Wallet, String> {
-        self.wallets
-            .get(address)
-            .map(|w| w.value().clone())
-            .ok_or("Wallet not found".to_string())
+        let signer = SigningKey::from_bytes(&[9; 32]);
+        let snapshot = source.export_snapshot(&signer);
+        assert_eq!(snapshot.contracts.len(), 1);
+
+        // The signature covers contract storage as well as balances, and
+        // the params and nonces too
+        let mut forged = snapshot.clone();
+        forged.contracts[0].storage.clear();
+        let trust = SnapshotTrust::Signer(signer.verifying_key());
+        let mut reparamed = snapshot.clone();
+        reparamed.params.max_tx_amount = u64::MAX;
+        assert!(!reparamed.verify_signature(&signer.verifying_key()));
+        let mut renonced = snapshot.clone();
+        renonced.nonces.insert("alice".to_string(), 99);
+        assert!(!renonced.verify_signature(&signer.verifying_key()));
+        assert!(snapshot.verify_signature(&signer.verifying_key()));
+        assert!(CommunityBlockchain::from_trusted_snapshot(forged, &trust, Arc::new(MemoryStore::new())).is_err());
+        // A trusted header doesn't vouch for contracts
+        let header = SnapshotTrust::Header(snapshot.tip.hash.clone());
+        assert!(CommunityBlockchain::from_trusted_snapshot(snapshot.clone(), &header, Arc::new(MemoryStore::new()))
+            .is_err());
+
+        let node = CommunityBlockchain::from_trusted_snapshot(snapshot, &trust, Arc::new(MemoryStore::new())).unwrap();
+        assert_eq!(node.get_contract(&contract).unwrap().storage, source.get_contract(&contract).unwrap().storage);
+        let count = node.call_contract_view(&contract, "get_count", 1_000_000).unwrap();
+        assert_eq!(count.return_value, Some(1));
     }
 
-    /// Get all wallets (for leaderboard)
-    pub fn get_leaderboard(&self) -> Vec<Wallet> {
-        let mut wallets: Vec<_> = self.wallets
-            .iter()
-            .map(|entry| entry.value().clone())
-            .collect();
-        wallets.sort_by(|a, b| b.balance.cmp(&a.balance));
-        wallets
+    #[test]
+    fn test_pending_for_address_only_shows_own_transactions() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("carol".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        let to_bob = blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let to_dave = blockchain.create_transaction("carol".to_string(), "dave".to_string(), 50).unwrap();
+
+        let ids = |address: &str| -> Vec<String> {
+            blockchain
+                .get_pending_for_address(address)
+                .into_iter()
+                .map(|tx| tx.tx_id)
+                .collect()
+        };
+        assert_eq!(ids("alice"), vec![to_bob.clone()]);
+        assert_eq!(ids("bob"), vec![to_bob]);
+        assert_eq!(ids("carol"), vec![to_dave.clone()]);
+        assert_eq!(ids("dave"), vec![to_dave]);
+        assert!(ids("erin").is_empty());
     }
 
-    /// Get user transactions (fast due to indexing)
-    pub fn get_user_transactions(&self, address: &str) -> Vec<Transaction> {
-        let chain = self.chain.lock().unwrap();
-        let mut txs = Vec::new();
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(256))]
 
-        if let Some(indices) = self.tx_index.get(address) {
-            for index in indices.iter() {
-                if let Some(block) = chain.get(index.block_index as usize) {
-                    if let Some(tx) = block.transactions.get(index.tx_index_in_block) {
-                        txs.push(tx.clone());
-                    }
+        // Any transfer either moves exactly what it says, less the fee and
+        // anything burned, or fails and leaves every balance as it was
+        #[test]
+        fn test_transfer_conserves_value_or_changes_nothing(
+            from_balance in proptest::num::u64::ANY,
+            to_balance in proptest::num::u64::ANY,
+            amount in proptest::num::u64::ANY,
+            fee in proptest::num::u64::ANY,
+            to in proptest::sample::select(vec!["bob", "alice", BURN_ADDRESS]),
+        ) {
+            let mut balances: HashMap<String, u64> =
+                [("alice".to_string(), from_balance), (to.to_string(), to_balance)].into();
+            let before = balances.clone();
+            let tx = Transaction {
+                from: "alice".to_string(),
+                to: to.to_string(),
+                amount,
+                fee,
+                timestamp: 0,
+                tx_id: "tx".to_string(),
+                signature: String::new(),
+                nonce: 1,
+                memo: None,
+                execute_at_height: None,
+                recent_block_hash: None,
+                sig_scheme: Default::default(),
+                contract: None,
+            };
+            let total = |balances: &HashMap<String, u64>| balances.values().map(|b| *b as u128).sum::<u128>();
+            match CommunityBlockchain::apply_transfer(&mut balances, &tx) {
+                Ok(()) => {
+                    let destroyed = fee as u128 + if to == BURN_ADDRESS { amount as u128 } else { 0 };
+                    proptest::prop_assert_eq!(total(&balances) + destroyed, total(&before));
+                }
+                Err(e) => {
+                    proptest::prop_assert_eq!(e, BlockchainError::ArithmeticOverflow);
+                    proptest::prop_assert_eq!(balances, before);
                 }
             }
         }
-
-        txs
     }
 
-    /// Get pending transactions
-    pub fn get_pending(&self) -> Vec<Transaction> {
-        self.pending_txs.lock().unwrap().clone()
-    }
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(24))]
 
-    /// Get blockchain
-    pub fn get_chain(&self) -> Vec<Block> {
-        self.chain.lock().unwrap().clone()
-    }
+        // However transfers, burns and huge amounts are mixed into blocks,
+        // supply reconciles and a peer replaying the blocks agrees
+        #[test]
+        fn test_supply_is_conserved_across_blocks(
+            steps in proptest::collection::vec(
+                (
+                    0..3usize,
+                    0..5usize,
+                    proptest::prop_oneof![1..2_000u64, proptest::num::u64::ANY],
+                    1..50u64,
+                    proptest::bool::weighted(0.3),
+                ),
+                1..40,
+            ),
+        ) {
+            let params = ChainParams {
+                max_tx_amount: u64::MAX,
+                max_block_value: u64::MAX,
+                ..ChainParams::default()
+            };
+            let accounts = ["alice", "bob", "carol", BURN_ADDRESS, "miner"];
+            let initial: HashMap<String, u64> = [
+                ("alice".to_string(), 5_000),
+                ("bob".to_string(), u64::MAX / 4),
+                ("carol".to_string(), u64::MAX / 2),
+            ]
+            .into();
+            let node = CommunityBlockchain::from_store(initial.clone(), params.clone(), Arc::new(MemoryStore::new())).unwrap();
+            let peer = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
 
-    pub fn get_balance(&self, address: &str) -> Result<u64, String> {
-        self.get_wallet(address).map(|w| w.balance)
+            let seal = || -> Result<(), proptest::test_runner::TestCaseError> {
+                if let Ok(block) = node.mine_block("miner".to_string()) {
+                    proptest::prop_assert_eq!(node.add_block(block.clone()), Ok(()));
+                    proptest::prop_assert_eq!(peer.add_block(block), Ok(()));
+                }
+                Ok(())
+            };
+            for (from, to, amount, fee, mine) in steps {
+                let options = TransferOptions { fee: Some(fee), ..Default::default() };
+                let _ = node.create_transaction_with_options(accounts[from].to_string(), accounts[to].to_string(), amount, options);
+                if mine {
+                    seal()?;
+                }
+            }
+            seal()?;
+
+            proptest::prop_assert_eq!(node.reconcile_supply(), Ok(()));
+            proptest::prop_assert_eq!(peer.reconcile_supply(), Ok(()));
+            proptest::prop_assert_eq!(peer.state_root(), node.state_root());
+            proptest::prop_assert_eq!(peer.total_supply(), node.total_supply());
+        }
     }
 
-    /// Verify chain integrity
-    pub fn verify_chain(&self) -> bool {
-        let chain = self.chain.lock().unwrap();
+    #[test]
+    fn test_balance_overflow_is_reported_not_wrapped() {
+        assert_eq!(add_amount(u64::MAX, 1), Err(BlockchainError::ArithmeticOverflow));
+        assert_eq!(sub_amount(0, 1), Err(BlockchainError::ArithmeticOverflow));
 
-        for i in 1..chain.len() {
-            let current = &chain[i];
-            let previous = &chain[i - 1];
+        // amount + fee overflows before the balance is even checked
+        let params = ChainParams {
+            max_tx_amount: u64::MAX,
+            max_block_value: u64::MAX,
+            ..ChainParams::demo()
+        };
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), u64::MAX);
+        let uncapped = CommunityBlockchain::from_store(initial, params, Arc::new(MemoryStore::new())).unwrap();
+        assert_eq!(
+            uncapped.create_transaction("alice".to_string(), "bob".to_string(), u64::MAX),
+            Err("Arithmetic overflow in balance math".to_string())
+        );
 
-            if current.prev_hash != previous.hash {
-                return false;
-            }
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial.clone()).unwrap();
+        source.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = source.mine_block("proposer".to_string()).unwrap();
 
-            let calc_hash = self.calculate_block_hash(current);
-            if calc_hash != current.hash {
-                return false;
-            }
-        }
+        initial.insert("bob".to_string(), u64::MAX - 10);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+
+        // A transfer that would overflow bob is left out of mined blocks
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        assert_eq!(
+            blockchain.mine_block("proposer".to_string()).unwrap_err(),
+            "No valid transactions after validation"
+        );
 
-        true
+        // and a peer's block carrying one is rejected without touching a
+        // wallet. It was mined on another genesis, so re-parent it first.
+        let mut block = block;
+        block.prev_hash = blockchain.tip().hash;
+        CommunityBlockchain::seal_block(&mut block);
+        assert_eq!(block.transactions[0].to, "bob");
+        assert_eq!(
+            blockchain.add_block(block),
+            Err("Arithmetic overflow in balance math".to_string())
+        );
+        assert_eq!(blockchain.get_balance("alice").unwrap(), 1000);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), u64::MAX - 10);
+        assert_eq!(blockchain.get_chain().len(), 1);
     }
 
-    /// Get stats
-    pub fn get_stats(&self) -> serde_json::Value {
-        let chain = self.chain.lock().unwrap();
-        let pending = self.pending_txs.lock().unwrap();
-        let total_txs: u64 = chain.iter().map(|b| b.transactions.len() as u64).sum();
-        let total_coins: u64 = self.wallets.iter().map(|entry| entry.value().balance).sum();
+    #[test]
+    fn test_rebuilt_tx_index_matches_the_original() {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert("bob".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        for (from, to) in [("alice", "bob"), ("bob", "carol"), ("alice", "carol")] {
+            blockchain.create_transaction(from.to_string(), to.to_string(), 10).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
 
-        serde_json::json!({
-            "chain_height": chain.len() - 1,
-            "total_blocks": chain.len(),
-            "total_wallets": self.wallets.len(),
-            "total_transactions": total_txs,
-            "pending_transactions": pending.len(),
-            "total_coins": total_coins,
-            "is_valid": self.verify_chain(),
-        })
+        let history = |address: &str| -> Vec<String> {
+            blockchain
+                .get_user_transactions(address)
+                .into_iter()
+                .map(|tx| tx.tx_id)
+                .collect()
+        };
+        let before: Vec<Vec<String>> = ["alice", "bob", "carol"].iter().map(|a| history(a)).collect();
+        assert_eq!(before.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 2]);
+
+        blockchain.tx_index().hot.clear();
+        assert!(history("alice").is_empty());
+
+        // Readers keep the old index until the new one is swapped in whole
+        let mut reports = Vec::new();
+        let rebuilt = blockchain.rebuild_tx_index_with_progress(|done, total| {
+            reports.push((done, total, history("bob").len()));
+        });
+        assert_eq!(rebuilt, 4);
+        assert_eq!(reports, vec![(1, 4, 0), (2, 4, 0), (3, 4, 0), (4, 4, 0)]);
+
+        let after: Vec<Vec<String>> = ["alice", "bob", "carol"].iter().map(|a| history(a)).collect();
+        assert_eq!(after, before);
+        assert_eq!(blockchain.get_user_transaction_count("carol"), 2);
     }
-}
 
-fn current_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-}
+    #[test]
+    fn test_batched_writes_match_immediate_writes_after_flush() {
+        let apply_blocks = |batching: Option<WriteBatching>| {
+            let mut initial = HashMap::new();
+            initial.insert("alice".to_string(), 100_000);
+            initial.insert("bob".to_string(), 100_000);
+            let store = Arc::new(MemoryStore::new());
+            let mut blockchain =
+                CommunityBlockchain::from_store_with_clock(initial, ChainParams::demo(), store.clone(), || 1_700_000_000)
+                    .unwrap();
+            if let Some(batching) = batching {
+                blockchain = blockchain.with_write_batching(batching);
+            }
+            for i in 0..20u64 {
+                let (from, to) = if i % 2 == 0 { ("alice", "bob") } else { ("bob", "carol") };
+                blockchain.create_transaction(from.to_string(), to.to_string(), 10 + i).unwrap();
+                let block = blockchain.mine_block("proposer".to_string()).unwrap();
+                blockchain.add_block(block).unwrap();
+            }
+            (blockchain, store)
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::atomic::{AtomicUsize, Ordering};
+        let (immediate, immediate_store) = apply_blocks(None);
+        let (batched, batched_store) = apply_blocks(Some(WriteBatching { max_blocks: 8, interval_secs: 3600 }));
 
-    static DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        // Two batches of 8 went out, the last 4 blocks are still in memory
+        let window = batched.durability_window();
+        assert_eq!(window.unflushed_blocks, 4);
+        assert!(batched_store.get(b"block:16").unwrap().is_some());
+        assert!(batched_store.get(b"block:17").unwrap().is_none());
+        assert_eq!(batched.get_block_by_index(20).unwrap().hash, immediate.get_block_by_index(20).unwrap().hash);
+        assert!(batched.get_diff(20).is_ok());
 
-    fn get_unique_db_path() -> String {
-        let count = DB_COUNTER.fetch_add(1, Ordering::SeqCst);
-        let path = format!("test_db_{}", count);
-        if std::path::Path::new(&path).exists() {
-            std::fs::remove_dir_all(&path).unwrap();
-        }
-        path
+        batched.flush_writes().unwrap();
+        assert_eq!(batched.durability_window().unflushed_blocks, 0);
+        assert_eq!(
+            batched_store.scan_prefix(b"").unwrap(),
+            immediate_store.scan_prefix(b"").unwrap()
+        );
+
+        let reloaded = CommunityBlockchain::load_from_store(batched_store).unwrap();
+        assert_eq!(reloaded.get_chain().len(), 21);
+        assert_eq!(reloaded.get_balance("carol").unwrap(), immediate.get_balance("carol").unwrap());
     }
 
     #[test]
-    fn test_transaction_with_fees() {
-        let db_path = get_unique_db_path();
+    fn test_reorg_stops_when_buffered_blocks_cannot_be_flushed() {
+        /// Storage whose atomic writes fail while `failing` is set
+        struct FlakyStore {
+            inner: MemoryStore,
+            failing: std::sync::atomic::AtomicBool,
+        }
+        impl KvStore for FlakyStore {
+            fn get(&self, key: &[u8]) -> crate::storage::StorageResult<Option<Vec<u8>>> {
+                self.inner.get(key)
+            }
+            fn insert(&self, key: &[u8], value: &[u8]) -> crate::storage::StorageResult<()> {
+                self.inner.insert(key, value)
+            }
+            fn remove(&self, key: &[u8]) -> crate::storage::StorageResult<()> {
+                self.inner.remove(key)
+            }
+            fn scan_prefix(&self, prefix: &[u8]) -> crate::storage::StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+                self.inner.scan_prefix(prefix)
+            }
+            fn flush(&self) -> crate::storage::StorageResult<()> {
+                self.inner.flush()
+            }
+            fn transaction(&self, ops: Vec<WriteOp>) -> crate::storage::StorageResult<()> {
+                match self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                    true => Err(StorageError("disk full".to_string())),
+                    false => self.inner.transaction(ops),
+                }
+            }
+        }
+
         let mut initial = HashMap::new();
         initial.insert("alice".to_string(), 1000);
-        initial.insert("bob".to_string(), 500);
-
-        let blockchain = CommunityBlockchain::new(initial, &db_path).unwrap();
+        let store = Arc::new(FlakyStore {
+            inner: MemoryStore::new(),
+            failing: std::sync::atomic::AtomicBool::new(false),
+        });
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone())
+            .unwrap()
+            .with_write_batching(WriteBatching { max_blocks: 100, interval_secs: 3600 });
+        let genesis = blockchain.get_chain()[0].clone();
+        let genesis_root = blockchain.state_root();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block.clone()).unwrap();
 
-        let tx_id = blockchain
-            .create_transaction("alice".to_string(), "bob".to_string(), 100)
-            .unwrap();
+        let mut competing = Block {
+            index: 1,
+            timestamp: current_timestamp(),
+            transactions: vec![],
+            prev_hash: genesis.hash,
+            hash: String::new(),
+            proposer: "other".to_string(),
+            state_root: genesis_root,
+            tx_root: merkle::root(&[]),
+            difficulty: 0,
+            pow_nonce: 0,
+            proposer_signature: None,
+        };
+        competing.hash = CommunityBlockchain::calculate_block_hash(&competing);
 
-        assert!(!tx_id.is_empty());
-        let pending = blockchain.get_pending();
-        assert_eq!(pending[0].fee, 1); // 1% of 100
+        // The buffered block can't reach disk, so the reorg stops before
+        // rolling anything back
+        store.failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        let err = blockchain.reorg(0, vec![competing.clone()]).unwrap_err();
+        assert!(err.contains("disk full"), "{}", err);
+        assert_eq!(blockchain.tip().hash, block.hash);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+        assert_eq!(blockchain.durability_window().unflushed_blocks, 1);
 
-        drop(blockchain);
+        store.failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        blockchain.reorg(0, vec![competing.clone()]).unwrap();
+        assert_eq!(blockchain.tip().hash, competing.hash);
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 0);
+        blockchain.flush_writes().unwrap();
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.tip().hash, competing.hash);
+        assert_eq!(reloaded.get_balance("bob").unwrap(), 0);
     }
 
     #[test]
-    fn test_block_persistence() {
-        let db_path = get_unique_db_path();
+    fn test_block_persists_only_the_wallets_it_touches() {
         let mut initial = HashMap::new();
         initial.insert("alice".to_string(), 1000);
+        initial.insert("carol".to_string(), 500);
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
 
-        let blockchain = CommunityBlockchain::new(initial, &db_path).unwrap();
-        blockchain
-            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+        // A stand-in record for carol shows whether her wallet is rewritten
+        let stand_in = Codec::default()
+            .encode(&Wallet {
+                address: "carol".to_string(),
+                balance: 7,
+                tx_count: 0,
+                created_at: 0,
+                last_updated: 0,
+            })
             .unwrap();
+        store.insert(b"wallet:carol", &stand_in).unwrap();
 
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
         let block = blockchain.mine_block("proposer".to_string()).unwrap();
         blockchain.add_block(block).unwrap();
+        assert_eq!(store.get(b"wallet:carol").unwrap(), Some(stand_in));
 
-        assert_eq!(blockchain.get_balance("alice").unwrap(), 899); // 1000 - 100 - 1 fee
-
-        drop(blockchain);
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.get_balance("alice").unwrap(), 899);
+        assert_eq!(reloaded.get_balance("bob").unwrap(), 100);
+        assert_eq!(reloaded.get_wallet("bob").unwrap().tx_count, 0);
+        assert_eq!(reloaded.get_wallet("alice").unwrap().tx_count, 1);
+        assert_eq!(reloaded.get_balance("carol").unwrap(), 7);
     }
 
     #[test]
-    fn test_leaderboard_ordering() {
-        let db_path = get_unique_db_path();
+    fn test_pruning_keeps_headers_state_and_nonces() {
+        let params = ChainParams {
+            recent_block_window: 0,
+            tx_dedup_blocks: 0,
+            ..ChainParams::demo()
+        };
         let mut initial = HashMap::new();
-        initial.insert("alice".to_string(), 1000);
-        initial.insert("bob".to_string(), 500);
-        initial.insert("charlie".to_string(), 750);
+        initial.insert("alice".to_string(), 10_000);
+        initial.insert("carol".to_string(), 10_000);
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_store(initial, params, store.clone())
+            .unwrap()
+            .with_fee_floor_blocks(1)
+            .with_block_pruning(3);
 
-        let blockchain = CommunityBlockchain::new(initial, &db_path).unwrap();
-        let leaderboard = blockchain.get_leaderboard();
+        // carol sends only in blocks that will be pruned
+        for i in 0..10u64 {
+            let from = if i < 2 { "carol" } else { "alice" };
+            blockchain.create_transaction(from.to_string(), "bob".to_string(), 10 + i).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let hashes: Vec<String> = blockchain.get_chain().iter().map(|b| b.hash.clone()).collect();
 
-        assert_eq!(leaderboard[0].address, "alice");
-        assert_eq!(leaderboard[1].address, "charlie");
-        assert_eq!(leaderboard[2].address, "bob");
+        // Blocks 1 to 7 are headers only, the last three are whole
+        assert_eq!(blockchain.pruned_height(), 7);
+        assert!(blockchain.is_pruned(7) && !blockchain.is_pruned(8) && !blockchain.is_pruned(0));
+        let chain = blockchain.get_chain();
+        assert!(chain[1..=7].iter().all(|b| b.transactions.is_empty()));
+        assert!(chain[8..].iter().all(|b| !b.transactions.is_empty()));
+        assert_eq!(chain.iter().map(|b| b.hash.clone()).collect::<Vec<_>>(), hashes);
+        assert!(store.get(b"diff:7").unwrap().is_none());
+        assert!(blockchain.get_diff(8).is_ok());
+        assert!(blockchain.verify_chain());
+        assert_eq!(blockchain.reconcile_supply(), Ok(()));
 
+        // History says what it can't show, and reorgs stay above the pruned blocks
+        let page = blockchain.get_recent_user_transactions("bob", None, 100);
+        assert_eq!(page.transactions.len(), 3);
+        assert!(page.pruned);
+        let err = blockchain.reorg(6, vec![chain[7].clone()]).unwrap_err();
+        assert_eq!(err, "Cannot reorg below pruned height 7");
+
+        // A restart keeps all of it, carol's nonce included
         drop(blockchain);
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(reloaded.pruned_height(), 7);
+        assert!(reloaded.verify_chain());
+        assert_eq!(reloaded.reconcile_supply(), Ok(()));
+        assert_eq!(reloaded.get_balance("bob").unwrap(), (10..20).sum::<u64>());
+        reloaded.create_transaction("carol".to_string(), "bob".to_string(), 5).unwrap();
+        assert_eq!(reloaded.get_pending()[0].nonce, 3);
     }
 
     #[test]
-    fn test_fast_transaction_lookup() {
-        let db_path = get_unique_db_path();
+    fn test_next_block_min_fee_tracks_recent_blocks() {
         let mut initial = HashMap::new();
-        initial.insert("alice".to_string(), 1000);
+        initial.insert("alice".to_string(), 100_000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial)
+            .unwrap()
+            .with_fee_floor_blocks(2);
+        assert_eq!(blockchain.next_block_min_fee(), MIN_FEE);
 
-        let blockchain = CommunityBlockchain::new(initial, &db_path).unwrap();
+        let mine = |amount: u64| {
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), amount).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        };
 
-        for _ in 0..100 {
-            blockchain
-                .create_transaction("alice".to_string(), "bob".to_string(), 1)
-                .unwrap();
+        // Fees are 1% of the amount
+        mine(500);
+        mine(1000);
+        assert_eq!(blockchain.next_block_min_fee(), 5);
+
+        // The 5 falls out of the window
+        mine(3000);
+        assert_eq!(blockchain.next_block_min_fee(), 10);
+        mine(2000);
+        assert_eq!(blockchain.next_block_min_fee(), 20);
+
+        // A fee-less mint doesn't drag the floor down
+        blockchain.mint("alice".to_string(), 100).unwrap();
+        assert_eq!(blockchain.next_block_min_fee(), 20);
+    }
+
+    #[test]
+    fn test_signature_scheme_tag_picks_the_verifier() {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let mut tx = Transaction {
+            from: hex::encode(key.verifying_key().as_bytes()),
+            to: "bob".to_string(),
+            amount: 100,
+            fee: 1,
+            timestamp: 0,
+            tx_id: "ed25519-tx".to_string(),
+            signature: String::new(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Ed25519,
+            contract: None,
+        };
+        tx.signature = hex::encode(ed25519_dalek::Signer::sign(&key, &tx.signing_payload()).to_bytes());
+        assert!(CommunityBlockchain::verify_signature(&tx, true));
+
+        // The tag survives both codecs and is spelled out in JSON, which
+        // leaves out the empty optional fields
+        let json = serde_json::to_value(&tx).unwrap();
+        assert_eq!(json["sig_scheme"], "ed25519");
+        for field in ["memo", "execute_at_height", "recent_block_hash", "contract"] {
+            assert!(json.get(field).is_none(), "{} is serialized", field);
+        }
+        for codec in [Codec::Json, Codec::Bincode] {
+            let decoded: Transaction = Codec::decode(&codec.encode(&tx).unwrap()).unwrap();
+            assert_eq!(decoded, tx);
+            assert!(CommunityBlockchain::verify_signature(&decoded, true));
         }
 
-        let block = blockchain.mine_block("proposer".to_string()).unwrap();
-        blockchain.add_block(block).unwrap();
+        // Relabelling the scheme breaks the signature
+        let mut relabelled = tx.clone();
+        relabelled.sig_scheme = SigScheme::Sha256;
+        assert!(!CommunityBlockchain::verify_signature(&relabelled, true));
 
-        let history = blockchain.get_user_transactions("alice");
-        assert_eq!(history.len(), 100);
+        // Schemes this node doesn't know are rejected
+        let mut unknown = json;
+        unknown["sig_scheme"] = "secp256k1".into();
+        let unknown: Transaction = serde_json::from_value(unknown).unwrap();
+        assert_eq!(unknown.sig_scheme, SigScheme::Unknown);
+        assert!(!CommunityBlockchain::verify_signature(&unknown, true));
 
-        drop(blockchain);
+        // Records from before tagging default to the node's SHA-256 scheme
+        let mut legacy = serde_json::to_value(&tx).unwrap();
+        legacy.as_object_mut().unwrap().remove("sig_scheme");
+        let legacy: Transaction = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.sig_scheme, SigScheme::Sha256);
+
+        // A SHA-256 signature covers the memo, so it can't be swapped after
+        // signing, while memo-less transactions keep their old digest
+        let mut node_signed = tx.clone();
+        node_signed.from = "alice".to_string();
+        node_signed.sig_scheme = SigScheme::Sha256;
+        let unsigned_memo = format!("{:x}", Sha256::digest(node_signed.signing_payload()));
+        node_signed.memo = Some("rent".to_string());
+        node_signed.signature = format!("{:x}", Sha256::digest(node_signed.signing_payload()));
+        assert_ne!(node_signed.signature, unsigned_memo);
+        assert!(CommunityBlockchain::verify_signature(&node_signed, true));
+        assert!(!CommunityBlockchain::verify_signature(&node_signed, false));
+        let mut rewritten = node_signed.clone();
+        rewritten.memo = Some("refund".to_string());
+        assert!(!CommunityBlockchain::verify_signature(&rewritten, true));
+        rewritten.memo = None;
+        assert!(!CommunityBlockchain::verify_signature(&rewritten, true));
+    }
+
+    #[test]
+    fn test_content_id_covers_every_field_but_id_and_signature() {
+        let tx = Transaction {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 100,
+            fee: 1,
+            timestamp: 0,
+            tx_id: "first".to_string(),
+            signature: "sig".to_string(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Ed25519,
+            contract: None,
+        };
+        let id = tx.content_id();
+
+        // The id and signature are filled in from it, so don't feed back
+        let mut signed = tx.clone();
+        signed.tx_id = id.clone();
+        signed.signature = "other".to_string();
+        assert_eq!(signed.content_id(), id);
+
+        let changed = [
+            ("memo", Transaction { memo: Some(String::new()), ..tx.clone() }),
+            ("execute_at_height", Transaction { execute_at_height: Some(5), ..tx.clone() }),
+            ("recent_block_hash", Transaction { recent_block_hash: Some("abc".to_string()), ..tx.clone() }),
+            ("sig_scheme", Transaction { sig_scheme: SigScheme::Sha256, ..tx.clone() }),
+            ("nonce", Transaction { nonce: 2, ..tx.clone() }),
+            ("to", Transaction { to: "carol".to_string(), ..tx.clone() }),
+        ];
+        for (field, changed) in changed {
+            assert_ne!(changed.content_id(), id, "{} isn't in the id", field);
+        }
+    }
+
+    #[test]
+    fn test_system_accounts_need_a_system_key_off_demo_chains() {
+        let system_key = SigningKey::from_bytes(&[21; 32]);
+        let mut initial = HashMap::new();
+        initial.insert(TREASURY_ADDRESS.to_string(), 1_000_000);
+        let params = ChainParams {
+            system_keys: vec![hex::encode(system_key.verifying_key().to_bytes())],
+            ..ChainParams::default()
+        };
+        let node = || CommunityBlockchain::from_store(initial.clone(), params.clone(), Arc::new(MemoryStore::new())).unwrap();
+        let (signer, peer) = (node().with_system_key(system_key.clone()), node());
+
+        // Anyone can compute the digest of a treasury transfer to themselves
+        let mut forged = Transaction {
+            from: TREASURY_ADDRESS.to_string(),
+            to: "mallory".to_string(),
+            amount: 900_000,
+            fee: MIN_FEE,
+            timestamp: 1_700_000_000,
+            tx_id: "treasury-mallory-1".to_string(),
+            signature: String::new(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: SigScheme::Sha256,
+            contract: None,
+        };
+        forged.signature = format!("{:x}", Sha256::digest(forged.signing_payload()));
+        assert_eq!(peer.accept_transaction(forged.clone()).unwrap_err(), "Invalid transaction signature");
+        assert!(!peer.check_transaction(&forged).signature_valid);
+
+        // Nor does it get in through a peer's block
+        let demo_params = ChainParams {
+            node_signing: true,
+            ..params.clone()
+        };
+        let demo = CommunityBlockchain::from_store(initial.clone(), demo_params, Arc::new(MemoryStore::new())).unwrap();
+        demo.accept_transaction(forged).unwrap();
+        let mut block = demo.mine_block("proposer".to_string()).unwrap();
+        block.prev_hash = peer.tip().hash;
+        CommunityBlockchain::seal_block(&mut block);
+        assert!(peer.add_block(block).unwrap_err().contains("signature"));
+        assert_eq!(peer.get_balance(TREASURY_ADDRESS).unwrap(), 1_000_000);
+
+        // A node holding a listed system key signs for the treasury, and
+        // peers accept its blocks; one without a key can't sign at all
+        assert!(peer
+            .create_transaction(TREASURY_ADDRESS.to_string(), "bob".to_string(), 500)
+            .unwrap_err()
+            .contains("no system key"));
+        signer.create_transaction(TREASURY_ADDRESS.to_string(), "bob".to_string(), 500).unwrap();
+        assert_eq!(signer.get_pending()[0].sig_scheme, SigScheme::Ed25519);
+        let block = signer.mine_block("proposer".to_string()).unwrap();
+        signer.add_block(block.clone()).unwrap();
+        peer.add_block(block).unwrap();
+        assert_eq!(peer.get_balance("bob").unwrap(), 500);
+        assert!(peer.verify_chain());
     }
 }
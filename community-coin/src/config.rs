@@ -0,0 +1,324 @@
+//! Node settings, layered: built-in defaults, then a TOML config file, then
+//! the environment variables nodes have always read, then command-line
+//! flags, each overriding the one before.
+
+use std::path::Path;
+
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{CommunityBlockchain, DEFAULT_FEE_FLOOR_BLOCKS, DEFAULT_FINALITY_QUORUM};
+use crate::gossip::GossipLimits;
+use crate::mempool::MempoolLimits;
+use crate::peers::PeerLimits;
+use crate::storage::StorageBackend;
+
+/// Default port of the HTTP API
+pub const DEFAULT_HTTP_PORT: u16 = 8000;
+
+/// Default port of the gRPC service
+pub const DEFAULT_GRPC_PORT: u16 = 50051;
+
+/// Default directory of the node's database
+pub const DEFAULT_DB_PATH: &str = "blockchain_state";
+
+/// Default cap on items returned by one page of a bulk endpoint
+pub const DEFAULT_MAX_PAGE_SIZE: usize = 100;
+
+/// Default cap on transactions one `/history` response returns
+pub const DEFAULT_MAX_HISTORY_LEN: usize = 100;
+
+/// Default seconds a computed leaderboard is served before recomputing it
+pub const DEFAULT_LEADERBOARD_CACHE_SECS: u64 = 30;
+
+/// Default memo searches allowed per client per minute
+pub const DEFAULT_SEARCH_RATE_LIMIT: f64 = 30.0;
+
+/// Default time in-flight requests get to finish after a shutdown signal
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Default seconds between mempool snapshots, the most a crash can lose
+pub const DEFAULT_MEMPOOL_FLUSH_INTERVAL_SECS: u64 = 5;
+
+/// Environment variables read into the config, with the key each sets
+pub const ENV_VARS: &[(&str, &str)] = &[
+    ("HTTP_PORT", "network.http_port"),
+    ("GRPC_PORT", "network.grpc_port"),
+    ("MAX_INBOUND_PEERS", "network.max_inbound_peers"),
+    ("MAX_OUTBOUND_PEERS", "network.max_outbound_peers"),
+    ("MAX_GOSSIP_TX_BYTES", "network.max_gossip_tx_bytes"),
+    ("MAX_GOSSIP_BLOCK_BYTES", "network.max_gossip_block_bytes"),
+    ("DB_PATH", "storage.db_path"),
+    ("STORAGE_BACKEND", "storage.backend"),
+    ("MAX_PENDING_TXS", "mempool.max_pending_txs"),
+    ("MAX_PENDING_PER_SENDER", "mempool.max_pending_per_sender"),
+    ("PENDING_TTL_SECS", "mempool.pending_ttl_secs"),
+    ("MEMPOOL_FLUSH_INTERVAL_SECS", "mempool.flush_interval_secs"),
+    ("FINALITY_QUORUM", "consensus.finality_quorum"),
+    ("FEE_FLOOR_BLOCKS", "consensus.fee_floor_blocks"),
+    ("MAX_PAGE_SIZE", "api.max_page_size"),
+    ("MAX_HISTORY_LEN", "api.max_history_len"),
+    ("LEADERBOARD_CACHE_SECS", "api.leaderboard_cache_secs"),
+    ("SEARCH_RATE_LIMIT", "api.search_rate_limit"),
+    ("DRAIN_TIMEOUT_SECS", "api.drain_timeout_secs"),
+];
+
+/// Everything a node is configured with besides its genesis
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub network: NetworkConfig,
+    pub storage: StorageConfig,
+    pub mempool: MempoolConfig,
+    pub consensus: ConsensusConfig,
+    pub api: ApiConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub http_port: u16,
+    pub grpc_port: u16,
+    pub max_inbound_peers: usize,
+    pub max_outbound_peers: usize,
+    /// Largest transaction or vote accepted over gossip, in bytes
+    pub max_gossip_tx_bytes: usize,
+    /// Largest block accepted over gossip, in bytes
+    pub max_gossip_block_bytes: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        let peers = PeerLimits::default();
+        let gossip = GossipLimits::default();
+        NetworkConfig {
+            http_port: DEFAULT_HTTP_PORT,
+            grpc_port: DEFAULT_GRPC_PORT,
+            max_inbound_peers: peers.max_inbound,
+            max_outbound_peers: peers.max_outbound,
+            max_gossip_tx_bytes: gossip.max_transaction_bytes,
+            max_gossip_block_bytes: gossip.max_block_bytes,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn peer_limits(&self) -> PeerLimits {
+        PeerLimits {
+            max_inbound: self.max_inbound_peers,
+            max_outbound: self.max_outbound_peers,
+        }
+    }
+
+    pub fn gossip_limits(&self) -> GossipLimits {
+        GossipLimits {
+            max_transaction_bytes: self.max_gossip_tx_bytes,
+            max_block_bytes: self.max_gossip_block_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub db_path: String,
+    pub backend: StorageBackend,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            db_path: DEFAULT_DB_PATH.to_string(),
+            backend: StorageBackend::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MempoolConfig {
+    pub max_pending_txs: usize,
+    pub max_pending_per_sender: usize,
+    /// 0 never expires pending transactions
+    pub pending_ttl_secs: u64,
+    /// 0 keeps the mempool in memory only
+    pub flush_interval_secs: u64,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        let limits = MempoolLimits::default();
+        MempoolConfig {
+            max_pending_txs: limits.max_txs,
+            max_pending_per_sender: limits.max_per_sender,
+            pending_ttl_secs: limits.ttl_secs,
+            flush_interval_secs: DEFAULT_MEMPOOL_FLUSH_INTERVAL_SECS,
+        }
+    }
+}
+
+impl MempoolConfig {
+    pub fn limits(&self) -> MempoolLimits {
+        MempoolLimits {
+            max_txs: self.max_pending_txs,
+            max_per_sender: self.max_pending_per_sender,
+            ttl_secs: self.pending_ttl_secs,
+        }
+    }
+}
+
+/// This node's side of consensus. Rules every node must agree on are chain
+/// params, fixed at genesis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsensusConfig {
+    /// Share of validator stake whose votes finalize a block
+    pub finality_quorum: f64,
+    /// Recent blocks the next block's minimum fee is taken over
+    pub fee_floor_blocks: usize,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        ConsensusConfig {
+            finality_quorum: DEFAULT_FINALITY_QUORUM,
+            fee_floor_blocks: DEFAULT_FEE_FLOOR_BLOCKS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub max_page_size: usize,
+    pub max_history_len: usize,
+    pub leaderboard_cache_secs: u64,
+    /// Memo searches allowed per client per minute
+    pub search_rate_limit: f64,
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            max_history_len: DEFAULT_MAX_HISTORY_LEN,
+            leaderboard_cache_secs: DEFAULT_LEADERBOARD_CACHE_SECS,
+            search_rate_limit: DEFAULT_SEARCH_RATE_LIMIT,
+            drain_timeout_secs: DEFAULT_DRAIN_TIMEOUT_SECS,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// The defaults, overridden by the TOML file at `path` if given, then by
+    /// the variables in [`ENV_VARS`]. Command-line flags are merged on top
+    /// by the caller, see [`NodeConfig::figment`].
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        Self::extract(Self::figment(path))
+    }
+
+    /// The layers [`NodeConfig::load`] reads, for merging more on top
+    pub fn figment(path: Option<&Path>) -> Figment {
+        let mut figment = Figment::from(Serialized::defaults(NodeConfig::default()));
+        if let Some(path) = path {
+            figment = figment.merge(Toml::file_exact(path));
+        }
+        let vars: Vec<&str> = ENV_VARS.iter().map(|(var, _)| *var).collect();
+        figment.merge(Env::raw().only(&vars).map(|var| {
+            ENV_VARS
+                .iter()
+                .find(|(name, _)| var == *name)
+                .map_or(var.into(), |(_, key)| (*key).into())
+        }))
+    }
+
+    /// Apply the consensus and mempool settings to a node's chain
+    pub fn apply(&self, blockchain: CommunityBlockchain) -> CommunityBlockchain {
+        blockchain
+            .with_finality_quorum(self.consensus.finality_quorum)
+            .with_fee_floor_blocks(self.consensus.fee_floor_blocks)
+            .with_mempool_limits(self.mempool.limits())
+    }
+
+    /// Read and check the config `figment` holds
+    pub fn extract(figment: Figment) -> Result<Self, String> {
+        let config: NodeConfig = figment.extract().map_err(|e| format!("Invalid node config: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(self.consensus.finality_quorum > 0.0 && self.consensus.finality_quorum <= 1.0) {
+            return Err("consensus.finality_quorum must be above 0 and at most 1".to_string());
+        }
+        if self.api.max_page_size == 0 || self.api.max_history_len == 0 {
+            return Err("api.max_page_size and api.max_history_len must be positive".to_string());
+        }
+        if self.api.search_rate_limit <= 0.0 {
+            return Err("api.search_rate_limit must be positive".to_string());
+        }
+        if self.network.max_gossip_tx_bytes == 0 || self.network.max_gossip_block_bytes == 0 {
+            return Err("network.max_gossip_tx_bytes and network.max_gossip_block_bytes must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_env_and_flags_layer_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.toml");
+        std::fs::write(
+            &path,
+            r#"
+[network]
+http_port = 9000
+grpc_port = 9001
+
+[mempool]
+max_pending_txs = 500
+
+[api]
+max_page_size = 50
+"#,
+        )
+        .unwrap();
+        // No other test reads these variables
+        std::env::set_var("GRPC_PORT", "9101");
+        std::env::set_var("MAX_PENDING_PER_SENDER", "7");
+
+        let config = NodeConfig::load(Some(&path)).unwrap();
+        assert_eq!(config.network.http_port, 9000);
+        assert_eq!(config.network.grpc_port, 9101);
+        assert_eq!(config.mempool.limits().max_txs, 500);
+        assert_eq!(config.mempool.limits().max_per_sender, 7);
+        assert_eq!(config.api.max_page_size, 50);
+        assert_eq!(config.storage, StorageConfig::default());
+
+        // Flags go on top of everything else
+        let flagged = NodeConfig::figment(Some(&path)).merge(Serialized::default("network.grpc_port", 9201));
+        assert_eq!(NodeConfig::extract(flagged).unwrap().network.grpc_port, 9201);
+
+        // Without a file, the environment still applies
+        let config = NodeConfig::load(None).unwrap();
+        assert_eq!((config.network.http_port, config.network.grpc_port), (DEFAULT_HTTP_PORT, 9101));
+
+        std::env::set_var("FINALITY_QUORUM", "1.5");
+        assert!(NodeConfig::load(None).unwrap_err().contains("finality_quorum"));
+        for var in ["GRPC_PORT", "MAX_PENDING_PER_SENDER", "FINALITY_QUORUM"] {
+            std::env::remove_var(var);
+        }
+
+        // Typos and missing files are errors, not silently ignored
+        let typo = dir.path().join("typo.toml");
+        std::fs::write(&typo, "[network]\nhttp_prot = 1\n").unwrap();
+        assert!(NodeConfig::load(Some(&typo)).is_err());
+        assert!(NodeConfig::load(Some(&dir.path().join("missing.toml"))).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Deployed smart contracts and the transactions that manage them.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A contract deployed on chain.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Contract {
+    pub address: String,
+    pub deployer: String,
+    /// Account allowed to upgrade the code, `None` makes the contract immutable
+    pub admin: Option<String>,
+    /// Current wasm bytecode (binary or text)
+    pub code: Vec<u8>,
+    /// Bumped on every upgrade, starting at 1
+    pub version: u32,
+    /// The contract's key/value storage, kept across upgrades
+    #[serde(with = "hex_storage")]
+    pub storage: HashMap<Vec<u8>, Vec<u8>>,
+    /// Chain height when the contract was deployed
+    #[serde(default)]
+    pub deployed_at: u64,
+}
+
+/// Summary of a deployed contract, as listed by `/contracts`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ContractInfo {
+    pub address: String,
+    pub deployer: String,
+    /// Size of the current code in bytes
+    pub code_size: usize,
+    pub deployed_at: u64,
+}
+
+impl From<&Contract> for ContractInfo {
+    fn from(contract: &Contract) -> Self {
+        ContractInfo {
+            address: contract.address.clone(),
+            deployer: contract.deployer.clone(),
+            code_size: contract.code.len(),
+            deployed_at: contract.deployed_at,
+        }
+    }
+}
+
+/// A wasm value type, as named in the text format
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AbiType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// One exported function in a contract's ABI
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct AbiFunction {
+    pub name: String,
+    #[serde(default)]
+    pub args: Vec<AbiType>,
+    #[serde(default)]
+    pub returns: Vec<AbiType>,
+}
+
+/// The functions a contract exports for callers, supplied by the deployer
+/// and stored next to the code under `abi:<address>`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct ContractAbi {
+    pub functions: Vec<AbiFunction>,
+}
+
+impl ContractAbi {
+    pub fn function(&self, name: &str) -> Option<&AbiFunction> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// Check that a call to `function` with no arguments, the only kind of
+    /// call the VM makes, is one the ABI declares
+    pub fn check_call(&self, function: &str) -> Result<(), String> {
+        let declared = self
+            .function(function)
+            .ok_or_else(|| format!("`{}` is not in the contract's ABI", function))?;
+        if !declared.args.is_empty() {
+            return Err(format!(
+                "`{}` takes {} arguments, but calls cannot pass any",
+                function,
+                declared.args.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Operations that deploy, call or upgrade contracts. Each is carried by a
+/// signed transaction to `CONTRACT_ADDRESS` and applied when its block is.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ContractTransaction {
+    Deploy {
+        code: Vec<u8>,
+        admin: Option<String>,
+        /// Optional description of the contract's exported functions,
+        /// checked against the code before deploying
+        #[serde(default)]
+        abi: Option<ContractAbi>,
+    },
+    Call {
+        contract: String,
+        function: String,
+        gas_limit: u64,
+    },
+    /// Replace a contract's code, keeping its address and storage. Only the
+    /// contract's admin may send this.
+    UpgradeContract {
+        contract: String,
+        code: Vec<u8>,
+    },
+}
+
+/// Outcome of an applied contract transaction.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ContractReceipt {
+    pub contract: String,
+    pub return_value: Option<u64>,
+    pub gas_used: u64,
+    /// Why the operation failed, leaving contract state as it was. The
+    /// transaction still paid its fee.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// JSON object keys must be strings, so storage is persisted hex-encoded.
+mod hex_storage {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        storage: &HashMap<Vec<u8>, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        storage
+            .iter()
+            .map(|(key, value)| (hex::encode(key), hex::encode(value)))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| {
+                let key = hex::decode(key).map_err(D::Error::custom)?;
+                let value = hex::decode(value).map_err(D::Error::custom)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
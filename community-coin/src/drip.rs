@@ -0,0 +1,172 @@
+//! Faucet drip for load testing: a background task that sends small
+//! transfers from the faucet account to a set of targets at a steady rate,
+//! until stopped.
+//!
+//! Only testnet builds expose it, through `/admin/faucet-drip`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::blockchain::{CommunityBlockchain, FAUCET_ADDRESS};
+
+/// Amount of each drip transfer unless the request sets one
+pub const DEFAULT_DRIP_AMOUNT: u64 = 1;
+
+/// Most transfers per second a drip may send
+pub const MAX_DRIP_RATE: f64 = 1_000.0;
+
+/// What a drip sends, and how fast
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DripConfig {
+    /// Transfers per second
+    pub rate: f64,
+    /// Addresses paid in turn
+    pub targets: Vec<String>,
+    #[serde(default = "default_amount")]
+    pub amount: u64,
+}
+
+fn default_amount() -> u64 {
+    DEFAULT_DRIP_AMOUNT
+}
+
+/// Where a drip stands, as reported by the admin endpoints
+#[derive(Debug, Clone, Serialize)]
+pub struct DripStatus {
+    pub running: bool,
+    pub config: Option<DripConfig>,
+    /// Transfers queued since the drip started
+    pub sent: u64,
+    /// Transfers the node refused, e.g. once the faucet runs dry
+    pub failed: u64,
+}
+
+/// A started drip and the task sending its transfers
+type RunningDrip = (DripConfig, JoinHandle<()>);
+
+/// Handle to the node's single faucet drip
+#[derive(Clone, Default)]
+pub struct FaucetDrip {
+    running: Arc<Mutex<Option<RunningDrip>>>,
+    sent: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl FaucetDrip {
+    /// Start dripping into `blockchain`. Fails if a drip is already running
+    /// or `config` is unusable.
+    pub fn start(&self, blockchain: Arc<RwLock<CommunityBlockchain>>, config: DripConfig) -> Result<(), String> {
+        if !(config.rate > 0.0 && config.rate <= MAX_DRIP_RATE) {
+            return Err(format!("Rate must be above 0 and at most {} per second", MAX_DRIP_RATE));
+        }
+        if config.targets.is_empty() {
+            return Err("Drip has no target addresses".to_string());
+        }
+        if config.amount == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+
+        let mut running = self.running.lock().unwrap();
+        if running.as_ref().is_some_and(|(_, task)| !task.is_finished()) {
+            return Err("A drip is already running".to_string());
+        }
+        self.sent.store(0, Ordering::SeqCst);
+        self.failed.store(0, Ordering::SeqCst);
+
+        let (sent, failed) = (self.sent.clone(), self.failed.clone());
+        let (targets, amount) = (config.targets.clone(), config.amount);
+        let period = Duration::from_secs_f64(1.0 / config.rate);
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            for to in targets.iter().cycle() {
+                ticker.tick().await;
+                let result = blockchain
+                    .read()
+                    .await
+                    .create_transaction(FAUCET_ADDRESS.to_string(), to.clone(), amount);
+                match result {
+                    Ok(_) => {
+                        sent.fetch_add(1, Ordering::SeqCst);
+                    }
+                    // Only the first failure is logged, they tend to repeat
+                    Err(e) => {
+                        if failed.fetch_add(1, Ordering::SeqCst) == 0 {
+                            tracing::warn!(to = %to, "Faucet drip transfer failed: {}", e);
+                        }
+                    }
+                }
+            }
+        });
+        *running = Some((config, task));
+        Ok(())
+    }
+
+    /// Stop the running drip. Returns whether one was running.
+    pub fn stop(&self) -> bool {
+        match self.running.lock().unwrap().take() {
+            Some((_, task)) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> DripStatus {
+        let running = self.running.lock().unwrap();
+        DripStatus {
+            running: running.as_ref().is_some_and(|(_, task)| !task.is_finished()),
+            config: running.as_ref().map(|(config, _)| config.clone()),
+            sent: self.sent.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_drip_sends_at_configured_rate_until_stopped() {
+        let mut initial = HashMap::new();
+        initial.insert(FAUCET_ADDRESS.to_string(), 1_000_000);
+        let blockchain = Arc::new(RwLock::new(CommunityBlockchain::new_in_memory(initial).unwrap()));
+        let drip = FaucetDrip::default();
+        let config = DripConfig {
+            rate: 50.0,
+            targets: vec!["alice".to_string(), "bob".to_string()],
+            amount: 5,
+        };
+
+        assert!(drip
+            .start(blockchain.clone(), DripConfig { rate: 0.0, ..config.clone() })
+            .is_err());
+        drip.start(blockchain.clone(), config.clone()).unwrap();
+        assert!(drip.start(blockchain.clone(), config).is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(drip.stop());
+        // Let a transfer caught mid-flight by the abort land
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!drip.status().running);
+
+        // About 25 transfers in half a second at 50 per second
+        let pending = blockchain.read().await.get_pending();
+        assert!((15..=35).contains(&pending.len()), "{} transfers", pending.len());
+        assert_eq!(drip.status().sent, pending.len() as u64);
+        assert!(pending.iter().all(|tx| tx.from == FAUCET_ADDRESS && tx.amount == 5));
+        assert!(pending.iter().any(|tx| tx.to == "alice") && pending.iter().any(|tx| tx.to == "bob"));
+
+        // Nothing more arrives once stopped
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(blockchain.read().await.get_pending().len(), pending.len());
+        assert!(!drip.stop());
+    }
+}
@@ -0,0 +1,370 @@
+//! Choice of genesis accounts when a node creates a new chain, from flags
+//! or from a genesis file shared by every node of a network.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{
+    ChainParams, CommunityBlockchain, MempoolPolicy, DEFAULT_CHAIN_ID, DEFAULT_MAX_BLOCK_BYTES, DEFAULT_MAX_BLOCK_TXS,
+    DEFAULT_TARGET_BLOCK_SECS, RESERVED_ADDRESSES, STAKE_ADDRESS,
+};
+use crate::settlement_layer::Validator;
+use crate::snapshot::parse_signing_key;
+
+/// Genesis flags shared by the node binaries. They only matter when no chain
+/// exists on disk yet.
+#[derive(Parser, Debug, Default)]
+pub struct GenesisArgs {
+    /// Create the chain from a genesis file, `.toml` or JSON, instead of
+    /// the flags below
+    #[arg(long = "genesis", value_name = "FILE", conflicts_with_all = ["empty_genesis", "demo", "genesis_keys"])]
+    pub genesis_file: Option<PathBuf>,
+
+    /// Start with no pre-funded accounts, only the reserved system accounts
+    /// (the default)
+    #[arg(long, conflicts_with = "demo")]
+    pub empty_genesis: bool,
+
+    /// Pre-fund the demo accounts: user_1..user_50, alice, bob and charlie
+    #[arg(long)]
+    pub demo: bool,
+
+    /// Pre-fund the ed25519 account of a known key, given as a hex 32-byte
+    /// seed and a balance. Repeatable. Whoever holds the seed can sign for
+    /// the account, so this is for tests and local networks only.
+    #[arg(long = "genesis-key", value_name = "SEED:BALANCE", value_parser = parse_seeded_account)]
+    pub genesis_keys: Vec<SeededAccount>,
+
+    /// Order blocks take transactions from different senders in: `fee`
+    /// (highest fee per byte first), `fifo` or `random:<seed>`
+    #[arg(long, value_name = "POLICY", value_parser = MempoolPolicy::parse, default_value = "fee")]
+    pub mempool_policy: MempoolPolicy,
+
+    /// Most transactions a block may hold, besides its reward
+    #[arg(long, default_value_t = DEFAULT_MAX_BLOCK_TXS)]
+    pub max_block_txs: u64,
+
+    /// Largest encoded size of a block's transactions together, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_BLOCK_BYTES)]
+    pub max_block_bytes: u64,
+
+    /// Leading zero bits block hashes must start out with. 0 (the default)
+    /// mines blocks without proof of work.
+    #[arg(long, default_value_t = 0)]
+    pub block_difficulty: u32,
+
+    /// Seconds between blocks that block difficulty retargets toward
+    #[arg(long, default_value_t = DEFAULT_TARGET_BLOCK_SECS)]
+    pub target_block_secs: u64,
+
+    /// New base units each block pays its proposer on top of its fees. 0
+    /// (the default) pays no block rewards.
+    #[arg(long, default_value_t = 0)]
+    pub block_reward: u64,
+
+    /// Blocks between halvings of the block reward. 0 (the default) keeps
+    /// it fixed.
+    #[arg(long, default_value_t = 0)]
+    pub reward_halving_interval: u64,
+}
+
+impl GenesisArgs {
+    /// The genesis a new chain starts from: the `--genesis` file if given,
+    /// else the one the other flags describe
+    pub fn genesis(&self) -> Result<Genesis, String> {
+        match &self.genesis_file {
+            Some(path) => Genesis::load(path),
+            None => Ok(Genesis {
+                chain_id: DEFAULT_CHAIN_ID.to_string(),
+                block_time_secs: None,
+                balances: self.wallets().into_iter().collect(),
+                validators: Vec::new(),
+                params: self.params(),
+            }),
+        }
+    }
+
+    /// Initial balances for a new chain
+    pub fn wallets(&self) -> HashMap<String, u64> {
+        let mut initial = if self.demo {
+            demo_wallets()
+        } else {
+            empty_wallets()
+        };
+        for account in &self.genesis_keys {
+            initial.insert(account.address(), account.balance);
+        }
+        initial
+    }
+
+    /// Chain params for a new chain
+    pub fn params(&self) -> ChainParams {
+        ChainParams {
+            mempool_policy: self.mempool_policy,
+            max_block_txs: self.max_block_txs,
+            max_block_bytes: self.max_block_bytes,
+            block_difficulty: self.block_difficulty,
+            target_block_secs: self.target_block_secs,
+            block_reward: self.block_reward,
+            reward_halving_interval: self.reward_halving_interval,
+            // The demo accounts have no keys, so the node signs for them
+            node_signing: self.demo,
+            ..ChainParams::default()
+        }
+    }
+}
+
+/// A chain's starting state, as a genesis file holds it. Every node of a
+/// network starts from the same file, and so the same genesis hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    pub chain_id: String,
+    /// Seconds between blocks, overriding `params.target_block_secs`
+    #[serde(default)]
+    pub block_time_secs: Option<u64>,
+    /// Pre-funded accounts, in base units
+    #[serde(default)]
+    pub balances: BTreeMap<String, u64>,
+    /// Validators staked from the start
+    #[serde(default)]
+    pub validators: Vec<Validator>,
+    /// Consensus parameters; any left out take their defaults
+    #[serde(default)]
+    pub params: ChainParams,
+}
+
+impl Genesis {
+    /// Read a genesis file, as TOML if its name ends in `.toml` and as JSON
+    /// otherwise
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read genesis file {}: {}", path.display(), e))?;
+        let genesis: Genesis = match path.extension().is_some_and(|ext| ext == "toml") {
+            true => toml::from_str(&text).map_err(|e| e.to_string()),
+            false => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Invalid genesis file {}: {}", path.display(), e))?;
+        genesis.wallets()?;
+        Ok(genesis)
+    }
+
+    /// Genesis hash of chains created from this genesis
+    pub fn hash(&self) -> Result<String, String> {
+        Ok(CommunityBlockchain::calculate_genesis_hash(&self.wallets()?, &self.validators, &self.params()))
+    }
+
+    /// Chain params, with the chain id and block time filled in
+    pub fn params(&self) -> ChainParams {
+        ChainParams {
+            chain_id: self.chain_id.clone(),
+            target_block_secs: self.block_time_secs.unwrap_or(self.params.target_block_secs),
+            ..self.params.clone()
+        }
+    }
+
+    /// Initial balances: the reserved accounts, the pre-funded ones, and the
+    /// staking account holding every validator's stake
+    pub fn wallets(&self) -> Result<HashMap<String, u64>, String> {
+        if self.chain_id.trim().is_empty() {
+            return Err("Genesis chain_id must not be empty".to_string());
+        }
+        let mut initial = empty_wallets();
+        initial.extend(self.balances.iter().map(|(address, balance)| (address.clone(), *balance)));
+
+        let mut seen = HashSet::new();
+        for validator in &self.validators {
+            if validator.stake == 0 {
+                return Err(format!("Genesis validator {} has no stake", validator.address));
+            }
+            if !CommunityBlockchain::is_externally_owned(&validator.address) {
+                return Err(format!(
+                    "Genesis validator {} must be the ed25519 public key its blocks are signed with",
+                    validator.address
+                ));
+            }
+            if !seen.insert(&validator.address) {
+                return Err(format!("Genesis validator {} is listed twice", validator.address));
+            }
+            let staked = initial.entry(STAKE_ADDRESS.to_string()).or_default();
+            *staked = staked
+                .checked_add(validator.stake)
+                .ok_or("Genesis validator stakes overflow")?;
+        }
+        Ok(initial)
+    }
+}
+
+/// A genesis account whose signing key is known up front
+#[derive(Debug, Clone)]
+pub struct SeededAccount {
+    pub key: SigningKey,
+    pub balance: u64,
+}
+
+impl SeededAccount {
+    /// The account's address: its hex-encoded ed25519 public key, as
+    /// ed25519-signed transactions expect
+    pub fn address(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+}
+
+/// Parse `SEED:BALANCE`, as in `--genesis-key`
+pub fn parse_seeded_account(value: &str) -> Result<SeededAccount, String> {
+    let (seed, balance) = value
+        .split_once(':')
+        .ok_or("Expected SEED:BALANCE")?;
+    Ok(SeededAccount {
+        key: parse_signing_key(seed)?,
+        balance: balance.trim().parse().map_err(|_| format!("Invalid balance {}", balance))?,
+    })
+}
+
+/// Only the reserved accounts, with nothing in them
+pub fn empty_wallets() -> HashMap<String, u64> {
+    RESERVED_ADDRESSES
+        .iter()
+        .map(|address| (address.to_string(), 0))
+        .collect()
+}
+
+/// Demo balances for local development
+pub fn demo_wallets() -> HashMap<String, u64> {
+    let mut initial = empty_wallets();
+
+    // Initialize 50 demo users
+    for i in 1..=50 {
+        initial.insert(format!("user_{}", i), 1000u64);
+    }
+
+    // Add named accounts
+    initial.insert("alice".to_string(), 10000);
+    initial.insert("bob".to_string(), 5000);
+    initial.insert("charlie".to_string(), 3000);
+
+    initial
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::storage::{KvStore, MemoryStore};
+
+    #[test]
+    fn test_empty_genesis_has_no_user_wallets() {
+        let args = GenesisArgs::try_parse_from(["node", "--empty-genesis"]).unwrap();
+        let blockchain = CommunityBlockchain::new_in_memory(args.wallets()).unwrap();
+
+        assert!(blockchain.get_leaderboard(RESERVED_ADDRESSES).is_empty());
+        assert_eq!(blockchain.total_supply(), 0);
+        assert_eq!(blockchain.get_chain().len(), 1);
+        assert_eq!(blockchain.get_chain()[0].index, 0);
+        assert!(blockchain.verify_chain());
+        assert!(blockchain.get_wallet("coinbase").is_ok());
+
+        // Demo accounts need asking for, and can't be combined with an empty genesis
+        let default = GenesisArgs::try_parse_from(["node"]).unwrap();
+        assert_eq!(default.wallets(), empty_wallets());
+        assert_eq!(GenesisArgs::try_parse_from(["node", "--demo"]).unwrap().wallets().len(), 60);
+        assert!(GenesisArgs::try_parse_from(["node", "--demo", "--empty-genesis"]).is_err());
+    }
+
+    #[test]
+    fn test_genesis_file_sets_accounts_validators_and_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("genesis.toml");
+        let validator = hex::encode(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes());
+        std::fs::write(
+            &toml_path,
+            format!(
+                r#"
+chain_id = "community-testnet"
+block_time_secs = 5
+
+[balances]
+alice = 5000
+bob = 2500
+
+[[validators]]
+address = "{validator}"
+stake = 3000
+
+[params]
+block_reward = 50
+max_block_txs = 200
+"#
+            ),
+        )
+        .unwrap();
+        let genesis = Genesis::load(&toml_path).unwrap();
+
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let blockchain = CommunityBlockchain::from_genesis(&genesis, store.clone()).unwrap();
+        assert_eq!(blockchain.get_balance("alice"), Ok(5000));
+        assert_eq!(blockchain.get_balance(STAKE_ADDRESS), Ok(3000));
+        let stats = blockchain.get_validator_stats();
+        assert_eq!((stats[0].address.as_str(), stats[0].stake), (validator.as_str(), 3000));
+        let params = blockchain.params();
+        assert_eq!(params.chain_id, "community-testnet");
+        assert_eq!((params.target_block_secs, params.block_reward, params.max_block_txs), (5, 50, 200));
+        assert_eq!(params.block_difficulty, 0);
+
+        // Block 0 carries the genesis hash, which survives a restart
+        assert_eq!(blockchain.get_chain()[0].hash, genesis.hash().unwrap());
+        assert_eq!(blockchain.genesis_hash(), Some(genesis.hash().unwrap()));
+        drop(blockchain);
+        let loaded = CommunityBlockchain::load_from_store(store).unwrap();
+        assert_eq!(loaded.get_validator_stats().len(), 1);
+
+        // The same genesis as JSON gives the same chain
+        let json_path = dir.path().join("genesis.json");
+        std::fs::write(&json_path, serde_json::to_string(&genesis).unwrap()).unwrap();
+        assert_eq!(Genesis::load(&json_path).unwrap().hash(), genesis.hash());
+
+        // The node refuses a stored chain made from another genesis file
+        let args = crate::startup::NodeArgs::try_parse_from(["node", "--genesis", toml_path.to_str().unwrap()]).unwrap();
+        assert!(args.check_loaded(&loaded).is_ok());
+        let other = CommunityBlockchain::new_in_memory(demo_wallets()).unwrap();
+        assert!(args.check_loaded(&other).unwrap_err().contains("genesis hash"));
+        assert!(GenesisArgs::try_parse_from(["node", "--genesis", "genesis.toml", "--demo"]).is_err());
+
+        // Unknown keys and duplicate validators are refused
+        std::fs::write(&json_path, r#"{"chain_id": "x", "balance": {"alice": 1}}"#).unwrap();
+        assert!(Genesis::load(&json_path).is_err());
+        let mut twice = genesis.clone();
+        twice.validators.push(twice.validators[0].clone());
+        assert!(twice.wallets().unwrap_err().contains("listed twice"));
+        let mut keyless = genesis.clone();
+        keyless.validators[0].address = "validator_1".to_string();
+        assert!(keyless.wallets().unwrap_err().contains("public key"));
+    }
+
+    #[test]
+    fn test_chains_from_different_genesis_do_not_link() {
+        let genesis = |chain_id: &str| Genesis {
+            chain_id: chain_id.to_string(),
+            block_time_secs: None,
+            balances: [("alice".to_string(), 1000)].into_iter().collect(),
+            validators: Vec::new(),
+            params: ChainParams::demo(),
+        };
+        let ours = CommunityBlockchain::from_genesis(&genesis("net-a"), Arc::new(MemoryStore::new())).unwrap();
+        let same = CommunityBlockchain::from_genesis(&genesis("net-a"), Arc::new(MemoryStore::new())).unwrap();
+        let theirs = CommunityBlockchain::from_genesis(&genesis("net-b"), Arc::new(MemoryStore::new())).unwrap();
+        assert_ne!(ours.get_chain()[0].hash, theirs.get_chain()[0].hash);
+
+        ours.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = ours.mine_block("proposer".to_string()).unwrap();
+        ours.add_block(block.clone()).unwrap();
+        assert!(theirs.add_block(block.clone()).is_err());
+        same.add_block(block).unwrap();
+        assert_eq!(same.state_root(), ours.state_root());
+    }
+}
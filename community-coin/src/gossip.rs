@@ -0,0 +1,515 @@
+//! Gossip messages, size caps on what peers may send, announcement of new
+//! blocks and periodic rebroadcast of stuck mempool transactions.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::RwLock;
+
+use crate::blockchain::{Block, CommunityBlockchain, Transaction};
+use crate::peers::PeerManager;
+use crate::settlement_layer::BlockVote;
+
+/// Reputation a peer loses for each message over the size caps
+pub const OVERSIZE_PENALTY: i64 = 10;
+
+/// Messages exchanged with peers over gossip.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GossipMessage {
+    NewTransaction(Transaction),
+    NewBlock(Block),
+    BlockVote(BlockVote),
+}
+
+/// Largest encoded gossip messages accepted from peers. Anything bigger is
+/// dropped before it is deserialized, going by the kind its envelope names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GossipLimits {
+    /// Cap on transaction and vote messages
+    pub max_transaction_bytes: usize,
+    /// Cap on block messages
+    pub max_block_bytes: usize,
+}
+
+impl Default for GossipLimits {
+    fn default() -> Self {
+        GossipLimits {
+            max_transaction_bytes: 16 * 1024,
+            max_block_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl GossipLimits {
+    /// Largest message of any kind
+    pub fn max_message_bytes(&self) -> usize {
+        self.max_transaction_bytes.max(self.max_block_bytes)
+    }
+
+    /// Gossipsub settings whose `max_transmit_size` matches these caps, so
+    /// the transport refuses oversize frames too. Message ids hash the
+    /// content, so a block or transaction published again, by us or another
+    /// peer, isn't delivered twice.
+    pub fn gossipsub_config(&self) -> Result<libp2p::gossipsub::Config, String> {
+        libp2p::gossipsub::ConfigBuilder::default()
+            .max_transmit_size(self.max_message_bytes())
+            .message_id_fn(|message: &libp2p::gossipsub::Message| {
+                libp2p::gossipsub::MessageId::from(hex::encode(sha2::Sha256::digest(&message.data)))
+            })
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Decodes gossip from peers, enforcing the size caps, and applies it to
+/// the chain.
+pub struct GossipHandler {
+    limits: GossipLimits,
+    peers: Arc<PeerManager>,
+}
+
+impl GossipHandler {
+    pub fn new(limits: GossipLimits, peers: Arc<PeerManager>) -> Self {
+        GossipHandler { limits, peers }
+    }
+
+    /// Decode a JSON message from `peer`. The cap for its kind, read from
+    /// the envelope's leading `{"Kind":`, is checked before the message is
+    /// parsed; one over it is dropped and costs the peer `OVERSIZE_PENALTY`
+    /// reputation.
+    pub fn decode(&self, peer: &str, bytes: &[u8]) -> Result<GossipMessage, String> {
+        let cap = match message_kind(bytes) {
+            Some(b"NewBlock") => self.limits.max_block_bytes,
+            Some(b"NewTransaction" | b"BlockVote") => self.limits.max_transaction_bytes,
+            _ => return Err(format!("Malformed gossip from {}: unknown message kind", peer)),
+        };
+        if bytes.len() > cap {
+            return Err(self.reject_oversize(peer, bytes.len(), cap));
+        }
+        serde_json::from_slice(bytes).map_err(|e| format!("Malformed gossip from {}: {}", peer, e))
+    }
+
+    fn reject_oversize(&self, peer: &str, len: usize, cap: usize) -> String {
+        self.peers.penalize(peer, OVERSIZE_PENALTY);
+        format!("Dropped {}-byte gossip from {}: cap is {} bytes", len, peer, cap)
+    }
+
+    /// Decode a message from `peer` and apply it to the chain
+    pub async fn handle(
+        &self,
+        blockchain: &RwLock<CommunityBlockchain>,
+        peer: &str,
+        bytes: &[u8],
+    ) -> Result<(), String> {
+        match self.decode(peer, bytes)? {
+            GossipMessage::NewTransaction(tx) => blockchain.read().await.accept_transaction(tx),
+            GossipMessage::NewBlock(block) => blockchain.write().await.add_block(block),
+            GossipMessage::BlockVote(vote) => blockchain.read().await.submit_block_vote(vote).map(|_| ()),
+        }
+    }
+}
+
+/// The variant an encoded [`GossipMessage`] names, read from its leading
+/// `{"Kind":` without parsing the rest
+fn message_kind(bytes: &[u8]) -> Option<&[u8]> {
+    let rest = bytes.trim_ascii_start().strip_prefix(b"{")?.trim_ascii_start().strip_prefix(b"\"")?;
+    let end = rest.iter().position(|byte| *byte == b'"')?;
+    Some(&rest[..end])
+}
+
+/// Somewhere gossip messages can be published, e.g. a gossipsub topic.
+#[async_trait::async_trait]
+pub trait GossipSink: Send + Sync {
+    /// Publish `message`, returning how many peers it was forwarded to,
+    /// e.g. the topic's mesh peers for gossipsub. A transport that
+    /// publishes in the background may only know how many it will reach.
+    fn broadcast(&self, message: GossipMessage) -> Result<usize, String>;
+
+    /// [`Self::broadcast`], waiting for the transport to report how many
+    /// peers the message actually went out to
+    async fn publish(&self, message: GossipMessage) -> Result<usize, String> {
+        self.broadcast(message)
+    }
+}
+
+/// What gossipsub's `PublishError::InsufficientPeers` looks like once
+/// turned into a string by a sink
+pub const INSUFFICIENT_PEERS: &str = "InsufficientPeers";
+
+/// Most messages held back for want of peers before the oldest are dropped
+pub const DEFAULT_MAX_QUEUED_BROADCASTS: usize = 1024;
+
+/// Wraps a sink so that publishing with no peers connected is not an
+/// error: the message is queued, a warning logged, and it goes out again
+/// from `peers_connected`. Other errors are passed through.
+pub struct QueuedSink {
+    inner: Arc<dyn GossipSink>,
+    queue: Mutex<VecDeque<GossipMessage>>,
+    max_queued: usize,
+}
+
+impl QueuedSink {
+    pub fn new(inner: Arc<dyn GossipSink>, max_queued: usize) -> Self {
+        QueuedSink {
+            inner,
+            queue: Mutex::new(VecDeque::new()),
+            max_queued,
+        }
+    }
+
+    fn enqueue(&self, message: GossipMessage) {
+        let mut queue = self.queue.lock().unwrap();
+        // One warning per stretch without peers rather than one per message
+        if queue.is_empty() {
+            tracing::warn!("No gossip peers connected, queueing broadcasts until one joins");
+        }
+        if queue.len() >= self.max_queued {
+            queue.pop_front();
+        }
+        queue.push_back(message);
+    }
+
+    /// Queue `message` if publishing it reached no peer
+    fn queue_unsent(&self, message: GossipMessage, result: Result<usize, String>) -> Result<usize, String> {
+        match result {
+            Ok(0) => {
+                self.enqueue(message);
+                Ok(0)
+            }
+            Err(e) if e.contains(INSUFFICIENT_PEERS) => {
+                self.enqueue(message);
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+
+    /// Messages waiting for peers
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Retry the queued messages, oldest first, e.g. when a peer connects.
+    /// Whatever still reaches nobody stays queued. Returns how many went out.
+    pub fn peers_connected(&self) -> usize {
+        let queued: Vec<GossipMessage> = self.queue.lock().unwrap().drain(..).collect();
+        let mut sent = 0;
+        for message in queued {
+            match self.broadcast(message) {
+                Ok(count) if count > 0 => sent += 1,
+                _ => {}
+            }
+        }
+        sent
+    }
+}
+
+#[async_trait::async_trait]
+impl GossipSink for QueuedSink {
+    fn broadcast(&self, message: GossipMessage) -> Result<usize, String> {
+        let result = self.inner.broadcast(message.clone());
+        self.queue_unsent(message, result)
+    }
+
+    async fn publish(&self, message: GossipMessage) -> Result<usize, String> {
+        let result = self.inner.publish(message.clone()).await;
+        self.queue_unsent(message, result)
+    }
+}
+
+/// How far a transaction submitted to this node was gossiped: to some
+/// number of peers, or nowhere because none are connected. Serializes as
+/// the peer count or `"local-only"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    Peers(usize),
+    LocalOnly,
+}
+
+impl Serialize for Propagation {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Propagation::Peers(count) => serializer.serialize_u64(*count as u64),
+            Propagation::LocalOnly => serializer.serialize_str("local-only"),
+        }
+    }
+}
+
+/// Gossip a transaction accepted from a client, once the transport has
+/// published it. Without a sink, or when publishing reaches no peer, it
+/// stays local until rebroadcast.
+pub async fn announce_transaction(sink: Option<&dyn GossipSink>, tx: Transaction) -> Propagation {
+    let Some(sink) = sink else {
+        return Propagation::LocalOnly;
+    };
+    match sink.publish(GossipMessage::NewTransaction(tx)).await {
+        Ok(count) if count > 0 => Propagation::Peers(count),
+        _ => Propagation::LocalOnly,
+    }
+}
+
+/// How aggressively stuck transactions are rebroadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebroadcastConfig {
+    /// Seconds a transaction must sit unmined since its last broadcast
+    pub interval_secs: u64,
+    /// Most transactions rebroadcast per tick, oldest first
+    pub max_per_tick: usize,
+    /// Times a single transaction is rebroadcast before giving up
+    pub max_attempts: u32,
+}
+
+impl Default for RebroadcastConfig {
+    fn default() -> Self {
+        RebroadcastConfig {
+            interval_secs: 60,
+            max_per_tick: 50,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Tracks when each pending transaction was last broadcast.
+#[derive(Debug, Default)]
+pub struct Rebroadcaster {
+    config: RebroadcastConfig,
+    /// tx_id -> (last broadcast timestamp, rebroadcasts so far)
+    sent: HashMap<String, (u64, u32)>,
+}
+
+impl Rebroadcaster {
+    pub fn new(config: RebroadcastConfig) -> Self {
+        Rebroadcaster {
+            config,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Rebroadcast the oldest pending transactions that have been quiet for
+    /// at least the interval. Transactions no longer pending (mined or
+    /// dropped) stop being tracked. Returns how many were sent.
+    pub fn tick(&mut self, pending: &[Transaction], sink: &dyn GossipSink, now: u64) -> usize {
+        self.sent.retain(|tx_id, _| pending.iter().any(|tx| &tx.tx_id == tx_id));
+
+        let mut due: Vec<&Transaction> = pending
+            .iter()
+            .filter(|tx| {
+                let (last_sent, attempts) =
+                    self.sent.get(&tx.tx_id).copied().unwrap_or((tx.timestamp, 0));
+                attempts < self.config.max_attempts
+                    && now.saturating_sub(last_sent) >= self.config.interval_secs
+            })
+            .collect();
+        due.sort_by_key(|tx| tx.timestamp);
+
+        let mut sent = 0;
+        for tx in due.into_iter().take(self.config.max_per_tick) {
+            if sink.broadcast(GossipMessage::NewTransaction(tx.clone())).is_err() {
+                continue;
+            }
+            let entry = self.sent.entry(tx.tx_id.clone()).or_insert((tx.timestamp, 0));
+            *entry = (now, entry.1 + 1);
+            sent += 1;
+        }
+        sent
+    }
+
+    /// Number of transactions currently tracked
+    pub fn tracked(&self) -> usize {
+        self.sent.len()
+    }
+}
+
+/// Gossip every block the chain accepts, as `blocks` receives them, until
+/// the chain is dropped
+pub async fn announce_blocks(mut blocks: broadcast::Receiver<Block>, sink: Arc<dyn GossipSink>) {
+    loop {
+        match blocks.recv().await {
+            Ok(block) => {
+                let _ = sink.broadcast(GossipMessage::NewBlock(block));
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Run the rebroadcaster against a node's mempool until the task is dropped.
+pub async fn run_rebroadcast(
+    blockchain: Arc<RwLock<CommunityBlockchain>>,
+    sink: Arc<dyn GossipSink>,
+    config: RebroadcastConfig,
+) {
+    let mut rebroadcaster = Rebroadcaster::new(config);
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        let pending = blockchain.read().await.get_pending();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        rebroadcaster.tick(&pending, sink.as_ref(), now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct MockSink {
+        sent: Mutex<Vec<GossipMessage>>,
+    }
+
+    impl GossipSink for MockSink {
+        fn broadcast(&self, message: GossipMessage) -> Result<usize, String> {
+            self.sent.lock().unwrap().push(message);
+            Ok(1)
+        }
+    }
+
+    /// Fails like gossipsub does until `peers` is raised above zero
+    #[derive(Default)]
+    struct PeerlessSink {
+        peers: AtomicUsize,
+        sent: Mutex<Vec<GossipMessage>>,
+    }
+
+    impl GossipSink for PeerlessSink {
+        fn broadcast(&self, message: GossipMessage) -> Result<usize, String> {
+            match self.peers.load(Ordering::SeqCst) {
+                0 => Err(format!("Publish failed: {}", INSUFFICIENT_PEERS)),
+                peers => {
+                    self.sent.lock().unwrap().push(message);
+                    Ok(peers)
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_without_peers_is_queued_until_one_connects() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let tx_id = blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let tx = blockchain.get_pending()[0].clone();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+
+        let transport = Arc::new(PeerlessSink::default());
+        let sink = QueuedSink::new(transport.clone(), 2);
+        assert_eq!(announce_transaction(Some(&sink), tx.clone()).await, Propagation::LocalOnly);
+        assert_eq!(sink.broadcast(GossipMessage::NewBlock(block)), Ok(0));
+        assert_eq!(sink.queued(), 2);
+        assert!(transport.sent.lock().unwrap().is_empty());
+
+        // Still nobody to send to: the messages stay queued
+        assert_eq!(sink.peers_connected(), 0);
+        assert_eq!(sink.queued(), 2);
+
+        // The queue is capped, dropping the oldest
+        assert_eq!(sink.broadcast(GossipMessage::NewTransaction(tx)), Ok(0));
+        assert_eq!(sink.queued(), 2);
+
+        transport.peers.store(3, Ordering::SeqCst);
+        assert_eq!(sink.peers_connected(), 2);
+        assert_eq!(sink.queued(), 0);
+        let sent = transport.sent.lock().unwrap();
+        assert!(matches!(&sent[0], GossipMessage::NewBlock(block) if block.transactions[0].tx_id == tx_id));
+        assert!(matches!(&sent[1], GossipMessage::NewTransaction(tx) if tx.tx_id == tx_id));
+    }
+
+    #[test]
+    fn test_stuck_transaction_is_rebroadcast() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 100)
+            .unwrap();
+        let submitted = blockchain.get_pending()[0].timestamp;
+
+        let sink = MockSink::default();
+        let config = RebroadcastConfig {
+            interval_secs: 30,
+            max_per_tick: 10,
+            max_attempts: 2,
+        };
+        let mut rebroadcaster = Rebroadcaster::new(config);
+
+        // Not stuck yet
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 29), 0);
+
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 30), 1);
+        match &sink.sent.lock().unwrap()[0] {
+            GossipMessage::NewTransaction(tx) => assert_eq!(tx.from, "alice"),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // Waits another interval, and stops after max_attempts
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 45), 0);
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 60), 1);
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 90), 0);
+
+        // Mined transactions are forgotten
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(rebroadcaster.tick(&blockchain.get_pending(), &sink, submitted + 120), 0);
+        assert_eq!(rebroadcaster.tracked(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversize_gossip_is_dropped_unapplied() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let source = CommunityBlockchain::new_in_memory(initial.clone()).unwrap();
+        source.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = source.mine_block("proposer".to_string()).unwrap();
+        let tx = block.transactions[0].clone();
+        let bytes = serde_json::to_vec(&GossipMessage::NewBlock(block)).unwrap();
+
+        let blockchain = RwLock::new(CommunityBlockchain::new_in_memory(initial).unwrap());
+        let peers = Arc::new(PeerManager::default());
+        let limits = GossipLimits {
+            max_transaction_bytes: 64,
+            max_block_bytes: bytes.len() - 1,
+        };
+        assert_eq!(limits.gossipsub_config().unwrap().max_transmit_size(), bytes.len() - 1);
+        let handler = GossipHandler::new(limits, peers.clone());
+
+        let err = handler.handle(&blockchain, "peer-1", &bytes).await.unwrap_err();
+        assert!(err.starts_with("Dropped"), "{}", err);
+        assert_eq!(peers.reputation("peer-1"), -OVERSIZE_PENALTY);
+        assert_eq!(blockchain.read().await.get_chain().len(), 1);
+        assert_eq!(blockchain.read().await.get_balance("alice").unwrap(), 1000);
+
+        // Transactions are held to their own, smaller cap
+        let tx_bytes = serde_json::to_vec(&GossipMessage::NewTransaction(tx)).unwrap();
+        assert!(tx_bytes.len() > 64 && tx_bytes.len() < bytes.len());
+        assert!(handler.handle(&blockchain, "peer-1", &tx_bytes).await.is_err());
+        assert_eq!(peers.reputation("peer-1"), -2 * OVERSIZE_PENALTY);
+        assert!(blockchain.read().await.get_pending().is_empty());
+
+        // The cap is checked from the envelope, before any parsing
+        let mut junk = br#"{"NewTransaction":"#.to_vec();
+        junk.extend(std::iter::repeat_n(b'[', 100));
+        assert!(handler.decode("peer-3", &junk).unwrap_err().starts_with("Dropped"));
+        assert!(handler.decode("peer-3", br#"{"Other":{}}"#).unwrap_err().contains("unknown message kind"));
+
+        // Within the caps the same messages go through
+        let roomy = GossipHandler::new(GossipLimits::default(), peers.clone());
+        roomy.handle(&blockchain, "peer-2", &tx_bytes).await.unwrap();
+        assert_eq!(blockchain.read().await.get_pending().len(), 1);
+        roomy.handle(&blockchain, "peer-2", &bytes).await.unwrap();
+        assert_eq!(blockchain.read().await.get_chain().len(), 2);
+        assert_eq!(peers.reputation("peer-2"), 0);
+    }
+}
@@ -0,0 +1,154 @@
+//! gRPC interface to the node, served next to the REST API on its own port
+//! and sharing its state.
+
+use std::pin::Pin;
+
+use axum::http::StatusCode;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use community_coin::blockchain;
+
+use crate::{submit_transfer, validate_address, AppState, TransferRequest};
+
+pub mod proto {
+    tonic::include_proto!("community_coin");
+}
+
+use proto::node_server::{Node, NodeServer};
+
+/// The `Node` gRPC service
+pub struct GrpcNode {
+    state: AppState,
+}
+
+impl GrpcNode {
+    pub fn new(state: AppState) -> Self {
+        GrpcNode { state }
+    }
+}
+
+#[tonic::async_trait]
+impl Node for GrpcNode {
+    async fn get_balance(
+        &self,
+        request: Request<proto::GetBalanceRequest>,
+    ) -> Result<Response<proto::Wallet>, Status> {
+        let address = request.into_inner().address;
+        validate_address(&address).map_err(Status::invalid_argument)?;
+
+        let blockchain = self.state.blockchain.read().await;
+        let wallet = blockchain.get_wallet(&address).map_err(Status::not_found)?;
+        Ok(Response::new(proto::Wallet {
+            address: wallet.address,
+            balance: wallet.balance,
+            tx_count: wallet.tx_count,
+            created_at: wallet.created_at,
+            next_nonce: blockchain.next_nonce(&address),
+        }))
+    }
+
+    /// Submitted as `/transfer` is, refused with the gRPC code matching its
+    /// HTTP status
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::TransferRequest>,
+    ) -> Result<Response<proto::TransferResponse>, Status> {
+        let req = request.into_inner();
+        let transfer = TransferRequest {
+            from: req.from,
+            to: req.to,
+            amount: req.amount,
+            memo: req.memo,
+            execute_at_height: req.execute_at_height,
+            pow_nonce: req.pow_nonce,
+            recent_block_hash: req.recent_block_hash,
+            fee: req.fee,
+        };
+        let (tx_id, _) = submit_transfer(&self.state, transfer)
+            .await
+            .map_err(|(status, e)| match status {
+                StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(e),
+                StatusCode::FORBIDDEN => Status::permission_denied(e),
+                StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(e),
+                _ => Status::invalid_argument(e),
+            })?;
+
+        Ok(Response::new(proto::TransferResponse {
+            tx_id,
+            status: "pending".to_string(),
+        }))
+    }
+
+    async fn get_block(
+        &self,
+        request: Request<proto::GetBlockRequest>,
+    ) -> Result<Response<proto::Block>, Status> {
+        let index = request.into_inner().index;
+        let blockchain = self.state.blockchain.read().await;
+        blockchain
+            .get_block_by_index(index)
+            .map(|block| Response::new(block.into()))
+            .ok_or_else(|| Status::not_found(format!("Block {} not found", index)))
+    }
+
+    type SubscribeBlocksStream = Pin<Box<dyn Stream<Item = Result<proto::Block, Status>> + Send>>;
+
+    async fn subscribe_blocks(
+        &self,
+        _request: Request<proto::SubscribeBlocksRequest>,
+    ) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        let blocks = self.state.blockchain.read().await.subscribe_blocks();
+        // A lagging subscriber skips the blocks it missed
+        let stream = BroadcastStream::new(blocks)
+            .filter_map(Result::ok)
+            .map(proto::Block::from)
+            .map(Ok);
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl From<blockchain::Transaction> for proto::Transaction {
+    fn from(tx: blockchain::Transaction) -> Self {
+        proto::Transaction {
+            from: tx.from,
+            to: tx.to,
+            amount: tx.amount,
+            fee: tx.fee,
+            timestamp: tx.timestamp,
+            tx_id: tx.tx_id,
+            signature: tx.signature,
+            nonce: tx.nonce,
+            memo: tx.memo,
+            execute_at_height: tx.execute_at_height,
+            recent_block_hash: tx.recent_block_hash,
+            sig_scheme: tx.sig_scheme.as_str().to_string(),
+        }
+    }
+}
+
+impl From<blockchain::Block> for proto::Block {
+    fn from(block: blockchain::Block) -> Self {
+        proto::Block {
+            index: block.index,
+            timestamp: block.timestamp,
+            transactions: block.transactions.into_iter().map(Into::into).collect(),
+            prev_hash: block.prev_hash,
+            hash: block.hash,
+            proposer: block.proposer,
+            state_root: block.state_root,
+            tx_root: block.tx_root,
+            difficulty: block.difficulty,
+            pow_nonce: block.pow_nonce,
+        }
+    }
+}
+
+/// Serve the gRPC service on `listener` until the process exits
+pub async fn serve(state: AppState, listener: tokio::net::TcpListener) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(NodeServer::new(GrpcNode::new(state)))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
@@ -4,8 +4,30 @@
 //! persistence, and security.
 
 pub mod abi;
+pub mod access;
+pub mod amount;
+pub mod config;
+pub mod contract;
+pub mod drip;
+pub mod genesis;
+pub mod gossip;
+pub mod mempool;
+pub mod merkle;
+pub mod message;
+pub mod p2p;
+pub mod peers;
+pub mod pow;
 pub mod vm;
 pub mod settlement_layer;
+pub mod snapshot;
+pub mod startup;
+pub mod state_tree;
 pub mod blockchain;
+#[cfg(test)]
+pub mod replay;
+pub mod storage;
+pub mod sync;
+pub mod timesync;
+pub mod webhooks;
 
 // ... (rest of the file)
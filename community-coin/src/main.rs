@@ -1,17 +1,32 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use dashmap::DashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use community_coin::blockchain::{self, CommunityBlockchain};
+mod grpc;
+
+use community_coin::access::AccessList;
+use community_coin::config::NodeConfig;
+use community_coin::gossip::{self, GossipSink};
+use community_coin::mempool;
+use community_coin::p2p::{NetworkService, P2pArgs};
+use community_coin::peers::PeerManager;
+use community_coin::settlement_layer::BlockVote;
+use community_coin::timesync::{ClockMonitor, DEFAULT_CLOCK_SKEW_THRESHOLD_SECS};
+use community_coin::webhooks::Webhooks;
+use community_coin::blockchain::{self, CommunityBlockchain, Transaction, RESERVED_ADDRESSES};
 
 /// Rate limiter
 #[derive(Clone)]
@@ -44,6 +59,12 @@ impl RateLimiter {
     }
 }
 
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Leaderboard cache
 #[derive(Clone)]
 pub struct LeaderboardCache {
@@ -85,6 +106,29 @@ impl LeaderboardCache {
 pub struct AppState {
     blockchain: Arc<RwLock<CommunityBlockchain>>,
     leaderboard_cache: LeaderboardCache,
+    rate_limiter: RateLimiter,
+    search_rate_limit: f64,
+    max_page_size: usize,
+    max_history_len: usize,
+    peers: Arc<PeerManager>,
+    clock_monitor: Arc<ClockMonitor>,
+    admin_token: Option<String>,
+    snapshot_key: Option<ed25519_dalek::SigningKey>,
+    node_key: Option<ed25519_dalek::SigningKey>,
+    reindex: Arc<std::sync::Mutex<ReindexProgress>>,
+    maintenance: Arc<std::sync::atomic::AtomicBool>,
+    webhooks: Webhooks,
+    gossip: Option<Arc<dyn GossipSink>>,
+    #[cfg(feature = "testnet")]
+    drip: community_coin::drip::FaucetDrip,
+}
+
+/// Progress of the latest `/admin/reindex` run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReindexProgress {
+    pub running: bool,
+    pub blocks_scanned: u64,
+    pub blocks_total: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -92,6 +136,135 @@ pub struct TransferRequest {
     pub from: String,
     pub to: String,
     pub amount: u64,
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// Hold the transfer until the chain reaches this height
+    #[serde(default)]
+    pub execute_at_height: Option<u64>,
+    /// Proof of work, required when `/version` reports a `pow_difficulty`
+    #[serde(default)]
+    pub pow_nonce: Option<u64>,
+    /// Bind the transfer to this block, which must be one of the chain's
+    /// latest `recent_block_window`
+    #[serde(default)]
+    pub recent_block_hash: Option<String>,
+    /// Fee to pay instead of the node's estimate, at least `MIN_FEE`
+    #[serde(default)]
+    pub fee: Option<u64>,
+}
+
+/// One leg of a batch transfer
+#[derive(Serialize, Deserialize)]
+pub struct BatchLeg {
+    pub to: String,
+    pub amount: u64,
+}
+
+/// Several transfers from one sender, accepted or rejected together
+#[derive(Serialize, Deserialize)]
+pub struct BatchTransferRequest {
+    pub from: String,
+    pub transfers: Vec<BatchLeg>,
+}
+
+/// `?cursor=&limit=` or `?offset=&limit=` for `/history`. Without a cursor
+/// the newest transactions come first, while `offset` pages oldest first;
+/// `limit` is clamped to the history cap.
+#[derive(Serialize, Deserialize, Default)]
+pub struct HistoryQuery {
+    pub cursor: Option<u64>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// `?offset=&limit=` for bulk endpoints. `limit` defaults to the page cap.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PageQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl PageQuery {
+    /// The effective limit, or a 400 body telling the client to paginate
+    fn limit(&self, max_page_size: usize) -> Result<usize, serde_json::Value> {
+        match self.limit {
+            Some(limit) if limit > max_page_size => Err(json!({
+                "error": format!(
+                    "limit {} exceeds the maximum page size of {}; request smaller pages with ?offset=&limit=",
+                    limit, max_page_size
+                ),
+                "max_limit": max_page_size,
+            })),
+            Some(limit) => Ok(limit),
+            None => Ok(max_page_size),
+        }
+    }
+}
+
+/// `?amount=&memo_len=` for `/estimate-fee`
+#[derive(Serialize, Deserialize)]
+pub struct FeeQuery {
+    pub amount: u64,
+    #[serde(default)]
+    pub memo_len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(default)]
+    pub page: usize,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    pub include_system: bool,
+}
+
+/// A signed personal message, with hex-encoded key and signature
+#[derive(Serialize, Deserialize)]
+pub struct VerifyMessageRequest {
+    pub public_key: String,
+    pub message: String,
+    pub signature: String,
+}
+
+/// Deploy a contract from hex-encoded wasm (binary or text), optionally
+/// with an ABI describing its exported functions
+#[derive(Serialize, Deserialize)]
+pub struct DeployContractRequest {
+    pub sender: String,
+    pub code: String,
+    #[serde(default)]
+    pub admin: Option<String>,
+    #[serde(default)]
+    pub abi: Option<community_coin::contract::ContractAbi>,
+}
+
+/// Call a deployed contract's exported function
+#[derive(Serialize, Deserialize)]
+pub struct CallContractRequest {
+    pub sender: String,
+    pub contract: String,
+    pub function: String,
+    pub gas_limit: u64,
+}
+
+/// Call a contract function without changing state
+#[derive(Serialize, Deserialize)]
+pub struct ViewContractRequest {
+    pub contract: String,
+    pub function: String,
+    pub gas_limit: u64,
+}
+
+/// Register an externally generated ed25519 public key as a wallet
+#[derive(Serialize, Deserialize)]
+pub struct RegisterWalletRequest {
+    /// Hex-encoded 32-byte public key, which becomes the address
+    pub public_key: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -99,6 +272,47 @@ pub struct MineBlockRequest {
     pub proposer: String,
 }
 
+#[cfg(feature = "testnet")]
+#[derive(Serialize, Deserialize)]
+pub struct SupplyRequest {
+    pub address: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessListKind {
+    Allow,
+    Deny,
+}
+
+/// Add an address to (or, with `remove`, take it off) the allowlist or denylist
+#[derive(Serialize, Deserialize)]
+pub struct AccessListRequest {
+    pub list: AccessListKind,
+    pub address: String,
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// A JSON-encoded transaction in hex or base64, for `/tx/decode`
+#[derive(Serialize, Deserialize)]
+pub struct DecodeTransactionRequest {
+    pub raw: String,
+}
+
+/// Turn maintenance mode on or off
+#[derive(Serialize, Deserialize)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+}
+
+/// Replace the webhook URLs notified of new blocks
+#[derive(Serialize, Deserialize)]
+pub struct WebhooksRequest {
+    pub urls: Vec<String>,
+}
+
 /// Validators
 fn validate_address(addr: &str) -> Result<(), String> {
     if addr.is_empty() || addr.len() > 255 {
@@ -114,33 +328,355 @@ fn validate_amount(amount: u64) -> Result<(), String> {
     if amount == 0 {
         return Err("Amount must be > 0".to_string());
     }
-    if amount > 1_000_000_000_000 {
-        return Err("Amount exceeds limit".to_string());
+    // The upper bound is the chain's `max_tx_amount`, enforced by the blockchain
+    Ok(())
+}
+
+/// Check the `Authorization: Bearer <token>` header against the admin token.
+/// Admin endpoints are disabled entirely when no token is configured.
+fn authorize_admin(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let expected = match &state.admin_token {
+        Some(token) => token,
+        None => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "Admin endpoints are disabled"})),
+            ))
+        }
+    };
+
+    let provided = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if provided != Some(expected.as_str()) {
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "Unauthorized"}))));
     }
     Ok(())
 }
 
+/// Error writes get while the node is in maintenance mode
+pub const MAINTENANCE_MODE: &str = "Node is in maintenance mode, writes are paused";
+
+/// Paths taking a POST that changes nothing, or that must keep working in
+/// maintenance mode to end it
+const MAINTENANCE_EXEMPT: &[&str] = &["/admin/maintenance", "/verify-message", "/tx/decode"];
+
+/// Refuse every write while the node is in maintenance mode: any request
+/// but a GET or HEAD, unless its path is in `MAINTENANCE_EXEMPT`
+async fn pause_in_maintenance(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let read = matches!(*request.method(), axum::http::Method::GET | axum::http::Method::HEAD);
+    if state.maintenance.load(std::sync::atomic::Ordering::SeqCst)
+        && !read
+        && !MAINTENANCE_EXEMPT.contains(&request.uri().path())
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"error": MAINTENANCE_MODE}))).into_response();
+    }
+    next.run(request).await
+}
+
+/// Prefix of the bytes signed for `X-Node-Signature`, so a response
+/// signature cannot pass for any other signature the node makes
+pub const RESPONSE_SIGNATURE_DOMAIN: &[u8] = b"community-coin response\n";
+
+/// Sign every response body with the node key, when one is set, and send
+/// the hex signature over `RESPONSE_SIGNATURE_DOMAIN || body` in the
+/// `X-Node-Signature` header
+async fn sign_responses(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let response = next.run(request).await;
+    let Some(key) = &state.node_key else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))).into_response();
+        }
+    };
+    let mut signed = RESPONSE_SIGNATURE_DOMAIN.to_vec();
+    signed.extend_from_slice(&bytes);
+    let signature = ed25519_dalek::Signer::sign(key, &signed);
+    parts.headers.insert(
+        "x-node-signature",
+        axum::http::HeaderValue::from_str(&hex::encode(signature.to_bytes())).unwrap(),
+    );
+    axum::response::Response::from_parts(parts, axum::body::Body::from(bytes))
+}
+
+/// Re-indent JSON responses to requests carrying `?pretty=true`, for reading
+/// by hand. Everything else passes through compact and untouched.
+async fn pretty_json(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let pretty = request
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|pair| pair == "pretty=true"));
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !pretty || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))).into_response();
+        }
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap().into(),
+        Err(_) => bytes,
+    };
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+}
+
 /// Transfer endpoint
 pub async fn transfer(
     State(state): State<AppState>,
     Json(req): Json<TransferRequest>,
 ) -> (StatusCode, Json<serde_json::Value>) {
-    if let Err(e) = validate_address(&req.from) {
-        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    match submit_transfer(&state, req).await {
+        Ok((tx_id, propagation)) => (
+            StatusCode::OK,
+            Json(json!({"success": true, "tx_id": tx_id, "status": "pending", "propagation": propagation})),
+        ),
+        Err((status, e)) => (status, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Queue a transfer the node signs and announce it to peers, for `/transfer`
+/// and the gRPC `SubmitTransaction` alike. A refusal comes with the HTTP
+/// status `/transfer` answers it with.
+pub async fn submit_transfer(
+    state: &AppState,
+    req: TransferRequest,
+) -> Result<(String, gossip::Propagation), (StatusCode, String)> {
+    if state.maintenance.load(std::sync::atomic::Ordering::SeqCst) {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, MAINTENANCE_MODE.to_string()));
+    }
+    validate_address(&req.from).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    validate_address(&req.to).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    validate_amount(req.amount).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let blockchain = state.blockchain.write().await;
+    match blockchain.create_transaction_with_options(
+        req.from,
+        req.to,
+        req.amount,
+        blockchain::TransferOptions {
+            memo: req.memo,
+            execute_at_height: req.execute_at_height,
+            recent_block_hash: req.recent_block_hash,
+            pow_nonce: req.pow_nonce,
+            fee: req.fee,
+            contract: None,
+        },
+    ) {
+        Ok(tx_id) => {
+            let tx = blockchain.get_pending().into_iter().find(|tx| tx.tx_id == tx_id);
+            drop(blockchain);
+            state.leaderboard_cache.invalidate().await;
+            let propagation = match tx {
+                Some(tx) => gossip::announce_transaction(state.gossip.as_deref(), tx).await,
+                None => gossip::Propagation::LocalOnly,
+            };
+            Ok((tx_id, propagation))
+        }
+        Err(e) => Err((rejection_status(&e), e)),
+    }
+}
+
+/// The HTTP status a node-signed transaction the node refused to queue is
+/// answered with
+fn rejection_status(error: &str) -> StatusCode {
+    if error.starts_with(blockchain::SENDER_RATE_LIMITED) {
+        StatusCode::TOO_MANY_REQUESTS
+    } else if error.starts_with(blockchain::CLIENT_SIGNING_REQUIRED) {
+        StatusCode::FORBIDDEN
+    } else if error.starts_with(mempool::MEMPOOL_FULL) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::BAD_REQUEST
     }
-    if let Err(e) = validate_address(&req.to) {
+}
+
+/// The transaction a stake, mint or burn was committed for. It closes its
+/// block, after the proposer's reward on chains that pay one.
+fn supply_tx_id(block: &blockchain::Block) -> &str {
+    block.transactions.last().map_or("", |tx| tx.tx_id.as_str())
+}
+
+/// Stake coins, or take stake back, in a block of its own with a
+/// transaction the staker signed. `unstake` picks the direction.
+async fn change_stake(state: AppState, tx: blockchain::Transaction, unstake: bool) -> (StatusCode, Json<serde_json::Value>) {
+    let staker = tx.signer().to_string();
+    if let Err(e) = validate_address(&staker).and_then(|_| validate_amount(tx.amount)) {
         return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
     }
-    if let Err(e) = validate_amount(req.amount) {
+
+    let blockchain = state.blockchain.write().await;
+    let result = match unstake {
+        false => blockchain.stake(tx),
+        true => blockchain.unstake(tx),
+    };
+    match result {
+        Ok(block) => {
+            let stake = blockchain
+                .get_validator_stats()
+                .into_iter()
+                .find(|validator| validator.address == staker)
+                .map_or(0, |validator| validator.stake);
+            drop(blockchain);
+            state.leaderboard_cache.invalidate().await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success": true,
+                    "tx_id": supply_tx_id(&block),
+                    "block_index": block.index,
+                    "stake": stake,
+                })),
+            )
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Stake coins to become a validator, with a transfer to the staking
+/// account signed by the sender's key
+pub async fn stake(
+    State(state): State<AppState>,
+    Json(tx): Json<blockchain::Transaction>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    change_stake(state, tx, false).await
+}
+
+/// Return staked coins to their owner's balance, with a transfer from the
+/// staking account signed by the owner's key
+pub async fn unstake(
+    State(state): State<AppState>,
+    Json(tx): Json<blockchain::Transaction>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    change_stake(state, tx, true).await
+}
+
+/// Queue a transaction the sender signed themselves, e.g. with the ed25519
+/// key of their account
+pub async fn submit_tx(
+    State(state): State<AppState>,
+    Json(tx): Json<blockchain::Transaction>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&tx.from).and_then(|_| validate_address(&tx.to)) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e})));
+    }
+
+    let tx_id = tx.tx_id.clone();
+    match state.blockchain.read().await.accept_transaction(tx.clone()) {
+        Ok(()) => {
+            state.leaderboard_cache.invalidate().await;
+            let propagation = gossip::announce_transaction(state.gossip.as_deref(), tx).await;
+            (
+                StatusCode::OK,
+                Json(json!({"success": true, "tx_id": tx_id, "status": "pending", "propagation": propagation})),
+            )
+        }
+        Err(e) if e.starts_with(mempool::MEMPOOL_FULL) => {
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"success": false, "error": e})))
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Batch transfer endpoint: every leg is queued, or none is. Queued legs
+/// are mined as separate transactions.
+pub async fn batch_transfer(
+    State(state): State<AppState>,
+    Json(req): Json<BatchTransferRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&req.from) {
         return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
     }
+    for leg in &req.transfers {
+        if let Err(e) = validate_address(&leg.to).and_then(|_| validate_amount(leg.amount)) {
+            return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+        }
+    }
 
+    let legs = req.transfers.into_iter().map(|leg| (leg.to, leg.amount)).collect();
     let blockchain = state.blockchain.write().await;
-    match blockchain.create_transaction(req.from, req.to, req.amount) {
-        Ok(tx_id) => {
+    match blockchain.create_batch_transaction(req.from, legs) {
+        Ok(tx_ids) => {
             state.leaderboard_cache.invalidate().await;
-            (StatusCode::OK, Json(json!({"success": true, "tx_id": tx_id, "status": "pending"})))
+            (StatusCode::OK, Json(json!({"success": true, "tx_ids": tx_ids, "status": "pending"})))
+        }
+        Err(e) if e.starts_with(blockchain::SENDER_RATE_LIMITED) => {
+            (StatusCode::TOO_MANY_REQUESTS, Json(json!({"success": false, "error": e})))
         }
+        Err(e) if e.starts_with(blockchain::CLIENT_SIGNING_REQUIRED) => {
+            (StatusCode::FORBIDDEN, Json(json!({"success": false, "error": e})))
+        }
+        Err(e) if e.starts_with(mempool::MEMPOOL_FULL) => {
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"success": false, "error": e})))
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Number of transactions involving an address, for paginating history
+pub async fn transaction_count(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.read().await;
+    (
+        StatusCode::OK,
+        Json(json!({
+            "address": address,
+            "count": blockchain.get_user_transaction_count(&address),
+        })),
+    )
+}
+
+/// Register an externally-owned wallet by its public key. The node never
+/// holds its private key, so transfers from it go through `/submit-tx`.
+pub async fn register_wallet(
+    State(state): State<AppState>,
+    Json(req): Json<RegisterWalletRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.write().await;
+    match blockchain.register_wallet(&req.public_key) {
+        Ok(wallet) => (
+            StatusCode::CREATED,
+            Json(json!({
+                "success": true,
+                "address": wallet.address,
+                "balance": wallet.balance,
+                "next_nonce": blockchain.next_nonce(&wallet.address),
+            })),
+        ),
+        Err(e) if e.starts_with("Wallet") => (StatusCode::CONFLICT, Json(json!({"success": false, "error": e}))),
         Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
     }
 }
@@ -161,24 +697,99 @@ pub async fn get_wallet(
             Json(json!({
                 "address": wallet.address,
                 "balance": wallet.balance,
+                "reserved": blockchain.reserved_balance(&address),
                 "tx_count": wallet.tx_count,
                 "created_at": wallet.created_at,
+                "next_nonce": blockchain.next_nonce(&address),
             })),
         ),
         Err(_) => (StatusCode::NOT_FOUND, Json(json!({"error": "Wallet not found"}))),
     }
 }
 
-/// Get leaderboard (cached)
+/// Nonce the next transaction from an address should carry
+pub async fn get_nonce(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.read().await;
+    (
+        StatusCode::OK,
+        Json(json!({"address": address, "nonce": blockchain.next_nonce(&address)})),
+    )
+}
+
+/// Look up a pending or confirmed transaction by id
+pub async fn get_transaction(
+    State(state): State<AppState>,
+    Path(tx_id): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    match blockchain.find_transaction(&tx_id) {
+        Some((tx, block)) => (
+            StatusCode::OK,
+            Json(json!({
+                "transaction": tx,
+                "status": if block.is_some() { "confirmed" } else { "pending" },
+                "block": block,
+            })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("Transaction {} not found", tx_id)})),
+        ),
+    }
+}
+
+/// Merkle proof that a confirmed transaction is in its block
+pub async fn transaction_proof(
+    State(state): State<AppState>,
+    Path(tx_id): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    match blockchain.get_merkle_proof(&tx_id) {
+        Some(proof) => (StatusCode::OK, Json(json!(proof))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No confirmed transaction {}", tx_id)})),
+        ),
+    }
+}
+
+/// Merkle proof of an address's balance and nonce against the state root
+pub async fn state_proof(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.read().await;
+    let (state_root, proof) = blockchain.get_state_proof(&address);
+    (
+        StatusCode::OK,
+        Json(json!({"block": blockchain.tip().index, "state_root": state_root, "proof": proof})),
+    )
+}
+
+/// Get leaderboard (cached). Reserved system accounts are left out unless
+/// `?include_system=true` is passed.
 pub async fn leaderboard(
     State(state): State<AppState>,
+    Query(query): Query<LeaderboardQuery>,
 ) -> (StatusCode, Json<Vec<serde_json::Value>>) {
     let blockchain = state.blockchain.read().await;
-    let wallets = blockchain.get_leaderboard();
+    let wallets = blockchain.get_leaderboard(&[]);
     let cached = state.leaderboard_cache.get_or_update(wallets).await;
 
     let result: Vec<_> = cached
         .iter()
+        .filter(|w| query.include_system || !RESERVED_ADDRESSES.contains(&w.address.as_str()))
         .enumerate()
         .map(|(rank, w)| {
             json!({
@@ -193,45 +804,88 @@ pub async fn leaderboard(
     (StatusCode::OK, Json(result))
 }
 
-/// Get transaction history (uses index for speed)
+/// Get transaction history (uses index for speed), newest first and never
+/// more than MAX_HISTORY_LEN transactions. A truncated response carries the
+/// `next_cursor` to pass as `?cursor=` for the older ones. `?offset=` pages
+/// oldest first instead, as before the cursor existed.
 pub async fn history(
     State(state): State<AppState>,
     Path(address): Path<String>,
-) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    Query(query): Query<HistoryQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
     if let Err(e) = validate_address(&address) {
-        return (StatusCode::BAD_REQUEST, Json(vec![json!({"error": e})]));
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
     }
+    let limit = query.limit.unwrap_or(state.max_history_len).min(state.max_history_len);
 
     let blockchain = state.blockchain.read().await;
-    let txs = blockchain.get_user_transactions(&address);
-
-    let result: Vec<_> = txs
-        .iter()
-        .map(|tx| {
-            json!({
-                "tx_id": tx.tx_id,
-                "from": tx.from,
-                "to": tx.to,
-                "amount": tx.amount,
-                "fee": tx.fee,
-                "timestamp": tx.timestamp,
-                "type": if tx.from == address { "sent" } else { "received" },
-            })
-        })
-        .collect();
+    if let Some(offset) = query.offset {
+        if query.cursor.is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Pass either cursor or offset, not both"})),
+            );
+        }
+        let txs = blockchain.get_user_transactions_page(&address, offset, limit);
+        let total = blockchain.get_user_transaction_count(&address);
+        let end = offset.saturating_add(limit) as u64;
+        return (
+            StatusCode::OK,
+            Json(json!({
+                "transactions": history_entries(&address, &txs),
+                "total": total,
+                "truncated": end < total,
+                "next_offset": (end < total).then_some(end),
+            })),
+        );
+    }
+    let page = blockchain.get_recent_user_transactions(&address, query.cursor, limit);
 
-    (StatusCode::OK, Json(result))
+    (
+        StatusCode::OK,
+        Json(json!({
+            "transactions": history_entries(&address, &page.transactions),
+            "truncated": page.truncated,
+            "next_cursor": page.next_cursor,
+            "pruned": page.pruned,
+        })),
+    )
 }
 
-/// Get pending transactions
-pub async fn pending(
+/// Search an address's history by memo substring (`?q=...&page=N`)
+pub async fn search_history(
     State(state): State<AppState>,
-) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(address): Path<String>,
+    Query(query): Query<SearchQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+    if query.q.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "Query must not be empty"})));
+    }
+    if !state
+        .rate_limiter
+        .check(format!("search:{}", client.ip()), state.search_rate_limit, 60)
+    {
+        return (StatusCode::TOO_MANY_REQUESTS, Json(json!({"error": "Rate limit exceeded"})));
+    }
+
     let blockchain = state.blockchain.read().await;
-    let pending_txs = blockchain.get_pending();
+    let txs = blockchain.search_transactions(&address, &query.q, query.page);
 
-    let result: Vec<_> = pending_txs
-        .iter()
+    (
+        StatusCode::OK,
+        Json(json!({
+            "page": query.page,
+            "transactions": history_entries(&address, &txs),
+        })),
+    )
+}
+
+fn history_entries(address: &str, txs: &[Transaction]) -> Vec<serde_json::Value> {
+    txs.iter()
         .map(|tx| {
             json!({
                 "tx_id": tx.tx_id,
@@ -240,13 +894,93 @@ pub async fn pending(
                 "amount": tx.amount,
                 "fee": tx.fee,
                 "timestamp": tx.timestamp,
+                "memo": tx.memo,
+                "type": if tx.from == address { "sent" } else { "received" },
             })
         })
-        .collect();
+        .collect()
+}
+
+/// Fee a transfer would be charged right now
+pub async fn estimate_fee(
+    State(state): State<AppState>,
+    Query(query): Query<FeeQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_amount(query.amount) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+    if query.memo_len > blockchain::MAX_MEMO_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Memo exceeds {} bytes", blockchain::MAX_MEMO_LEN)})),
+        );
+    }
+
+    let blockchain = state.blockchain.read().await;
+    (
+        StatusCode::OK,
+        Json(json!({
+            "amount": query.amount,
+            "memo_len": query.memo_len,
+            "fee": blockchain.estimate_fee(query.amount, query.memo_len),
+            "pending": blockchain.get_pending().len(),
+            "next_block_min_fee": blockchain.next_block_min_fee(),
+        })),
+    )
+}
+
+/// Mempool size, fees, limits and departures, and the fee floor of recent blocks
+pub async fn mempool_stats(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    let mut stats = json!(blockchain.mempool_stats());
+    stats["next_block_min_fee"] = json!(blockchain.next_block_min_fee());
+
+    (StatusCode::OK, Json(stats))
+}
+
+fn pending_json(tx: &Transaction) -> serde_json::Value {
+    json!({
+        "tx_id": tx.tx_id,
+        "from": tx.from,
+        "to": tx.to,
+        "amount": tx.amount,
+        "fee": tx.fee,
+        "timestamp": tx.timestamp,
+        "memo": tx.memo,
+    })
+}
+
+/// Get pending transactions
+pub async fn pending(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    let blockchain = state.blockchain.read().await;
+    let result: Vec<_> = blockchain.get_pending().iter().map(pending_json).collect();
 
     (StatusCode::OK, Json(result))
 }
 
+/// Pending transactions sent or received by one address
+pub async fn wallet_pending(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.read().await;
+    let result: Vec<_> = blockchain
+        .get_pending_for_address(&address)
+        .iter()
+        .map(pending_json)
+        .collect();
+
+    (StatusCode::OK, Json(json!(result)))
+}
+
 /// Mine block
 pub async fn mine_block(
     State(state): State<AppState>,
@@ -299,151 +1033,2304 @@ pub async fn add_block(
     }
 }
 
-/// Get full blockchain
+/// Get the blockchain, one page of blocks at a time. A page that stops
+/// short of the tip carries the `next_offset` to ask for next.
 pub async fn get_chain(
     State(state): State<AppState>,
-) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    Query(page): Query<PageQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let limit = match page.limit(state.max_page_size) {
+        Ok(limit) => limit,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(e)),
+    };
+
     let blockchain = state.blockchain.read().await;
-    let chain = blockchain.get_chain();
+    let chain = blockchain.get_blocks(page.offset, limit);
+    let total = blockchain.tip().index + 1;
 
-    let result: Vec<_> = chain
+    // Pruned blocks are listed by header, their transactions discarded
+    let blocks: Vec<_> = chain
         .iter()
         .map(|block| {
-            json!({
-                "index": block.index,
-                "hash": block.hash,
-                "prev_hash": block.prev_hash,
-                "timestamp": block.timestamp,
-                "transactions": block.transactions.len(),
-                "state_root": block.state_root,
-            })
+            let mut header = block_header_json(block);
+            header["pruned"] = json!(blockchain.is_pruned(block.index));
+            header
         })
         .collect();
+    let next_offset = chain.last().map(|block| block.index + 1).filter(|next| *next < total);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "blocks": blocks,
+            "total": total,
+            "truncated": next_offset.is_some(),
+            "next_offset": next_offset,
+        })),
+    )
+}
+
+/// Get the latest block's header, for clients that only need the tip
+pub async fn chain_tip(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let tip = state.blockchain.read().await.tip();
+    (StatusCode::OK, Json(block_header_json(&tip)))
+}
+
+/// A block as listed by `/chain`, with a transaction count in place of the
+/// transactions
+fn block_header_json(block: &blockchain::Block) -> serde_json::Value {
+    json!({
+        "index": block.index,
+        "hash": block.hash,
+        "prev_hash": block.prev_hash,
+        "timestamp": block.timestamp,
+        "transactions": block.transactions.len(),
+        "state_root": block.state_root,
+    })
+}
+
+/// Queue a contract transaction the node signs for `sender` and announce it
+/// to peers. Checking wasm code happens on a blocking thread. The operation
+/// runs once a block includes it, and its receipt is then at
+/// `/receipt/:tx_id`.
+async fn submit_contract(
+    state: &AppState,
+    sender: String,
+    op: community_coin::contract::ContractTransaction,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = validate_address(&sender) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e})));
+    }
+
+    let blockchain = state.blockchain.clone().write_owned().await;
+    let fee = CommunityBlockchain::contract_fee(&op);
+    let result = tokio::task::spawn_blocking(move || {
+        let (tx_id, contract) = blockchain.create_contract_transaction(sender, op)?;
+        let tx = blockchain.get_pending().into_iter().find(|tx| tx.tx_id == tx_id);
+        Ok::<_, String>((tx_id, contract, tx))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((tx_id, contract, tx))) => {
+            let propagation = match tx {
+                Some(tx) => gossip::announce_transaction(state.gossip.as_deref(), tx).await,
+                None => gossip::Propagation::LocalOnly,
+            };
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success": true,
+                    "tx_id": tx_id,
+                    "contract": contract,
+                    "fee": fee,
+                    "status": "pending",
+                    "propagation": propagation,
+                })),
+            )
+        }
+        Ok(Err(e)) => (rejection_status(&e), Json(json!({"success": false, "error": e}))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"success": false, "error": e.to_string()})),
+        ),
+    }
+}
+
+/// Deploy a contract
+pub async fn deploy_contract(
+    State(state): State<AppState>,
+    Json(req): Json<DeployContractRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let code = match hex::decode(&req.code) {
+        Ok(code) => code,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"success": false, "error": "Code must be hex-encoded"})),
+            )
+        }
+    };
+    let op = community_coin::contract::ContractTransaction::Deploy {
+        code,
+        admin: req.admin,
+        abi: req.abi,
+    };
+    submit_contract(&state, req.sender, op).await
+}
+
+/// The ABI a contract was deployed with
+pub async fn contract_abi(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    match blockchain.get_contract_abi(&address) {
+        Some(abi) => (StatusCode::OK, Json(json!(abi))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No ABI for contract {}", address)})),
+        ),
+    }
+}
+
+/// Call a contract
+pub async fn call_contract(
+    State(state): State<AppState>,
+    Json(req): Json<CallContractRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let op = community_coin::contract::ContractTransaction::Call {
+        contract: req.contract,
+        function: req.function,
+        gas_limit: req.gas_limit,
+    };
+    submit_contract(&state, req.sender, op).await
+}
+
+/// Outcome of a contract transaction, once a block has applied it
+pub async fn contract_receipt(
+    State(state): State<AppState>,
+    Path(tx_id): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    match blockchain.get_contract_receipt(&tx_id) {
+        Some(receipt) => (StatusCode::OK, Json(json!(receipt))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No receipt for transaction {}", tx_id)})),
+        ),
+    }
+}
+
+/// Call a contract as a view: it may read storage but not change it, and
+/// nothing is committed or charged
+pub async fn call_contract_view(
+    State(state): State<AppState>,
+    Json(req): Json<ViewContractRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.clone().read_owned().await;
+    let result = tokio::task::spawn_blocking(move || {
+        blockchain.call_contract_view(&req.contract, &req.function, req.gas_limit)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(receipt)) => (StatusCode::OK, Json(json!({"success": true, "receipt": receipt}))),
+        Ok(Err(e)) if e.starts_with(blockchain::CONTRACT_EXECUTIONS_BUSY) => {
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"success": false, "error": e})))
+        }
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"success": false, "error": e.to_string()})),
+        ),
+    }
+}
+
+/// Deployed contracts, oldest first (paginated)
+pub async fn list_contracts(
+    State(state): State<AppState>,
+    Query(page): Query<PageQuery>,
+) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    let limit = match page.limit(state.max_page_size) {
+        Ok(limit) => limit,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(vec![e])),
+    };
+
+    let blockchain = state.blockchain.read().await;
+    let result: Vec<_> = blockchain
+        .list_contracts()
+        .into_iter()
+        .skip(page.offset)
+        .take(limit)
+        .map(|contract| json!(contract))
+        .collect();
 
     (StatusCode::OK, Json(result))
 }
 
-/// Verify chain integrity
-pub async fn verify(
+/// Get the balance changes made by a block
+pub async fn diff(
     State(state): State<AppState>,
+    Path(index): Path<u64>,
 ) -> (StatusCode, Json<serde_json::Value>) {
     let blockchain = state.blockchain.read().await;
-    let is_valid = blockchain.verify_chain();
+    match blockchain.get_diff(index) {
+        Ok(diff) => (StatusCode::OK, Json(json!({"index": index, "changes": diff}))),
+        Err(e) => (StatusCode::NOT_FOUND, Json(json!({"error": e}))),
+    }
+}
 
-    (
-        StatusCode::OK,
-        Json(json!({
-            "valid": is_valid,
-            "message": if is_valid { "Blockchain is valid" } else { "Chain corrupted" }
-        })),
-    )
+/// A whole block, transactions included, for peers syncing the chain
+pub async fn get_block(
+    State(state): State<AppState>,
+    Path(index): Path<u64>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match state.blockchain.read().await.get_block_by_index(index) {
+        Some(block) => (StatusCode::OK, Json(json!(block))),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error": format!("No block at height {}", index)}))),
+    }
 }
 
-/// Get stats
-pub async fn stats(
+/// Accept a validator's vote for a block, finalizing it once a quorum of
+/// stake has voted
+pub async fn vote(
     State(state): State<AppState>,
+    Json(vote): Json<BlockVote>,
 ) -> (StatusCode, Json<serde_json::Value>) {
     let blockchain = state.blockchain.read().await;
-    let stats = blockchain.get_stats();
+    match blockchain.submit_block_vote(vote) {
+        Ok(finalized_height) => (
+            StatusCode::OK,
+            Json(json!({"success": true, "finalized_height": finalized_height})),
+        ),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
 
-    (StatusCode::OK, Json(stats))
+/// Connected peers and the connection limits
+pub async fn peers(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(json!(state.peers.counts())))
 }
 
-/// Health check
-pub async fn health() -> (StatusCode, Json<serde_json::Value>) {
+/// Per-validator stake, blocks proposed and next-height selection odds
+pub async fn validator_stats(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
     (
         StatusCode::OK,
         Json(json!({
-            "status": "healthy",
-            "service": "Community Coin Blockchain"
+            "next_proposer": blockchain.next_proposer(),
+            "validators": blockchain.get_validator_stats(),
         })),
     )
 }
 
-/// Start server
-pub async fn start_server(
-    blockchain: Arc<RwLock<CommunityBlockchain>>,
-    port: u16,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let state = AppState {
-        blockchain,
-        leaderboard_cache: LeaderboardCache::new(30), // 30 second TTL
-    };
-
-    let app = Router::new()
-        .route("/wallet/:address", get(get_wallet))
-        .route("/leaderboard", get(leaderboard))
-        .route("/history/:address", get(history))
-        .route("/transfer", post(transfer))
-        .route("/pending", get(pending))
-        .route("/mine", post(mine_block))
-        .route("/add-block", post(add_block))
-        .route("/chain", get(get_chain))
-        .route("/verify", get(verify))
-        .route("/stats", get(stats))
-        .route("/health", get(health))
-        .with_state(state);
-
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+/// Verify chain integrity, reporting the first invalid block
+pub async fn verify(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
 
-    println!("🚀 Community Coin Blockchain API running on http://0.0.0.0:{}", port);
-    println!("\n📋 Endpoints:");
-    println!("  GET    /wallet/{{address}}      - Get wallet balance");
-    println!("  GET    /leaderboard             - Top wallets (cached 30s)");
-    println!("  GET    /history/{{address}}      - Transaction history (indexed)");
-    println!("  POST   /transfer                - Send coins");
-    println!("  GET    /pending                 - Pending transactions");
-    println!("  POST   /mine                    - Mine new block");
-    println!("  POST   /add-block               - Add mined block");
-    println!("  GET    /chain                   - Full blockchain");
-    println!("  GET    /verify                  - Verify integrity");
-    println!("  GET    /stats                   - Blockchain stats");
-    println!("  GET    /health                  - Health check\n");
+    let mut body = match blockchain.verify_chain_detailed() {
+        Ok(()) => json!({
+            "valid": true,
+            "message": "Blockchain is valid"
+        }),
+        Err(e) => json!({
+            "valid": false,
+            "message": "Chain corrupted",
+            "invalid_block": e.index,
+            "reason": e.reason.to_string(),
+        }),
+    };
+    body["supply"] = match blockchain.reconcile_supply() {
+        Ok(()) => json!({"reconciled": true}),
+        Err(e) => json!({"reconciled": false, "reason": e.to_string()}),
+    };
 
-    axum::serve(listener, app).await?;
-    Ok(())
+    (StatusCode::OK, Json(body))
 }
 
-fn current_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+/// Decode a raw transaction, trying hex before base64
+fn decode_raw_transaction(raw: &str) -> Result<(&'static str, Transaction), String> {
+    let raw = raw.trim();
+    let (encoding, bytes) = match hex::decode(raw) {
+        Ok(bytes) => ("hex", bytes),
+        Err(_) => (
+            "base64",
+            base64::decode(raw).map_err(|_| "Transaction must be hex or base64 encoded".to_string())?,
+        ),
+    };
+    let tx = serde_json::from_slice(&bytes).map_err(|e| format!("Not a transaction: {}", e))?;
+    Ok((encoding, tx))
 }
 
-#[cfg(not(test))]
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Initializing Community Coin Blockchain...\n");
-
-    // Load or create blockchain
-    let blockchain = match CommunityBlockchain::load("blockchain_state") {
-        Ok(bc) => {
-            println!("✓ Loaded existing blockchain from disk");
-            bc
-        }
-        Err(_) => {
-            println!("✓ Creating new blockchain");
-            let mut initial = std::collections::HashMap::new();
+/// Show how the node parses a raw transaction and whether its signature and
+/// nonce would pass, without submitting it
+pub async fn decode_transaction(
+    State(state): State<AppState>,
+    Json(req): Json<DecodeTransactionRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (encoding, tx) = match decode_raw_transaction(&req.raw) {
+        Ok(decoded) => decoded,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    };
 
-            // Initialize 50 demo users
-            for i in 1..=50 {
-                initial.insert(format!("user_{}", i), 1000u64);
-            }
+    let check = state.blockchain.read().await.check_transaction(&tx);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "encoding": encoding,
+            "transaction": tx,
+            "signature_valid": check.signature_valid,
+            "nonce_valid": check.nonce_valid,
+            "committed_nonce": check.committed_nonce,
+        })),
+    )
+}
 
-            // Add named accounts
-            initial.insert("alice".to_string(), 10000);
-            initial.insert("bob".to_string(), 5000);
-            initial.insert("charlie".to_string(), 3000);
+/// Check a personal message signature made with `message::sign_message`
+pub async fn verify_message(Json(req): Json<VerifyMessageRequest>) -> (StatusCode, Json<serde_json::Value>) {
+    match community_coin::message::verify_message_hex(&req.public_key, req.message.as_bytes(), &req.signature) {
+        Ok(valid) => (StatusCode::OK, Json(json!({"valid": valid}))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    }
+}
 
-            CommunityBlockchain::new(initial, "blockchain_state")?
-        }
+/// Signed snapshot of the current state, served when SNAPSHOT_SIGNING_KEY is set
+pub async fn snapshot(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(key) = &state.snapshot_key else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Snapshot export is not enabled on this node"})),
+        );
     };
+    let snapshot = state.blockchain.read().await.export_snapshot(key);
+    (StatusCode::OK, Json(json!(snapshot)))
+}
 
-    let blockchain = Arc::new(RwLock::new(blockchain));
+/// Heights of the snapshots this node has stored
+pub async fn list_snapshots(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match state.blockchain.read().await.stored_snapshots() {
+        Ok(heights) => (StatusCode::OK, Json(json!({"heights": heights}))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))),
+    }
+}
 
-    // Start server on port 8000
-    start_server(blockchain, 8000).await?;
+/// A stored snapshot, for nodes fast-syncing from a given height
+pub async fn stored_snapshot(
+    State(state): State<AppState>,
+    Path(height): Path<u64>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match state.blockchain.read().await.stored_snapshot(height) {
+        Ok(Some(snapshot)) => (StatusCode::OK, Json(json!(snapshot))),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No snapshot stored at height {}", height)})),
+        ),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))),
+    }
+}
 
+/// Public key that `X-Node-Signature` headers verify against, when
+/// NODE_SIGNING_KEY is set
+pub async fn node_pubkey(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match &state.node_key {
+        Some(key) => (
+            StatusCode::OK,
+            Json(json!({"public_key": hex::encode(key.verifying_key().as_bytes())})),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Response signing is not enabled on this node"})),
+        ),
+    }
+}
+
+/// Get stats
+pub async fn stats(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+    let stats = blockchain.get_stats();
+
+    (StatusCode::OK, Json(stats))
+}
+
+/// Mint coins to an account (testnet only)
+#[cfg(feature = "testnet")]
+pub async fn admin_mint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<SupplyRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    if let Err(e) = validate_address(&req.address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+    if let Err(e) = validate_amount(req.amount) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.write().await;
+    match blockchain.mint(req.address, req.amount) {
+        Ok(block) => {
+            state.leaderboard_cache.invalidate().await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success": true,
+                    "tx_id": supply_tx_id(&block),
+                    "block_index": block.index,
+                    "total_supply": blockchain.total_supply(),
+                })),
+            )
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Burn coins from an account (testnet only)
+#[cfg(feature = "testnet")]
+pub async fn admin_burn(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<SupplyRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    if let Err(e) = validate_address(&req.address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+    if let Err(e) = validate_amount(req.amount) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.write().await;
+    match blockchain.burn(req.address, req.amount) {
+        Ok(block) => {
+            state.leaderboard_cache.invalidate().await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success": true,
+                    "tx_id": supply_tx_id(&block),
+                    "block_index": block.index,
+                    "total_supply": blockchain.total_supply(),
+                })),
+            )
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Queue a batch of signed transactions in one pass, e.g. to seed test data
+pub async fn bulk_ingest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(txs): Json<Vec<blockchain::Transaction>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    let blockchain = state.blockchain.clone().read_owned().await;
+    match tokio::task::spawn_blocking(move || blockchain.load_transactions(txs)).await {
+        Ok(report) => (StatusCode::OK, Json(json!(report))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))),
+    }
+}
+
+/// Start a faucet drip for load testing (testnet only)
+#[cfg(feature = "testnet")]
+pub async fn start_faucet_drip(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(config): Json<community_coin::drip::DripConfig>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    if let Some(e) = config.targets.iter().find_map(|target| validate_address(target).err()) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    match state.drip.start(state.blockchain.clone(), config) {
+        Ok(()) => (StatusCode::OK, Json(json!(state.drip.status()))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    }
+}
+
+/// Whether a faucet drip is running, and what it has sent (testnet only)
+#[cfg(feature = "testnet")]
+pub async fn faucet_drip_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    (StatusCode::OK, Json(json!(state.drip.status())))
+}
+
+/// Stop the running faucet drip (testnet only)
+#[cfg(feature = "testnet")]
+pub async fn stop_faucet_drip(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    let stopped = state.drip.stop();
+    (StatusCode::OK, Json(json!({"stopped": stopped, "sent": state.drip.status().sent})))
+}
+
+/// Show the account allowlist/denylist
+pub async fn get_access_list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    let blockchain = state.blockchain.read().await;
+    (StatusCode::OK, Json(json!(blockchain.access_list())))
+}
+
+/// Drop a pending transaction, and its sender's later ones, from the mempool
+pub async fn drop_pending(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(tx_id): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    match state.blockchain.read().await.drop_transaction(&tx_id) {
+        Ok(dropped) => {
+            state.leaderboard_cache.invalidate().await;
+            let tx_ids: Vec<&str> = dropped.iter().map(|tx| tx.tx_id.as_str()).collect();
+            (StatusCode::OK, Json(json!({"success": true, "dropped": tx_ids})))
+        }
+        Err(e) => (StatusCode::NOT_FOUND, Json(json!({"success": false, "error": e}))),
+    }
+}
+
+/// Edit the account allowlist/denylist
+pub async fn update_access_list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<AccessListRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+    if let Err(e) = validate_address(&req.address) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
+    let blockchain = state.blockchain.read().await;
+    blockchain.update_access_list(|access| match (req.list, req.remove) {
+        (AccessListKind::Allow, false) => access.allow(req.address),
+        (AccessListKind::Allow, true) => access.disallow(&req.address),
+        (AccessListKind::Deny, false) => access.deny(req.address),
+        (AccessListKind::Deny, true) => access.undeny(&req.address),
+    });
+
+    (StatusCode::OK, Json(json!(blockchain.access_list())))
+}
+
+/// Whether the node is in maintenance mode
+pub async fn get_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    let enabled = state.maintenance.load(std::sync::atomic::Ordering::SeqCst);
+    (StatusCode::OK, Json(json!({"maintenance": enabled})))
+}
+
+/// Pause or resume writes (transfers, mining and adding blocks). Reads keep
+/// working throughout.
+pub async fn set_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<MaintenanceRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    state.maintenance.store(req.enabled, std::sync::atomic::Ordering::SeqCst);
+    (StatusCode::OK, Json(json!({"maintenance": req.enabled})))
+}
+
+/// List the webhook URLs notified of new blocks
+pub async fn get_webhooks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    (StatusCode::OK, Json(json!({"urls": state.webhooks.urls()})))
+}
+
+/// Replace the webhook URLs notified of new blocks
+pub async fn set_webhooks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<WebhooksRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    match state.webhooks.set_urls(req.urls) {
+        Ok(()) => (StatusCode::OK, Json(json!({"urls": state.webhooks.urls()}))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    }
+}
+
+/// Start rebuilding the transaction index from stored blocks in the
+/// background. Progress is reported by `GET /admin/reindex`.
+pub async fn start_reindex(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    {
+        let mut progress = state.reindex.lock().unwrap();
+        if progress.running {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({"error": "A reindex is already running", "progress": *progress})),
+            );
+        }
+        *progress = ReindexProgress {
+            running: true,
+            ..Default::default()
+        };
+    }
+
+    // Holding the read lock keeps new blocks out until the index is whole
+    let blockchain = state.blockchain.clone().read_owned().await;
+    let reindex = state.reindex.clone();
+    tokio::task::spawn_blocking(move || {
+        let _done = ReindexDone(reindex.clone());
+        blockchain.rebuild_tx_index_with_progress(|scanned, total| {
+            let mut progress = reindex.lock().unwrap();
+            progress.blocks_scanned = scanned;
+            progress.blocks_total = total;
+        });
+    });
+
+    (StatusCode::ACCEPTED, Json(json!({"status": "reindexing"})))
+}
+
+/// Clears the running flag when a reindex ends, even if it panicked, so
+/// another can be started
+struct ReindexDone(Arc<std::sync::Mutex<ReindexProgress>>);
+
+impl Drop for ReindexDone {
+    fn drop(&mut self) {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).running = false;
+    }
+}
+
+/// Progress of the latest reindex
+pub async fn reindex_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(rejection) = authorize_admin(&state, &headers) {
+        return rejection;
+    }
+
+    let progress = state.reindex.lock().unwrap().clone();
+    (StatusCode::OK, Json(json!(progress)))
+}
+
+/// Get node version and chain parameters
+pub async fn version(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let blockchain = state.blockchain.read().await;
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "service": "Community Coin Blockchain",
+            "version": env!("CARGO_PKG_VERSION"),
+            "decimals": blockchain.params().decimals,
+            "pow_difficulty": blockchain.pow_difficulty(),
+            "mempool_policy": blockchain.params().mempool_policy,
+            "max_block_txs": blockchain.params().max_block_txs,
+            "max_block_bytes": blockchain.params().max_block_bytes,
+            "block_difficulty": blockchain.required_difficulty(blockchain.tip().index + 1),
+            "block_reward": blockchain.block_reward_at(blockchain.tip().index + 1),
+            "genesis_hash": blockchain.genesis_hash(),
+        })),
+    )
+}
+
+/// Which replay protections are on, and how many transactions each has
+/// rejected
+pub async fn replay_protection_status(State(state): State<AppState>) -> Json<blockchain::ReplayProtectionStatus> {
+    Json(state.blockchain.read().await.replay_protection_status())
+}
+
+/// Health check
+pub async fn health(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let clock = state.clock_monitor.status();
+    let (finalized_height, durability) = {
+        let blockchain = state.blockchain.read().await;
+        (blockchain.finalized_height(), blockchain.durability_window())
+    };
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": if clock.skewed { "degraded" } else { "healthy" },
+            "service": "Community Coin Blockchain",
+            "clock": clock,
+            "finalized_height": finalized_height,
+            "maintenance": state.maintenance.load(std::sync::atomic::Ordering::SeqCst),
+            "durability": durability,
+        })),
+    )
+}
+
+
+/// Persist the mempool every `interval` until the task is aborted
+fn spawn_mempool_flush(
+    blockchain: Arc<RwLock<CommunityBlockchain>>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = blockchain.read().await.persist_mempool() {
+                println!("⚠ Failed to persist the mempool: {}", e);
+            }
+        }
+    })
+}
+
+/// Store a snapshot signed with `key` every `interval` until the task is
+/// aborted
+fn spawn_snapshot_writer(
+    blockchain: Arc<RwLock<CommunityBlockchain>>,
+    key: ed25519_dalek::SigningKey,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = blockchain.read().await.save_snapshot(&key) {
+                println!("⚠ Failed to store a snapshot: {}", e);
+            }
+        }
+    })
+}
+
+/// Resolve on Ctrl+C (or SIGTERM on unix)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Socket options applied to the API listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerOptions {
+    /// Maximum number of connections queued before `accept`
+    pub backlog: i32,
+    /// Allow rebinding the port while old connections sit in TIME_WAIT
+    pub reuse_address: bool,
+    /// Idle time before TCP keepalive probes start, `None` to disable
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        ListenerOptions {
+            backlog: 1024,
+            reuse_address: true,
+            keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl ListenerOptions {
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn reuse_address(mut self, reuse_address: bool) -> Self {
+        self.reuse_address = reuse_address;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Bind a listener on `addr` with these options
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(self.reuse_address)?;
+        if let Some(idle) = self.keepalive {
+            // Accepted connections inherit keepalive from the listener
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        tokio::net::TcpListener::from_std(socket.into())
+    }
+}
+
+/// Serve `app` until `shutdown` resolves. New connections are then refused
+/// while in-flight requests get up to `drain_timeout` to complete, after
+/// which the blockchain is flushed to disk.
+pub async fn serve_with_shutdown<F>(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    blockchain: Arc<RwLock<CommunityBlockchain>>,
+    shutdown: F,
+    drain_timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (draining_tx, mut draining_rx) = tokio::sync::watch::channel(false);
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            let _ = draining_tx.send(true);
+        })
+        .into_future();
+
+    let drain_deadline = async move {
+        while !*draining_rx.borrow() {
+            if draining_rx.changed().await.is_err() {
+                return std::future::pending::<()>().await;
+            }
+        }
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = drain_deadline => {
+            println!("⚠ Drain timeout of {:?} elapsed, dropping remaining connections", drain_timeout);
+        }
+    }
+
+    let blockchain = blockchain.read().await;
+    blockchain.persist_mempool()?;
+    blockchain.flush().await?;
+    println!("✓ Blockchain state flushed to disk");
     Ok(())
 }
+
+/// Start server, joined to the peer-to-peer network through `network`:
+/// transactions and blocks the node accepts are gossiped over it, and its
+/// peers and clock samples are what `/peers` and `/health` report
+pub async fn start_server(
+    blockchain: Arc<RwLock<CommunityBlockchain>>,
+    config: &NodeConfig,
+    network: Option<NetworkService>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = config.network.http_port;
+    let drain_timeout = Duration::from_secs(config.api.drain_timeout_secs);
+
+    let clock_monitor = Arc::new(ClockMonitor::new(
+        std::env::var("CLOCK_SKEW_THRESHOLD_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_CLOCK_SKEW_THRESHOLD_SECS),
+    ));
+    let (network_peers, gossip_sink): (Arc<PeerManager>, Option<Arc<dyn GossipSink>>) = match network {
+        Some(network) => {
+            let network = network.with_clock_monitor(clock_monitor.clone());
+            let peers = network.peers();
+            let sink = network.sink();
+            tokio::spawn(gossip::run_rebroadcast(
+                blockchain.clone(),
+                sink.clone(),
+                gossip::RebroadcastConfig::default(),
+            ));
+            tokio::spawn(gossip::announce_blocks(blockchain.read().await.subscribe_blocks(), sink.clone()));
+            tokio::spawn(network.run(blockchain.clone()));
+            (peers, Some(sink))
+        }
+        // Without a network, transfers stay local until a peer picks them
+        // up through the API
+        None => (Arc::new(PeerManager::new(config.network.peer_limits())), None),
+    };
+
+    let state = AppState {
+        blockchain: blockchain.clone(),
+        leaderboard_cache: LeaderboardCache::new(config.api.leaderboard_cache_secs),
+        rate_limiter: RateLimiter::new(),
+        search_rate_limit: config.api.search_rate_limit,
+        max_page_size: config.api.max_page_size,
+        max_history_len: config.api.max_history_len,
+        peers: network_peers,
+        clock_monitor,
+        admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+        snapshot_key: match std::env::var("SNAPSHOT_SIGNING_KEY") {
+            Ok(key) => Some(community_coin::snapshot::parse_signing_key(&key)?),
+            Err(_) => None,
+        },
+        node_key: match std::env::var("NODE_SIGNING_KEY") {
+            Ok(key) => Some(community_coin::snapshot::parse_signing_key(&key)?),
+            Err(_) => None,
+        },
+        reindex: Default::default(),
+        maintenance: Default::default(),
+        webhooks: Webhooks::new(
+            match std::env::var("WEBHOOK_URLS") {
+                Ok(urls) => Webhooks::parse_urls(&urls)?,
+                Err(_) => Vec::new(),
+            },
+            Duration::from_secs(
+                std::env::var("WEBHOOK_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(community_coin::webhooks::DEFAULT_WEBHOOK_TIMEOUT_SECS),
+            ),
+            std::env::var("WEBHOOK_RETRIES")
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(community_coin::webhooks::DEFAULT_WEBHOOK_RETRIES),
+        ),
+        gossip: gossip_sink,
+        #[cfg(feature = "testnet")]
+        drip: Default::default(),
+    };
+    state.webhooks.clone().spawn(blockchain.read().await.subscribe_blocks());
+
+    let allowlist = std::env::var("ALLOWLIST").ok();
+    let denylist = std::env::var("DENYLIST").ok();
+    if allowlist.is_some() || denylist.is_some() {
+        blockchain
+            .read()
+            .await
+            .set_access_list(AccessList::parse(allowlist.as_deref(), denylist.as_deref()));
+    }
+
+    // SNAPSHOT_INTERVAL_SECS stores a signed snapshot that often (needs SNAPSHOT_SIGNING_KEY)
+    let snapshot_secs: u64 = std::env::var("SNAPSHOT_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(0);
+    if let (Some(key), true) = (&state.snapshot_key, snapshot_secs > 0) {
+        spawn_snapshot_writer(blockchain.clone(), key.clone(), Duration::from_secs(snapshot_secs));
+    }
+
+    let app = Router::new()
+        .route("/wallet", post(register_wallet))
+        .route("/wallet/:address", get(get_wallet))
+        .route("/wallet/:address/transactions/count", get(transaction_count))
+        .route("/wallet/:address/pending", get(wallet_pending))
+        .route("/nonce/:address", get(get_nonce))
+        .route("/state-proof/:address", get(state_proof))
+        .route("/transaction/:tx_id", get(get_transaction))
+        .route("/transaction/:tx_id/proof", get(transaction_proof))
+        .route("/leaderboard", get(leaderboard))
+        .route("/history/:address", get(history))
+        .route("/history/:address/search", get(search_history))
+        .route("/transfer", post(transfer))
+        .route("/transfer/batch", post(batch_transfer))
+        .route("/submit-tx", post(submit_tx))
+        .route("/stake", post(stake))
+        .route("/unstake", post(unstake))
+        .route("/pending", get(pending))
+        .route("/estimate-fee", get(estimate_fee))
+        .route("/mempool/stats", get(mempool_stats))
+        .route("/mine", post(mine_block))
+        .route("/add-block", post(add_block))
+        .route("/vote", post(vote))
+        .route("/chain", get(get_chain))
+        .route("/chain/tip", get(chain_tip))
+        .route("/block/:index", get(get_block))
+        .route("/diff/:index", get(diff))
+        .route("/contracts", get(list_contracts))
+        .route("/contracts/:address/abi", get(contract_abi))
+        .route("/deploy", post(deploy_contract))
+        .route("/call-contract", post(call_contract))
+        .route("/call-contract/view", post(call_contract_view))
+        .route("/receipt/:tx_id", get(contract_receipt))
+        .route("/verify", get(verify))
+        .route("/verify-message", post(verify_message))
+        .route("/tx/decode", post(decode_transaction))
+        .route("/snapshot", get(snapshot))
+        .route("/snapshots", get(list_snapshots))
+        .route("/snapshot/:height", get(stored_snapshot))
+        .route("/node-pubkey", get(node_pubkey))
+        .route("/stats", get(stats))
+        .route("/stats/validators", get(validator_stats))
+        .route("/peers", get(peers))
+        .route("/version", get(version))
+        .route("/replay-protection/status", get(replay_protection_status))
+        .route("/health", get(health))
+        .route("/admin/access-list", get(get_access_list).post(update_access_list))
+        .route("/admin/reindex", get(reindex_progress).post(start_reindex))
+        .route("/admin/maintenance", get(get_maintenance).post(set_maintenance))
+        .route("/admin/webhooks", get(get_webhooks).post(set_webhooks))
+        .route("/admin/bulk-ingest", post(bulk_ingest))
+        .route("/admin/mempool/:tx_id", delete(drop_pending));
+
+    #[cfg(feature = "testnet")]
+    let app = app
+        .route("/admin/mint", post(admin_mint))
+        .route("/admin/burn", post(admin_burn))
+        .route("/admin/faucet-drip", get(faucet_drip_status).post(start_faucet_drip))
+        .route("/admin/faucet-drip/stop", post(stop_faucet_drip));
+
+    let grpc_state = state.clone();
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(state.clone(), pause_in_maintenance))
+        .layer(axum::middleware::from_fn(pretty_json))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), sign_responses))
+        .with_state(state);
+
+    let mut listener_options = ListenerOptions::default();
+    if let Some(backlog) = std::env::var("LISTEN_BACKLOG").ok().and_then(|n| n.parse().ok()) {
+        listener_options = listener_options.backlog(backlog);
+    }
+    if let Some(secs) = std::env::var("TCP_KEEPALIVE_SECS").ok().and_then(|secs| secs.parse().ok()) {
+        listener_options = listener_options.keepalive((secs > 0).then(|| Duration::from_secs(secs)));
+    }
+
+    let listener = listener_options.bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+
+    let grpc_port = config.network.grpc_port;
+    let grpc_listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], grpc_port))).await?;
+    tokio::spawn(async move {
+        if let Err(e) = grpc::serve(grpc_state, grpc_listener).await {
+            println!("⚠ gRPC server stopped: {}", e);
+        }
+    });
+
+    // Batched blocks still reach disk when no new block comes to push them
+    if let Some(batching) = blockchain.read().await.durability_window().batching {
+        let blockchain = blockchain.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(batching.interval_secs.max(1)));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = blockchain.read().await.flush_writes_if_due() {
+                    println!("⚠ Failed to flush batched writes: {}", e);
+                }
+            }
+        });
+    }
+
+    // A flush interval of 0 keeps the mempool in memory only
+    let mempool_flush_secs = config.mempool.flush_interval_secs;
+    if mempool_flush_secs > 0 {
+        spawn_mempool_flush(blockchain.clone(), Duration::from_secs(mempool_flush_secs));
+    }
+
+    println!("🚀 Community Coin Blockchain API running on http://0.0.0.0:{}", port);
+    println!("🔌 gRPC service (community_coin.Node) on 0.0.0.0:{}", grpc_port);
+    println!("\n📋 Endpoints:");
+    println!("  POST   /wallet                  - Register an ed25519 public key as a wallet");
+    println!("  GET    /wallet/{{address}}      - Get wallet balance");
+    println!("  GET    /wallet/{{address}}/transactions/count - Transactions involving an address");
+    println!("  GET    /wallet/{{address}}/pending - Unconfirmed transactions of an address");
+    println!("  GET    /nonce/{{address}}       - Nonce for an address's next transaction");
+    println!("  GET    /state-proof/{{address}} - Merkle proof of an address's balance and nonce");
+    println!("  GET    /transaction/{{tx_id}}   - Look up a pending or confirmed transaction");
+    println!("  GET    /transaction/{{tx_id}}/proof - Merkle proof of a confirmed transaction");
+    println!("  GET    /leaderboard             - Top wallets (cached 30s)");
+    println!("  GET    /history/{{address}}      - Transaction history, newest first (?cursor= or ?offset=)");
+    println!("  GET    /history/{{address}}/search?q= - Search history by memo");
+    println!("  POST   /transfer                - Send coins");
+    println!("  POST   /transfer/batch          - Send coins to several recipients at once");
+    println!("  POST   /submit-tx               - Queue a transaction signed by its sender");
+    println!("  POST   /stake                   - Stake coins to become a validator (signed tx)");
+    println!("  POST   /unstake                 - Return staked coins (signed tx)");
+    println!("  GET    /pending                 - Pending transactions");
+    println!("  GET    /estimate-fee?amount=    - Current fee for a transfer");
+    println!("  GET    /mempool/stats           - Pending count, fees, limits and recent fee floor");
+    println!("  POST   /mine                    - Mine new block");
+    println!("  POST   /add-block               - Add mined block");
+    println!("  POST   /vote                    - Validator vote to finalize a block");
+    println!("  GET    /chain                   - Blockchain (paginated)");
+    println!("  GET    /chain/tip               - Latest block header");
+    println!("  GET    /block/{{index}}          - Full block, for syncing peers");
+    println!("  GET    /diff/{{index}}           - Balance changes in a block");
+    println!("  GET    /contracts               - Deployed contracts (paginated)");
+    println!("  GET    /contracts/:address/abi  - A contract's declared ABI");
+    println!("  POST   /deploy                  - Deploy a contract");
+    println!("  POST   /call-contract           - Call a contract function");
+    println!("  POST   /call-contract/view      - Call a contract function without changing state");
+    println!("  GET    /receipt/:tx_id          - Outcome of an applied contract transaction");
+    println!("  GET    /verify                  - Verify integrity");
+    println!("  POST   /verify-message          - Check a signed personal message");
+    println!("  POST   /tx/decode               - Parse a raw transaction without submitting it");
+    println!("  GET    /snapshot                - Signed state snapshot (SNAPSHOT_SIGNING_KEY)");
+    println!("  GET    /snapshots               - Heights of stored snapshots");
+    println!("  GET    /snapshot/{{height}}      - Stored snapshot at a height");
+    println!("  GET    /node-pubkey             - Key response signatures verify against (NODE_SIGNING_KEY)");
+    println!("  GET    /stats                   - Blockchain stats");
+    println!("  GET    /stats/validators        - Validator stakes and proposer odds");
+    println!("  GET    /peers                   - Connected peers and limits");
+    println!("  GET    /version                 - Node version and chain params");
+    println!("  GET    /replay-protection/status - Replay protections and their rejection counts");
+    println!("  GET    /health                  - Health check");
+    println!("  GET    /admin/access-list       - Account allowlist/denylist (ADMIN_TOKEN)");
+    println!("  POST   /admin/access-list       - Edit allowlist/denylist (ADMIN_TOKEN)");
+    println!("  POST   /admin/reindex           - Rebuild the transaction index (ADMIN_TOKEN)");
+    println!("  GET    /admin/reindex           - Reindex progress (ADMIN_TOKEN)");
+    println!("  GET    /admin/maintenance       - Whether writes are paused (ADMIN_TOKEN)");
+    println!("  POST   /admin/maintenance       - Pause or resume writes (ADMIN_TOKEN)");
+    println!("  GET    /admin/webhooks          - Webhook URLs notified of new blocks (ADMIN_TOKEN)");
+    println!("  POST   /admin/webhooks          - Replace the webhook URLs (ADMIN_TOKEN)");
+    println!("  POST   /admin/bulk-ingest       - Queue a batch of signed transactions (ADMIN_TOKEN)");
+    println!("  DELETE /admin/mempool/:tx_id    - Drop a pending transaction (ADMIN_TOKEN)\n");
+    #[cfg(feature = "testnet")]
+    println!("  POST   /admin/mint              - Mint coins (testnet, ADMIN_TOKEN)\n  POST   /admin/burn              - Burn coins (testnet, ADMIN_TOKEN)");
+    #[cfg(feature = "testnet")]
+    println!("  POST   /admin/faucet-drip       - Start a faucet drip (testnet, ADMIN_TOKEN)\n  GET    /admin/faucet-drip       - Faucet drip status (testnet, ADMIN_TOKEN)\n  POST   /admin/faucet-drip/stop  - Stop the faucet drip (testnet, ADMIN_TOKEN)\n");
+
+    serve_with_shutdown(listener, app, blockchain, shutdown_signal(), drain_timeout).await
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Flags of the API node
+#[derive(clap::Parser, Debug)]
+struct ApiNodeArgs {
+    #[command(flatten)]
+    node: community_coin::startup::NodeArgs,
+
+    #[command(flatten)]
+    p2p: P2pArgs,
+
+    /// Don't join the peer-to-peer network
+    #[arg(long)]
+    no_p2p: bool,
+}
+
+#[cfg(not(test))]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let ApiNodeArgs { node: args, p2p, no_p2p } = clap::Parser::parse();
+    // RUST_LOG filters the node's log output, `info` and up by default
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+    println!("Initializing Community Coin Blockchain...\n");
+
+    // Ports, storage, mempool limits and API settings, from --config, the
+    // environment and flags
+    let config = args.node_config()?;
+
+    // storage.backend picks the key/value store: sled (default), rocksdb
+    // (builds with the rocksdb feature) or memory
+    let store = config.storage.backend.open(&config.storage.db_path)?;
+    // The libp2p identity lives in the store, so the peer id survives restarts
+    let keypair = community_coin::p2p::node_keypair(store.as_ref())?;
+
+    // Load or create blockchain
+    let blockchain = match CommunityBlockchain::load_from_store(store.clone()) {
+        Ok(bc) => {
+            // --verify-on-start refuses to serve a tampered database
+            args.check_loaded(&bc)?;
+            println!("✓ Loaded existing blockchain from disk");
+            bc
+        }
+        // SNAPSHOT_URL starts a new node from a snapshot instead of from
+        // genesis, trusting it if signed by the SNAPSHOT_SIGNER public key,
+        // or if its tip is the block SNAPSHOT_TRUSTED_HASH names
+        Err(_) => match std::env::var("SNAPSHOT_URL") {
+            Ok(url) => {
+                let trust = match (std::env::var("SNAPSHOT_SIGNER"), std::env::var("SNAPSHOT_TRUSTED_HASH")) {
+                    (Ok(signer), _) => {
+                        community_coin::snapshot::SnapshotTrust::Signer(community_coin::snapshot::parse_verifying_key(&signer)?)
+                    }
+                    (_, Ok(hash)) => community_coin::snapshot::SnapshotTrust::Header(hash),
+                    _ => return Err("SNAPSHOT_URL requires SNAPSHOT_SIGNER or SNAPSHOT_TRUSTED_HASH".into()),
+                };
+                let bc = CommunityBlockchain::from_snapshot_url_into(&url, store, &trust).await?;
+                println!("✓ Initialized blockchain from snapshot at {}", url);
+                bc
+            }
+            Err(_) => {
+                println!("✓ Creating new blockchain");
+                CommunityBlockchain::from_genesis(&args.genesis.genesis()?, store)?
+            }
+        },
+    };
+
+    // Records are written with STORAGE_CODEC (json or bincode); any codec
+    // can be read. STORAGE_MIGRATE=1 rewrites existing records to match.
+    let codec = match std::env::var("STORAGE_CODEC") {
+        Ok(name) => community_coin::storage::Codec::parse(&name)?,
+        Err(_) => Default::default(),
+    };
+    let mut blockchain = blockchain.with_codec(codec);
+
+    // SENDER_TX_LIMIT caps transactions per sender address per
+    // SENDER_TX_WINDOW_SECS (default 60), on top of the per-IP limits
+    if let Some(limit) = std::env::var("SENDER_TX_LIMIT").ok().and_then(|n| n.parse().ok()) {
+        blockchain = blockchain.with_sender_rate_limit(blockchain::SenderRateLimit {
+            limit,
+            window_secs: std::env::var("SENDER_TX_WINDOW_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(60),
+        });
+    }
+
+    // Finality quorum, fee floor window, dedup window and mempool limits
+    blockchain = config.apply(blockchain);
+    // POW_DIFFICULTY makes transfers carry a proof of work with that many
+    // leading zero bits
+    if let Some(difficulty) = std::env::var("POW_DIFFICULTY")
+        .ok()
+        .and_then(|bits| bits.parse::<u32>().ok())
+        .filter(|bits| *bits <= 256)
+    {
+        blockchain = blockchain.with_pow_difficulty(difficulty);
+    }
+    // --prune-depth discards block bodies that far below the tip
+    if let Some(depth) = args.pruning(None) {
+        blockchain = blockchain.with_block_pruning(depth);
+    }
+    // --snapshot-retention prunes old stored snapshots now and after each new one
+    if let Some(keep) = args.snapshot_retention {
+        blockchain = blockchain.with_snapshot_retention(keep);
+        let pruned = blockchain.prune_snapshots(keep)?;
+        if pruned > 0 {
+            println!("✓ Pruned {} old snapshots", pruned);
+        }
+    }
+
+    // VALIDATOR_SIGNING_KEY (hex ed25519 secret key) signs the blocks this
+    // node proposes; the validator stakes under its public key
+    if let Ok(key) = std::env::var("VALIDATOR_SIGNING_KEY") {
+        blockchain = blockchain.with_validator_key(community_coin::snapshot::parse_signing_key(&key)?);
+    }
+
+    // SYSTEM_SIGNING_KEY (hex ed25519 secret key) signs faucet, treasury and
+    // other system account transactions; its public key must be among the
+    // chain's `system_keys`
+    if let Ok(key) = std::env::var("SYSTEM_SIGNING_KEY") {
+        blockchain = blockchain.with_system_key(community_coin::snapshot::parse_signing_key(&key)?);
+    }
+
+    // SUPPLY_CHECKS=1 reconciles the supply after every block (debug builds)
+    if std::env::var("SUPPLY_CHECKS").is_ok_and(|v| v == "1") {
+        blockchain = blockchain.with_supply_checks();
+    }
+
+    // RESERVE_PENDING_BALANCE=0 stops setting aside what pending transfers
+    // will spend
+    if std::env::var("RESERVE_PENDING_BALANCE").is_ok_and(|v| v == "0") {
+        blockchain = blockchain.without_balance_reservation();
+    }
+
+    // WRITE_BATCH_BLOCKS turns on write batching, flushing every that many
+    // blocks or WRITE_BATCH_INTERVAL_SECS, whichever comes first
+    if let Some(max_blocks) = std::env::var("WRITE_BATCH_BLOCKS")
+        .ok()
+        .and_then(|n| n.parse::<u64>().ok())
+        .filter(|n| *n > 1)
+    {
+        let interval_secs = std::env::var("WRITE_BATCH_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(blockchain::DEFAULT_WRITE_BATCH_INTERVAL_SECS);
+        blockchain = blockchain.with_write_batching(blockchain::WriteBatching { max_blocks, interval_secs });
+    }
+
+    // MAX_CONTRACT_EXECUTIONS caps how many contract view calls run at once
+    if let Some(max) = std::env::var("MAX_CONTRACT_EXECUTIONS")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+    {
+        blockchain = blockchain.with_max_contract_executions(max);
+    }
+    // MAX_INDEX_LEN caps each address's in-memory transaction index
+    if let Some(len) = std::env::var("MAX_INDEX_LEN")
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|len| *len > 0)
+    {
+        blockchain = blockchain.with_max_index_len(len);
+    }
+    // MAX_CHAIN_LEN bounds how many recent blocks are kept in memory
+    if let Some(len) = std::env::var("MAX_CHAIN_LEN")
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|len| *len > 0)
+    {
+        blockchain = blockchain.with_max_chain_len(len);
+    }
+    if std::env::var("STORAGE_MIGRATE").is_ok_and(|v| v == "1") {
+        let rewritten = blockchain.migrate_storage()?;
+        println!("✓ Migrated {} records to {:?}", rewritten, codec);
+    }
+
+    // SYNC_FROM fetches and validates the blocks a peer (its base URL) has
+    // above the local tip, e.g. to catch up after starting from a snapshot
+    if let Ok(peer) = std::env::var("SYNC_FROM") {
+        match blockchain.sync_from_peer(&peer).await {
+            Ok(synced) => println!("✓ Synced {} blocks from {}", synced, peer),
+            Err(e) => println!("⚠ Sync from {} stopped: {}", peer, e),
+        }
+    }
+
+    let genesis = blockchain.genesis_hash();
+    let blockchain = Arc::new(RwLock::new(blockchain));
+
+    // Gossip and block sync with peers on --p2p-port, unless --no-p2p
+    let network = match no_p2p {
+        true => None,
+        false => {
+            let peers = Arc::new(PeerManager::new(config.network.peer_limits()));
+            let genesis = genesis.ok_or("Genesis hash unknown, start with --no-p2p or from a snapshot that carries it")?;
+            let mut network =
+                NetworkService::new(keypair, genesis, config.network.gossip_limits(), peers, !p2p.no_mdns)?;
+            let address = network
+                .listen(format!("/ip4/0.0.0.0/tcp/{}", p2p.p2p_port).parse()?)
+                .await?;
+            println!("✓ Listening for peers on {}/p2p/{}", address, network.peer_id());
+            for peer in p2p.bootstrap {
+                if let Err(e) = network.dial(peer.clone()) {
+                    println!("⚠ Failed to dial {}: {}", peer, e);
+                }
+            }
+            Some(network)
+        }
+    };
+
+    start_server(blockchain, &config, network).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use community_coin::config::{DEFAULT_MAX_HISTORY_LEN, DEFAULT_MAX_PAGE_SIZE, DEFAULT_SEARCH_RATE_LIMIT};
+    use community_coin::peers::PeerLimits;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn test_state(blockchain: CommunityBlockchain) -> AppState {
+        AppState {
+            blockchain: Arc::new(RwLock::new(blockchain)),
+            leaderboard_cache: LeaderboardCache::new(30),
+            search_rate_limit: DEFAULT_SEARCH_RATE_LIMIT,
+            rate_limiter: RateLimiter::new(),
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            max_history_len: DEFAULT_MAX_HISTORY_LEN,
+            peers: Arc::new(PeerManager::new(PeerLimits::default())),
+            clock_monitor: Arc::new(ClockMonitor::new(DEFAULT_CLOCK_SKEW_THRESHOLD_SECS)),
+            admin_token: None,
+            snapshot_key: None,
+            node_key: None,
+            reindex: Default::default(),
+            maintenance: Default::default(),
+            webhooks: Webhooks::default(),
+            gossip: None,
+            #[cfg(feature = "testnet")]
+            drip: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leaderboard_include_system_override() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        initial.insert(blockchain::TREASURY_ADDRESS.to_string(), 1_000_000);
+        let state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+
+        let (_, Json(default)) = leaderboard(State(state.clone()), Query(LeaderboardQuery::default())).await;
+        assert_eq!(default.len(), 1);
+        assert_eq!(default[0]["address"], "alice");
+
+        let (_, Json(all)) = leaderboard(
+            State(state.clone()),
+            Query(LeaderboardQuery { include_system: true }),
+        )
+        .await;
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0]["address"], blockchain::TREASURY_ADDRESS);
+    }
+
+    #[tokio::test]
+    async fn test_validator_stats_reflect_stake_weights() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let keys = [ed25519_dalek::SigningKey::from_bytes(&[1; 32]), ed25519_dalek::SigningKey::from_bytes(&[2; 32])];
+        let [val_a, val_b] = keys.clone().map(|key| hex::encode(key.verifying_key().to_bytes()));
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        blockchain.register_validator(val_a.clone(), 300).unwrap();
+        blockchain.register_validator(val_b.clone(), 100).unwrap();
+
+        blockchain
+            .create_transaction("alice".to_string(), "bob".to_string(), 10)
+            .unwrap();
+        let proposer = blockchain.next_proposer().unwrap();
+        let key = if proposer == val_a { &keys[0] } else { &keys[1] };
+        let blockchain = blockchain.with_validator_key(key.clone());
+        let block = blockchain.mine_block(proposer.clone()).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        let (status, Json(body)) = validator_stats(State(test_state(blockchain))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let validators = body["validators"].as_array().unwrap();
+        assert_eq!(validators.len(), 2);
+        let probability = |i: usize| validators[i]["selection_probability"].as_f64().unwrap();
+        assert!((probability(0) + probability(1) - 1.0).abs() < 1e-9);
+        // Listed by address
+        let a = validators.iter().position(|validator| validator["address"] == val_a.as_str()).unwrap();
+        assert!((probability(a) - 0.75).abs() < 1e-9);
+        assert_eq!(validators[a]["stake"], 300);
+        for validator in validators {
+            let proposed = if validator["address"] == proposer.as_str() { 1 } else { 0 };
+            assert_eq!(validator["blocks_proposed"], proposed);
+        }
+        assert!(body["next_proposer"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_stake_reports_its_own_tx_id_behind_the_reward() {
+        use ed25519_dalek::Signer;
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let alice = hex::encode(key.verifying_key().to_bytes());
+        let mut initial = std::collections::HashMap::new();
+        initial.insert(alice.clone(), 1000);
+        let params = blockchain::ChainParams {
+            block_reward: 50,
+            ..Default::default()
+        };
+        let store = Arc::new(community_coin::storage::MemoryStore::new());
+        let state = test_state(CommunityBlockchain::from_store(initial, params, store).unwrap());
+
+        let mut tx = blockchain::Transaction {
+            tx_id: "stake-1".to_string(),
+            from: alice.clone(),
+            to: blockchain::STAKE_ADDRESS.to_string(),
+            amount: 100,
+            fee: 0,
+            timestamp: 1_700_000_000,
+            signature: String::new(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: blockchain::SigScheme::Ed25519,
+            contract: None,
+        };
+        tx.signature = hex::encode(key.sign(&tx.signing_payload()).to_bytes());
+        let (status, Json(body)) = stake(State(state.clone()), Json(tx)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["stake"], 100);
+        let tx_id = body["tx_id"].as_str().unwrap();
+        assert_eq!(tx_id, "stake-1");
+        assert_eq!(state.blockchain.read().await.find_transaction(tx_id).unwrap().0.to, blockchain::STAKE_ADDRESS);
+    }
+
+    #[tokio::test]
+    async fn test_chain_rejects_oversized_page() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let state = test_state(blockchain);
+
+        let (status, Json(body)) = get_chain(
+            State(state.clone()),
+            Query(PageQuery {
+                offset: 0,
+                limit: Some(DEFAULT_MAX_PAGE_SIZE + 1),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("?offset=&limit="));
+        assert_eq!(body["max_limit"], DEFAULT_MAX_PAGE_SIZE);
+
+        let (status, Json(body)) = get_chain(State(state.clone()), Query(PageQuery::default())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["blocks"].as_array().unwrap().len(), 1);
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["truncated"], false);
+
+        // A short page says how many blocks there are and where to go on
+        for _ in 0..2 {
+            let blockchain = state.blockchain.write().await;
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let page = |offset| get_chain(State(state.clone()), Query(PageQuery { offset, limit: Some(2) }));
+        let (_, Json(first)) = page(0).await;
+        assert_eq!(first["blocks"].as_array().unwrap().len(), 2);
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["truncated"], true);
+        assert_eq!(first["next_offset"], 2);
+        let (_, Json(last)) = page(2).await;
+        assert_eq!(last["blocks"][0]["index"], 2);
+        assert_eq!(last["truncated"], false);
+        assert!(last["next_offset"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_grpc_client_submits_transfer_and_reads_balance() {
+        use grpc::proto::{node_client::NodeClient, GetBalanceRequest, SubscribeBlocksRequest, TransferRequest};
+
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let mut state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+        let (to_peer, mut peer) = tokio::sync::mpsc::unbounded_channel();
+        state.gossip = Some(Arc::new(ChannelSink { peers: vec![to_peer] }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(grpc::serve(state.clone(), listener));
+        let mut client = NodeClient::connect(format!("http://{}", addr)).await.unwrap();
+        let mut blocks = client
+            .subscribe_blocks(SubscribeBlocksRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        let transfer = || TransferRequest {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 100,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            pow_nonce: None,
+            fee: None,
+        };
+        // Refused during maintenance, as /transfer is
+        state.maintenance.store(true, std::sync::atomic::Ordering::SeqCst);
+        let paused = client.submit_transaction(transfer()).await.unwrap_err();
+        assert_eq!(paused.code(), tonic::Code::Unavailable);
+        state.maintenance.store(false, std::sync::atomic::Ordering::SeqCst);
+
+        let submitted = client.submit_transaction(transfer()).await.unwrap().into_inner();
+        assert_eq!(submitted.status, "pending");
+        // and announced to peers
+        let announced: gossip::GossipMessage = serde_json::from_slice(&peer.try_recv().unwrap()).unwrap();
+        assert!(matches!(announced, gossip::GossipMessage::NewTransaction(tx) if tx.tx_id == submitted.tx_id));
+
+        {
+            let blockchain = state.blockchain.write().await;
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let block = blocks.message().await.unwrap().unwrap();
+        assert_eq!(block.index, 1);
+        assert_eq!(block.transactions[0].tx_id, submitted.tx_id);
+
+        let balance = |address: &str| GetBalanceRequest {
+            address: address.to_string(),
+        };
+        let bob = client.get_balance(balance("bob")).await.unwrap().into_inner();
+        assert_eq!(bob.balance, 100);
+        let alice = client.get_balance(balance("alice")).await.unwrap().into_inner();
+        assert_eq!(alice.balance, 1000 - 100 - block.transactions[0].fee);
+
+        let missing = client.get_balance(balance("nobody")).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode_pauses_writes_only() {
+        use tower::ServiceExt;
+
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let mut state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+        state.admin_token = Some("secret".to_string());
+        let app = Router::new()
+            .route("/wallet/:address", get(get_wallet))
+            .route("/transfer", post(transfer))
+            .route("/mine", post(mine_block))
+            .route("/add-block", post(add_block))
+            .route("/stake", post(stake))
+            .route("/unstake", post(unstake))
+            .route("/vote", post(vote))
+            .route("/call-contract", post(call_contract))
+            .route("/call-contract/view", post(call_contract_view))
+            .route("/tx/decode", post(decode_transaction))
+            .route("/admin/maintenance", get(get_maintenance).post(set_maintenance))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), pause_in_maintenance))
+            .with_state(state.clone());
+        let send = |method: &str, uri: &str, body: serde_json::Value, admin: bool| {
+            let mut request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(axum::http::header::CONTENT_TYPE, "application/json");
+            if admin {
+                request = request.header(axum::http::header::AUTHORIZATION, "Bearer secret");
+            }
+            let request = request.body(axum::body::Body::from(body.to_string())).unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+        let toggle = |enabled: bool| send("POST", "/admin/maintenance", json!({"enabled": enabled}), true);
+        let transfer = || send("POST", "/transfer", json!({"from": "alice", "to": "bob", "amount": 100}), false);
+        let mine = || send("POST", "/mine", json!({"proposer": "proposer"}), false);
+
+        // Only the admin may flip the switch
+        let (status, _) =
+            set_maintenance(State(state.clone()), HeaderMap::new(), Json(MaintenanceRequest { enabled: true })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(toggle(true).await, StatusCode::OK);
+        assert_eq!(health(State(state.clone())).await.1["maintenance"], true);
+
+        // Every write is paused, whatever its body
+        assert_eq!(transfer().await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(mine().await, StatusCode::SERVICE_UNAVAILABLE);
+        for uri in ["/add-block", "/stake", "/unstake", "/vote", "/call-contract", "/call-contract/view"] {
+            assert_eq!(send("POST", uri, json!({}), false).await, StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+        }
+        assert!(state.blockchain.read().await.get_pending().is_empty());
+
+        // Reads carry on, as do POSTs that change nothing
+        assert_eq!(send("GET", "/wallet/alice", json!({}), false).await, StatusCode::OK);
+        assert_eq!(send("GET", "/admin/maintenance", json!({}), true).await, StatusCode::OK);
+        assert_ne!(send("POST", "/tx/decode", json!({"raw": "00"}), false).await, StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(toggle(false).await, StatusCode::OK);
+        assert_eq!(health(State(state.clone())).await.1["maintenance"], false);
+        assert_eq!(transfer().await, StatusCode::OK);
+        assert_eq!(mine().await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_decode_reports_signature_and_nonce_validity() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+        let tx = {
+            let blockchain = state.blockchain.read().await;
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+            blockchain.get_pending()[0].clone()
+        };
+        let decode = |raw: String| decode_transaction(State(state.clone()), Json(DecodeTransactionRequest { raw }));
+
+        let raw = serde_json::to_vec(&tx).unwrap();
+        let (status, Json(body)) = decode(hex::encode(&raw)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["encoding"], "hex");
+        assert_eq!(body["transaction"]["tx_id"], tx.tx_id);
+        assert_eq!(body["signature_valid"], true);
+        assert_eq!(body["nonce_valid"], true);
+
+        // Same verdict from base64, and nothing was queued
+        let (_, Json(body)) = decode(base64::encode(&raw)).await;
+        assert_eq!(body["encoding"], "base64");
+        assert_eq!(body["signature_valid"], true);
+        assert_eq!(state.blockchain.read().await.get_pending().len(), 1);
+
+        // Tampered fields and a used nonce are flagged
+        let mut forged = tx.clone();
+        forged.tx_id = "alice-mallory-1-0".to_string();
+        forged.nonce = 0;
+        let (status, Json(body)) = decode(hex::encode(serde_json::to_vec(&forged).unwrap())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["signature_valid"], false);
+        assert_eq!(body["nonce_valid"], false);
+
+        // Bytes that aren't a transaction at all are refused
+        let (status, Json(body)) = decode(hex::encode(b"{\"from\": 7}")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Not a transaction"));
+        let (status, _) = decode("!!not encoded!!".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_replay_protection_status_counts_nonce_replay() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        // With confirmed tx_ids forgotten at once, only the nonce stops a replay
+        let params = blockchain::ChainParams {
+            tx_dedup_blocks: 0,
+            ..blockchain::ChainParams::demo()
+        };
+        let store = Arc::new(community_coin::storage::MemoryStore::new());
+        let state = test_state(CommunityBlockchain::from_store(initial, params, store).unwrap());
+
+        let Json(status) = replay_protection_status(State(state.clone())).await;
+        assert!(status.nonce_enforcement && status.recent_block_binding && status.tx_dedup);
+        assert!(!status.chain_id_binding);
+        assert_eq!(status.tx_dedup_blocks, 0);
+        assert_eq!(status.rejected, blockchain::ReplayRejections::default());
+
+        let tx = {
+            let blockchain = state.blockchain.write().await;
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            let err = blockchain.accept_transaction(block.transactions[0].clone()).unwrap_err();
+            assert!(err.contains("already used"), "{}", err);
+            block.transactions[0].clone()
+        };
+
+        let Json(status) = replay_protection_status(State(state.clone())).await;
+        assert_eq!(status.rejected.nonce, 1);
+        assert_eq!(status.rejected.duplicate, 0);
+        let body = serde_json::to_value(&status).unwrap();
+        assert_eq!(body["rejected"]["nonce"], 1);
+
+        state.blockchain.read().await.accept_transaction(tx).unwrap_err();
+        let Json(status) = replay_protection_status(State(state)).await;
+        assert_eq!(status.rejected.nonce, 2);
+    }
+
+    #[tokio::test]
+    async fn test_submit_tx_signed_with_seeded_genesis_key() {
+        use ed25519_dalek::Signer;
+
+        let seed = "07".repeat(32);
+        let args = <community_coin::genesis::GenesisArgs as clap::Parser>::try_parse_from([
+            "node",
+            "--genesis-key",
+            &format!("{}:5000", seed),
+        ])
+        .unwrap();
+        let account = args.genesis_keys[0].clone();
+        let state = test_state(CommunityBlockchain::new_in_memory(args.wallets()).unwrap());
+        assert_eq!(state.blockchain.read().await.get_balance(&account.address()).unwrap(), 5000);
+
+        // Signed outside the node with the key fixed at genesis
+        let mut tx = blockchain::Transaction {
+            tx_id: "seeded-1".to_string(),
+            from: account.address(),
+            to: "bob".to_string(),
+            amount: 100,
+            fee: blockchain::MIN_FEE,
+            timestamp: 1_700_000_000,
+            signature: String::new(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: blockchain::SigScheme::Ed25519,
+            contract: None,
+        };
+        tx.signature = hex::encode(account.key.sign(&tx.signing_payload()).to_bytes());
+
+        let mut forged = tx.clone();
+        forged.signature = hex::encode(
+            ed25519_dalek::SigningKey::from_bytes(&[8; 32]).sign(&tx.signing_payload()).to_bytes(),
+        );
+        let (status, Json(body)) = submit_tx(State(state.clone()), Json(forged)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Invalid transaction signature");
+
+        let (status, Json(body)) = submit_tx(State(state.clone()), Json(tx)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["tx_id"], "seeded-1");
+
+        let blockchain = state.blockchain.read().await;
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.get_balance("bob").unwrap(), 100);
+        assert!(community_coin::genesis::parse_seeded_account("not-a-seed:5").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_externally_owned_wallet_needs_client_signatures() {
+        use ed25519_dalek::Signer;
+        use sha2::Digest;
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[11; 32]);
+        let address = hex::encode(key.verifying_key().as_bytes());
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        // The system key signs the mint that funds the account
+        let system_key = ed25519_dalek::SigningKey::from_bytes(&[12; 32]);
+        let params = blockchain::ChainParams {
+            system_keys: vec![hex::encode(system_key.verifying_key().as_bytes())],
+            ..Default::default()
+        };
+        let blockchain = CommunityBlockchain::from_store(initial, params, Arc::new(community_coin::storage::MemoryStore::new()))
+            .unwrap()
+            .with_system_key(system_key);
+        let state = test_state(blockchain);
+        let register = |public_key: &str| {
+            register_wallet(
+                State(state.clone()),
+                Json(RegisterWalletRequest {
+                    public_key: public_key.to_string(),
+                }),
+            )
+        };
+
+        let (status, Json(body)) = register(&address.to_uppercase()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["address"], address.as_str());
+        assert_eq!(register(&address).await.0, StatusCode::CONFLICT);
+        assert_eq!(register("not-a-key").await.0, StatusCode::BAD_REQUEST);
+
+        // The node signs for neither the key's account nor, with client
+        // signing required, anyone else's
+        let transfer_from = |from: &str| {
+            transfer(
+                State(state.clone()),
+                Json(TransferRequest {
+                    from: from.to_string(),
+                    to: "bob".to_string(),
+                    amount: 10,
+                    memo: None,
+                    execute_at_height: None,
+                    pow_nonce: None,
+                    recent_block_hash: None,
+                    fee: None,
+                }),
+            )
+        };
+        assert_eq!(transfer_from(&address).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(transfer_from("alice").await.0, StatusCode::FORBIDDEN);
+
+        state.blockchain.read().await.mint(address.clone(), 500).unwrap();
+        let mut tx = blockchain::Transaction {
+            tx_id: "eoa-1".to_string(),
+            from: address.clone(),
+            to: "bob".to_string(),
+            amount: 100,
+            fee: blockchain::MIN_FEE,
+            timestamp: 1_700_000_000,
+            signature: String::new(),
+            nonce: 1,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: blockchain::SigScheme::Sha256,
+            contract: None,
+        };
+
+        // Anyone can compute a digest, so it doesn't pass for the key
+        tx.signature = format!("{:x}", sha2::Sha256::digest(tx.signing_payload()));
+        let (status, Json(body)) = submit_tx(State(state.clone()), Json(tx.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Invalid transaction signature");
+
+        tx.sig_scheme = blockchain::SigScheme::Ed25519;
+        tx.signature = hex::encode(key.sign(&tx.signing_payload()).to_bytes());
+        let (status, Json(body)) = submit_tx(State(state.clone()), Json(tx)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_pretty_query_indents_json() {
+        use tower::ServiceExt;
+
+        let state = test_state(CommunityBlockchain::new_in_memory(std::collections::HashMap::new()).unwrap());
+        let app = Router::new()
+            .route("/version", get(version))
+            .route("/plain", get(|| async { "not json" }))
+            .layer(axum::middleware::from_fn(pretty_json))
+            .with_state(state);
+        let body = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                String::from_utf8(bytes.to_vec()).unwrap()
+            }
+        };
+
+        let compact = body("/version").await;
+        assert!(!compact.contains('\n'));
+        let pretty = body("/version?pretty=true").await;
+        assert!(pretty.lines().count() > 1);
+        assert!(pretty.starts_with("{\n  \""));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap()
+        );
+
+        assert_eq!(body("/plain?pretty=true").await, "not json");
+    }
+
+    #[tokio::test]
+    async fn test_signed_stats_response_verifies_against_node_pubkey() {
+        use ed25519_dalek::Verifier;
+        use tower::ServiceExt;
+
+        let mut state = test_state(CommunityBlockchain::new_in_memory(std::collections::HashMap::new()).unwrap());
+        state.node_key = Some(ed25519_dalek::SigningKey::from_bytes(&[9; 32]));
+        let app = Router::new()
+            .route("/stats", get(stats))
+            .route("/node-pubkey", get(node_pubkey))
+            .layer(axum::middleware::from_fn(pretty_json))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), sign_responses))
+            .with_state(state);
+        let fetch = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let signature = response.headers()["x-node-signature"].to_str().unwrap().to_string();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (body, signature)
+            }
+        };
+
+        let (pubkey, _) = fetch("/node-pubkey").await;
+        let pubkey: serde_json::Value = serde_json::from_slice(&pubkey).unwrap();
+        let pubkey: [u8; 32] = hex::decode(pubkey["public_key"].as_str().unwrap()).unwrap().try_into().unwrap();
+        let pubkey = ed25519_dalek::VerifyingKey::from_bytes(&pubkey).unwrap();
+
+        let (body, signature) = fetch("/stats?pretty=true").await;
+        let signature: [u8; 64] = hex::decode(signature).unwrap().try_into().unwrap();
+        let signature = ed25519_dalek::Signature::from_bytes(&signature);
+        let mut signed = RESPONSE_SIGNATURE_DOMAIN.to_vec();
+        signed.extend_from_slice(&body);
+        assert!(pubkey.verify(&signed, &signature).is_ok());
+
+        // A proxy altering the body breaks the signature
+        signed.push(b' ');
+        assert!(pubkey.verify(&signed, &signature).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_version_reports_genesis_hash() {
+        let genesis_hash = |balance: u64, decimals: u8| {
+            let mut initial = std::collections::HashMap::new();
+            initial.insert("alice".to_string(), balance);
+            initial.insert("bob".to_string(), 500);
+            let params = blockchain::ChainParams {
+                decimals,
+                ..Default::default()
+            };
+            let store = std::sync::Arc::new(community_coin::storage::MemoryStore::new());
+            let state = test_state(CommunityBlockchain::from_store(initial, params, store).unwrap());
+            async move {
+                let (_, Json(version)) = version(State(state)).await;
+                version["genesis_hash"].as_str().unwrap().to_string()
+            }
+        };
+
+        let hash = genesis_hash(1000, 8).await;
+        assert_eq!(hash, genesis_hash(1000, 8).await);
+        assert_ne!(hash, genesis_hash(1001, 8).await);
+        assert_ne!(hash, genesis_hash(1000, 6).await);
+    }
+
+    #[tokio::test]
+    async fn test_mempool_flush_survives_crash() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let store: Arc<dyn community_coin::storage::KvStore> =
+            Arc::new(community_coin::storage::MemoryStore::new());
+        let node = CommunityBlockchain::from_store(initial, blockchain::ChainParams::demo(), store.clone()).unwrap();
+        node.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = node.mine_block("proposer".to_string()).unwrap();
+        node.add_block(block).unwrap();
+        node.create_transaction("alice".to_string(), "bob".to_string(), 200).unwrap();
+        node.create_transaction("alice".to_string(), "carol".to_string(), 300).unwrap();
+        let reserved = node.reserved_balance("alice");
+        let blockchain = Arc::new(RwLock::new(node));
+
+        let flush = spawn_mempool_flush(blockchain.clone(), Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        flush.abort();
+        let _ = flush.await;
+
+        // Submitted after the last flush, so lost in the crash
+        blockchain
+            .read()
+            .await
+            .create_transaction("alice".to_string(), "dave".to_string(), 50)
+            .unwrap();
+        drop(blockchain);
+
+        let reloaded = CommunityBlockchain::load_from_store(store).unwrap();
+        let pending = reloaded.get_pending();
+        assert_eq!(pending.iter().map(|tx| tx.amount).collect::<Vec<_>>(), vec![200, 300]);
+        assert_eq!(pending.iter().map(|tx| tx.nonce).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(reloaded.next_nonce("alice"), 4);
+        assert_eq!(reloaded.reserved_balance("alice"), reserved);
+
+        // The restored transactions still go into the next block
+        let block = reloaded.mine_block("proposer".to_string()).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        reloaded.add_block(block).unwrap();
+        assert!(reloaded.get_pending().is_empty());
+        assert_eq!(reloaded.reserved_balance("alice"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chain_tip_follows_new_blocks() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+
+        let (_, Json(genesis)) = chain_tip(State(state.clone())).await;
+        assert_eq!(genesis["index"], 0);
+
+        let block = {
+            let blockchain = state.blockchain.write().await;
+            blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            let block = blockchain.mine_block("proposer".to_string()).unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            block
+        };
+
+        let (status, Json(tip)) = chain_tip(State(state.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tip["index"], 1);
+        assert_eq!(tip["hash"], block.hash);
+        assert_eq!(tip["prev_hash"], genesis["hash"]);
+        assert_eq!(tip["transactions"], 1);
+        assert_eq!(tip["state_root"], block.state_root);
+    }
+
+    /// Delivers gossip to connected peers' channels, like a transport would
+    struct ChannelSink {
+        peers: Vec<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+    }
+
+    impl GossipSink for ChannelSink {
+        fn broadcast(&self, message: gossip::GossipMessage) -> Result<usize, String> {
+            let bytes = serde_json::to_vec(&message).map_err(|e| e.to_string())?;
+            Ok(self.peers.iter().filter(|peer| peer.send(bytes.clone()).is_ok()).count())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_reports_gossip_propagation() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let request = || TransferRequest {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 10,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            pow_nonce: None,
+            fee: None,
+        };
+
+        // A node with no peers keeps the transaction to itself
+        let alone = test_state(CommunityBlockchain::new_in_memory(initial.clone()).unwrap());
+        let (_, Json(body)) = transfer(State(alone), Json(request())).await;
+        assert_eq!(body["propagation"], "local-only");
+
+        // Node B applies whatever node A gossips to it
+        let node_b = test_state(CommunityBlockchain::new_in_memory(initial.clone()).unwrap());
+        let (to_b, mut inbox) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        let handler = gossip::GossipHandler::new(gossip::GossipLimits::default(), node_b.peers.clone());
+        let blockchain_b = node_b.blockchain.clone();
+        tokio::spawn(async move {
+            while let Some(bytes) = inbox.recv().await {
+                let _ = handler.handle(&blockchain_b, "node-a", &bytes).await;
+            }
+        });
+
+        let mut node_a = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+        node_a.gossip = Some(Arc::new(ChannelSink { peers: vec![to_b] }));
+        let (status, Json(body)) = transfer(State(node_a), Json(request())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["propagation"].as_u64().unwrap() >= 1);
+
+        let tx_id = body["tx_id"].as_str().unwrap().to_string();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !node_b.blockchain.read().await.get_pending().iter().any(|tx| tx.tx_id == tx_id) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_is_capped_and_continues_from_cursor() {
+        let mut initial = std::collections::HashMap::new();
+        initial.insert("alice".to_string(), 100_000);
+        let mut state = test_state(CommunityBlockchain::new_in_memory(initial).unwrap());
+        state.max_history_len = 3;
+        let mut sent = Vec::new();
+        {
+            let blockchain = state.blockchain.write().await;
+            for amount in 1..=7 {
+                sent.push(blockchain.create_transaction("alice".to_string(), "bob".to_string(), amount).unwrap());
+                let block = blockchain.mine_block("proposer".to_string()).unwrap();
+                blockchain.add_block(block).unwrap();
+            }
+        }
+
+        let fetch = |cursor: Option<u64>, limit: Option<usize>| {
+            let state = state.clone();
+            async move {
+                let query = HistoryQuery { cursor, offset: None, limit };
+                let (status, Json(body)) = history(State(state), Path("alice".to_string()), Query(query)).await;
+                assert_eq!(status, StatusCode::OK);
+                body
+            }
+        };
+
+        // Asking for more than the cap still returns only the newest 3
+        let first = fetch(None, Some(1000)).await;
+        assert_eq!(first["transactions"].as_array().unwrap().len(), 3);
+        assert_eq!(first["truncated"], true);
+        assert_eq!(first["next_cursor"], 4);
+
+        let mut received = Vec::new();
+        let mut page = first;
+        loop {
+            received.extend(
+                page["transactions"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|tx| tx["tx_id"].as_str().unwrap().to_string()),
+            );
+            match page["next_cursor"].as_u64() {
+                Some(cursor) => page = fetch(Some(cursor), None).await,
+                None => break,
+            }
+        }
+        assert_eq!(page["truncated"], false);
+        let mut newest_first = sent.clone();
+        newest_first.reverse();
+        assert_eq!(received, newest_first);
+
+        // Offset pages still walk the history oldest first
+        let by_offset = |offset: usize, cursor: Option<u64>| {
+            let state = state.clone();
+            async move {
+                let query = HistoryQuery { cursor, offset: Some(offset), limit: Some(1000) };
+                history(State(state), Path("alice".to_string()), Query(query)).await
+            }
+        };
+        let mut received = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (status, Json(page)) = by_offset(offset, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(page["total"], 7);
+            let txs = page["transactions"].as_array().unwrap();
+            assert!(txs.len() <= 3);
+            received.extend(txs.iter().map(|tx| tx["tx_id"].as_str().unwrap().to_string()));
+            match page["next_offset"].as_u64() {
+                Some(next) => offset = next as usize,
+                None => break,
+            }
+        }
+        assert_eq!(received, sent);
+        let (status, _) = by_offset(0, Some(4)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_listener_rebinds_port_immediately() {
+        let options = ListenerOptions::default();
+        let listener = options.bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Close an accepted connection server-side first so it lingers in TIME_WAIT
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        drop(server_side);
+        drop(listener);
+        drop(client);
+
+        let rebound = options.bind(addr).unwrap();
+        assert_eq!(rebound.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        let blockchain = Arc::new(RwLock::new(
+            CommunityBlockchain::new_in_memory(std::collections::HashMap::new()).unwrap(),
+        ));
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let handler_finished = finished.clone();
+        let app = Router::new().route(
+            "/slow",
+            get(move || async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                handler_finished.store(true, Ordering::SeqCst);
+                "done"
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let request = tokio::spawn(reqwest::get(format!("http://{}/slow", addr)));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            shutdown_tx.send(()).unwrap();
+        });
+
+        serve_with_shutdown(
+            listener,
+            app,
+            blockchain,
+            async move {
+                let _ = shutdown_rx.await;
+            },
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert!(finished.load(Ordering::SeqCst));
+
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        // The listener is gone once the server task exits
+        assert!(reqwest::get(format!("http://{}/slow", addr)).await.is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! The mempool: transactions waiting for a block.
+//!
+//! Each pending transaction is held once, by `tx_id`, alongside the time it
+//! arrived. The pool is bounded in total and per sender, and transactions
+//! that wait longer than their time to live expire. A sender can replace a
+//! pending transaction by sending another with the same nonce and a high
+//! enough fee. Admission is planned with [`Mempool::admit`] before anything
+//! changes, so the caller can still refuse the transaction, e.g. for lack
+//! of balance, and applied with [`Mempool::insert`].
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::blockchain::Transaction;
+
+/// Start of the error returned when the mempool is full and a transaction
+/// doesn't outbid anything in it
+pub const MEMPOOL_FULL: &str = "Mempool is full";
+
+/// Default cap on pending transactions
+pub const DEFAULT_MAX_PENDING_TXS: usize = 10_000;
+
+/// Default cap on one sender's pending transactions
+pub const DEFAULT_MAX_PENDING_PER_SENDER: usize = 500;
+
+/// Default time a transaction may wait in the mempool
+pub const DEFAULT_PENDING_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// How much more fee, in percent, a replacement must pay than the
+/// transaction it replaces
+pub const REPLACEMENT_FEE_BUMP_PERCENT: u64 = 10;
+
+/// Bounds on what the mempool holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolLimits {
+    /// Most transactions pending at once
+    pub max_txs: usize,
+    /// Most transactions one sender may have pending
+    pub max_per_sender: usize,
+    /// Seconds a transaction may wait before it expires, 0 to keep it until
+    /// it is mined
+    pub ttl_secs: u64,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits {
+            max_txs: DEFAULT_MAX_PENDING_TXS,
+            max_per_sender: DEFAULT_MAX_PENDING_PER_SENDER,
+            ttl_secs: DEFAULT_PENDING_TTL_SECS,
+        }
+    }
+}
+
+/// Why the mempool won't take a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MempoolError {
+    #[error("Transaction {0} is already pending")]
+    Duplicate(String),
+    #[error("{MEMPOOL_FULL}: {pending} transactions pending, none paying less than {rate} per 1000 bytes")]
+    Full { pending: usize, rate: u64 },
+    #[error("{sender} already has the most pending transactions allowed ({max})")]
+    SenderLimit { sender: String, max: usize },
+    #[error("Transaction {tx_id} replaces pending nonce {nonce} and must pay a fee of at least {min_fee}")]
+    Underpriced { tx_id: String, nonce: u64, min_fee: u64 },
+}
+
+impl From<MempoolError> for String {
+    fn from(e: MempoolError) -> Self {
+        e.to_string()
+    }
+}
+
+/// What admitting transactions takes, by `tx_id`: the pending transactions
+/// they replace, and those evicted to make room
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Admission {
+    pub replaced: Vec<String>,
+    pub evicted: Vec<String>,
+}
+
+/// Mempool size, fees, limits and what has left it without being mined
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MempoolStats {
+    pub pending: usize,
+    pub senders: usize,
+    pub total_fees: u64,
+    pub min_fee: Option<u64>,
+    pub max_fee: Option<u64>,
+    /// Seconds the longest-waiting transaction has been pending
+    pub oldest_secs: Option<u64>,
+    pub limits: MempoolLimits,
+    pub expired: u64,
+    pub evicted: u64,
+    pub replaced: u64,
+    pub dropped: u64,
+}
+
+struct Entry {
+    tx: Transaction,
+    added_at: u64,
+}
+
+/// Pending transactions, in arrival order
+pub struct Mempool {
+    entries: Vec<Entry>,
+    ids: HashSet<String>,
+    limits: MempoolLimits,
+    expired: u64,
+    evicted: u64,
+    replaced: u64,
+    dropped: u64,
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        Mempool {
+            entries: Vec::new(),
+            ids: HashSet::new(),
+            limits: MempoolLimits {
+                max_txs: limits.max_txs.max(1),
+                max_per_sender: limits.max_per_sender.max(1),
+                ..limits
+            },
+            expired: 0,
+            evicted: 0,
+            replaced: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn limits(&self) -> MempoolLimits {
+        self.limits
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> + Clone {
+        self.entries.iter().map(|entry| &entry.tx)
+    }
+
+    pub fn contains(&self, tx_id: &str) -> bool {
+        self.ids.contains(tx_id)
+    }
+
+    pub fn get(&self, tx_id: &str) -> Option<&Transaction> {
+        if !self.contains(tx_id) {
+            return None;
+        }
+        self.iter().find(|tx| tx.tx_id == tx_id)
+    }
+
+    pub fn to_vec(&self) -> Vec<Transaction> {
+        self.iter().cloned().collect()
+    }
+
+    /// Plan admitting `incoming`, transactions from one sender, without
+    /// changing anything. A transaction with the nonce of one of the
+    /// sender's pending transactions replaces it if it pays at least
+    /// `REPLACEMENT_FEE_BUMP_PERCENT` more. Once the pool is full, only
+    /// the latest pending transaction of each other sender can be evicted,
+    /// so no sender is left with a nonce gap, and only if it pays a lower
+    /// fee rate than every incoming transaction. The lowest rates go first.
+    pub fn admit(&self, incoming: &[Transaction]) -> Result<Admission, MempoolError> {
+        let Some(sender) = incoming.first().map(|tx| tx.from.as_str()) else {
+            return Ok(Admission::default());
+        };
+        let mut seen = HashSet::new();
+        for tx in incoming {
+            if self.contains(&tx.tx_id) || !seen.insert(tx.tx_id.as_str()) {
+                return Err(MempoolError::Duplicate(tx.tx_id.clone()));
+            }
+        }
+
+        let mut admission = Admission::default();
+        let mut own = 0;
+        for pending in self.iter().filter(|pending| pending.from == sender) {
+            let Some(tx) = incoming.iter().find(|tx| tx.nonce == pending.nonce) else {
+                own += 1;
+                continue;
+            };
+            let bump = (pending.fee.saturating_mul(REPLACEMENT_FEE_BUMP_PERCENT) / 100).max(1);
+            let min_fee = pending.fee.saturating_add(bump);
+            if tx.fee < min_fee {
+                return Err(MempoolError::Underpriced {
+                    tx_id: tx.tx_id.clone(),
+                    nonce: tx.nonce,
+                    min_fee,
+                });
+            }
+            admission.replaced.push(pending.tx_id.clone());
+        }
+        if own + incoming.len() > self.limits.max_per_sender {
+            return Err(MempoolError::SenderLimit {
+                sender: sender.to_string(),
+                max: self.limits.max_per_sender,
+            });
+        }
+
+        let rate = incoming.iter().map(Transaction::fee_rate).min().unwrap_or(0);
+        let mut chains: HashMap<&str, Vec<&Transaction>> = HashMap::new();
+        for tx in self.iter().filter(|tx| tx.from != sender) {
+            chains.entry(tx.from.as_str()).or_default().push(tx);
+        }
+        for chain in chains.values_mut() {
+            chain.sort_by_key(|tx| tx.nonce);
+        }
+        let mut remaining = self.len() - admission.replaced.len();
+        while remaining + incoming.len() > self.limits.max_txs {
+            let cheapest = chains
+                .values()
+                .filter_map(|chain| chain.last().copied())
+                .min_by_key(|tx| (tx.fee_rate(), &tx.tx_id))
+                .filter(|tx| tx.fee_rate() < rate);
+            let Some(tx) = cheapest else {
+                return Err(MempoolError::Full {
+                    pending: self.len(),
+                    rate,
+                });
+            };
+            chains.get_mut(tx.from.as_str()).unwrap().pop();
+            admission.evicted.push(tx.tx_id.clone());
+            remaining -= 1;
+        }
+        Ok(admission)
+    }
+
+    /// Apply a planned `admission`: take out what it replaces and evicts,
+    /// which is returned, and queue `incoming` as arriving at `now`
+    pub fn insert(&mut self, admission: &Admission, incoming: Vec<Transaction>, now: u64) -> Vec<Transaction> {
+        let leaving: HashSet<&str> = admission
+            .replaced
+            .iter()
+            .chain(&admission.evicted)
+            .map(String::as_str)
+            .collect();
+        let removed = self.take(|tx| leaving.contains(tx.tx_id.as_str()));
+        self.replaced += admission.replaced.len() as u64;
+        self.evicted += admission.evicted.len() as u64;
+        for tx in incoming {
+            self.push(tx, now);
+        }
+        removed
+    }
+
+    /// Queue `tx` as arriving at `now` without admission checks, e.g. for
+    /// transactions coming back from storage
+    pub fn push(&mut self, tx: Transaction, now: u64) {
+        if self.ids.insert(tx.tx_id.clone()) {
+            self.entries.push(Entry { tx, added_at: now });
+        }
+    }
+
+    /// Queue `txs` ahead of everything pending without admission checks,
+    /// e.g. transactions a reorg took out of the chain
+    pub fn requeue(&mut self, txs: Vec<Transaction>, now: u64) {
+        let entries: Vec<Entry> = txs
+            .into_iter()
+            .filter(|tx| self.ids.insert(tx.tx_id.clone()))
+            .map(|tx| Entry { tx, added_at: now })
+            .collect();
+        self.entries.splice(0..0, entries);
+    }
+
+    /// Keep only the transactions `keep` accepts, returning the rest
+    pub fn retain(&mut self, mut keep: impl FnMut(&Transaction) -> bool) -> Vec<Transaction> {
+        self.take(|tx| !keep(tx))
+    }
+
+    /// Take `tx_id` out of the pool, along with its sender's later
+    /// transactions, which could no longer be mined. Returns them all,
+    /// nothing if `tx_id` isn't pending.
+    pub fn remove(&mut self, tx_id: &str) -> Vec<Transaction> {
+        let Some((from, nonce)) = self.get(tx_id).map(|tx| (tx.from.clone(), tx.nonce)) else {
+            return Vec::new();
+        };
+        let removed = self.take(|tx| tx.from == from && tx.nonce >= nonce);
+        self.dropped += removed.len() as u64;
+        removed
+    }
+
+    /// Take out transactions that have waited `ttl_secs` or longer by
+    /// `now`, along with their senders' later transactions. Returns them.
+    pub fn expire(&mut self, now: u64) -> Vec<Transaction> {
+        if self.limits.ttl_secs == 0 {
+            return Vec::new();
+        }
+        let mut first_expired: HashMap<String, u64> = HashMap::new();
+        for entry in &self.entries {
+            if now.saturating_sub(entry.added_at) >= self.limits.ttl_secs {
+                let nonce = first_expired.entry(entry.tx.from.clone()).or_insert(entry.tx.nonce);
+                *nonce = (*nonce).min(entry.tx.nonce);
+            }
+        }
+        let removed = self.take(|tx| first_expired.get(&tx.from).is_some_and(|nonce| tx.nonce >= *nonce));
+        self.expired += removed.len() as u64;
+        removed
+    }
+
+    /// Size, fees and limits as of `now`
+    pub fn stats(&self, now: u64) -> MempoolStats {
+        let fees = self.iter().map(|tx| tx.fee);
+        MempoolStats {
+            pending: self.len(),
+            senders: self.iter().map(|tx| tx.from.as_str()).collect::<HashSet<_>>().len(),
+            total_fees: fees.clone().fold(0, u64::saturating_add),
+            min_fee: fees.clone().min(),
+            max_fee: fees.max(),
+            oldest_secs: self.entries.iter().map(|entry| now.saturating_sub(entry.added_at)).max(),
+            limits: self.limits,
+            expired: self.expired,
+            evicted: self.evicted,
+            replaced: self.replaced,
+            dropped: self.dropped,
+        }
+    }
+
+    /// Take out the transactions `leave` picks, in order
+    fn take(&mut self, mut leave: impl FnMut(&Transaction) -> bool) -> Vec<Transaction> {
+        let mut removed = Vec::new();
+        let mut kept = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.drain(..) {
+            if leave(&entry.tx) {
+                self.ids.remove(&entry.tx.tx_id);
+                removed.push(entry.tx);
+            } else {
+                kept.push(entry);
+            }
+        }
+        self.entries = kept;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(from: &str, nonce: u64, fee: u64) -> Transaction {
+        Transaction {
+            from: from.to_string(),
+            to: "bob".to_string(),
+            amount: 10,
+            fee,
+            timestamp: 0,
+            tx_id: format!("{}-{}-{}", from, nonce, fee),
+            signature: String::new(),
+            nonce,
+            memo: None,
+            execute_at_height: None,
+            recent_block_hash: None,
+            sig_scheme: Default::default(),
+            contract: None,
+        }
+    }
+
+    #[test]
+    fn test_full_pool_evicts_cheapest_chain_tails_and_expires_by_age() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_txs: 3,
+            max_per_sender: 10,
+            ttl_secs: 100,
+        });
+        // carol's cheap first transaction sits behind a pricier one, so it
+        // can't go before it
+        for (pending, now) in [(tx("alice", 1, 50), 0), (tx("carol", 1, 5), 0), (tx("carol", 2, 30), 50)] {
+            let admission = mempool.admit(std::slice::from_ref(&pending)).unwrap();
+            assert!(mempool.insert(&admission, vec![pending], now).is_empty());
+        }
+
+        let incoming = tx("dave", 1, 40);
+        let admission = mempool.admit(std::slice::from_ref(&incoming)).unwrap();
+        assert_eq!(admission.evicted, vec!["carol-2-30"]);
+        assert!(matches!(mempool.admit(&[tx("dave", 1, 20)]), Err(MempoolError::Full { .. })));
+        assert_eq!(mempool.insert(&admission, vec![incoming.clone()], 100).len(), 1);
+        assert!(matches!(mempool.admit(&[incoming]), Err(MempoolError::Duplicate(_))));
+
+        // Expiry takes a sender's later transactions along
+        mempool.push(tx("carol", 2, 30), 100);
+        let expired: Vec<String> = mempool.expire(150).into_iter().map(|tx| tx.tx_id).collect();
+        assert_eq!(expired, ["alice-1-50", "carol-1-5", "carol-2-30"]);
+        assert_eq!(mempool.to_vec(), vec![tx("dave", 1, 40)]);
+
+        let stats = mempool.stats(150);
+        assert_eq!((stats.pending, stats.evicted, stats.expired, stats.oldest_secs), (1, 1, 3, Some(50)));
+    }
+}
@@ -0,0 +1,176 @@
+//! Merkle trees over a block's transactions. A block commits to its
+//! transactions through the tree's root, and a proof of a few sibling
+//! hashes shows a transaction is in a block without the rest of it.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blockchain::Transaction;
+
+// Leaves and inner nodes are hashed under different prefixes, so an inner
+// node can't pass for a transaction
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// One step up from a leaf to the root
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProofStep {
+    /// Hex hash of the sibling node
+    pub hash: String,
+    /// Whether the sibling sits on the left
+    pub left: bool,
+}
+
+/// Proof that a transaction is included in a block
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub tx_id: String,
+    /// Height of the block holding the transaction
+    pub block_index: u64,
+    /// Position of the transaction in the block
+    pub position: usize,
+    /// Siblings from the leaf up
+    pub siblings: Vec<ProofStep>,
+    /// The block's `tx_root`
+    pub root: String,
+}
+
+impl MerkleProof {
+    /// Whether `tx` hashes up through the siblings to `root`. Light clients
+    /// should also check `root` against the block header they trust.
+    pub fn verify(&self, tx: &Transaction) -> bool {
+        let mut hash = leaf_hash(tx);
+        for step in &self.siblings {
+            let Some(sibling) = decode(&step.hash) else {
+                return false;
+            };
+            hash = match step.left {
+                true => node_hash(&sibling, &hash),
+                false => node_hash(&hash, &sibling),
+            };
+        }
+        tx.tx_id == self.tx_id && hex::encode(hash) == self.root
+    }
+}
+
+fn decode(hash: &str) -> Option<[u8; 32]> {
+    hex::decode(hash).ok()?.try_into().ok()
+}
+
+/// Hash of a transaction as a leaf, over its full JSON encoding
+pub fn leaf_hash(tx: &Transaction) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(serde_json::to_vec(tx).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The tree's levels, leaves first. A node without a sibling moves up a
+/// level as it is rather than being paired with a copy of itself.
+fn levels(txs: &[Transaction]) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![txs.iter().map(leaf_hash).collect::<Vec<_>>()];
+    while levels.last().unwrap().len() > 1 {
+        let next = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// Hex Merkle root of `txs`. A block without transactions has an all-zero
+/// root.
+pub fn root(txs: &[Transaction]) -> String {
+    match levels(txs).last().unwrap().first() {
+        Some(root) => hex::encode(root),
+        None => hex::encode([0u8; 32]),
+    }
+}
+
+/// Siblings proving the transaction at `position` in `txs`, or `None` if
+/// there is none there
+pub fn proof(txs: &[Transaction], position: usize) -> Option<Vec<ProofStep>> {
+    if position >= txs.len() {
+        return None;
+    }
+    let levels = levels(txs);
+    let mut index = position;
+    let mut siblings = Vec::new();
+    for level in &levels[..levels.len() - 1] {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            siblings.push(ProofStep {
+                hash: hex::encode(hash),
+                left: sibling < index,
+            });
+        }
+        index /= 2;
+    }
+    Some(siblings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_transaction_proves_against_the_root() {
+        let txs: Vec<Transaction> = (1..=5)
+            .map(|nonce| Transaction {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: 10,
+                fee: 1,
+                timestamp: 0,
+                tx_id: format!("tx-{}", nonce),
+                signature: String::new(),
+                nonce,
+                memo: None,
+                execute_at_height: None,
+                recent_block_hash: None,
+                sig_scheme: Default::default(),
+                contract: None,
+            })
+            .collect();
+        let root = root(&txs);
+
+        for (position, tx) in txs.iter().enumerate() {
+            let proof = MerkleProof {
+                tx_id: tx.tx_id.clone(),
+                block_index: 1,
+                position,
+                siblings: proof(&txs, position).unwrap(),
+                root: root.clone(),
+            };
+            assert!(proof.verify(tx));
+
+            // A tampered transaction or a proof for another one fails
+            let mut forged = tx.clone();
+            forged.amount = 1_000;
+            assert!(!proof.verify(&forged));
+            assert!(!proof.verify(&txs[(position + 1) % txs.len()]));
+        }
+        assert!(proof(&txs, 5).is_none());
+
+        // Order matters, and an empty block has the zero root
+        let mut reordered = txs.clone();
+        reordered.swap(0, 1);
+        assert_ne!(super::root(&reordered), root);
+        assert_eq!(super::root(&[]), "0".repeat(64));
+    }
+}
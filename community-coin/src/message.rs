@@ -0,0 +1,79 @@
+//! Signing of arbitrary messages, e.g. for wallet login, kept apart from
+//! transaction signing.
+//!
+//! Like EIP-191 personal messages, the signed bytes are a fixed domain tag,
+//! the message length in decimal and then the message. Nothing else the
+//! chain signs starts with the tag, so a message signature can never pass
+//! for another kind of signature or the other way round.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+/// Prefix of every signed message
+pub const MESSAGE_DOMAIN: &[u8] = b"\x19Community Coin Signed Message:\n";
+
+/// The bytes actually signed for `message`
+pub fn prefixed(message: &[u8]) -> Vec<u8> {
+    let mut bytes = MESSAGE_DOMAIN.to_vec();
+    bytes.extend_from_slice(message.len().to_string().as_bytes());
+    bytes.extend_from_slice(message);
+    bytes
+}
+
+pub fn sign_message(key: &SigningKey, message: &[u8]) -> Signature {
+    key.sign(&prefixed(message))
+}
+
+pub fn verify_message(public_key: &VerifyingKey, message: &[u8], signature: &Signature) -> bool {
+    public_key.verify(&prefixed(message), signature).is_ok()
+}
+
+/// [`verify_message`] with a hex-encoded public key and signature, as sent
+/// to `/verify-message`. Fails if either is malformed.
+pub fn verify_message_hex(public_key: &str, message: &[u8], signature: &str) -> Result<bool, String> {
+    let public_key: [u8; 32] = hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Public key must be 32 hex-encoded bytes")?;
+    let public_key = VerifyingKey::from_bytes(&public_key).map_err(|e| format!("Invalid public key: {}", e))?;
+    let signature: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Signature must be 64 hex-encoded bytes")?;
+
+    Ok(verify_message(&public_key, message, &Signature::from_bytes(&signature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_and_transaction_signatures_are_not_interchangeable() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key();
+        let message = b"Log in to community-coin.example at 1700000000";
+
+        let signature = sign_message(&key, message);
+        assert!(verify_message(&public_key, message, &signature));
+        assert!(!verify_message(&public_key, b"Log in somewhere else", &signature));
+        assert_eq!(
+            verify_message_hex(
+                &hex::encode(public_key.as_bytes()),
+                message,
+                &hex::encode(signature.to_bytes())
+            ),
+            Ok(true)
+        );
+
+        // A raw signature over a transaction id is not a message signature
+        let tx_id = b"alice-bob-1-1700000000";
+        let tx_signature = key.sign(tx_id);
+        assert!(!verify_message(&public_key, tx_id, &tx_signature));
+
+        // and a message signature over the same bytes is no transaction signature
+        let message_signature = sign_message(&key, tx_id);
+        assert!(public_key.verify(tx_id, &message_signature).is_err());
+
+        assert!(verify_message_hex("zz", message, "00").is_err());
+    }
+}
@@ -0,0 +1,701 @@
+//! The libp2p transport behind gossip: a gossipsub swarm over TCP with
+//! noise and yamux, finding peers on the local network with mDNS, that
+//! publishes what the node broadcasts and applies what peers send to the
+//! chain. Alongside it runs the [`crate::sync`] request-response protocol,
+//! so a node that falls behind downloads the blocks it missed, and pings
+//! that sample each peer's clock for [`ClockMonitor`].
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use libp2p::futures::StreamExt;
+use libp2p::gossipsub::{self, IdentTopic, MessageAuthenticity, PublishError};
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{identity, mdns, noise, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm};
+use tokio::sync::{mpsc, oneshot, RwLock};
+
+use crate::blockchain::CommunityBlockchain;
+use crate::gossip::{
+    GossipHandler, GossipLimits, GossipMessage, GossipSink, QueuedSink, DEFAULT_MAX_QUEUED_BROADCASTS,
+    INSUFFICIENT_PEERS,
+};
+use crate::peers::PeerManager;
+use crate::storage::KvStore;
+use crate::sync::{self, BlockSync, SyncRequest, SyncResponse, SYNC_PROTOCOL};
+use crate::timesync::{self, ClockMonitor, Ping, Pong, DEFAULT_CLOCK_SKEW_THRESHOLD_SECS, PING_PROTOCOL};
+
+/// Prefix of the gossipsub topic every message is published on. The
+/// chain's genesis hash follows it, so nodes on other chains never share a
+/// topic.
+pub const GOSSIP_TOPIC: &str = "community-coin/gossip/1";
+
+/// Port the full node listens for peers on unless `--p2p-port` is given
+pub const DEFAULT_P2P_PORT: u16 = 10000;
+
+/// Seconds between asking a peer whether it is ahead of us, and pinging
+/// every peer for its clock, besides when they connect
+pub const SYNC_INTERVAL_SECS: u64 = 30;
+
+/// Key the node's libp2p identity is stored under
+const NODE_KEY: &[u8] = b"p2p_key";
+
+/// The node's libp2p identity from `store`, generated and stored on first
+/// start, so its peer id stays the same across restarts
+pub fn node_keypair(store: &dyn KvStore) -> Result<identity::Keypair, String> {
+    if let Some(bytes) = store.get(NODE_KEY).map_err(|e| e.to_string())? {
+        return identity::Keypair::from_protobuf_encoding(&bytes).map_err(|e| format!("Invalid node key: {}", e));
+    }
+    let keypair = identity::Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding().map_err(|e| e.to_string())?;
+    store.insert(NODE_KEY, &bytes).map_err(|e| format!("Failed to store node key: {}", e))?;
+    Ok(keypair)
+}
+
+/// Peer-to-peer flags of the node binaries
+#[derive(Parser, Debug, Default)]
+pub struct P2pArgs {
+    /// TCP port to listen for peers on
+    #[arg(long, default_value_t = DEFAULT_P2P_PORT)]
+    pub p2p_port: u16,
+
+    /// Address of a peer to dial at startup, e.g.
+    /// `/ip4/10.0.0.2/tcp/10000`. Repeatable.
+    #[arg(long = "bootstrap", value_name = "MULTIADDR")]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Don't look for peers on the local network with mDNS
+    #[arg(long)]
+    pub no_mdns: bool,
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    sync: request_response::json::Behaviour<SyncRequest, SyncResponse>,
+    ping: request_response::json::Behaviour<Ping, Pong>,
+}
+
+/// A message for the event loop to publish, with where to send the result
+/// when the publisher waits for it
+type Outgoing = (Vec<u8>, Option<oneshot::Sender<Result<usize, String>>>);
+
+/// Hands messages to the swarm's event loop, which publishes them. Fails
+/// like gossipsub when no peer is subscribed, so [`QueuedSink`] holds them.
+struct TopicSink {
+    outbox: mpsc::UnboundedSender<Outgoing>,
+    topic_peers: Arc<AtomicUsize>,
+}
+
+#[async_trait::async_trait]
+impl GossipSink for TopicSink {
+    /// Queue `message` for publishing, reporting the peers subscribed to
+    /// the topic now
+    fn broadcast(&self, message: GossipMessage) -> Result<usize, String> {
+        let peers = self.topic_peers.load(Ordering::SeqCst);
+        if peers == 0 {
+            return Err(format!("Publish failed: {}", INSUFFICIENT_PEERS));
+        }
+        let bytes = serde_json::to_vec(&message).map_err(|e| e.to_string())?;
+        self.outbox.send((bytes, None)).map_err(|_| "Network service stopped".to_string())?;
+        Ok(peers)
+    }
+
+    /// Publish `message` and report what gossipsub did with it
+    async fn publish(&self, message: GossipMessage) -> Result<usize, String> {
+        if self.topic_peers.load(Ordering::SeqCst) == 0 {
+            return Err(format!("Publish failed: {}", INSUFFICIENT_PEERS));
+        }
+        let bytes = serde_json::to_vec(&message).map_err(|e| e.to_string())?;
+        let (reply, published) = oneshot::channel();
+        self.outbox
+            .send((bytes, Some(reply)))
+            .map_err(|_| "Network service stopped".to_string())?;
+        published.await.map_err(|_| "Network service stopped".to_string())?
+    }
+}
+
+/// A node's gossipsub swarm
+pub struct NetworkService {
+    swarm: Swarm<Behaviour>,
+    topic: IdentTopic,
+    handler: GossipHandler,
+    peers: Arc<PeerManager>,
+    outbox: mpsc::UnboundedReceiver<Outgoing>,
+    topic_peers: Arc<AtomicUsize>,
+    sink: Arc<QueuedSink>,
+    /// The peer being synced from, with the sync once it answered `Status`
+    syncing: Option<(PeerId, Option<BlockSync>)>,
+    /// Best height each peer last reported
+    heights: HashMap<PeerId, u64>,
+    /// Counts the turns taken asking peers for their height
+    sync_turn: usize,
+    /// Our chain's genesis hash, which peers must report too
+    genesis: String,
+    clock: Arc<ClockMonitor>,
+    now: fn() -> u64,
+}
+
+impl NetworkService {
+    /// Build the swarm under `keypair` and subscribe to the [`GOSSIP_TOPIC`]
+    /// of the chain with `genesis` hash. Connections are admitted against
+    /// `peers`' limits, and gossip over `limits` is refused.
+    pub fn new(
+        keypair: identity::Keypair,
+        genesis: String,
+        limits: GossipLimits,
+        peers: Arc<PeerManager>,
+        mdns: bool,
+    ) -> Result<Self, String> {
+        let config = limits.gossipsub_config()?;
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)
+            .map_err(|e| e.to_string())?
+            .with_behaviour(|key| {
+                let gossipsub = gossipsub::Behaviour::new(MessageAuthenticity::Signed(key.clone()), config)?;
+                let mdns = match mdns {
+                    true => Some(mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?),
+                    false => None,
+                };
+                let sync = request_response::json::Behaviour::new(
+                    [(StreamProtocol::new(SYNC_PROTOCOL), ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let ping = request_response::json::Behaviour::new(
+                    [(StreamProtocol::new(PING_PROTOCOL), ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                Ok(Behaviour {
+                    gossipsub,
+                    mdns: Toggle::from(mdns),
+                    sync,
+                    ping,
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+
+        let topic = IdentTopic::new(format!("{}/{}", GOSSIP_TOPIC, genesis));
+        swarm.behaviour_mut().gossipsub.subscribe(&topic).map_err(|e| e.to_string())?;
+
+        let (sender, outbox) = mpsc::unbounded_channel();
+        let topic_peers = Arc::new(AtomicUsize::new(0));
+        let transport = TopicSink {
+            outbox: sender,
+            topic_peers: topic_peers.clone(),
+        };
+        Ok(NetworkService {
+            swarm,
+            topic,
+            handler: GossipHandler::new(limits, peers.clone()),
+            peers,
+            outbox,
+            topic_peers,
+            sink: Arc::new(QueuedSink::new(Arc::new(transport), DEFAULT_MAX_QUEUED_BROADCASTS)),
+            syncing: None,
+            heights: HashMap::new(),
+            sync_turn: 0,
+            genesis,
+            clock: Arc::new(ClockMonitor::new(DEFAULT_CLOCK_SKEW_THRESHOLD_SECS)),
+            now: timesync::local_time,
+        })
+    }
+
+    /// Record peers' clock samples in `clock`, e.g. the monitor `/health`
+    /// reports from
+    pub fn with_clock_monitor(mut self, clock: Arc<ClockMonitor>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Read the local time from `now` instead of the system clock
+    #[cfg(test)]
+    fn with_clock(mut self, now: fn() -> u64) -> Self {
+        self.now = now;
+        self
+    }
+
+    /// The monitor peers' clock samples are recorded in
+    pub fn clock_monitor(&self) -> Arc<ClockMonitor> {
+        self.clock.clone()
+    }
+
+    /// Where to broadcast transactions, blocks and votes. Messages sent
+    /// while no peer is subscribed go out once one is.
+    pub fn sink(&self) -> Arc<QueuedSink> {
+        self.sink.clone()
+    }
+
+    /// Connected peers, admitted against the limits given at startup
+    pub fn peers(&self) -> Arc<PeerManager> {
+        self.peers.clone()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Start listening on `address`, returning the address actually bound,
+    /// e.g. the port picked for `/tcp/0`
+    pub async fn listen(&mut self, address: Multiaddr) -> Result<Multiaddr, String> {
+        self.swarm.listen_on(address).map_err(|e| e.to_string())?;
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = self.swarm.select_next_some().await {
+                return Ok(address);
+            }
+        }
+    }
+
+    /// Dial a peer, e.g. a bootstrap node
+    pub fn dial(&mut self, address: Multiaddr) -> Result<(), String> {
+        self.swarm.dial(address).map_err(|e| e.to_string())
+    }
+
+    /// Drive the swarm: publish what is broadcast, apply peers' gossip to
+    /// `blockchain`, validated like any other block or transaction, catch up
+    /// with peers that are ahead and serve peers that are behind. Runs until
+    /// the task is dropped.
+    pub async fn run(mut self, blockchain: Arc<RwLock<CommunityBlockchain>>) {
+        let mut sync_ticker = tokio::time::interval(Duration::from_secs(SYNC_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                _ = sync_ticker.tick() => {
+                    let tip = blockchain.read().await.tip().index;
+                    if let Some(peer) = self.sync_peer(tip) {
+                        self.request_status(peer);
+                    }
+                    let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+                    for peer in peers {
+                        self.ping(peer);
+                    }
+                }
+                Some((bytes, reply)) = self.outbox.recv() => {
+                    let published = match self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), bytes) {
+                        // Sent to every subscribed peer, as gossipsub floods
+                        // what it publishes itself. A duplicate, e.g. a block
+                        // we received being announced, already went to them.
+                        Ok(_) | Err(PublishError::Duplicate) => Ok(self.topic_peer_count()),
+                        Err(PublishError::InsufficientPeers) => Err(format!("Publish failed: {}", INSUFFICIENT_PEERS)),
+                        Err(e) => {
+                            tracing::warn!("Failed to publish gossip: {}", e);
+                            Err(format!("Publish failed: {}", e))
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        let _ = reply.send(published);
+                    }
+                }
+                event = self.swarm.select_next_some() => self.on_event(event, &blockchain).await,
+            }
+        }
+    }
+
+    async fn on_event(&mut self, event: SwarmEvent<BehaviourEvent>, blockchain: &RwLock<CommunityBlockchain>) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } if num_established.get() == 1 => {
+                let peer = peer_id.to_string();
+                let admitted = match endpoint.is_dialer() {
+                    true => self.peers.dial_outbound(&peer),
+                    false => self.peers.accept_inbound(&peer),
+                };
+                match admitted {
+                    Ok(()) => {
+                        self.request_status(peer_id);
+                        self.ping(peer_id);
+                    }
+                    Err(e) => {
+                        tracing::warn!(peer = %peer_id, "{}", e);
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.peers.disconnect(&peer_id.to_string());
+                self.heights.remove(&peer_id);
+                self.clock.forget(&peer_id.to_string());
+                self.count_topic_peers();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(event)) => match event {
+                gossipsub::Event::Message {
+                    propagation_source,
+                    message,
+                    ..
+                } => {
+                    let peer = propagation_source.to_string();
+                    if let Err(e) = self.handler.handle(blockchain, &peer, &message.data).await {
+                        tracing::warn!(%peer, "Rejected gossip: {}", e);
+                    }
+                }
+                gossipsub::Event::Subscribed { .. } => {
+                    self.count_topic_peers();
+                    self.sink.peers_connected();
+                }
+                gossipsub::Event::Unsubscribed { .. } => self.count_topic_peers(),
+                _ => {}
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(event)) => self.on_sync_event(event, blockchain).await,
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(event)) => self.on_ping_event(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                for (peer_id, address) in found {
+                    if !self.swarm.is_connected(&peer_id) {
+                        let _ = self.swarm.dial(address);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The peer to sync from next: the one reporting the most blocks above
+    /// our `tip`, or if none is known to be ahead, the next peer in turn
+    fn sync_peer(&mut self, tip: u64) -> Option<PeerId> {
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        let tallest = peers
+            .iter()
+            .filter_map(|peer| Some((*peer, *self.heights.get(peer)?)))
+            .filter(|(_, height)| *height > tip)
+            .max_by_key(|(_, height)| *height);
+        if let Some((peer, _)) = tallest {
+            return Some(peer);
+        }
+        self.sync_turn = self.sync_turn.wrapping_add(1);
+        peers.get(self.sync_turn % peers.len().max(1)).copied()
+    }
+
+    /// Ask `peer` for its height and genesis hash, and sync from it unless
+    /// already syncing from a peer
+    fn request_status(&mut self, peer: PeerId) {
+        self.swarm.behaviour_mut().sync.send_request(&peer, SyncRequest::Status);
+        if self.syncing.is_none() {
+            self.syncing = Some((peer, None));
+        }
+    }
+
+    /// Sample `peer`'s clock
+    fn ping(&mut self, peer: PeerId) {
+        let ping = Ping { sent_at: (self.now)() };
+        self.swarm.behaviour_mut().ping.send_request(&peer, ping);
+    }
+
+    /// Answer peers' pings with our time, and record their answers to ours
+    fn on_ping_event(&mut self, event: request_response::Event<Ping, Pong>) {
+        match event {
+            request_response::Event::Message {
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let pong = Pong::reply(&request, (self.now)());
+                let _ = self.swarm.behaviour_mut().ping.send_response(channel, pong);
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+            } => {
+                self.clock.record_pong(&peer.to_string(), &response, (self.now)());
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_sync_event(
+        &mut self,
+        event: request_response::Event<SyncRequest, SyncResponse>,
+        blockchain: &RwLock<CommunityBlockchain>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let response = sync::serve(&*blockchain.read().await, request);
+                let _ = self.swarm.behaviour_mut().sync.send_response(channel, response);
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+            } => {
+                // Peers on another chain, or that can't say which, are
+                // dropped when they first report their status
+                if let SyncResponse::Status { genesis, .. } = &response {
+                    if genesis.as_deref() != Some(self.genesis.as_str()) {
+                        tracing::warn!(%peer, "Disconnecting a peer that isn't on our chain");
+                        self.heights.remove(&peer);
+                        let _ = self.swarm.disconnect_peer_id(peer);
+                        if matches!(self.syncing, Some((syncing_from, _)) if syncing_from == peer) {
+                            self.syncing = None;
+                        }
+                        return;
+                    }
+                }
+                let Some((syncing_from, sync)) = self.syncing.take() else {
+                    return;
+                };
+                if syncing_from != peer {
+                    self.syncing = Some((syncing_from, sync));
+                    return;
+                }
+                if let SyncResponse::Status { height, .. } = &response {
+                    self.heights.insert(peer, *height);
+                }
+                let next = match sync {
+                    None => BlockSync::start(&*blockchain.read().await, response),
+                    Some(mut sync) => {
+                        let added = sync.on_response(&*blockchain.write().await, response);
+                        added.map(|_| Some(sync))
+                    }
+                };
+                match next {
+                    Ok(Some(sync)) => match sync.next_request() {
+                        Some(request) => {
+                            self.swarm.behaviour_mut().sync.send_request(&peer, request);
+                            self.syncing = Some((peer, Some(sync)));
+                        }
+                        None => tracing::info!(%peer, "Synced to height {}", blockchain.read().await.tip().index),
+                    },
+                    Ok(None) => {}
+                    Err(e) => {
+                        // Not chosen for its height again until it reports
+                        // one afresh
+                        self.heights.remove(&peer);
+                        tracing::warn!(%peer, "Stopped syncing: {}", e);
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                if matches!(self.syncing, Some((syncing_from, _)) if syncing_from == peer) {
+                    tracing::warn!(%peer, "Stopped syncing: {}", error);
+                    self.syncing = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Peers subscribed to the gossip topic
+    fn topic_peer_count(&self) -> usize {
+        let topic = self.topic.hash();
+        self.swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .count()
+    }
+
+    fn count_topic_peers(&self) {
+        self.topic_peers.store(self.topic_peer_count(), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::peers::PeerLimits;
+
+    fn node() -> (NetworkService, Arc<RwLock<CommunityBlockchain>>) {
+        node_with("alice")
+    }
+
+    /// A node on the chain that funds `account`
+    fn node_with(account: &str) -> (NetworkService, Arc<RwLock<CommunityBlockchain>>) {
+        let mut initial = HashMap::new();
+        initial.insert(account.to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let genesis = blockchain.genesis_hash().unwrap();
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let service = NetworkService::new(
+            identity::Keypair::generate_ed25519(),
+            genesis,
+            GossipLimits::default(),
+            Arc::new(PeerManager::new(PeerLimits::default())),
+            false,
+        )
+        .unwrap();
+        (service, blockchain)
+    }
+
+    #[tokio::test]
+    async fn test_gossiped_block_reaches_a_connected_node() {
+        let (mut a, chain_a) = node();
+        let (mut b, chain_b) = node();
+        let address = a.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        b.dial(address).unwrap();
+
+        let block = {
+            let chain = chain_a.read().await;
+            chain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+            let block = chain.mine_block("proposer".to_string()).unwrap();
+            chain.add_block(block.clone()).unwrap();
+            block
+        };
+        // Sent before B has subscribed, so it waits in the queue
+        let sink = a.sink();
+        assert_eq!(sink.broadcast(GossipMessage::NewBlock(block.clone())), Ok(0));
+        assert_eq!(sink.queued(), 1);
+
+        tokio::spawn(a.run(chain_a.clone()));
+        tokio::spawn(b.run(chain_b.clone()));
+
+        let synced = tokio::time::timeout(Duration::from_secs(20), async {
+            while chain_b.read().await.tip().index < 1 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(synced.is_ok(), "block never reached the other node");
+        assert_eq!(chain_b.read().await.tip().hash, block.hash);
+        assert_eq!(chain_b.read().await.get_balance("bob"), Ok(100));
+        assert_eq!(sink.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_late_node_syncs_missed_blocks() {
+        let (mut a, chain_a) = node();
+        let (mut b, chain_b) = node();
+        // A mines blocks nobody hears about over gossip
+        {
+            let chain = chain_a.read().await;
+            for _ in 0..3 {
+                chain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+                let block = chain.mine_block("proposer".to_string()).unwrap();
+                chain.add_block(block).unwrap();
+            }
+        }
+        let address = a.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        b.dial(address).unwrap();
+        tokio::spawn(a.run(chain_a.clone()));
+        tokio::spawn(b.run(chain_b.clone()));
+
+        let synced = tokio::time::timeout(Duration::from_secs(20), async {
+            while chain_b.read().await.tip().index < 3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(synced.is_ok(), "late node never caught up");
+        assert_eq!(chain_b.read().await.tip().hash, chain_a.read().await.tip().hash);
+        assert_eq!(chain_b.read().await.get_balance("bob"), Ok(30));
+    }
+
+    #[tokio::test]
+    async fn test_propagation_counts_peers_a_transaction_reached() {
+        let (mut a, chain_a) = node();
+        let (mut b, chain_b) = node();
+        let sink = a.sink();
+        let tx = |chain: &CommunityBlockchain| {
+            let tx_id = chain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+            chain.get_pending().into_iter().find(|tx| tx.tx_id == tx_id).unwrap()
+        };
+
+        // Nobody to publish to yet
+        let first = tx(&*chain_a.read().await);
+        let propagation = crate::gossip::announce_transaction(Some(sink.as_ref()), first).await;
+        assert_eq!(propagation, crate::gossip::Propagation::LocalOnly);
+
+        let address = a.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        b.dial(address).unwrap();
+        tokio::spawn(a.run(chain_a.clone()));
+        tokio::spawn(b.run(chain_b.clone()));
+        // The queued transaction goes out once B subscribes
+        let delivered = tokio::time::timeout(Duration::from_secs(20), async {
+            while chain_b.read().await.get_pending().is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(delivered.is_ok(), "queued transaction never reached the other node");
+
+        let second = tx(&*chain_a.read().await);
+        let propagation = crate::gossip::announce_transaction(Some(sink.as_ref()), second.clone()).await;
+        assert_eq!(propagation, crate::gossip::Propagation::Peers(1));
+        let delivered = tokio::time::timeout(Duration::from_secs(20), async {
+            while !chain_b.read().await.get_pending().iter().any(|tx| tx.tx_id == second.tx_id) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(delivered.is_ok(), "published transaction never reached the other node");
+    }
+
+    #[tokio::test]
+    async fn test_node_on_another_chain_is_disconnected() {
+        let (mut a, chain_a) = node();
+        let (mut c, chain_c) = node_with("mallory");
+        // Nor do they share a gossip topic
+        assert_ne!(a.topic.hash(), c.topic.hash());
+        let address = a.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        c.dial(address).unwrap();
+        tokio::spawn(a.run(chain_a));
+
+        // Drive C by hand to watch it connect, then drop on the first status
+        let dropped = tokio::time::timeout(Duration::from_secs(20), async {
+            let mut connected = false;
+            loop {
+                let event = c.swarm.select_next_some().await;
+                connected |= matches!(event, SwarmEvent::ConnectionEstablished { .. });
+                let closed = connected && matches!(event, SwarmEvent::ConnectionClosed { .. });
+                c.on_event(event, &chain_c).await;
+                if closed {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(dropped.is_ok(), "peer on another chain stayed connected");
+        assert!(c.peers.counts().peers.is_empty());
+    }
+
+    #[test]
+    fn test_node_key_survives_restarts() {
+        let store = crate::storage::MemoryStore::new();
+        let first = node_keypair(&store).unwrap();
+        let again = node_keypair(&store).unwrap();
+        assert_eq!(first.public().to_peer_id(), again.public().to_peer_id());
+        let other = node_keypair(&crate::storage::MemoryStore::new()).unwrap();
+        assert_ne!(first.public().to_peer_id(), other.public().to_peer_id());
+    }
+
+    #[tokio::test]
+    async fn test_connected_nodes_sample_each_others_clocks() {
+        // A's clock runs ten minutes ahead of B's
+        let (a, chain_a) = node();
+        let mut a = a.with_clock(|| timesync::local_time() + 600);
+        let (mut b, chain_b) = node();
+        let clock_a = a.clock_monitor();
+        let clock_b = b.clock_monitor();
+        let address = a.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        b.dial(address).unwrap();
+        tokio::spawn(a.run(chain_a));
+        tokio::spawn(b.run(chain_b));
+
+        let sampled = tokio::time::timeout(Duration::from_secs(20), async {
+            while clock_a.status().peers_sampled == 0 || clock_b.status().peers_sampled == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(sampled.is_ok(), "the nodes never pinged each other");
+        let (a, b) = (clock_a.status(), clock_b.status());
+        assert!(a.skewed && b.skewed);
+        assert!((-601..=-599).contains(&a.median_offset_secs.unwrap()), "{:?}", a);
+        assert!((599..=601).contains(&b.median_offset_secs.unwrap()), "{:?}", b);
+    }
+}
@@ -0,0 +1,150 @@
+//! Peer connection bookkeeping and per-direction connection limits.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// Which side opened a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Caps on concurrent peer connections, counted separately per direction so
+/// a flood of inbound peers never stops us dialing bootstrap peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        PeerLimits {
+            max_inbound: 50,
+            max_outbound: 8,
+        }
+    }
+}
+
+/// Snapshot of current connections, as served by `/peers`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCounts {
+    pub inbound: usize,
+    pub outbound: usize,
+    pub limits: PeerLimits,
+    pub peers: BTreeMap<String, Direction>,
+}
+
+/// Tracks connected peers and gates new connections against the limits.
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    limits: PeerLimits,
+    peers: Mutex<BTreeMap<String, Direction>>,
+    /// Misbehaviour score per peer, starting at 0 and only going down
+    reputation: Mutex<BTreeMap<String, i64>>,
+}
+
+impl PeerManager {
+    pub fn new(limits: PeerLimits) -> Self {
+        PeerManager {
+            limits,
+            peers: Mutex::new(BTreeMap::new()),
+            reputation: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Admit a peer that connected to us, unless the inbound cap is reached
+    pub fn accept_inbound(&self, peer: &str) -> Result<(), String> {
+        self.connect(peer, Direction::Inbound)
+    }
+
+    /// Record a dial to `peer`, unless the outbound cap is reached
+    pub fn dial_outbound(&self, peer: &str) -> Result<(), String> {
+        self.connect(peer, Direction::Outbound)
+    }
+
+    fn connect(&self, peer: &str, direction: Direction) -> Result<(), String> {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(peer) {
+            return Err(format!("Already connected to {}", peer));
+        }
+
+        let (max, kind) = match direction {
+            Direction::Inbound => (self.limits.max_inbound, "inbound"),
+            Direction::Outbound => (self.limits.max_outbound, "outbound"),
+        };
+        if peers.values().filter(|d| **d == direction).count() >= max {
+            return Err(format!(
+                "Refusing {} connection with {}: limit of {} reached",
+                kind, peer, max
+            ));
+        }
+
+        peers.insert(peer.to_string(), direction);
+        Ok(())
+    }
+
+    /// Forget a peer once its connection closes, freeing its slot
+    pub fn disconnect(&self, peer: &str) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+
+    /// Lower `peer`'s reputation by `penalty`, returning the new score
+    pub fn penalize(&self, peer: &str, penalty: i64) -> i64 {
+        let mut reputation = self.reputation.lock().unwrap();
+        let score = reputation.entry(peer.to_string()).or_insert(0);
+        *score = score.saturating_sub(penalty);
+        *score
+    }
+
+    /// `peer`'s reputation, 0 unless it has been penalized
+    pub fn reputation(&self, peer: &str) -> i64 {
+        self.reputation.lock().unwrap().get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn counts(&self) -> PeerCounts {
+        let peers = self.peers.lock().unwrap().clone();
+        let inbound = peers.values().filter(|d| **d == Direction::Inbound).count();
+        PeerCounts {
+            inbound,
+            outbound: peers.len() - inbound,
+            limits: self.limits,
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_connections_refused_at_cap() {
+        let manager = PeerManager::new(PeerLimits {
+            max_inbound: 3,
+            max_outbound: 1,
+        });
+
+        for i in 0..3 {
+            manager.accept_inbound(&format!("peer-{}", i)).unwrap();
+        }
+        let refused = manager.accept_inbound("peer-3");
+        assert!(refused.unwrap_err().contains("limit of 3 reached"));
+
+        // Inbound saturation doesn't block dialing a bootstrap peer
+        manager.dial_outbound("bootstrap-0").unwrap();
+        assert!(manager.dial_outbound("bootstrap-1").is_err());
+
+        let counts = manager.counts();
+        assert_eq!((counts.inbound, counts.outbound), (3, 1));
+
+        // A dropped peer frees its slot
+        manager.disconnect("peer-0");
+        manager.accept_inbound("peer-3").unwrap();
+        assert_eq!(manager.counts().inbound, 3);
+    }
+}
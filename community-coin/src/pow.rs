@@ -0,0 +1,54 @@
+//! Proof of work: optional anti-spam work on transaction submission, and
+//! the work blocks carry when the chain is created with a block difficulty.
+
+use sha2::{Digest, Sha256};
+
+/// What a transfer's proof of work commits to. The sender nonce the
+/// transfer will take makes every solution single-use.
+pub fn payload(from: &str, to: &str, amount: u64, nonce: u64) -> String {
+    format!("{}:{}:{}:{}", from, to, amount, nonce)
+}
+
+/// Whether `sha256(payload || pow_nonce)` starts with at least `difficulty`
+/// zero bits, with `pow_nonce` as 8 little-endian bytes
+pub fn verify(payload: &str, pow_nonce: u64, difficulty: u32) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(payload.as_bytes());
+    hasher.update(pow_nonce.to_le_bytes());
+    leading_zero_bits(&hasher.finalize()) >= difficulty
+}
+
+/// Find the first `pow_nonce` meeting `difficulty`, as a client would
+pub fn solve(payload: &str, difficulty: u32) -> u64 {
+    (0..).find(|pow_nonce| verify(payload, *pow_nonce, difficulty)).unwrap()
+}
+
+/// Whether the hex block hash `hash` starts with at least `difficulty`
+/// zero bits
+pub fn hash_meets_difficulty(hash: &str, difficulty: u32) -> bool {
+    difficulty == 0 || hex::decode(hash).is_ok_and(|bytes| leading_zero_bits(&bytes) >= difficulty)
+}
+
+/// Difficulty for the next `interval` blocks, given how long the last ones
+/// took against the `expected_secs` they should have. Each step doubles or
+/// halves the work, and difficulty never drops below one bit.
+pub fn retarget(difficulty: u32, actual_secs: u64, expected_secs: u64) -> u32 {
+    if actual_secs < expected_secs / 2 {
+        (difficulty + 1).min(256)
+    } else if actual_secs > expected_secs.saturating_mul(2) {
+        difficulty.saturating_sub(1).max(1)
+    } else {
+        difficulty
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
@@ -1 +1,159 @@
 //! A simulated settlement layer for the Community Coin sovereign rollup.
+
+use std::collections::BTreeMap;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A validator and the stake it has locked on the settlement layer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Validator {
+    pub address: String,
+    pub stake: u64,
+}
+
+/// A validator's signature approving the block at `index` with `hash`.
+/// Votes are gossiped between nodes; once validators holding the finality
+/// quorum of stake have voted, the block is final.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockVote {
+    pub index: u64,
+    pub hash: String,
+    /// The voting validator's address, the hex ed25519 public key it signs
+    /// with
+    pub validator: String,
+    /// Hex ed25519 signature over the vote
+    pub signature: String,
+}
+
+impl BlockVote {
+    /// Sign a vote for the block `hash` at `index` with a validator's key
+    pub fn new(index: u64, hash: String, key: &SigningKey) -> Self {
+        let validator = hex::encode(key.verifying_key().to_bytes());
+        let signature = hex::encode(key.sign(&Self::signing_bytes(index, &hash, &validator)).to_bytes());
+        BlockVote {
+            index,
+            hash,
+            validator,
+            signature,
+        }
+    }
+
+    fn signing_bytes(index: u64, hash: &str, validator: &str) -> Vec<u8> {
+        let mut bytes = b"community-coin block vote\n".to_vec();
+        bytes.extend_from_slice(&index.to_le_bytes());
+        bytes.extend_from_slice(hash.as_bytes());
+        bytes.extend_from_slice(validator.as_bytes());
+        bytes
+    }
+
+    /// Whether the vote is signed by the key its validator address is
+    pub fn verify(&self) -> bool {
+        let Ok(key) = crate::snapshot::parse_verifying_key(&self.validator) else {
+            return false;
+        };
+        let signature: Option<[u8; 64]> = hex::decode(&self.signature).ok().and_then(|bytes| bytes.try_into().ok());
+        signature.is_some_and(|signature| {
+            let message = Self::signing_bytes(self.index, &self.hash, &self.validator);
+            key.verify(&message, &Signature::from_bytes(&signature)).is_ok()
+        })
+    }
+}
+
+/// Registered validators. Proposers are drawn with probability proportional
+/// to stake.
+#[derive(Debug, Clone, Default)]
+pub struct ValidatorRegistry {
+    stakes: BTreeMap<String, u64>,
+}
+
+impl ValidatorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a validator, or top up the stake of an existing one
+    pub fn register(&mut self, address: String, stake: u64) -> Result<u64, String> {
+        if stake == 0 {
+            return Err("Stake must be greater than 0".to_string());
+        }
+        let total = self.stakes.entry(address).or_insert(0);
+        *total = total
+            .checked_add(stake)
+            .ok_or("Stake overflow".to_string())?;
+        Ok(*total)
+    }
+
+    /// Take `stake` back from a validator, dropping it once nothing is
+    /// left. Returns the stake that remains.
+    pub fn withdraw(&mut self, address: &str, stake: u64) -> Result<u64, String> {
+        let total = self
+            .stakes
+            .get_mut(address)
+            .ok_or_else(|| format!("{} is not a registered validator", address))?;
+        *total = total
+            .checked_sub(stake)
+            .ok_or_else(|| format!("{} has only {} staked", address, total))?;
+        let remaining = *total;
+        if remaining == 0 {
+            self.stakes.remove(address);
+        }
+        Ok(remaining)
+    }
+
+    pub fn stake_of(&self, address: &str) -> Option<u64> {
+        self.stakes.get(address).copied()
+    }
+
+    /// All validators, ordered by address
+    pub fn validators(&self) -> Vec<Validator> {
+        self.stakes
+            .iter()
+            .map(|(address, stake)| Validator {
+                address: address.clone(),
+                stake: *stake,
+            })
+            .collect()
+    }
+
+    /// Sum of every stake, saturating at `u64::MAX`. Proposer selection
+    /// still lands on a validator when it saturates.
+    pub fn total_stake(&self) -> u64 {
+        self.stakes.values().copied().fold(0u64, u64::saturating_add)
+    }
+
+    /// Chance that `address` proposes the next block
+    pub fn selection_probability(&self, address: &str) -> f64 {
+        let total = self.total_stake();
+        match self.stakes.get(address) {
+            Some(stake) if total > 0 => *stake as f64 / total as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// Deterministically pick the proposer for `height`, weighted by stake.
+    /// `seed` should be something every node agrees on, like the parent hash.
+    pub fn select_proposer(&self, height: u64, seed: &str) -> Option<String> {
+        let total = self.total_stake();
+        if total == 0 {
+            return None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(seed.as_bytes());
+        hasher.update(height.to_le_bytes());
+        let digest = hasher.finalize();
+        let mut draw_bytes = [0u8; 8];
+        draw_bytes.copy_from_slice(&digest[..8]);
+        let mut draw = u64::from_le_bytes(draw_bytes) % total;
+
+        for (address, stake) in &self.stakes {
+            if draw < *stake {
+                return Some(address.clone());
+            }
+            draw -= stake;
+        }
+        None
+    }
+}
@@ -0,0 +1,174 @@
+//! Signed chain snapshots, letting a new node start from a trusted
+//! checkpoint instead of replaying the chain from genesis.
+//!
+//! A snapshot carries the tip block, every wallet balance, every sender's
+//! committed nonce and every contract's storage at that height. The
+//! checkpoint, i.e. tip height, tip hash and a digest of everything else the
+//! snapshot carries, is signed by a key the importing operator trusts. Alternatively the operator trusts the tip block's hash, taken from
+//! a header they already hold; the tip's state root then vouches for the
+//! balances and nonces.
+
+use std::collections::BTreeMap;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blockchain::{Block, ChainParams, Wallet};
+use crate::contract::Contract;
+
+/// Prefix of every signed checkpoint
+pub const CHECKPOINT_DOMAIN: &[u8] = b"\x19Community Coin Checkpoint:\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub params: ChainParams,
+    /// Hash of the chain's genesis, so the importing node can tell peers on
+    /// its chain from others
+    #[serde(default)]
+    pub genesis_hash: Option<String>,
+    pub tip: Block,
+    pub wallets: Vec<Wallet>,
+    pub nonces: BTreeMap<String, u64>,
+    /// State root of `wallets`' balances
+    pub state_root: String,
+    /// Deployed contracts with their storage, by address
+    #[serde(default)]
+    pub contracts: Vec<Contract>,
+    /// Hex-encoded signature over the checkpoint
+    pub signature: String,
+}
+
+impl Snapshot {
+    /// The bytes signed for this snapshot's checkpoint
+    pub fn checkpoint(&self) -> Vec<u8> {
+        let mut bytes = CHECKPOINT_DOMAIN.to_vec();
+        bytes.extend_from_slice(&self.tip.index.to_le_bytes());
+        bytes.extend_from_slice(self.tip.hash.as_bytes());
+        bytes.push(b'\n');
+        bytes.extend_from_slice(self.body_digest().as_bytes());
+        bytes
+    }
+
+    /// Hex digest of every field but the signature: params, genesis hash,
+    /// tip block, wallets, nonces, state root and contracts. Contracts enter through
+    /// [`contracts_root`], so their storage maps hash the same in any order.
+    pub fn body_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(&json(&self.params));
+        field(&json(&self.genesis_hash));
+        field(&json(&self.tip));
+        field(&json(&self.wallets));
+        field(&json(&self.nonces));
+        field(self.state_root.as_bytes());
+        field(contracts_root(&self.contracts).as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = hex::encode(key.sign(&self.checkpoint()).to_bytes());
+    }
+
+    /// Whether the checkpoint was signed by `trusted`
+    pub fn verify_signature(&self, trusted: &VerifyingKey) -> bool {
+        let signature: Option<[u8; 64]> = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok());
+        signature.is_some_and(|signature| {
+            trusted
+                .verify(&self.checkpoint(), &Signature::from_bytes(&signature))
+                .is_ok()
+        })
+    }
+}
+
+/// JSON encoding of a field with no maps but ordered ones, which is the
+/// same on every node
+fn json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap_or_default()
+}
+
+/// Hex digest committing to every field of `contracts`, storage entries in
+/// key order, whatever order they were listed or held in
+pub fn contracts_root(contracts: &[Contract]) -> String {
+    let mut sorted: Vec<&Contract> = contracts.iter().collect();
+    sorted.sort_by(|a, b| a.address.cmp(&b.address));
+
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    for contract in sorted {
+        field(contract.address.as_bytes());
+        field(contract.deployer.as_bytes());
+        field(contract.admin.as_deref().unwrap_or_default().as_bytes());
+        field(&contract.code);
+        field(&contract.version.to_le_bytes());
+        field(&contract.deployed_at.to_le_bytes());
+        let storage: BTreeMap<&Vec<u8>, &Vec<u8>> = contract.storage.iter().collect();
+        field(&(storage.len() as u64).to_le_bytes());
+        for (key, value) in storage {
+            field(key);
+            field(value);
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// What vouches for a snapshot a node starts from
+#[derive(Debug, Clone)]
+pub enum SnapshotTrust {
+    /// The checkpoint must be signed by this key, as in `SNAPSHOT_SIGNER`
+    Signer(VerifyingKey),
+    /// The snapshot's tip must be the block with this hash, as in
+    /// `SNAPSHOT_TRUSTED_HASH`
+    Header(String),
+}
+
+impl SnapshotTrust {
+    /// Check `snapshot` against what is trusted. The snapshot's own
+    /// consistency, e.g. its balances against its state root, is checked
+    /// on import.
+    pub fn check(&self, snapshot: &Snapshot) -> Result<(), String> {
+        match self {
+            SnapshotTrust::Signer(key) if snapshot.verify_signature(key) => Ok(()),
+            SnapshotTrust::Signer(_) => Err("Snapshot checkpoint signature is invalid".to_string()),
+            SnapshotTrust::Header(hash) if !hash.eq_ignore_ascii_case(&snapshot.tip.hash) => Err(format!(
+                "Snapshot tip {} is not the trusted block {}",
+                snapshot.tip.hash, hash
+            )),
+            // Block state roots cover balances and nonces, not contracts
+            SnapshotTrust::Header(_) if !snapshot.contracts.is_empty() => {
+                Err("Snapshot contracts can only be trusted through a signed checkpoint".to_string())
+            }
+            SnapshotTrust::Header(_) if snapshot.state_root != snapshot.tip.state_root => {
+                Err("Snapshot state root does not match its tip block".to_string())
+            }
+            SnapshotTrust::Header(_) => Ok(()),
+        }
+    }
+}
+
+/// Parse a hex-encoded 32-byte signing key seed, as in `SNAPSHOT_SIGNING_KEY`
+pub fn parse_signing_key(hex_key: &str) -> Result<SigningKey, String> {
+    let seed: [u8; 32] = hex::decode(hex_key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Signing key must be 32 hex-encoded bytes")?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Parse a hex-encoded public key, as in `SNAPSHOT_SIGNER` or the sender
+/// address of an ed25519-signed transaction
+pub fn parse_verifying_key(hex_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(hex_key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Public key must be 32 hex-encoded bytes")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("Invalid public key: {}", e))
+}
@@ -0,0 +1,178 @@
+//! Flags for starting a node on top of an existing or new chain.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use figment::providers::Serialized;
+
+use crate::blockchain::CommunityBlockchain;
+use crate::config::NodeConfig;
+use crate::genesis::GenesisArgs;
+
+/// Command-line flags shared by the node binaries
+#[derive(Parser, Debug, Default)]
+pub struct NodeArgs {
+    #[command(flatten)]
+    pub genesis: GenesisArgs,
+
+    /// Re-verify every stored block's hash, linkage and transaction
+    /// signatures after loading, and refuse to start if one is invalid
+    #[arg(long, conflicts_with = "skip_verify")]
+    pub verify_on_start: bool,
+
+    /// Trust the stored chain without verifying it (the default)
+    #[arg(long)]
+    pub skip_verify: bool,
+
+    /// Keep only the latest K stored snapshots, plus the one this node was
+    /// started from, pruning older ones at startup and after each snapshot
+    #[arg(long, value_name = "K")]
+    pub snapshot_retention: Option<usize>,
+
+    /// Discard the transactions of blocks more than N below the tip,
+    /// keeping their headers and the state
+    #[arg(long, value_name = "N", conflicts_with = "archive")]
+    pub prune_depth: Option<u64>,
+
+    /// Keep every block whole, for nodes that prune by default
+    #[arg(long)]
+    pub archive: bool,
+
+    /// Read node settings from this TOML file. Environment variables and
+    /// the flags below override it.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Port the HTTP API listens on
+    #[arg(long)]
+    pub http_port: Option<u16>,
+
+    /// Port the gRPC service listens on
+    #[arg(long)]
+    pub grpc_port: Option<u16>,
+
+    /// Directory of the node's database
+    #[arg(long)]
+    pub db_path: Option<String>,
+
+    /// Most transactions pending at once
+    #[arg(long)]
+    pub max_pending_txs: Option<usize>,
+
+    /// Share of validator stake whose votes finalize a block
+    #[arg(long)]
+    pub finality_quorum: Option<f64>,
+
+    /// Cap on items returned by one page of a bulk endpoint
+    #[arg(long)]
+    pub max_page_size: Option<usize>,
+}
+
+impl NodeArgs {
+    /// Node settings: the `--config` file, the environment and then these
+    /// flags, over the defaults
+    pub fn node_config(&self) -> Result<NodeConfig, String> {
+        let mut figment = NodeConfig::figment(self.config.as_deref());
+        if let Some(port) = self.http_port {
+            figment = figment.merge(Serialized::default("network.http_port", port));
+        }
+        if let Some(port) = self.grpc_port {
+            figment = figment.merge(Serialized::default("network.grpc_port", port));
+        }
+        if let Some(path) = &self.db_path {
+            figment = figment.merge(Serialized::default("storage.db_path", path));
+        }
+        if let Some(max) = self.max_pending_txs {
+            figment = figment.merge(Serialized::default("mempool.max_pending_txs", max));
+        }
+        if let Some(quorum) = self.finality_quorum {
+            figment = figment.merge(Serialized::default("consensus.finality_quorum", quorum));
+        }
+        if let Some(max) = self.max_page_size {
+            figment = figment.merge(Serialized::default("api.max_page_size", max));
+        }
+        NodeConfig::extract(figment)
+    }
+
+    /// Depth to prune blocks below the tip at: `--prune-depth` if given,
+    /// none with `--archive`, and the binary's `default` otherwise
+    pub fn pruning(&self, default: Option<u64>) -> Option<u64> {
+        match self.archive {
+            true => None,
+            false => self.prune_depth.or(default),
+        }
+    }
+
+    /// Check a chain just loaded from disk: that it was created from the
+    /// `--genesis` file, if one was given, and if `--verify-on-start` was
+    /// given, every block. Fails with the first invalid block.
+    pub fn check_loaded(&self, blockchain: &CommunityBlockchain) -> Result<(), String> {
+        if self.genesis.genesis_file.is_some() {
+            let expected = self.genesis.genesis()?.hash()?;
+            if let Some(stored) = blockchain.genesis_hash().filter(|stored| *stored != expected) {
+                return Err(format!(
+                    "Refusing to start, stored chain has genesis hash {} but the genesis file gives {}",
+                    stored, expected
+                ));
+            }
+        }
+        if !self.verify_on_start {
+            return Ok(());
+        }
+        blockchain
+            .verify_chain_detailed()
+            .map_err(|e| format!("Refusing to start, stored chain is corrupt: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::blockchain::{Block, ChainParams};
+    use crate::storage::{Codec, KvStore, MemoryStore};
+
+    #[test]
+    fn test_verify_on_start_rejects_a_tampered_block() {
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::from_store(initial, ChainParams::demo(), store.clone()).unwrap();
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 100).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block).unwrap();
+        drop(blockchain);
+
+        // Rewrite the stored block's state root behind the node's back
+        let mut block: Block = Codec::decode(&store.get(b"block:1").unwrap().unwrap()).unwrap();
+        block.state_root = "forged_root".to_string();
+        store.insert(b"block:1", &Codec::default().encode(&block).unwrap()).unwrap();
+
+        let loaded = CommunityBlockchain::load_from_store(store).unwrap();
+
+        let fast = NodeArgs::try_parse_from(["node"]).unwrap();
+        assert!(fast.check_loaded(&loaded).is_ok());
+        let skip = NodeArgs::try_parse_from(["node", "--skip-verify"]).unwrap();
+        assert!(skip.check_loaded(&loaded).is_ok());
+
+        let verify = NodeArgs::try_parse_from(["node", "--verify-on-start"]).unwrap();
+        assert_eq!(
+            verify.check_loaded(&loaded),
+            Err("Refusing to start, stored chain is corrupt: block 1 is invalid: stored hash does not match the recomputed hash"
+                .to_string())
+        );
+        assert!(NodeArgs::try_parse_from(["node", "--verify-on-start", "--skip-verify"]).is_err());
+    }
+
+    #[test]
+    fn test_archive_overrides_the_default_pruning() {
+        let parse = |args: &[&str]| NodeArgs::try_parse_from(std::iter::once("node").chain(args.iter().copied())).unwrap();
+        assert_eq!(parse(&[]).pruning(None), None);
+        assert_eq!(parse(&[]).pruning(Some(500)), Some(500));
+        assert_eq!(parse(&["--prune-depth", "64"]).pruning(Some(500)), Some(64));
+        assert_eq!(parse(&["--archive"]).pruning(Some(500)), None);
+        assert!(NodeArgs::try_parse_from(["node", "--archive", "--prune-depth", "64"]).is_err());
+    }
+}
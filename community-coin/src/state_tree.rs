@@ -0,0 +1,245 @@
+//! Sparse Merkle tree over account balances and nonces, whose root is a
+//! block's `state_root`.
+//!
+//! An account sits at the path given by the bits of `sha256(address)`. A
+//! subtree holding a single account hashes to that account's leaf, so paths
+//! are only as deep as it takes to tell accounts apart. Empty accounts,
+//! with neither a balance nor a nonce, are left out, and an empty subtree
+//! hashes to all zeros. A proof shows an account's balance and nonce, or
+//! that it has neither, against a state root.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type Hash = [u8; 32];
+
+const EMPTY: Hash = [0; 32];
+
+// Leaves and inner nodes are hashed under different prefixes, so an inner
+// node can't pass for an account
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Path of `address` in the tree
+fn account_key(address: &str) -> Hash {
+    Sha256::digest(address.as_bytes()).into()
+}
+
+/// Whether the path `key` turns right below depth `depth`
+fn bit(key: &Hash, depth: usize) -> bool {
+    key[depth / 8] >> (7 - depth % 8) & 1 == 1
+}
+
+fn leaf_hash(key: &Hash, balance: u64, nonce: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(key);
+    hasher.update(balance.to_le_bytes());
+    hasher.update(nonce.to_le_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// An account's place in the tree and what it holds
+#[derive(Debug, Clone, Copy)]
+struct Leaf {
+    key: Hash,
+    balance: u64,
+    nonce: u64,
+}
+
+/// The tree's non-empty accounts, sorted by path
+pub struct StateTree {
+    leaves: Vec<Leaf>,
+}
+
+impl StateTree {
+    /// Build the tree over `(address, balance, nonce)` accounts
+    pub fn new<'a>(accounts: impl IntoIterator<Item = (&'a str, u64, u64)>) -> Self {
+        let mut leaves: Vec<Leaf> = accounts
+            .into_iter()
+            .filter(|(_, balance, nonce)| *balance > 0 || *nonce > 0)
+            .map(|(address, balance, nonce)| Leaf {
+                key: account_key(address),
+                balance,
+                nonce,
+            })
+            .collect();
+        leaves.sort_by_key(|leaf| leaf.key);
+        StateTree { leaves }
+    }
+
+    /// Hex root hash
+    pub fn root(&self) -> String {
+        hex::encode(subtree_hash(&self.leaves, 0))
+    }
+
+    /// Proof of `address`'s balance and nonce, both 0 if it isn't in the tree
+    pub fn prove(&self, address: &str) -> StateProof {
+        let key = account_key(address);
+        let mut leaves = &self.leaves[..];
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+        while leaves.len() > 1 {
+            let split = leaves.partition_point(|leaf| !bit(&leaf.key, depth));
+            let (left, right) = leaves.split_at(split);
+            let (path, sibling) = if bit(&key, depth) { (right, left) } else { (left, right) };
+            siblings.push(hex::encode(subtree_hash(sibling, depth + 1)));
+            leaves = path;
+            depth += 1;
+        }
+
+        let (balance, nonce, neighbor) = match leaves.first() {
+            Some(leaf) if leaf.key == key => (leaf.balance, leaf.nonce, None),
+            Some(leaf) => (
+                0,
+                0,
+                Some(Neighbor {
+                    key: hex::encode(leaf.key),
+                    balance: leaf.balance,
+                    nonce: leaf.nonce,
+                }),
+            ),
+            None => (0, 0, None),
+        };
+        StateProof {
+            address: address.to_string(),
+            balance,
+            nonce,
+            siblings,
+            neighbor,
+        }
+    }
+}
+
+/// Hash of the subtree at `depth` holding `leaves`, which share its path
+fn subtree_hash(leaves: &[Leaf], depth: usize) -> Hash {
+    match leaves {
+        [] => EMPTY,
+        [leaf] => leaf_hash(&leaf.key, leaf.balance, leaf.nonce),
+        _ => {
+            let split = leaves.partition_point(|leaf| !bit(&leaf.key, depth));
+            let (left, right) = leaves.split_at(split);
+            node_hash(&subtree_hash(left, depth + 1), &subtree_hash(right, depth + 1))
+        }
+    }
+}
+
+/// The one account in the subtree where a missing account's path ends
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Neighbor {
+    /// Hex path of the account
+    pub key: String,
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+/// Proof of an account's balance and nonce against a state root
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateProof {
+    pub address: String,
+    pub balance: u64,
+    pub nonce: u64,
+    /// Hex hashes of the subtrees beside the account's path, from the root
+    /// down
+    pub siblings: Vec<String>,
+    /// For an account not in the tree, the other account its path leads
+    /// to, if any
+    pub neighbor: Option<Neighbor>,
+}
+
+impl StateProof {
+    /// Whether the proof holds against the hex state root `root`
+    pub fn verify(&self, root: &str) -> bool {
+        let key = account_key(&self.address);
+        let depth = self.siblings.len();
+        if depth > 256 {
+            return false;
+        }
+
+        let mut hash = match &self.neighbor {
+            None if self.balance == 0 && self.nonce == 0 => EMPTY,
+            None => leaf_hash(&key, self.balance, self.nonce),
+            Some(neighbor) => {
+                let Some(other) = hex::decode(&neighbor.key).ok().and_then(|bytes| Hash::try_from(bytes).ok())
+                else {
+                    return false;
+                };
+                // The neighbor must really sit where this account would
+                let shares_path = (0..depth).all(|d| bit(&other, d) == bit(&key, d));
+                if self.balance != 0
+                    || self.nonce != 0
+                    || other == key
+                    || !shares_path
+                    || (neighbor.balance == 0 && neighbor.nonce == 0)
+                {
+                    return false;
+                }
+                leaf_hash(&other, neighbor.balance, neighbor.nonce)
+            }
+        };
+
+        for (d, sibling) in self.siblings.iter().enumerate().rev() {
+            let Some(sibling) = hex::decode(sibling).ok().and_then(|bytes| Hash::try_from(bytes).ok()) else {
+                return false;
+            };
+            hash = match bit(&key, d) {
+                true => node_hash(&sibling, &hash),
+                false => node_hash(&hash, &sibling),
+            };
+        }
+        hex::encode(hash) == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proofs_for_present_and_missing_accounts() {
+        let accounts: Vec<(String, u64, u64)> = (0..40)
+            .map(|i| (format!("user_{}", i), 100 + i, i % 3))
+            .collect();
+        let tree = StateTree::new(accounts.iter().map(|(a, b, n)| (a.as_str(), *b, *n)));
+        let root = tree.root();
+
+        for (address, balance, nonce) in &accounts {
+            let proof = tree.prove(address);
+            assert_eq!((proof.balance, proof.nonce), (*balance, *nonce));
+            assert!(proof.verify(&root));
+
+            let mut inflated = proof.clone();
+            inflated.balance += 1;
+            assert!(!inflated.verify(&root));
+        }
+
+        // Missing accounts prove as empty, whether their path ends at an
+        // empty subtree or at another account
+        let missing: Vec<StateProof> = (0..20).map(|i| tree.prove(&format!("stranger_{}", i))).collect();
+        assert!(missing.iter().any(|proof| proof.neighbor.is_some()));
+        for proof in &missing {
+            assert_eq!((proof.balance, proof.nonce), (0, 0));
+            assert!(proof.verify(&root));
+            let mut claimed = proof.clone();
+            claimed.balance = 5;
+            claimed.neighbor = None;
+            assert!(!claimed.verify(&root));
+        }
+
+        // A change to any account moves the root, and stale proofs fail
+        let mut changed = accounts.clone();
+        changed[7].1 += 1;
+        let new_root = StateTree::new(changed.iter().map(|(a, b, n)| (a.as_str(), *b, *n))).root();
+        assert_ne!(new_root, root);
+        assert!(!tree.prove("user_7").verify(&new_root));
+        assert_eq!(StateTree::new([("nobody", 0, 0)]).root(), "0".repeat(64));
+    }
+}
@@ -0,0 +1,388 @@
+//! Key/value storage backends for persisting chain state.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+/// Error raised by a storage backend.
+#[derive(Debug, Error)]
+#[error("storage error: {0}")]
+pub struct StorageError(pub String);
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for StorageError {
+    fn from(e: rocksdb::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Which key/value store a node keeps its state in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Sled,
+    /// Only in builds with the `rocksdb` feature
+    RocksDb,
+    /// Nothing survives a restart
+    Memory,
+}
+
+impl StorageBackend {
+    /// Parse a `STORAGE_BACKEND` value
+    pub fn parse(name: &str) -> Result<StorageBackend, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "sled" => Ok(StorageBackend::Sled),
+            "rocksdb" if cfg!(feature = "rocksdb") => Ok(StorageBackend::RocksDb),
+            "rocksdb" => Err("The rocksdb storage backend needs a build with the rocksdb feature".to_string()),
+            "memory" => Ok(StorageBackend::Memory),
+            other => Err(format!("Unknown storage backend {:?}", other)),
+        }
+    }
+
+    /// Open the store at `path`, creating it if needed. The memory backend
+    /// ignores `path`.
+    pub fn open(self, path: &str) -> StorageResult<Arc<dyn KvStore>> {
+        match self {
+            StorageBackend::Sled => Ok(Arc::new(sled::open(path)?)),
+            #[cfg(feature = "rocksdb")]
+            StorageBackend::RocksDb => Ok(Arc::new(rocksdb::DB::open_default(path)?)),
+            #[cfg(not(feature = "rocksdb"))]
+            StorageBackend::RocksDb => Err(StorageError("built without the rocksdb feature".to_string())),
+            StorageBackend::Memory => Ok(Arc::new(MemoryStore::new())),
+        }
+    }
+}
+
+/// Leading byte tagging a record written as JSON
+const JSON_MARKER: u8 = 0xF0;
+/// Leading byte tagging a record written as bincode
+const BINCODE_MARKER: u8 = 0xF1;
+
+/// How records are serialized. Each record starts with a marker byte naming
+/// its codec, so a store can be read whichever codec wrote it. Records from
+/// before markers existed are plain JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Human-readable, for debugging
+    #[default]
+    Json,
+    /// Compact and fast, for production
+    Bincode,
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(self, value: &T) -> StorageResult<Vec<u8>> {
+        let (marker, body) = match self {
+            Codec::Json => (
+                JSON_MARKER,
+                serde_json::to_vec(value).map_err(|e| StorageError(e.to_string()))?,
+            ),
+            Codec::Bincode => (
+                BINCODE_MARKER,
+                bincode::serialize(value).map_err(|e| StorageError(e.to_string()))?,
+            ),
+        };
+        let mut bytes = Vec::with_capacity(body.len() + 1);
+        bytes.push(marker);
+        bytes.extend(body);
+        Ok(bytes)
+    }
+
+    /// Decode a record written by any codec
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> StorageResult<T> {
+        match bytes.split_first() {
+            Some((&BINCODE_MARKER, body)) => {
+                bincode::deserialize(body).map_err(|e| StorageError(e.to_string()))
+            }
+            Some((&JSON_MARKER, body)) => {
+                serde_json::from_slice(body).map_err(|e| StorageError(e.to_string()))
+            }
+            _ => serde_json::from_slice(bytes).map_err(|e| StorageError(e.to_string())),
+        }
+    }
+
+    /// The codec that wrote `bytes`
+    pub fn of(bytes: &[u8]) -> Codec {
+        match bytes.first() {
+            Some(&BINCODE_MARKER) => Codec::Bincode,
+            _ => Codec::Json,
+        }
+    }
+
+    /// Parse a `STORAGE_CODEC` value
+    pub fn parse(name: &str) -> Result<Codec, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            other => Err(format!("Unknown storage codec {:?}", other)),
+        }
+    }
+}
+
+/// A single write applied as part of a [`KvStore::transaction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// Ordered key/value store the blockchain persists its state into.
+pub trait KvStore: Send + Sync {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> StorageResult<()>;
+
+    fn remove(&self, key: &[u8]) -> StorageResult<()>;
+
+    /// All entries whose key starts with `prefix`, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Make all previous writes durable
+    fn flush(&self) -> StorageResult<()>;
+
+    /// Apply all writes atomically: either every op lands or none do
+    fn transaction(&self, ops: Vec<WriteOp>) -> StorageResult<()>;
+}
+
+impl KvStore for sled::Db {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        sled::Tree::insert(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> StorageResult<()> {
+        sled::Tree::remove(self, key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        sled::Tree::scan_prefix(self, prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+
+    fn transaction(&self, ops: Vec<WriteOp>) -> StorageResult<()> {
+        let mut batch = sled::Batch::default();
+        for op in ops {
+            match op {
+                WriteOp::Insert(key, value) => batch.insert(key, value),
+                WriteOp::Remove(key) => batch.remove(key),
+            }
+        }
+        self.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl KvStore for rocksdb::DB {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(rocksdb::DB::get(self, key)?)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.put(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> StorageResult<()> {
+        self.delete(key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let start = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+        for entry in self.iterator(start) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.flush_wal(true)?;
+        rocksdb::DB::flush(self)?;
+        Ok(())
+    }
+
+    fn transaction(&self, ops: Vec<WriteOp>) -> StorageResult<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        for op in ops {
+            match op {
+                WriteOp::Insert(key, value) => batch.put(key, value),
+                WriteOp::Remove(key) => batch.delete(key),
+            }
+        }
+        self.write(batch)?;
+        Ok(())
+    }
+}
+
+/// Volatile store kept entirely in memory, for tests and throwaway nodes.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.entries.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.entries.write().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> StorageResult<()> {
+        self.entries.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .entries
+            .read()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn transaction(&self, ops: Vec<WriteOp>) -> StorageResult<()> {
+        let mut entries = self.entries.write().unwrap();
+        for op in ops {
+            match op {
+                WriteOp::Insert(key, value) => {
+                    entries.insert(key, value);
+                }
+                WriteOp::Remove(key) => {
+                    entries.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(store: &dyn KvStore) {
+        store.insert(b"wallet:alice", b"1").unwrap();
+        store.insert(b"wallet:bob", b"2").unwrap();
+        store.insert(b"block:0", b"genesis").unwrap();
+        assert_eq!(store.get(b"wallet:alice").unwrap(), Some(b"1".to_vec()));
+
+        let wallets = store.scan_prefix(b"wallet:").unwrap();
+        assert_eq!(
+            wallets,
+            vec![
+                (b"wallet:alice".to_vec(), b"1".to_vec()),
+                (b"wallet:bob".to_vec(), b"2".to_vec()),
+            ]
+        );
+
+        store
+            .transaction(vec![
+                WriteOp::Remove(b"wallet:alice".to_vec()),
+                WriteOp::Insert(b"block:1".to_vec(), b"next".to_vec()),
+            ])
+            .unwrap();
+        assert_eq!(store.get(b"wallet:alice").unwrap(), None);
+        assert_eq!(store.get(b"block:1").unwrap(), Some(b"next".to_vec()));
+
+        store.remove(b"block:0").unwrap();
+        assert_eq!(store.scan_prefix(b"block:").unwrap().len(), 1);
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        exercise(&db);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&MemoryStore::new());
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_rocksdb_store() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise(&rocksdb::DB::open_default(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_backend_selection() {
+        assert_eq!(StorageBackend::parse("Sled"), Ok(StorageBackend::Sled));
+        assert_eq!(StorageBackend::parse("memory"), Ok(StorageBackend::Memory));
+        assert!(StorageBackend::parse("postgres").is_err());
+        assert_eq!(StorageBackend::parse("rocksdb").is_ok(), cfg!(feature = "rocksdb"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        exercise(StorageBackend::Sled.open(path.to_str().unwrap()).unwrap().as_ref());
+        exercise(StorageBackend::Memory.open("ignored").unwrap().as_ref());
+        assert!(!std::path::Path::new("ignored").exists());
+    }
+
+    #[test]
+    fn test_codec_round_trip_and_legacy_json() {
+        let value = (String::from("alice"), 1000u64, Some(vec![1u8, 2, 3]));
+
+        let bytes = Codec::Bincode.encode(&value).unwrap();
+        assert_eq!(Codec::of(&bytes), Codec::Bincode);
+        assert_eq!(Codec::decode::<(String, u64, Option<Vec<u8>>)>(&bytes).unwrap(), value);
+
+        let bytes = Codec::Json.encode(&value).unwrap();
+        assert_eq!(Codec::of(&bytes), Codec::Json);
+        assert_eq!(Codec::decode::<(String, u64, Option<Vec<u8>>)>(&bytes).unwrap(), value);
+
+        // Unmarked records predate codecs and are JSON
+        let legacy = serde_json::to_vec(&value).unwrap();
+        assert_eq!(Codec::decode::<(String, u64, Option<Vec<u8>>)>(&legacy).unwrap(), value);
+    }
+}
@@ -0,0 +1,374 @@
+//! Block synchronization between peers: a node asks a peer for its best
+//! height, downloads the headers above its own tip, checks they link up,
+//! then downloads the blocks behind them in batches and applies each like
+//! any other block. A peer whose headers don't link onto our tip is on
+//! another branch: the node walks back through its headers to where the
+//! branches meet and applies the peer's branch from there, which reorgs
+//! onto it once it outweighs ours. The same requests are served to peers
+//! catching up.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Block, CommunityBlockchain};
+use crate::pow;
+
+/// Protocol name the sync requests are exchanged under
+pub const SYNC_PROTOCOL: &str = "/community-coin/sync/1";
+
+/// Most headers or blocks one request asks for, and one response carries
+pub const MAX_SYNC_BATCH: u64 = 128;
+
+/// Encoded size a response of blocks stops growing at, under the 10 MiB the
+/// transport accepts. A response always carries at least one block.
+pub const MAX_SYNC_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Everything about a block but its transactions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u64,
+    pub prev_hash: String,
+    pub hash: String,
+    pub proposer: String,
+    pub state_root: String,
+    pub tx_root: String,
+    pub difficulty: u32,
+    pub pow_nonce: u64,
+}
+
+impl From<&Block> for BlockHeader {
+    fn from(block: &Block) -> Self {
+        BlockHeader {
+            index: block.index,
+            timestamp: block.timestamp,
+            prev_hash: block.prev_hash.clone(),
+            hash: block.hash.clone(),
+            proposer: block.proposer.clone(),
+            state_root: block.state_root.clone(),
+            tx_root: block.tx_root.clone(),
+            difficulty: block.difficulty,
+            pow_nonce: block.pow_nonce,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncRequest {
+    /// The peer's best height and genesis block
+    Status,
+    /// Up to `count` headers from height `from`
+    Headers { from: u64, count: u64 },
+    /// Up to `count` blocks from height `from`
+    Blocks { from: u64, count: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Status {
+        height: u64,
+        /// The chain's genesis hash, `None` if the peer doesn't know it
+        genesis: Option<String>,
+    },
+    /// Consecutive headers from the height asked for, fewer than asked
+    /// (or none) where the peer's chain ends
+    Headers(Vec<BlockHeader>),
+    Blocks(Vec<Block>),
+}
+
+/// Answer a peer's sync request from `blockchain`. Batches are capped at
+/// [`MAX_SYNC_BATCH`] (and blocks at [`MAX_SYNC_RESPONSE_BYTES`]), and stop
+/// at the first block whose body isn't held.
+pub fn serve(blockchain: &CommunityBlockchain, request: SyncRequest) -> SyncResponse {
+    let range = |from: u64, count: u64| {
+        (from..from.saturating_add(count.min(MAX_SYNC_BATCH))).map_while(|index| blockchain.get_block_by_index(index))
+    };
+    match request {
+        SyncRequest::Status => SyncResponse::Status {
+            height: blockchain.tip().index,
+            genesis: blockchain.genesis_hash(),
+        },
+        SyncRequest::Headers { from, count } => {
+            SyncResponse::Headers(range(from, count).map(|block| BlockHeader::from(&block)).collect())
+        }
+        SyncRequest::Blocks { from, count } => {
+            let mut blocks = Vec::new();
+            let mut size = 0;
+            for block in range(from, count) {
+                size += serde_json::to_vec(&block).map_or(0, |bytes| bytes.len());
+                if size > MAX_SYNC_RESPONSE_BYTES && !blocks.is_empty() {
+                    break;
+                }
+                blocks.push(block);
+            }
+            SyncResponse::Blocks(blocks)
+        }
+    }
+}
+
+/// Check a peer reporting `genesis` is on our chain. Without both genesis
+/// hashes it can't be told, so it is refused.
+pub fn check_genesis(blockchain: &CommunityBlockchain, genesis: Option<&str>) -> Result<(), String> {
+    let ours = blockchain
+        .genesis_hash()
+        .ok_or("Our genesis hash is unknown, so no peer can be told to be on our chain")?;
+    match genesis {
+        Some(theirs) if theirs == ours => Ok(()),
+        Some(_) => Err("Peer is on another chain: its genesis block differs from ours".to_string()),
+        None => Err("Peer doesn't know its genesis hash, so it can't be told to be on our chain".to_string()),
+    }
+}
+
+/// Catching up with one peer. Headers are fetched ahead of the blocks, so
+/// a peer serving a chain that doesn't link onto ours is caught before any
+/// block is downloaded, and where its branch leaves ours is found first.
+#[derive(Debug)]
+pub struct BlockSync {
+    /// The peer's best height
+    target: u64,
+    /// Height of the next header to ask for
+    next_header: u64,
+    /// Hash the next header must point back to
+    last_hash: String,
+    /// Checked headers whose blocks aren't applied yet
+    headers: VecDeque<BlockHeader>,
+    /// How far below `next_header` to look next for where the peer's branch
+    /// meets ours, doubling each time its headers don't link
+    back_step: u64,
+}
+
+impl BlockSync {
+    /// Start syncing with a peer that answered a status request, `None` if
+    /// it isn't ahead of us. A peer with another genesis block is refused,
+    /// as is any peer while either side's genesis is unknown.
+    pub fn start(blockchain: &CommunityBlockchain, status: SyncResponse) -> Result<Option<Self>, String> {
+        let SyncResponse::Status { height, genesis } = status else {
+            return Err("Expected a status response".to_string());
+        };
+        check_genesis(blockchain, genesis.as_deref())?;
+        let tip = blockchain.tip();
+        if height <= tip.index {
+            return Ok(None);
+        }
+        Ok(Some(BlockSync {
+            target: height,
+            next_header: tip.index + 1,
+            last_hash: tip.hash,
+            headers: VecDeque::new(),
+            back_step: 1,
+        }))
+    }
+
+    /// What to ask the peer for next, `None` once caught up
+    pub fn next_request(&self) -> Option<SyncRequest> {
+        if let Some(first) = self.headers.front() {
+            return Some(SyncRequest::Blocks {
+                from: first.index,
+                count: (self.headers.len() as u64).min(MAX_SYNC_BATCH),
+            });
+        }
+        (self.next_header <= self.target).then(|| SyncRequest::Headers {
+            from: self.next_header,
+            count: (self.target - self.next_header + 1).min(MAX_SYNC_BATCH),
+        })
+    }
+
+    /// Take the peer's answer to [`Self::next_request`]: check headers, or
+    /// apply blocks to `blockchain`. Returns how many blocks were added. On
+    /// an error the peer can't be trusted to finish, so drop the sync.
+    ///
+    /// Headers that don't link onto our chain move the next request further
+    /// down it, until one links where the branches meet. Headers of blocks
+    /// we already hold are skipped, and the blocks above the fork go to
+    /// [`CommunityBlockchain::add_block`], which keeps them as a side branch
+    /// and reorgs onto it once it outweighs ours.
+    pub fn on_response(&mut self, blockchain: &CommunityBlockchain, response: SyncResponse) -> Result<u64, String> {
+        match response {
+            SyncResponse::Headers(headers) => {
+                if headers.is_empty() {
+                    return Err(format!("Peer claimed height {} but has no header {}", self.target, self.next_header));
+                }
+                if headers[0].index == self.next_header
+                    && headers[0].prev_hash != self.last_hash
+                    && self.headers.is_empty()
+                    && self.holds_last(blockchain)
+                {
+                    return self.step_back(blockchain);
+                }
+                for header in headers {
+                    if header.index != self.next_header {
+                        return Err(format!("Asked for header {}, got header {}", self.next_header, header.index));
+                    }
+                    if header.prev_hash != self.last_hash {
+                        return Err(format!("Header {} doesn't link to the one below it", header.index));
+                    }
+                    if !pow::hash_meets_difficulty(&header.hash, header.difficulty) {
+                        return Err(format!("Header {} doesn't meet its difficulty", header.index));
+                    }
+                    self.next_header += 1;
+                    self.last_hash = header.hash.clone();
+                    if self.headers.is_empty() && self.holds_last(blockchain) {
+                        // Below the fork, we hold this block already
+                        continue;
+                    }
+                    self.headers.push_back(header);
+                }
+                Ok(0)
+            }
+            SyncResponse::Blocks(blocks) => {
+                if blocks.is_empty() {
+                    return Err("Peer sent no blocks".to_string());
+                }
+                let mut added = 0;
+                for block in blocks {
+                    let Some(header) = self.headers.front() else {
+                        return Err(format!("Got block {} that wasn't asked for", block.index));
+                    };
+                    if block.index != header.index || block.hash != header.hash {
+                        return Err(format!("Block {} doesn't match its header", block.index));
+                    }
+                    blockchain.add_block(block)?;
+                    self.headers.pop_front();
+                    added += 1;
+                }
+                Ok(added)
+            }
+            SyncResponse::Status { .. } => Err("Unexpected status response".to_string()),
+        }
+    }
+
+    /// Whether the block `last_hash` names is the one on our chain below
+    /// `next_header`
+    fn holds_last(&self, blockchain: &CommunityBlockchain) -> bool {
+        blockchain
+            .get_block_by_index(self.next_header - 1)
+            .is_some_and(|block| block.hash == self.last_hash)
+    }
+
+    /// Ask for headers further down our chain, the peer's branch having
+    /// left it below `next_header`
+    fn step_back(&mut self, blockchain: &CommunityBlockchain) -> Result<u64, String> {
+        if self.next_header <= 1 {
+            return Err("Peer is on another chain: its genesis block differs from ours".to_string());
+        }
+        self.next_header = self.next_header.saturating_sub(self.back_step).max(1);
+        self.back_step = self.back_step.saturating_mul(2);
+        let below = blockchain
+            .get_block_by_index(self.next_header - 1)
+            .ok_or_else(|| format!("Peer's branch leaves ours below height {}, which was pruned", self.next_header))?;
+        self.last_hash = below.hash;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn chain() -> CommunityBlockchain {
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        CommunityBlockchain::new_in_memory(initial).unwrap()
+    }
+
+    #[test]
+    fn test_late_node_catches_up_in_batches() {
+        // Both start now, so the late node's genesis isn't stamped after
+        // the blocks it syncs
+        let ahead = chain();
+        let behind = chain();
+        let blocks = MAX_SYNC_BATCH + 5;
+        for _ in 0..blocks {
+            ahead.create_transaction("alice".to_string(), "bob".to_string(), 1).unwrap();
+            let block = ahead.mine_block("proposer".to_string()).unwrap();
+            ahead.add_block(block).unwrap();
+        }
+
+        let status = serve(&ahead, SyncRequest::Status);
+        let mut sync = BlockSync::start(&behind, status).unwrap().unwrap();
+        let mut requests = Vec::new();
+        while let Some(request) = sync.next_request() {
+            requests.push(request.clone());
+            sync.on_response(&behind, serve(&ahead, request)).unwrap();
+        }
+        assert_eq!(behind.tip().hash, ahead.tip().hash);
+        assert_eq!(behind.get_balance("bob"), Ok(blocks));
+        // Two batches of headers, then two of blocks
+        assert_eq!(
+            requests,
+            vec![
+                SyncRequest::Headers { from: 1, count: MAX_SYNC_BATCH },
+                SyncRequest::Blocks { from: 1, count: MAX_SYNC_BATCH },
+                SyncRequest::Headers { from: MAX_SYNC_BATCH + 1, count: 5 },
+                SyncRequest::Blocks { from: MAX_SYNC_BATCH + 1, count: 5 },
+            ]
+        );
+
+        // Caught up, there's nothing left to ask for
+        assert!(BlockSync::start(&behind, serve(&ahead, SyncRequest::Status)).unwrap().is_none());
+
+        // Headers that don't link onto our tip are refused before any block
+        let mut sync = BlockSync::start(&chain(), serve(&ahead, SyncRequest::Status)).unwrap().unwrap();
+        let mut headers = serve(&ahead, SyncRequest::Headers { from: 1, count: 2 });
+        if let SyncResponse::Headers(headers) = &mut headers {
+            headers[1].prev_hash = "forged".to_string();
+        }
+        assert!(sync.on_response(&chain(), headers).unwrap_err().contains("doesn't link"));
+
+        // So is a peer on another genesis
+        let mut other = HashMap::new();
+        other.insert("mallory".to_string(), 1000);
+        let other = CommunityBlockchain::new_in_memory(other).unwrap();
+        assert!(BlockSync::start(&other, serve(&ahead, SyncRequest::Status)).is_err());
+        // And one that can't say which genesis it is on
+        let unknown = SyncResponse::Status { height: blocks, genesis: None };
+        assert!(BlockSync::start(&chain(), unknown).unwrap_err().contains("genesis"));
+    }
+
+    #[test]
+    fn test_forked_node_finds_where_branches_meet_and_reorgs() {
+        let ahead = chain();
+        let forked = chain();
+        let mine = |chain: &CommunityBlockchain, from: &str, to: &str| {
+            chain.create_transaction(from.to_string(), to.to_string(), 1).unwrap();
+            let block = chain.mine_block("proposer".to_string()).unwrap();
+            chain.add_block(block.clone()).unwrap();
+            block
+        };
+        for _ in 0..4 {
+            forked.add_block(mine(&ahead, "alice", "bob")).unwrap();
+        }
+        // The nodes part ways above height 4, and the peer's branch is longer
+        for _ in 0..2 {
+            mine(&forked, "bob", "carol");
+        }
+        for _ in 0..5 {
+            mine(&ahead, "alice", "bob");
+        }
+
+        let mut sync = BlockSync::start(&forked, serve(&ahead, SyncRequest::Status)).unwrap().unwrap();
+        let mut requests = Vec::new();
+        while let Some(request) = sync.next_request() {
+            requests.push(request.clone());
+            sync.on_response(&forked, serve(&ahead, request)).unwrap();
+        }
+        assert_eq!(forked.tip().hash, ahead.tip().hash);
+        assert_eq!(forked.get_balance("bob"), Ok(9));
+        assert_eq!(forked.get_balance("carol"), Ok(0));
+        // Its own blocks are kept as a side branch
+        assert_eq!(forked.side_block_count(), 2);
+        // Two steps back from the tip find the fork, and only the peer's
+        // blocks above it are downloaded
+        assert_eq!(
+            requests,
+            vec![
+                SyncRequest::Headers { from: 7, count: 3 },
+                SyncRequest::Headers { from: 6, count: 4 },
+                SyncRequest::Headers { from: 4, count: 6 },
+                SyncRequest::Blocks { from: 5, count: 5 },
+            ]
+        );
+    }
+}
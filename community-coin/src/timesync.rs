@@ -0,0 +1,141 @@
+//! Sanity check of the local clock against peers' clocks.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Protocol name peers exchange pings under
+pub const PING_PROTOCOL: &str = "/community-coin/ping/1";
+
+/// Default seconds the local clock may drift from the peer median
+pub const DEFAULT_CLOCK_SKEW_THRESHOLD_SECS: u64 = 60;
+
+/// Local time in seconds since the Unix epoch, as pings carry it
+pub fn local_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Sent to a peer to sample its clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ping {
+    /// Sender's local time when the ping left
+    pub sent_at: u64,
+}
+
+/// A peer's answer to a [`Ping`], carrying its local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pong {
+    /// Echo of the ping's `sent_at`
+    pub ping_sent_at: u64,
+    /// Responder's local time when the ping arrived
+    pub peer_time: u64,
+}
+
+impl Pong {
+    /// Answer `ping` with our local time `now`
+    pub fn reply(ping: &Ping, now: u64) -> Self {
+        Pong {
+            ping_sent_at: ping.sent_at,
+            peer_time: now,
+        }
+    }
+}
+
+/// How far the local clock is from the peers, as served by `/health`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockStatus {
+    /// Median of peer time minus local time, `None` before any samples
+    pub median_offset_secs: Option<i64>,
+    pub peers_sampled: usize,
+    pub threshold_secs: u64,
+    pub skewed: bool,
+}
+
+/// Keeps the latest clock offset reported by each peer.
+#[derive(Debug)]
+pub struct ClockMonitor {
+    threshold_secs: u64,
+    offsets: Mutex<BTreeMap<String, i64>>,
+}
+
+impl ClockMonitor {
+    pub fn new(threshold_secs: u64) -> Self {
+        ClockMonitor {
+            threshold_secs,
+            offsets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record `peer`'s pong, received at local time `now`. The peer's time
+    /// is compared with the midpoint of the round trip. Returns the median
+    /// offset, after logging a warning, when it exceeds the threshold.
+    pub fn record_pong(&self, peer: &str, pong: &Pong, now: u64) -> Option<i64> {
+        let midpoint = (pong.ping_sent_at as i64 + now as i64) / 2;
+        let offset = pong.peer_time as i64 - midpoint;
+        self.offsets.lock().unwrap().insert(peer.to_string(), offset);
+
+        let status = self.status();
+        if !status.skewed {
+            return None;
+        }
+        let median = status.median_offset_secs?;
+        tracing::warn!(
+            "Peer clocks are {:+}s from the local clock (median of {} peers, threshold {}s)",
+            median, status.peers_sampled, self.threshold_secs
+        );
+        Some(median)
+    }
+
+    /// Stop counting a disconnected peer
+    pub fn forget(&self, peer: &str) {
+        self.offsets.lock().unwrap().remove(peer);
+    }
+
+    pub fn status(&self) -> ClockStatus {
+        let offsets = self.offsets.lock().unwrap();
+        let mut sorted: Vec<i64> = offsets.values().copied().collect();
+        sorted.sort_unstable();
+        let median = match sorted.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(sorted[n / 2]),
+            n => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2),
+        };
+
+        ClockStatus {
+            median_offset_secs: median,
+            peers_sampled: sorted.len(),
+            threshold_secs: self.threshold_secs,
+            skewed: median.is_some_and(|offset| offset.unsigned_abs() > self.threshold_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skewed_peer_clock_raises_warning() {
+        // Node A's clock is correct; node B's runs ten minutes ahead
+        let a_clock = 1_700_000_000;
+        let b_clock = a_clock + 600;
+        let monitor = ClockMonitor::new(60);
+
+        let ping = Ping { sent_at: a_clock };
+        let pong = Pong::reply(&ping, b_clock + 1);
+        let skew = monitor.record_pong("node-b", &pong, a_clock + 2);
+        assert_eq!(skew, Some(600));
+
+        let status = monitor.status();
+        assert!(status.skewed);
+        assert_eq!(status.peers_sampled, 1);
+
+        // A peer in sync with us is within the threshold
+        let healthy = ClockMonitor::new(60);
+        let pong = Pong::reply(&ping, a_clock + 1);
+        assert_eq!(healthy.record_pong("node-c", &pong, a_clock + 2), None);
+        assert!(!healthy.status().skewed);
+    }
+}
@@ -1 +1,544 @@
 //! The Community Coin Virtual Machine (VM) for executing smart contracts.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasmer::wasmparser::Operator;
+use wasmer::sys::{CompilerConfig, Cranelift, EngineBuilder};
+use wasmer::{
+    imports, ExternType, Function, FunctionEnv, FunctionEnvMut, Instance, Memory, Module, RuntimeError,
+    Store, Type, Value,
+};
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
+use wasmer_middlewares::Metering;
+
+use crate::abi::Abi;
+use crate::contract::{AbiType, ContractAbi};
+
+/// Module name contracts import host functions from.
+pub const HOST_MODULE: &str = "env";
+
+/// Gas charged per class of operation. Part of the chain params so node
+/// operators can tune contract economics at genesis.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct GasSchedule {
+    /// Any plain wasm instruction (arithmetic, locals, control flow, loads/stores)
+    pub arithmetic: u64,
+    /// A `memory.grow` instruction
+    pub memory_grow: u64,
+    /// A `get_storage`, `set_storage` or `get_balance` host call
+    pub host_call_storage: u64,
+    /// A `transfer` or `destroy` host call
+    pub host_call_transfer: u64,
+}
+
+impl Default for GasSchedule {
+    fn default() -> Self {
+        GasSchedule {
+            arithmetic: 1,
+            memory_grow: 1_000,
+            host_call_storage: 100,
+            host_call_transfer: 500,
+        }
+    }
+}
+
+impl GasSchedule {
+    /// Cost of a single wasm instruction under this schedule
+    fn instruction_cost(&self, operator: &Operator) -> u64 {
+        match operator {
+            Operator::MemoryGrow { .. } => self.memory_grow,
+            _ => self.arithmetic,
+        }
+    }
+
+    /// Cost of a host call under this schedule
+    fn host_call_cost(&self, abi: Abi) -> u64 {
+        match abi {
+            Abi::GetBalance | Abi::GetStorage | Abi::SetStorage => self.host_call_storage,
+            Abi::Transfer | Abi::SelfDestruct => self.host_call_transfer,
+        }
+    }
+}
+
+/// Errors raised while executing a contract.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum VmError {
+    #[error("failed to compile contract: {0}")]
+    Compile(String),
+    #[error("failed to instantiate contract: {0}")]
+    Instantiate(String),
+    #[error("contract does not export `{0}`")]
+    MissingExport(String),
+    #[error("contract does not match its ABI: {0}")]
+    AbiMismatch(String),
+    #[error("out of gas")]
+    OutOfGas,
+    #[error("contract trapped: {0}")]
+    Trap(String),
+    #[error("`{0}` is not allowed in a view call")]
+    ReadOnly(&'static str),
+}
+
+/// The state a contract call reads and writes. The caller owns applying the
+/// resulting storage and transfers to the chain. A call that traps or runs
+/// out of gas leaves it untouched.
+#[derive(Debug, Clone, Default)]
+pub struct ContractContext {
+    /// Address of the contract being executed
+    pub contract: String,
+    /// The contract's key/value storage
+    pub storage: HashMap<Vec<u8>, Vec<u8>>,
+    /// Balances visible to `get_balance`
+    pub balances: HashMap<String, u64>,
+    /// Transfers requested by the contract, as `(recipient, amount)`
+    pub transfers: Vec<(String, u64)>,
+    /// Set once the contract self-destructs, to who receives its balance
+    pub destroyed: Option<String>,
+}
+
+/// Outcome of a successful contract call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionResult {
+    /// The function's first return value, if any
+    pub return_value: Option<u64>,
+    /// Total gas consumed by instructions and host calls
+    pub gas_used: u64,
+}
+
+/// Mutations made during a call, applied to the context only once the call
+/// returns successfully.
+#[derive(Debug, Default)]
+struct Journal {
+    storage: HashMap<Vec<u8>, Vec<u8>>,
+    transfers: Vec<(String, u64)>,
+    destroyed: Option<String>,
+}
+
+/// Per-call host environment shared with host functions.
+#[derive(Default)]
+struct HostEnv {
+    context: ContractContext,
+    journal: Journal,
+    schedule: GasSchedule,
+    memory: Option<Memory>,
+    instance: Option<Instance>,
+    out_of_gas: bool,
+    /// Set for view calls, which trap on any host call that mutates state
+    read_only: bool,
+    /// The mutating host call a view call trapped on
+    read_only_violation: Option<Abi>,
+}
+
+/// Executes WASM contracts under a gas schedule.
+#[derive(Debug, Clone, Default)]
+pub struct Vm {
+    schedule: GasSchedule,
+}
+
+impl Vm {
+    pub fn new(schedule: GasSchedule) -> Self {
+        Vm { schedule }
+    }
+
+    pub fn schedule(&self) -> &GasSchedule {
+        &self.schedule
+    }
+
+    /// Check that `code` compiles, without running it
+    pub fn validate(&self, code: &[u8]) -> Result<(), VmError> {
+        Module::new(&Store::default(), code).map_err(|e| VmError::Compile(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Check that every function declared in `abi` is exported by `code`
+    /// with exactly the declared argument and return types
+    pub fn check_abi(&self, code: &[u8], abi: &ContractAbi) -> Result<(), VmError> {
+        let module = Module::new(&Store::default(), code).map_err(|e| VmError::Compile(e.to_string()))?;
+        for (i, declared) in abi.functions.iter().enumerate() {
+            if abi.functions[..i].iter().any(|f| f.name == declared.name) {
+                return Err(VmError::AbiMismatch(format!("`{}` is declared twice", declared.name)));
+            }
+            let export = module
+                .exports()
+                .find(|export| export.name() == declared.name)
+                .ok_or_else(|| VmError::MissingExport(declared.name.clone()))?;
+            let ExternType::Function(signature) = export.ty() else {
+                return Err(VmError::AbiMismatch(format!("`{}` is not a function", declared.name)));
+            };
+            let matches = |actual: &[Type], expected: &[AbiType]| {
+                actual.len() == expected.len()
+                    && actual.iter().zip(expected).all(|(a, e)| *a == wasm_type(*e))
+            };
+            if !matches(signature.params(), &declared.args) || !matches(signature.results(), &declared.returns) {
+                return Err(VmError::AbiMismatch(format!(
+                    "`{}` has signature {}",
+                    declared.name, signature
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `function` on the contract `code` (wasm binary or text) with at
+    /// most `gas_limit` gas. Storage writes and transfers are journaled and
+    /// land in `context` only if the call succeeds.
+    pub fn execute(
+        &self,
+        code: &[u8],
+        function: &str,
+        context: &mut ContractContext,
+        gas_limit: u64,
+    ) -> Result<ExecutionResult, VmError> {
+        self.run(code, function, context, gas_limit, false)
+    }
+
+    /// Call `function` as a view: storage and balances can be read, but
+    /// `set_storage`, `transfer` and `destroy` trap at once. `context` is
+    /// never changed.
+    pub fn call_view(
+        &self,
+        code: &[u8],
+        function: &str,
+        context: &ContractContext,
+        gas_limit: u64,
+    ) -> Result<ExecutionResult, VmError> {
+        self.run(code, function, &mut context.clone(), gas_limit, true)
+    }
+
+    fn run(
+        &self,
+        code: &[u8],
+        function: &str,
+        context: &mut ContractContext,
+        gas_limit: u64,
+        read_only: bool,
+    ) -> Result<ExecutionResult, VmError> {
+        let schedule = self.schedule;
+        let metering = Arc::new(Metering::new(gas_limit, move |operator: &Operator| {
+            schedule.instruction_cost(operator)
+        }));
+        let mut compiler = Cranelift::default();
+        compiler.push_middleware(metering);
+        let mut store = Store::new(EngineBuilder::new(compiler));
+
+        let module = Module::new(&store, code).map_err(|e| VmError::Compile(e.to_string()))?;
+
+        let env = FunctionEnv::new(
+            &mut store,
+            HostEnv {
+                context: std::mem::take(context),
+                journal: Journal::default(),
+                schedule,
+                memory: None,
+                instance: None,
+                out_of_gas: false,
+                read_only,
+                read_only_violation: None,
+            },
+        );
+        let import_object = imports! {
+            HOST_MODULE => {
+                Abi::GetBalance.name() => Function::new_typed_with_env(&mut store, &env, host_get_balance),
+                Abi::Transfer.name() => Function::new_typed_with_env(&mut store, &env, host_transfer),
+                Abi::GetStorage.name() => Function::new_typed_with_env(&mut store, &env, host_get_storage),
+                Abi::SetStorage.name() => Function::new_typed_with_env(&mut store, &env, host_set_storage),
+                Abi::SelfDestruct.name() => Function::new_typed_with_env(&mut store, &env, host_destroy),
+            }
+        };
+
+        let outcome = Instance::new(&mut store, &module, &import_object)
+            .map_err(|e| VmError::Instantiate(e.to_string()))
+            .and_then(|instance| {
+                let memory = instance.exports.get_memory("memory").ok().cloned();
+                let host = env.as_mut(&mut store);
+                host.memory = memory;
+                host.instance = Some(instance.clone());
+
+                let entry = instance
+                    .exports
+                    .get_function(function)
+                    .map_err(|_| VmError::MissingExport(function.to_string()))?
+                    .clone();
+                let result = entry.call(&mut store, &[]);
+
+                let remaining = get_remaining_points(&mut store, &instance);
+                let host_out_of_gas = env.as_ref(&store).out_of_gas;
+                // Wasm can't catch a trap, so a violation always ends the call
+                if let Some(abi) = env.as_ref(&store).read_only_violation {
+                    return Err(VmError::ReadOnly(abi.name()));
+                }
+
+                match (result, remaining) {
+                    (_, MeteringPoints::Exhausted) => Err(VmError::OutOfGas),
+                    (Err(_), _) if host_out_of_gas => Err(VmError::OutOfGas),
+                    (Err(e), _) => Err(VmError::Trap(e.message())),
+                    (Ok(values), MeteringPoints::Remaining(points)) => Ok(ExecutionResult {
+                        return_value: values.first().and_then(value_as_u64),
+                        gas_used: gas_limit - points,
+                    }),
+                }
+            });
+
+        let host = env.as_mut(&mut store);
+        *context = std::mem::take(&mut host.context);
+        if outcome.is_ok() {
+            let journal = std::mem::take(&mut host.journal);
+            context.storage.extend(journal.storage);
+            context.transfers.extend(journal.transfers);
+            if journal.destroyed.is_some() {
+                context.destroyed = journal.destroyed;
+            }
+        }
+        outcome
+    }
+}
+
+fn wasm_type(ty: AbiType) -> Type {
+    match ty {
+        AbiType::I32 => Type::I32,
+        AbiType::I64 => Type::I64,
+        AbiType::F32 => Type::F32,
+        AbiType::F64 => Type::F64,
+    }
+}
+
+fn value_as_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::I32(v) => Some(*v as u32 as u64),
+        Value::I64(v) => Some(*v as u64),
+        _ => None,
+    }
+}
+
+/// Deduct the host call's cost from the instance's remaining gas, trapping
+/// once it runs out. In a view call, host calls that mutate state trap
+/// before being charged.
+fn charge_host_call(env: &mut FunctionEnvMut<HostEnv>, abi: Abi) -> Result<(), RuntimeError> {
+    let (host, mut store) = env.data_and_store_mut();
+    if host.read_only && abi.mutates_state() {
+        host.read_only_violation = Some(abi);
+        return Err(RuntimeError::new(format!("`{}` is not allowed in a view call", abi.name())));
+    }
+    let cost = host.schedule.host_call_cost(abi);
+    let instance = host
+        .instance
+        .as_ref()
+        .ok_or_else(|| RuntimeError::new("host call before instantiation"))?;
+
+    match get_remaining_points(&mut store, instance) {
+        MeteringPoints::Remaining(points) if points >= cost => {
+            set_remaining_points(&mut store, instance, points - cost);
+            Ok(())
+        }
+        _ => {
+            set_remaining_points(&mut store, instance, 0);
+            host.out_of_gas = true;
+            Err(RuntimeError::new("out of gas"))
+        }
+    }
+}
+
+fn read_memory(env: &FunctionEnvMut<HostEnv>, ptr: u32, len: u32) -> Result<Vec<u8>, RuntimeError> {
+    let memory = env
+        .data()
+        .memory
+        .as_ref()
+        .ok_or_else(|| RuntimeError::new("contract does not export memory"))?;
+    let mut buf = vec![0u8; len as usize];
+    memory
+        .view(env)
+        .read(ptr as u64, &mut buf)
+        .map_err(|e| RuntimeError::new(e.to_string()))?;
+    Ok(buf)
+}
+
+fn write_memory(env: &FunctionEnvMut<HostEnv>, ptr: u32, data: &[u8]) -> Result<(), RuntimeError> {
+    let memory = env
+        .data()
+        .memory
+        .as_ref()
+        .ok_or_else(|| RuntimeError::new("contract does not export memory"))?;
+    memory
+        .view(env)
+        .write(ptr as u64, data)
+        .map_err(|e| RuntimeError::new(e.to_string()))
+}
+
+fn host_get_balance(
+    mut env: FunctionEnvMut<HostEnv>,
+    address_ptr: u32,
+    address_len: u32,
+) -> Result<u64, RuntimeError> {
+    charge_host_call(&mut env, Abi::GetBalance)?;
+    let address = read_memory(&env, address_ptr, address_len)?;
+    let address = String::from_utf8_lossy(&address);
+    Ok(env.data().context.balances.get(address.as_ref()).copied().unwrap_or(0))
+}
+
+fn host_transfer(
+    mut env: FunctionEnvMut<HostEnv>,
+    to_ptr: u32,
+    to_len: u32,
+    amount: u64,
+) -> Result<(), RuntimeError> {
+    charge_host_call(&mut env, Abi::Transfer)?;
+    let to = read_memory(&env, to_ptr, to_len)?;
+    let to = String::from_utf8_lossy(&to).into_owned();
+    env.data_mut().journal.transfers.push((to, amount));
+    Ok(())
+}
+
+fn host_destroy(
+    mut env: FunctionEnvMut<HostEnv>,
+    beneficiary_ptr: u32,
+    beneficiary_len: u32,
+) -> Result<(), RuntimeError> {
+    charge_host_call(&mut env, Abi::SelfDestruct)?;
+    let beneficiary = read_memory(&env, beneficiary_ptr, beneficiary_len)?;
+    let beneficiary = String::from_utf8_lossy(&beneficiary).into_owned();
+    env.data_mut().journal.destroyed = Some(beneficiary);
+    Ok(())
+}
+
+fn host_get_storage(
+    mut env: FunctionEnvMut<HostEnv>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<u32, RuntimeError> {
+    charge_host_call(&mut env, Abi::GetStorage)?;
+    let key = read_memory(&env, key_ptr, key_len)?;
+    let host = env.data();
+    let value = match host.journal.storage.get(&key).or_else(|| host.context.storage.get(&key)) {
+        Some(value) => value.clone(),
+        None => return Ok(0),
+    };
+    let written = value.len().min(value_len as usize);
+    write_memory(&env, value_ptr, &value[..written])?;
+    Ok(written as u32)
+}
+
+fn host_set_storage(
+    mut env: FunctionEnvMut<HostEnv>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<(), RuntimeError> {
+    charge_host_call(&mut env, Abi::SetStorage)?;
+    let key = read_memory(&env, key_ptr, key_len)?;
+    let value = read_memory(&env, value_ptr, value_len)?;
+    env.data_mut().journal.storage.insert(key, value);
+    Ok(())
+}
+
+/// The `example-contract/counter-contract` logic in wasm text form.
+#[cfg(test)]
+pub(crate) const COUNTER_WAT: &str = r#"
+(module
+  (import "env" "get_storage" (func $get_storage (param i32 i32 i32 i32) (result i32)))
+  (import "env" "set_storage" (func $set_storage (param i32 i32 i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "count")
+  (func $load (result i64)
+    (if (result i64)
+      (i32.gt_u (call $get_storage (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)) (i32.const 0))
+      (then (i64.load (i32.const 16)))
+      (else (i64.const 0))))
+  (func (export "increment")
+    (i64.store (i32.const 16) (i64.add (call $load) (i64.const 1)))
+    (call $set_storage (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)))
+  (func (export "get_count") (result i64)
+    (call $load)))
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_gas_depends_on_schedule() {
+        let cheap = Vm::new(GasSchedule::default());
+        let expensive = Vm::new(GasSchedule {
+            arithmetic: 3,
+            host_call_storage: 1_000,
+            ..GasSchedule::default()
+        });
+
+        let mut cheap_context = ContractContext::default();
+        let cheap_result = cheap
+            .execute(COUNTER_WAT.as_bytes(), "increment", &mut cheap_context, 1_000_000)
+            .unwrap();
+        let mut expensive_context = ContractContext::default();
+        let expensive_result = expensive
+            .execute(COUNTER_WAT.as_bytes(), "increment", &mut expensive_context, 1_000_000)
+            .unwrap();
+
+        assert!(cheap_result.gas_used > 0);
+        assert!(expensive_result.gas_used > cheap_result.gas_used);
+        assert_eq!(cheap_context.storage, expensive_context.storage);
+
+        let count = cheap
+            .execute(COUNTER_WAT.as_bytes(), "get_count", &mut cheap_context, 1_000_000)
+            .unwrap();
+        assert_eq!(count.return_value, Some(1));
+    }
+
+    #[test]
+    fn test_call_view_traps_on_mutation() {
+        let vm = Vm::default();
+        let mut context = ContractContext::default();
+        vm.execute(COUNTER_WAT.as_bytes(), "increment", &mut context, 1_000_000)
+            .unwrap();
+        let before = context.storage.clone();
+
+        let count = vm
+            .call_view(COUNTER_WAT.as_bytes(), "get_count", &context, 1_000_000)
+            .unwrap();
+        assert_eq!(count.return_value, Some(1));
+
+        let result = vm.call_view(COUNTER_WAT.as_bytes(), "increment", &context, 1_000_000);
+        assert_eq!(result, Err(VmError::ReadOnly("set_storage")));
+        assert_eq!(context.storage, before);
+    }
+
+    #[test]
+    fn test_out_of_gas() {
+        let vm = Vm::default();
+        let mut context = ContractContext::default();
+
+        let result = vm.execute(COUNTER_WAT.as_bytes(), "increment", &mut context, 50);
+
+        assert_eq!(result, Err(VmError::OutOfGas));
+        assert!(context.storage.is_empty());
+    }
+
+    #[test]
+    fn test_trap_rolls_back_storage_writes() {
+        // Bumps the counter, then traps before returning
+        let faulty = COUNTER_WAT.replace(
+            "(call $set_storage (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)))",
+            "(call $set_storage (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8))
+    unreachable)",
+        );
+        let vm = Vm::default();
+        let mut context = ContractContext::default();
+        vm.execute(COUNTER_WAT.as_bytes(), "increment", &mut context, 1_000_000)
+            .unwrap();
+        let before = context.storage.clone();
+
+        let result = vm.execute(faulty.as_bytes(), "increment", &mut context, 1_000_000);
+
+        assert!(matches!(result, Err(VmError::Trap(_))));
+        assert_eq!(context.storage, before);
+        let count = vm
+            .execute(COUNTER_WAT.as_bytes(), "get_count", &mut context, 1_000_000)
+            .unwrap();
+        assert_eq!(count.return_value, Some(1));
+    }
+}
@@ -0,0 +1,195 @@
+//! Block-production webhooks: every block the node adds is POSTed, as a
+//! short summary, to each configured URL.
+//!
+//! Delivery is best effort. A failed POST is retried a few times and then
+//! logged, and never holds up or fails block processing.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use crate::blockchain::Block;
+
+/// Time one webhook POST gets before it counts as failed
+pub const DEFAULT_WEBHOOK_TIMEOUT_SECS: u64 = 5;
+
+/// Extra attempts after a failed POST
+pub const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+
+/// The configured webhook URLs and how to deliver to them
+#[derive(Clone)]
+pub struct Webhooks {
+    urls: Arc<Mutex<Vec<String>>>,
+    client: reqwest::Client,
+    retries: u32,
+    backoff: Duration, // wait before the first retry, doubled for each one after
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks::new(
+            Vec::new(),
+            Duration::from_secs(DEFAULT_WEBHOOK_TIMEOUT_SECS),
+            DEFAULT_WEBHOOK_RETRIES,
+        )
+    }
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>, timeout: Duration, retries: u32) -> Self {
+        Webhooks {
+            urls: Arc::new(Mutex::new(urls)),
+            client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
+            retries,
+            backoff: Duration::from_millis(500),
+        }
+    }
+
+    /// Parse a comma-separated list of URLs, as in `WEBHOOK_URLS`
+    pub fn parse_urls(list: &str) -> Result<Vec<String>, String> {
+        let urls: Vec<String> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+        Self::check_urls(&urls)?;
+        Ok(urls)
+    }
+
+    fn check_urls(urls: &[String]) -> Result<(), String> {
+        for url in urls {
+            let parsed = url::Url::parse(url).map_err(|e| format!("Invalid webhook URL {}: {}", url, e))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(format!("Webhook URL {} must be http or https", url));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        self.urls.lock().unwrap().clone()
+    }
+
+    /// Replace the URLs notified of new blocks
+    pub fn set_urls(&self, urls: Vec<String>) -> Result<(), String> {
+        Self::check_urls(&urls)?;
+        *self.urls.lock().unwrap() = urls;
+        Ok(())
+    }
+
+    /// What is POSTed for `block`
+    pub fn summary(block: &Block) -> serde_json::Value {
+        json!({
+            "index": block.index,
+            "hash": block.hash,
+            "prev_hash": block.prev_hash,
+            "timestamp": block.timestamp,
+            "proposer": block.proposer,
+            "transactions": block.transactions.len(),
+            "state_root": block.state_root,
+        })
+    }
+
+    /// POST `block`'s summary to every URL at once, logging the ones that
+    /// still fail after all retries
+    pub async fn notify(&self, block: &Block) {
+        let summary = Self::summary(block);
+        let deliveries = self.urls().into_iter().map(|url| {
+            let summary = &summary;
+            async move {
+                if let Err(e) = self.deliver(&url, summary).await {
+                    tracing::warn!(%url, height = block.index, "Webhook delivery failed: {}", e);
+                }
+            }
+        });
+        futures::future::join_all(deliveries).await;
+    }
+
+    async fn deliver(&self, url: &str, summary: &serde_json::Value) -> Result<(), String> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let result = match self.client.post(url).json(summary).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => Err(format!("HTTP {}", response.status())),
+                Err(e) => Err(e.to_string()),
+            };
+            if attempt == self.retries {
+                return result;
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    /// Notify the webhooks of each block from `blocks` until the chain is
+    /// dropped. Blocks missed while lagging behind are skipped.
+    pub fn spawn(self, mut blocks: broadcast::Receiver<Block>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match blocks.recv().await {
+                    Ok(block) => self.notify(&block).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Webhooks skipped {} blocks", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::CommunityBlockchain;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_webhook_receives_block_summary_after_retry() {
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        // The first delivery fails, so the payload only arrives on a retry
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |axum::Json(payload): axum::Json<serde_json::Value>| async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                received_tx.send(payload).unwrap();
+                axum::http::StatusCode::OK
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut initial = HashMap::new();
+        initial.insert("alice".to_string(), 1000);
+        let blockchain = CommunityBlockchain::new_in_memory(initial).unwrap();
+        let mut webhooks = Webhooks::new(Vec::new(), Duration::from_secs(5), 2);
+        webhooks.backoff = Duration::from_millis(10);
+        assert!(webhooks.set_urls(vec!["ftp://example.com".to_string()]).is_err());
+        webhooks.set_urls(Webhooks::parse_urls(&format!("http://{}/hook", addr)).unwrap()).unwrap();
+        webhooks.spawn(blockchain.subscribe_blocks());
+
+        blockchain.create_transaction("alice".to_string(), "bob".to_string(), 10).unwrap();
+        let block = blockchain.mine_block("proposer".to_string()).unwrap();
+        blockchain.add_block(block.clone()).unwrap();
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), received_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, Webhooks::summary(&block));
+        assert_eq!(payload["index"], 1);
+        assert_eq!(payload["hash"], block.hash);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}